@@ -0,0 +1,366 @@
+//! Local HTTP agent that runs requests for the web app.
+//!
+//! Browsers can't use client certificates, custom root certificates or
+//! NTLM proxies for `fetch`, so the web app hands requests to this agent on
+//! `localhost` instead. Every call except the handshake needs the pairing
+//! token printed at startup, and browsers are limited to the configured
+//! origins by CORS.
+//!
+//! Endpoints:
+//! - `GET /handshake` tells the app an agent is listening.
+//! - `POST /request` runs a JSON `RequestWithMetadata` and answers with the
+//!   `ResponseWithMetadata`, or `{"error": RelayError}` with status 502.
+//! - `POST /resolve` substitutes variables in a `{"request", "scopes"}`
+//!   template and answers with the `ResolvedRequest`.
+//! - `POST /cancel/{req_id}` cancels a running request.
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    error::{RelayError, RelayResult},
+    interop::RequestWithMetadata,
+    relay::run_request_task,
+    server::{
+        is_loopback_host, read_request, write_response, HttpRequest, HttpResponse, READ_TIMEOUT,
+    },
+    variables::{resolve_request, VariableScope},
+};
+
+pub const DEFAULT_AGENT_PORT: u16 = 9119;
+
+#[derive(Clone, Debug)]
+pub struct AgentConfig {
+    pub port: u16,
+    /// Secret the app has to send as `Authorization: Bearer <token>`.
+    pub token: String,
+    /// Origins browsers may call the agent from, e.g. `https://app.example.com`.
+    pub allowed_origins: Vec<String>,
+}
+
+impl AgentConfig {
+    /// A random 32 byte token, hex encoded.
+    pub fn generate_token() -> String {
+        rand::thread_rng()
+            .gen::<[u8; 32]>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct Agent {
+    config: Arc<AgentConfig>,
+    cancellation_tokens: Arc<Mutex<HashMap<usize, CancellationToken>>>,
+}
+
+/// Answer of the agent with a serialized `RelayError` as its body.
+fn error_response(status: u16, error: RelayError) -> HttpResponse {
+    #[derive(Serialize)]
+    struct ErrorBody {
+        error: RelayError,
+    }
+    HttpResponse::json(status, &ErrorBody { error })
+}
+
+impl Agent {
+    pub fn new(config: AgentConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            cancellation_tokens: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Binds the configured port on the loopback interface only.
+    pub fn listen(&self) -> RelayResult<TcpListener> {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, self.config.port)).map_err(|err| {
+            RelayError::RequestRunError(format!(
+                "Failed to listen on port {}: {}",
+                self.config.port, err
+            ))
+        })
+    }
+
+    /// Accepts connections until the listener fails, one thread per connection.
+    pub fn serve(&self, listener: TcpListener) -> RelayResult<()> {
+        log::info!(
+            "Agent listening on {:?} for origins {:?}",
+            listener.local_addr(),
+            self.config.allowed_origins
+        );
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warn!("Failed to accept agent connection: {}", err);
+                    continue;
+                }
+            };
+
+            let agent = self.clone();
+            std::thread::Builder::new()
+                .name("relay-agent".to_string())
+                .spawn(move || agent.handle_connection(stream))
+                .map_err(|err| RelayError::RequestRunError(err.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) {
+        if let Err(err) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+            log::warn!("Failed to set agent read timeout: {}", err);
+        }
+
+        let (response, origin) = match read_request(&mut stream) {
+            Ok(request) => {
+                let origin = request.header("origin").map(str::to_string);
+                (self.respond(request), origin)
+            }
+            Err(status) => (HttpResponse::empty(status), None),
+        };
+
+        if let Err(err) = self.write_response(&mut stream, response, origin.as_deref()) {
+            log::debug!("Failed to write agent response: {}", err);
+        }
+    }
+
+    fn respond(&self, request: HttpRequest) -> HttpResponse {
+        // Browsers send the name they resolved, so a foreign host points at
+        // a DNS rebinding attempt.
+        if !request.header("host").is_some_and(is_loopback_host) {
+            log::warn!(
+                "Rejected agent request for host {:?}",
+                request.header("host")
+            );
+            return HttpResponse::empty(403);
+        }
+
+        if let Some(origin) = request.header("origin") {
+            if !self.origin_allowed(origin) {
+                log::warn!("Rejected agent request from origin {}", origin);
+                return HttpResponse::empty(403);
+            }
+        }
+
+        match (request.method.as_str(), request.path.as_str()) {
+            ("OPTIONS", _) => HttpResponse::empty(204),
+            ("GET", "/handshake") => HttpResponse::json(
+                200,
+                &serde_json::json!({
+                    "status": "ok",
+                    "version": env!("CARGO_PKG_VERSION"),
+                }),
+            ),
+            _ if !self.authorized(&request) => HttpResponse::empty(401),
+            ("POST", "/request") => self.run_request(&request.body),
+            ("POST", "/resolve") => resolve(&request.body),
+            ("POST", path) => match path
+                .strip_prefix("/cancel/")
+                .and_then(|req_id| req_id.parse::<usize>().ok())
+            {
+                Some(req_id) => self.cancel_request(req_id),
+                None => HttpResponse::empty(404),
+            },
+            _ => HttpResponse::empty(404),
+        }
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        self.config
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/') == origin)
+    }
+
+    fn authorized(&self, request: &HttpRequest) -> bool {
+        request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| constant_time_eq(token.as_bytes(), self.config.token.as_bytes()))
+    }
+
+    fn run_request(&self, body: &[u8]) -> HttpResponse {
+        let req: RequestWithMetadata = match serde_json::from_slice(body) {
+            Ok(req) => req,
+            Err(err) => {
+                return error_response(
+                    400,
+                    RelayError::RequestRunError(format!("Invalid request: {}", err)),
+                )
+            }
+        };
+
+        let req_id = req.req_id;
+        let cancel_token = CancellationToken::new();
+        // Same as the desktop app, a request with the ID of a running one
+        // takes over its cancellation slot.
+        self.tokens().insert(req_id, cancel_token.clone());
+
+        let result = run_request_task(&req, cancel_token.clone());
+        self.tokens().remove(&req_id);
+
+        match result {
+            Ok(response) => HttpResponse::json(200, &response),
+            // curl reports the abort from the progress callback as a plain error.
+            Err(_) if cancel_token.is_cancelled() => {
+                error_response(502, RelayError::RequestCancelled)
+            }
+            Err(err) => error_response(502, err),
+        }
+    }
+
+    fn cancel_request(&self, req_id: usize) -> HttpResponse {
+        match self.tokens().remove(&req_id) {
+            Some(token) => {
+                token.cancel();
+                HttpResponse::empty(204)
+            }
+            None => HttpResponse::empty(404),
+        }
+    }
+
+    fn tokens(&self) -> std::sync::MutexGuard<'_, HashMap<usize, CancellationToken>> {
+        self.cancellation_tokens
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write_response(
+        &self,
+        stream: &mut TcpStream,
+        mut response: HttpResponse,
+        origin: Option<&str>,
+    ) -> std::io::Result<()> {
+        if let Some(origin) = origin.filter(|origin| self.origin_allowed(origin)) {
+            response.headers.extend(
+                [
+                    ("Access-Control-Allow-Origin", origin),
+                    ("Access-Control-Allow-Methods", "GET, POST, OPTIONS"),
+                    (
+                        "Access-Control-Allow-Headers",
+                        "Authorization, Content-Type",
+                    ),
+                    ("Access-Control-Max-Age", "600"),
+                    ("Vary", "Origin"),
+                ]
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+            );
+        }
+
+        write_response(stream, &response, false)
+    }
+}
+
+fn resolve(body: &[u8]) -> HttpResponse {
+    #[derive(Deserialize)]
+    struct ResolveBody {
+        request: RequestWithMetadata,
+        #[serde(default)]
+        scopes: Vec<VariableScope>,
+    }
+
+    match serde_json::from_slice::<ResolveBody>(body) {
+        Ok(body) => HttpResponse::json(200, &resolve_request(&body.request, &body.scopes)),
+        Err(err) => error_response(
+            400,
+            RelayError::RequestRunError(format!("Invalid request: {}", err)),
+        ),
+    }
+}
+
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |diff, (left, right)| diff | (left ^ right))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn send(port: u16, request: &str) -> String {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn guards_requests_with_token_origin_and_host() {
+        let agent = Agent::new(AgentConfig {
+            port: 0,
+            token: "secret".to_string(),
+            allowed_origins: vec!["https://app.example.com/".to_string()],
+        });
+        let listener = agent.listen().unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || agent.serve(listener));
+
+        let response = send(
+            port,
+            "OPTIONS /request HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example.com\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 204"));
+        assert!(response.contains("Access-Control-Allow-Origin: https://app.example.com\r\n"));
+
+        let response = send(
+            port,
+            "GET /handshake HTTP/1.1\r\nHost: localhost\r\nOrigin: https://evil.example\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 403"));
+        assert!(!response.contains("Access-Control-Allow-Origin"));
+
+        let response = send(
+            port,
+            "GET /handshake HTTP/1.1\r\nHost: evil.example\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 403"));
+
+        let response = send(
+            port,
+            "GET /handshake HTTP/1.1\r\nHost: 127.0.0.1:9119\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("\"status\":\"ok\""));
+
+        let response = send(
+            port,
+            "POST /cancel/1 HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer wrong\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        let response = send(
+            port,
+            "POST /cancel/1 HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        let body = "{\"not\": \"a request\"}";
+        let response = send(
+            port,
+            &format!(
+                "POST /request HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret\r\n\
+                 Content-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        );
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert!(response.contains("Invalid request"));
+    }
+}
@@ -0,0 +1,482 @@
+//! Declarative checks on responses.
+//!
+//! A request carries its assertions in `RequestWithMetadata::assertions`.
+//! They are evaluated as soon as the response is complete, by
+//! `run_request_task`, the engine and the HTTP cache alike, so the report in
+//! `ResponseWithMetadata::assertions` is the same wherever a request runs.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    interop::{RequestWithMetadata, ResponseWithMetadata},
+    runner::status_matches,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Assertion {
+    /// `200`, a class such as `2xx` or a range such as `200-299`.
+    Status {
+        expected: String,
+    },
+    HeaderPresent {
+        name: String,
+    },
+    HeaderEquals {
+        name: String,
+        value: String,
+    },
+    HeaderMatches {
+        name: String,
+        pattern: String,
+    },
+    /// Compares the values an RFC 9535 JSONPath query selects. Passes when
+    /// any selected value satisfies the comparison, or for `not_exists`
+    /// when nothing is selected.
+    JsonPath {
+        path: String,
+        op: CompareOp,
+        #[serde(default)]
+        value: Value,
+    },
+    /// Compares the string, number or boolean an XPath 1.0 expression
+    /// evaluates to. A node set compares by the string value of its first
+    /// node.
+    #[serde(rename = "xpath")]
+    XPath {
+        path: String,
+        op: CompareOp,
+        #[serde(default)]
+        value: Value,
+    },
+    BodyContains {
+        text: String,
+    },
+    ResponseTime {
+        max_ms: f64,
+    },
+    /// Validates the JSON body against a JSON Schema, draft detected from
+    /// `$schema`.
+    JsonSchema {
+        schema: Value,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompareOp {
+    Equals,
+    NotEquals,
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
+    /// Substring of a string or element of an array.
+    Contains,
+    /// Regular expression match on the string value.
+    Matches,
+    Exists,
+    NotExists,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AssertionResult {
+    pub assertion: Assertion,
+    pub passed: bool,
+    /// The value that was checked, when there was one.
+    #[serde(default)]
+    pub actual: Option<Value>,
+    /// Why the assertion failed.
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AssertionReport {
+    pub passed: usize,
+    pub failed: usize,
+    pub results: Vec<AssertionResult>,
+}
+
+impl AssertionReport {
+    pub fn success(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+/// Evaluates `assertions` in order against `response`.
+pub fn evaluate_assertions(
+    assertions: &[Assertion],
+    response: &ResponseWithMetadata,
+) -> AssertionReport {
+    let body = Body::new(&response.data);
+    let mut report = AssertionReport::default();
+
+    for assertion in assertions {
+        let (actual, failure) = match check(assertion, response, &body) {
+            Ok(actual) => (actual, None),
+            Err((actual, message)) => (actual, Some(message)),
+        };
+        if failure.is_none() {
+            report.passed += 1;
+        } else {
+            report.failed += 1;
+        }
+        report.results.push(AssertionResult {
+            assertion: assertion.clone(),
+            passed: failure.is_none(),
+            actual,
+            message: failure,
+        });
+    }
+
+    report
+}
+
+/// Sets the report for the assertions of `req` on `response`.
+pub(crate) fn attach_report(req: &RequestWithMetadata, response: &mut ResponseWithMetadata) {
+    response.assertions =
+        (!req.assertions.is_empty()).then(|| evaluate_assertions(&req.assertions, response));
+}
+
+/// The response body, parsed on first use by the assertions that need it.
+struct Body<'a> {
+    data: &'a [u8],
+    json: std::cell::OnceCell<Result<Value, String>>,
+}
+
+impl<'a> Body<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            json: std::cell::OnceCell::new(),
+        }
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(self.data).into_owned()
+    }
+
+    fn json(&self) -> Result<&Value, String> {
+        self.json
+            .get_or_init(|| {
+                serde_json::from_slice(self.data)
+                    .map_err(|err| format!("Body is not valid JSON: {}", err))
+            })
+            .as_ref()
+            .map_err(Clone::clone)
+    }
+}
+
+type CheckResult = Result<Option<Value>, (Option<Value>, String)>;
+
+fn check(assertion: &Assertion, response: &ResponseWithMetadata, body: &Body) -> CheckResult {
+    let header = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|header| header.key.eq_ignore_ascii_case(name))
+            .map(|header| header.value.clone())
+    };
+
+    match assertion {
+        Assertion::Status { expected } => {
+            let actual = Some(Value::from(response.status));
+            if status_matches(expected, response.status) {
+                Ok(actual)
+            } else {
+                Err((
+                    actual,
+                    format!("Expected status {}, got {}", expected, response.status),
+                ))
+            }
+        }
+        Assertion::HeaderPresent { name } => match header(name) {
+            Some(value) => Ok(Some(Value::String(value))),
+            None => Err((None, format!("Header {} is missing", name))),
+        },
+        Assertion::HeaderEquals { name, value } => match header(name) {
+            Some(actual) if actual == *value => Ok(Some(Value::String(actual))),
+            Some(actual) => Err((
+                Some(Value::String(actual.clone())),
+                format!(
+                    "Expected header {} to be '{}', got '{}'",
+                    name, value, actual
+                ),
+            )),
+            None => Err((None, format!("Header {} is missing", name))),
+        },
+        Assertion::HeaderMatches { name, pattern } => {
+            let regex = regex::Regex::new(pattern)
+                .map_err(|err| (None, format!("Invalid pattern '{}': {}", pattern, err)))?;
+            match header(name) {
+                Some(actual) if regex.is_match(&actual) => Ok(Some(Value::String(actual))),
+                Some(actual) => Err((
+                    Some(Value::String(actual.clone())),
+                    format!("Header {} '{}' doesn't match /{}/", name, actual, pattern),
+                )),
+                None => Err((None, format!("Header {} is missing", name))),
+            }
+        }
+        Assertion::JsonPath { path, op, value } => {
+            let query = serde_json_path::JsonPath::parse(path)
+                .map_err(|err| (None, format!("Invalid JSONPath '{}': {}", path, err)))?;
+            let json = body.json().map_err(|err| (None, err))?;
+            let selected = query.query(json).all();
+            let actual = match selected.as_slice() {
+                [] => None,
+                [single] => Some((*single).clone()),
+                many => Some(Value::Array(
+                    many.iter().map(|value| (*value).clone()).collect(),
+                )),
+            };
+
+            let passed = match op {
+                CompareOp::Exists => !selected.is_empty(),
+                CompareOp::NotExists => selected.is_empty(),
+                _ => selected
+                    .iter()
+                    .try_fold(false, |passed, selected| {
+                        Ok::<_, String>(passed || compare(selected, *op, value)?)
+                    })
+                    .map_err(|err| (actual.clone(), err))?,
+            };
+            if passed {
+                Ok(actual)
+            } else {
+                Err((
+                    actual.clone(),
+                    comparison_failure(path, *op, value, &actual),
+                ))
+            }
+        }
+        Assertion::XPath { path, op, value } => {
+            let actual = evaluate_xpath(&body.text(), path).map_err(|err| (None, err))?;
+            let passed = match (op, &actual) {
+                (CompareOp::Exists, actual) => actual.is_some(),
+                (CompareOp::NotExists, actual) => actual.is_none(),
+                (_, Some(actual)) => compare(actual, *op, value).map_err(|err| (None, err))?,
+                (_, None) => false,
+            };
+            if passed {
+                Ok(actual)
+            } else {
+                Err((
+                    actual.clone(),
+                    comparison_failure(path, *op, value, &actual),
+                ))
+            }
+        }
+        Assertion::BodyContains { text } => {
+            if body.text().contains(text.as_str()) {
+                Ok(None)
+            } else {
+                Err((None, format!("Body doesn't contain '{}'", text)))
+            }
+        }
+        Assertion::ResponseTime { max_ms } => {
+            let duration_ms = response.time_end_ms.saturating_sub(response.time_start_ms) as f64;
+            let actual = Some(Value::from(duration_ms));
+            if duration_ms <= *max_ms {
+                Ok(actual)
+            } else {
+                Err((
+                    actual,
+                    format!("Took {} ms, more than {} ms", duration_ms, max_ms),
+                ))
+            }
+        }
+        Assertion::JsonSchema { schema } => {
+            let validator = jsonschema::validator_for(schema)
+                .map_err(|err| (None, format!("Invalid JSON Schema: {}", err)))?;
+            let json = body.json().map_err(|err| (None, err))?;
+            let errors = validator
+                .iter_errors(json)
+                .map(|err| {
+                    let location = err.instance_path.to_string();
+                    if location.is_empty() {
+                        err.to_string()
+                    } else {
+                        format!("{}: {}", location, err)
+                    }
+                })
+                .collect::<Vec<_>>();
+            if errors.is_empty() {
+                Ok(None)
+            } else {
+                Err((None, errors.join("; ")))
+            }
+        }
+    }
+}
+
+fn compare(actual: &Value, op: CompareOp, expected: &Value) -> Result<bool, String> {
+    let ordered = |check: fn(f64, f64) -> bool| match (actual.as_f64(), number(expected)) {
+        (Some(actual), Some(expected)) => Ok(check(actual, expected)),
+        _ => Ok(false),
+    };
+
+    match op {
+        CompareOp::Equals => Ok(loosely_equal(actual, expected)),
+        CompareOp::NotEquals => Ok(!loosely_equal(actual, expected)),
+        CompareOp::LessThan => ordered(|a, b| a < b),
+        CompareOp::LessOrEqual => ordered(|a, b| a <= b),
+        CompareOp::GreaterThan => ordered(|a, b| a > b),
+        CompareOp::GreaterOrEqual => ordered(|a, b| a >= b),
+        CompareOp::Contains => Ok(match actual {
+            Value::String(text) => text.contains(&text_value(expected)),
+            Value::Array(items) => items.iter().any(|item| loosely_equal(item, expected)),
+            Value::Object(object) => expected
+                .as_str()
+                .is_some_and(|key| object.contains_key(key)),
+            _ => false,
+        }),
+        CompareOp::Matches => {
+            let pattern = text_value(expected);
+            let regex = regex::Regex::new(&pattern)
+                .map_err(|err| format!("Invalid pattern '{}': {}", pattern, err))?;
+            Ok(regex.is_match(&text_value(actual)))
+        }
+        CompareOp::Exists | CompareOp::NotExists => Ok(true),
+    }
+}
+
+/// Equality that lets `"42"` match `42`, as XPath results and values typed
+/// in a form lose their JSON type.
+fn loosely_equal(actual: &Value, expected: &Value) -> bool {
+    if actual == expected {
+        return true;
+    }
+    match (number(actual), number(expected)) {
+        (Some(actual), Some(expected)) => actual == expected,
+        _ => match (actual, expected) {
+            (Value::String(_), _) | (_, Value::String(_)) => {
+                text_value(actual) == text_value(expected)
+            }
+            _ => false,
+        },
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn text_value(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn comparison_failure(
+    path: &str,
+    op: CompareOp,
+    expected: &Value,
+    actual: &Option<Value>,
+) -> String {
+    let actual = actual
+        .as_ref()
+        .map_or_else(|| "nothing".to_string(), Value::to_string);
+    match op {
+        CompareOp::Exists => format!("{} selected nothing", path),
+        CompareOp::NotExists => format!("{} selected {}", path, actual),
+        _ => format!(
+            "Expected {} to satisfy {:?} {}, got {}",
+            path, op, expected, actual
+        ),
+    }
+}
+
+/// Result of `xpath` on the XML `text`, `None` for an empty node set.
+fn evaluate_xpath(text: &str, xpath: &str) -> Result<Option<Value>, String> {
+    let package = sxd_document::parser::parse(text)
+        .map_err(|err| format!("Body is not valid XML: {:?}", err))?;
+    let document = package.as_document();
+    let value = sxd_xpath::evaluate_xpath(&document, xpath)
+        .map_err(|err| format!("Invalid XPath '{}': {}", xpath, err))?;
+
+    Ok(match value {
+        sxd_xpath::Value::Nodeset(nodes) => nodes
+            .document_order_first()
+            .map(|node| Value::String(node.string_value())),
+        sxd_xpath::Value::Boolean(value) => Some(Value::Bool(value)),
+        sxd_xpath::Value::Number(value) => Some(Value::from(value)),
+        sxd_xpath::Value::String(value) => Some(Value::String(value)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interop::KeyValuePair;
+
+    #[test]
+    fn reports_each_assertion() {
+        let mut response: ResponseWithMetadata = serde_json::from_value(serde_json::json!({
+            "status": 201,
+            "status_text": "Created",
+            "headers": [],
+            "data": br#"{"id": 7, "tags": ["a", "b"], "user": {"name": "Ada"}}"#.to_vec(),
+            "time_start_ms": 1000,
+            "time_end_ms": 1150
+        }))
+        .unwrap();
+        response.headers = vec![KeyValuePair {
+            key: "Content-Type".to_string(),
+            value: "application/json; charset=utf-8".to_string(),
+        }];
+
+        let assertions: Vec<Assertion> = serde_json::from_value(serde_json::json!([
+            {"type": "status", "expected": "2xx"},
+            {"type": "header_matches", "name": "content-type", "pattern": "^application/json"},
+            {"type": "header_present", "name": "ETag"},
+            {"type": "json_path", "path": "$.id", "op": "greater_or_equal", "value": 7},
+            {"type": "json_path", "path": "$.tags", "op": "contains", "value": "b"},
+            {"type": "json_path", "path": "$.user.email", "op": "exists"},
+            {"type": "body_contains", "text": "Ada"},
+            {"type": "response_time", "max_ms": 100},
+            {"type": "json_schema", "schema": {
+                "type": "object",
+                "required": ["id", "user"],
+                "properties": {"id": {"type": "string"}}
+            }}
+        ]))
+        .unwrap();
+
+        let report = evaluate_assertions(&assertions, &response);
+        let passed = report
+            .results
+            .iter()
+            .map(|result| result.passed)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            passed,
+            [true, true, false, true, true, false, true, false, false]
+        );
+        assert_eq!((report.passed, report.failed), (5, 4));
+        assert_eq!(report.results[7].actual, Some(Value::from(150.0)));
+        assert!(report.results[8]
+            .message
+            .as_deref()
+            .is_some_and(|message| message.starts_with("/id")));
+
+        response.data =
+            b"<feed><entry id=\"1\"><title>First</title></entry><entry id=\"2\"/></feed>".to_vec();
+        let report = evaluate_assertions(
+            &serde_json::from_value::<Vec<Assertion>>(serde_json::json!([
+                {"type": "xpath", "path": "count(//entry)", "op": "equals", "value": 2},
+                {"type": "xpath", "path": "//entry[1]/title", "op": "matches", "value": "^Fir"},
+                {"type": "xpath", "path": "//entry[3]", "op": "not_exists"}
+            ]))
+            .unwrap(),
+            &response,
+        );
+        assert!(report.success(), "{:?}", report.results);
+    }
+}
@@ -0,0 +1,370 @@
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    engine::RelayEngine,
+    error::RelayError,
+    interop::{RequestWithMetadata, ResponseWithMetadata},
+    relay::now_ms,
+};
+
+/// How the requests of a batch are scheduled.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum BatchMode {
+    /// One request at a time, in the given order.
+    Sequential,
+    /// Up to `limit` requests at a time. Results arrive in completion order.
+    Parallel { limit: usize },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BatchOptions {
+    pub mode: BatchMode,
+    /// Skip the remaining requests and cancel running ones after the first failure.
+    pub stop_on_failure: bool,
+    /// How many of the slowest requests to list in the summary.
+    pub slowest_count: usize,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            mode: BatchMode::Sequential,
+            stop_on_failure: false,
+            slowest_count: 5,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub enum BatchOutcome {
+    Response(Box<ResponseWithMetadata>),
+    Error(RelayError),
+    /// Not run, or cancelled while running, because the batch was stopped.
+    Skipped,
+}
+
+/// Result of a single request in a batch, streamed as soon as it is known.
+#[derive(Debug, Serialize)]
+pub struct BatchItemResult {
+    /// Position of the request in the submitted list.
+    pub index: usize,
+    pub req_id: usize,
+    /// A request passes when all of its assertions pass, or when it has none
+    /// and completes with a status below 400.
+    pub passed: bool,
+    pub duration_ms: u128,
+    pub outcome: BatchOutcome,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BatchTiming {
+    pub index: usize,
+    pub req_id: usize,
+    pub duration_ms: u128,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BatchSummary {
+    pub total: usize,
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub stopped_early: bool,
+    pub total_time_ms: u128,
+    /// Slowest completed requests, slowest first.
+    pub slowest: Vec<BatchTiming>,
+}
+
+/// Runs `requests` on `engine` according to `options`.
+///
+/// `on_result` is called for every request as soon as its outcome is known,
+/// including skipped ones, so callers can stream progress. Cancelling
+/// `cancel_token` stops the whole batch.
+pub async fn run_batch<F>(
+    engine: &RelayEngine,
+    requests: Vec<RequestWithMetadata>,
+    options: BatchOptions,
+    cancel_token: CancellationToken,
+    mut on_result: F,
+) -> BatchSummary
+where
+    F: FnMut(BatchItemResult),
+{
+    let limit = match options.mode {
+        BatchMode::Sequential => 1,
+        BatchMode::Parallel { limit } => limit.max(1),
+    };
+    log::info!(
+        "Starting batch of {} requests with concurrency {}",
+        requests.len(),
+        limit
+    );
+
+    let batch_token = cancel_token.child_token();
+    let batch_start_ms = now_ms();
+    let mut summary = BatchSummary {
+        total: requests.len(),
+        ..Default::default()
+    };
+    let mut timings = Vec::new();
+
+    let mut results = stream::iter(requests.into_iter().enumerate())
+        .map(|(index, req)| run_item(engine, index, req, batch_token.clone()))
+        .buffer_unordered(limit);
+
+    while let Some(item) = results.next().await {
+        match item.outcome {
+            BatchOutcome::Skipped => summary.skipped += 1,
+            _ if item.passed => summary.passed += 1,
+            _ => summary.failed += 1,
+        }
+
+        if !matches!(item.outcome, BatchOutcome::Skipped) {
+            timings.push(BatchTiming {
+                index: item.index,
+                req_id: item.req_id,
+                duration_ms: item.duration_ms,
+            });
+        }
+
+        let failed = !item.passed && !matches!(item.outcome, BatchOutcome::Skipped);
+        if failed && options.stop_on_failure && !batch_token.is_cancelled() {
+            log::warn!(
+                "Stopping batch after failure of request {} (index {})",
+                item.req_id,
+                item.index
+            );
+            summary.stopped_early = true;
+            batch_token.cancel();
+        }
+
+        on_result(item);
+    }
+
+    timings.sort_by_key(|timing| std::cmp::Reverse(timing.duration_ms));
+    timings.truncate(options.slowest_count);
+    summary.slowest = timings;
+    summary.stopped_early |= cancel_token.is_cancelled();
+    summary.total_time_ms = now_ms() - batch_start_ms;
+
+    log::info!(
+        "Batch finished:\nPassed: {}\nFailed: {}\nSkipped: {}\nDuration: {}ms",
+        summary.passed,
+        summary.failed,
+        summary.skipped,
+        summary.total_time_ms
+    );
+
+    summary
+}
+
+async fn run_item(
+    engine: &RelayEngine,
+    index: usize,
+    req: RequestWithMetadata,
+    batch_token: CancellationToken,
+) -> BatchItemResult {
+    let req_id = req.req_id;
+
+    if batch_token.is_cancelled() {
+        return BatchItemResult {
+            index,
+            req_id,
+            passed: false,
+            duration_ms: 0,
+            outcome: BatchOutcome::Skipped,
+        };
+    }
+
+    let start_ms = now_ms();
+    let result = engine.execute(req, batch_token.child_token()).await;
+    let duration_ms = now_ms() - start_ms;
+
+    let (passed, outcome) = match result {
+        Ok(response) => (
+            match &response.assertions {
+                Some(report) => report.success(),
+                None => response.status < 400,
+            },
+            BatchOutcome::Response(Box::new(response)),
+        ),
+        Err(RelayError::RequestCancelled) => (false, BatchOutcome::Skipped),
+        Err(err) => (false, BatchOutcome::Error(err)),
+    };
+
+    BatchItemResult {
+        index,
+        req_id,
+        passed,
+        duration_ms,
+        outcome,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assertions::Assertion, engine::EngineConfig};
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        time::Duration,
+    };
+
+    /// Answers `/ok` with 200 and `/missing` with 404, and holds `/hang`
+    /// until the client goes away.
+    fn spawn_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for socket in listener.incoming() {
+                let mut socket = socket.unwrap();
+                std::thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match socket.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let status = if request.starts_with("GET /ok ") {
+                        "200 OK"
+                    } else if request.starts_with("GET /missing ") {
+                        "404 Not Found"
+                    } else {
+                        while matches!(socket.read(&mut buf), Ok(n) if n > 0) {}
+                        return;
+                    };
+                    let _ = write!(
+                        socket,
+                        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    );
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn requests(base: &str, paths: &[&str]) -> Vec<RequestWithMetadata> {
+        paths
+            .iter()
+            .enumerate()
+            .map(|(req_id, path)| {
+                RequestWithMetadata::new(
+                    req_id,
+                    "GET".to_string(),
+                    format!("{}{}", base, path),
+                    Vec::new(),
+                    None,
+                    true,
+                    Vec::new(),
+                    None,
+                    None,
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn streams_results_and_counts_outcomes() {
+        let base = spawn_server();
+        let engine = RelayEngine::new(EngineConfig::default()).unwrap();
+        let run = |paths: &[&str], options, cancel_token| {
+            let requests = requests(&base, paths);
+            let engine = &engine;
+            async move {
+                let mut streamed = Vec::new();
+                let summary = run_batch(engine, requests, options, cancel_token, |item| {
+                    streamed.push((item.index, item.passed, item.outcome))
+                })
+                .await;
+                (summary, streamed)
+            }
+        };
+
+        let (summary, streamed) = run(
+            &["/ok", "/missing", "/ok"],
+            BatchOptions::default(),
+            CancellationToken::new(),
+        )
+        .await;
+        assert_eq!(
+            streamed
+                .iter()
+                .map(|(index, passed, _)| (*index, *passed))
+                .collect::<Vec<_>>(),
+            [(0, true), (1, false), (2, true)]
+        );
+        assert!(
+            matches!(&streamed[1].2, BatchOutcome::Response(response) if response.status == 404)
+        );
+        assert_eq!(
+            (
+                summary.total,
+                summary.passed,
+                summary.failed,
+                summary.skipped
+            ),
+            (3, 2, 1, 0)
+        );
+        assert!(!summary.stopped_early);
+        assert_eq!(summary.slowest.len(), 3);
+
+        let stop_on_failure = BatchOptions {
+            stop_on_failure: true,
+            ..Default::default()
+        };
+        let (summary, streamed) = run(
+            &["/ok", "/missing", "/ok"],
+            stop_on_failure,
+            CancellationToken::new(),
+        )
+        .await;
+        assert!(matches!(streamed[2].2, BatchOutcome::Skipped));
+        assert_eq!((summary.passed, summary.failed, summary.skipped), (1, 1, 1));
+        assert!(summary.stopped_early);
+
+        // Assertions decide instead of the status when a request has any.
+        let mut asserted = requests(&base, &["/missing", "/ok"]);
+        asserted[0].assertions = vec![Assertion::Status {
+            expected: "404".to_string(),
+        }];
+        asserted[1].assertions = vec![Assertion::HeaderPresent {
+            name: "X-Missing".to_string(),
+        }];
+        let mut streamed = Vec::new();
+        let summary = run_batch(
+            &engine,
+            asserted,
+            BatchOptions::default(),
+            CancellationToken::new(),
+            |item| streamed.push(item.passed),
+        )
+        .await;
+        assert_eq!(streamed, [true, false]);
+        assert_eq!((summary.passed, summary.failed), (1, 1));
+
+        // Cancelling the batch token cancels the running requests.
+        let cancel_token = CancellationToken::new();
+        tokio::spawn({
+            let cancel_token = cancel_token.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                cancel_token.cancel();
+            }
+        });
+        let parallel = BatchOptions {
+            mode: BatchMode::Parallel { limit: 2 },
+            ..Default::default()
+        };
+        let (summary, streamed) = run(&["/hang", "/hang", "/hang"], parallel, cancel_token).await;
+        assert_eq!(streamed.len(), 3);
+        assert_eq!((summary.passed, summary.failed, summary.skipped), (0, 0, 3));
+        assert!(summary.stopped_early && summary.slowest.is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+//! Postdata Agent, runs requests from the web app through the relay.
+//!
+//! ```text
+//! postdata-agent [--port <port>] [--token <token>] [--allow-origin <origin>]...
+//! ```
+//!
+//! The pairing token can also be set with `POSTDATA_AGENT_TOKEN`. Without
+//! one, a fresh token is generated and printed at startup.
+
+use postdata_relay::{Agent, AgentConfig, DEFAULT_AGENT_PORT};
+
+fn parse_args() -> Result<AgentConfig, String> {
+    let mut config = AgentConfig {
+        port: DEFAULT_AGENT_PORT,
+        token: std::env::var("POSTDATA_AGENT_TOKEN").unwrap_or_default(),
+        allowed_origins: Vec::new(),
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--port" => {
+                let port = value()?;
+                config.port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid port '{}'", port))?;
+            }
+            "--token" => config.token = value()?,
+            "--allow-origin" => config.allowed_origins.push(value()?),
+            "--help" | "-h" => {
+                println!(
+                    "Usage: postdata-agent [--port <port>] [--token <token>] \
+                     [--allow-origin <origin>]..."
+                );
+                std::process::exit(0);
+            }
+            _ => return Err(format!("Unknown argument '{}'", arg)),
+        }
+    }
+
+    if config.token.is_empty() {
+        config.token = AgentConfig::generate_token();
+    }
+    Ok(config)
+}
+
+fn main() {
+    env_logger::init();
+
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    if config.allowed_origins.is_empty() {
+        eprintln!("No --allow-origin given, browsers won't be able to reach the agent");
+    }
+
+    let agent = Agent::new(config.clone());
+    let result = agent.listen().and_then(|listener| {
+        println!(
+            "Postdata Agent listening on http://127.0.0.1:{}",
+            config.port
+        );
+        println!("Pairing token: {}", config.token);
+        agent.serve(listener)
+    });
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,162 @@
+//! Postdata Runner, runs the collections of a workspace directory from CI.
+//!
+//! ```text
+//! postdata-runner [--workspace <dir>] [--collection <name>] [--folder <path>]
+//!                 [--env <name>] [--var <key=value>]... [--expectations <file>]
+//!                 [--junit <file>] [--json <file>]
+//! ```
+//!
+//! Exits with `1` when a request fails its expectations or can't be run and
+//! with `2` when the workspace can't be loaded.
+
+use std::path::PathBuf;
+
+use postdata_relay::{
+    junit_xml, run_collection, workspace_file, Expectations, KeyValuePair, RequestOutcome,
+    RunReport, RunnerOptions, Workspace, EXPECTATIONS_FILE,
+};
+use tokio_util::sync::CancellationToken;
+
+const USAGE: &str = "Usage: postdata-runner [--workspace <dir>] [--collection <name>] \
+                     [--folder <path>] [--env <name>] [--var <key=value>]... \
+                     [--expectations <file>] [--junit <file>] [--json <file>]";
+
+#[derive(Default)]
+struct Args {
+    workspace: PathBuf,
+    collection: Option<String>,
+    expectations: Option<PathBuf>,
+    junit: Option<PathBuf>,
+    json: Option<PathBuf>,
+    options: RunnerOptions,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut parsed = Args {
+        workspace: PathBuf::from("."),
+        ..Default::default()
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--workspace" => parsed.workspace = PathBuf::from(value()?),
+            "--collection" => parsed.collection = Some(value()?),
+            "--folder" => parsed.options.folder = Some(value()?),
+            "--env" => parsed.options.environment = Some(value()?),
+            "--var" => {
+                let var = value()?;
+                let (key, value) = var
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected key=value, got '{}'", var))?;
+                parsed.options.variables.push(KeyValuePair {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                });
+            }
+            "--expectations" => parsed.expectations = Some(PathBuf::from(value()?)),
+            "--junit" => parsed.junit = Some(PathBuf::from(value()?)),
+            "--json" => parsed.json = Some(PathBuf::from(value()?)),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => return Err(format!("Unknown argument '{}'", arg)),
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn run(mut args: Args) -> Result<Vec<RunReport>, String> {
+    let workspace = Workspace::load(&args.workspace).map_err(|err| err.to_string())?;
+
+    let expectations = args
+        .expectations
+        .clone()
+        .or_else(|| workspace_file(&args.workspace, EXPECTATIONS_FILE));
+    if let Some(path) = expectations {
+        args.options.expectations = Expectations::load(&path).map_err(|err| err.to_string())?;
+    }
+
+    let collections = match &args.collection {
+        Some(name) => vec![workspace
+            .collection(name)
+            .ok_or_else(|| format!("No collection named '{}'", name))?],
+        None => workspace.collections.iter().collect(),
+    };
+
+    let mut reports = Vec::new();
+    for collection in collections {
+        println!("{}", collection.name);
+        let report = run_collection(
+            collection,
+            &workspace,
+            &args.options,
+            CancellationToken::new(),
+            |request| {
+                let label = match request.outcome {
+                    RequestOutcome::Passed => "PASS",
+                    RequestOutcome::Failed => "FAIL",
+                    RequestOutcome::Errored => "ERROR",
+                };
+                let status = request
+                    .status
+                    .map(|status| status.to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "  {:<5} {} {} ({}, {} ms)",
+                    label,
+                    request.method,
+                    request.path(),
+                    status,
+                    request.duration_ms
+                );
+                for failure in &request.failures {
+                    println!("        {}", failure);
+                }
+                if let Some(error) = &request.error {
+                    println!("        {}", error);
+                }
+            },
+        )
+        .map_err(|err| err.to_string())?;
+        println!(
+            "  {} passed, {} failed, {} errored\n",
+            report.passed, report.failed, report.errored
+        );
+        reports.push(report);
+    }
+
+    if let Some(path) = &args.junit {
+        std::fs::write(path, junit_xml(&reports))
+            .map_err(|err| format!("Failed to write {}: {}", path.display(), err))?;
+    }
+    if let Some(path) = &args.json {
+        let json = serde_json::to_string_pretty(&reports).map_err(|err| err.to_string())?;
+        std::fs::write(path, json)
+            .map_err(|err| format!("Failed to write {}: {}", path.display(), err))?;
+    }
+
+    Ok(reports)
+}
+
+fn main() {
+    env_logger::init();
+
+    let reports = match parse_args().and_then(run) {
+        Ok(reports) => reports,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    if !reports.iter().all(RunReport::success) {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,820 @@
+//! Client side HTTP cache for requests that opt in with
+//! `RequestWithMetadata::cache`.
+//!
+//! Behaves like a private browser cache. `GET` and `HEAD` responses are
+//! stored when `Cache-Control` allows it, fresh entries are served without
+//! contacting the server, and stale ones are revalidated with
+//! `If-None-Match`/`If-Modified-Since`. Freshness comes from `max-age`, then
+//! `Expires`, then 10% of the time since `Last-Modified`. Successful unsafe
+//! requests drop the entry for their URL.
+//!
+//! Each entry is a JSON file named by a hash of its method and URL, so the
+//! cache survives restarts and a broken file only loses one entry.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    assertions::attach_report,
+    error::{RelayError, RelayResult},
+    interop::{
+        CacheStatus, KeyValuePair, RequestWithMetadata, ResponseTimings, ResponseWithMetadata,
+    },
+    relay::{now_ms, run_request_task},
+};
+
+/// Statuses a response may be stored with, RFC 9110 section 15.1.
+const CACHEABLE_STATUSES: &[u16] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+/// Headers of a `304` that don't describe the stored body.
+const NOT_UPDATED_BY_304: &[&str] = &["content-length", "content-encoding", "transfer-encoding"];
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Upper bound for the stored bodies, the oldest entries go first.
+    pub max_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// A stored entry as reported by [`HttpCache::entries`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntryInfo {
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    pub size: usize,
+    pub stored_at_ms: u128,
+    /// Until when the entry is served without revalidation.
+    pub fresh_until_ms: u128,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Request headers named by `Vary`, each with a SHA-256 of its value
+    /// when stored.
+    pub vary: Vec<KeyValuePair>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    method: String,
+    url: String,
+    /// Hashed, as `Vary` may name headers such as `Authorization`.
+    vary: Vec<KeyValuePair>,
+    /// Stored without `data`, the body lives in `body`, and without anything
+    /// describing the request that fetched it.
+    response: ResponseWithMetadata,
+    /// Base64 encoded body.
+    body: String,
+    stored_at_ms: u128,
+    /// Age of the response when it was stored.
+    initial_age_secs: u64,
+    freshness_secs: u64,
+}
+
+impl CacheEntry {
+    fn age_secs(&self, now_ms: u128) -> u64 {
+        self.initial_age_secs + (now_ms.saturating_sub(self.stored_at_ms) / 1000) as u64
+    }
+
+    fn is_fresh(&self, now_ms: u128) -> bool {
+        self.age_secs(now_ms) < self.freshness_secs
+    }
+
+    fn size(&self) -> usize {
+        self.body.len() / 4 * 3
+    }
+
+    fn info(&self) -> CacheEntryInfo {
+        CacheEntryInfo {
+            method: self.method.clone(),
+            url: self.url.clone(),
+            status: self.response.status,
+            size: self.size(),
+            stored_at_ms: self.stored_at_ms,
+            fresh_until_ms: self.stored_at_ms
+                + u128::from(self.freshness_secs.saturating_sub(self.initial_age_secs)) * 1000,
+            etag: header(&self.response.headers, "etag").map(str::to_string),
+            last_modified: header(&self.response.headers, "last-modified").map(str::to_string),
+            vary: self.vary.clone(),
+        }
+    }
+}
+
+/// What to do for a request, from [`HttpCache::lookup`].
+pub enum CacheLookup {
+    /// Answered from a fresh entry, nothing has to be sent.
+    Fresh(Box<ResponseWithMetadata>),
+    /// `request` has to be sent and its result passed to
+    /// [`HttpCache::complete`]. It carries validators when a stale entry is
+    /// being revalidated.
+    Send {
+        request: Box<RequestWithMetadata>,
+        revalidating: bool,
+    },
+}
+
+pub struct HttpCache {
+    dir: PathBuf,
+    config: CacheConfig,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl HttpCache {
+    /// Opens the cache stored in `dir`, creating it when needed.
+    pub fn open(dir: &Path, config: CacheConfig) -> RelayResult<Self> {
+        std::fs::create_dir_all(dir).map_err(|err| {
+            RelayError::RequestRunError(format!(
+                "Failed to create HTTP cache at {}: {}",
+                dir.display(),
+                err
+            ))
+        })?;
+
+        let mut entries = HashMap::new();
+        let files = std::fs::read_dir(dir).map_err(|err| {
+            RelayError::RequestRunError(format!(
+                "Failed to read HTTP cache at {}: {}",
+                dir.display(),
+                err
+            ))
+        })?;
+        for file in files.flatten() {
+            let path = file.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let entry = std::fs::read(&path)
+                .ok()
+                .and_then(|contents| serde_json::from_slice::<CacheEntry>(&contents).ok());
+            match entry {
+                Some(entry) => {
+                    entries.insert(cache_key(&entry.method, &entry.url), entry);
+                }
+                None => {
+                    log::warn!("Dropping unreadable cache entry {}", path.display());
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            config,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Runs `req` through the cache with the blocking relay.
+    pub fn run(
+        &self,
+        req: &RequestWithMetadata,
+        cancel_token: CancellationToken,
+    ) -> RelayResult<ResponseWithMetadata> {
+        match self.lookup(req) {
+            CacheLookup::Fresh(response) => Ok(*response),
+            CacheLookup::Send {
+                request,
+                revalidating,
+            } => self.complete(req, revalidating, run_request_task(&request, cancel_token)),
+        }
+    }
+
+    pub fn lookup(&self, req: &RequestWithMetadata) -> CacheLookup {
+        self.lookup_as(&req.endpoint, req)
+    }
+
+    /// Like [`HttpCache::lookup`], with entries keyed and labelled by `url`
+    /// instead of the request's endpoint, e.g. the endpoint with its secret
+    /// values redacted, so they never reach the cache directory.
+    pub fn lookup_as(&self, url: &str, req: &RequestWithMetadata) -> CacheLookup {
+        let send = |request: RequestWithMetadata, revalidating| CacheLookup::Send {
+            request: Box::new(request),
+            revalidating,
+        };
+        if !req.cache || !is_cacheable_method(&req.method) {
+            return send(req.clone(), false);
+        }
+
+        let directives = cache_control(&req.headers);
+        if directives.contains_key("no-store") {
+            return send(req.clone(), false);
+        }
+        let must_revalidate = directives.contains_key("no-cache")
+            || directives.get("max-age").is_some_and(|age| age == "0")
+            || header(&req.headers, "pragma").is_some_and(|pragma| pragma.contains("no-cache"));
+
+        let entries = self.lock();
+        let Some(entry) = entries
+            .get(&cache_key(&req.method, url))
+            .filter(|entry| vary_matches(entry, req))
+        else {
+            return send(req.clone(), false);
+        };
+
+        let now = now_ms();
+        if entry.is_fresh(now) && !must_revalidate {
+            let mut response = entry.response.clone();
+            response.data = BASE64.decode(&entry.body).unwrap_or_default();
+            set_header(
+                &mut response.headers,
+                "Age",
+                entry.age_secs(now).to_string(),
+            );
+            // Nothing was sent, so there is nothing to report as sent.
+            response.request_line = String::new();
+            response.sent_headers = Vec::new();
+            response.timings = ResponseTimings::default();
+            response.attempts = Vec::new();
+            response.trace = Vec::new();
+            response.time_start_ms = now;
+            response.time_end_ms = now;
+            response.cache_status = Some(CacheStatus::Hit);
+            attach_report(req, &mut response);
+            return CacheLookup::Fresh(Box::new(response));
+        }
+
+        // Validators the request sets itself take precedence.
+        let mut request = req.clone();
+        let etag = header(&entry.response.headers, "etag");
+        let last_modified = header(&entry.response.headers, "last-modified");
+        if etag.is_none() && last_modified.is_none() {
+            return send(request, false);
+        }
+        if let Some(etag) = etag.filter(|_| header(&req.headers, "if-none-match").is_none()) {
+            request.headers.push(KeyValuePair {
+                key: "If-None-Match".to_string(),
+                value: etag.to_string(),
+            });
+        }
+        if let Some(last_modified) =
+            last_modified.filter(|_| header(&req.headers, "if-modified-since").is_none())
+        {
+            request.headers.push(KeyValuePair {
+                key: "If-Modified-Since".to_string(),
+                value: last_modified.to_string(),
+            });
+        }
+        send(request, true)
+    }
+
+    /// Stores or refreshes entries from the outcome of a request
+    /// [`HttpCache::lookup`] asked to send, and tells how it was answered.
+    pub fn complete(
+        &self,
+        req: &RequestWithMetadata,
+        revalidating: bool,
+        result: RelayResult<ResponseWithMetadata>,
+    ) -> RelayResult<ResponseWithMetadata> {
+        self.complete_as(&req.endpoint, req, revalidating, result)
+    }
+
+    /// Like [`HttpCache::complete`] for a request looked up with
+    /// [`HttpCache::lookup_as`] under the same `url`.
+    pub fn complete_as(
+        &self,
+        url: &str,
+        req: &RequestWithMetadata,
+        revalidating: bool,
+        result: RelayResult<ResponseWithMetadata>,
+    ) -> RelayResult<ResponseWithMetadata> {
+        let mut response = result?;
+        if !req.cache {
+            return Ok(response);
+        }
+        let key = cache_key(&req.method, url);
+
+        if !is_cacheable_method(&req.method) {
+            if (200..400).contains(&response.status) {
+                self.remove(&cache_key("GET", url));
+                self.remove(&cache_key("HEAD", url));
+            }
+            response.cache_status = Some(CacheStatus::Miss);
+            return Ok(response);
+        }
+
+        if revalidating && response.status == 304 {
+            if let Some(mut refreshed) = self.refresh(&key, &response) {
+                // The report on the 304 doesn't describe the stored body.
+                attach_report(req, &mut refreshed);
+                return Ok(refreshed);
+            }
+        }
+
+        match storable_entry(url, req, &response) {
+            Some(entry) => self.store(key, entry),
+            None => {
+                if cache_control(&response.headers).contains_key("no-store") {
+                    self.remove(&key);
+                }
+            }
+        }
+        response.cache_status = Some(CacheStatus::Miss);
+        Ok(response)
+    }
+
+    pub fn entries(&self) -> Vec<CacheEntryInfo> {
+        let mut entries = self
+            .lock()
+            .values()
+            .map(CacheEntry::info)
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.stored_at_ms));
+        entries
+    }
+
+    pub fn clear(&self) -> RelayResult<()> {
+        let mut entries = self.lock();
+        for key in entries.keys() {
+            let path = self.entry_path(key);
+            if let Err(err) = std::fs::remove_file(&path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    return Err(RelayError::RequestRunError(format!(
+                        "Failed to remove {}: {}",
+                        path.display(),
+                        err
+                    )));
+                }
+            }
+        }
+        entries.clear();
+        Ok(())
+    }
+
+    /// Applies the headers of a `304` to the stored entry and answers with it.
+    fn refresh(
+        &self,
+        key: &str,
+        not_modified: &ResponseWithMetadata,
+    ) -> Option<ResponseWithMetadata> {
+        let mut entries = self.lock();
+        let entry = entries.get_mut(key)?;
+
+        for header in &not_modified.headers {
+            let name = header.key.to_ascii_lowercase();
+            if !NOT_UPDATED_BY_304.contains(&name.as_str()) {
+                set_header(
+                    &mut entry.response.headers,
+                    &header.key,
+                    header.value.clone(),
+                );
+            }
+        }
+        let now = now_ms();
+        entry.stored_at_ms = now;
+        entry.initial_age_secs = initial_age(&entry.response.headers, now);
+        entry.freshness_secs = freshness(&entry.response.headers);
+        self.write_entry(key, entry);
+
+        let mut response = entry.response.clone();
+        response.data = BASE64.decode(&entry.body).unwrap_or_default();
+        response.http_version = not_modified.http_version.clone();
+        response.request_line = not_modified.request_line.clone();
+        response.sent_headers = not_modified.sent_headers.clone();
+        response.time_start_ms = not_modified.time_start_ms;
+        response.time_end_ms = not_modified.time_end_ms;
+        response.timings = not_modified.timings.clone();
+        response.attempts = not_modified.attempts.clone();
+        response.trace = not_modified.trace.clone();
+        response.cache_status = Some(CacheStatus::Revalidated);
+        response.assertions = None;
+        Some(response)
+    }
+
+    fn store(&self, key: String, entry: CacheEntry) {
+        let mut entries = self.lock();
+        self.write_entry(&key, &entry);
+        entries.insert(key, entry);
+
+        let mut total = entries.values().map(CacheEntry::size).sum::<usize>();
+        while total > self.config.max_bytes {
+            let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored_at_ms)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = entries.remove(&oldest) {
+                total -= evicted.size();
+                let _ = std::fs::remove_file(self.entry_path(&oldest));
+            }
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        if self.lock().remove(key).is_some() {
+            let _ = std::fs::remove_file(self.entry_path(key));
+        }
+    }
+
+    fn write_entry(&self, key: &str, entry: &CacheEntry) {
+        let path = self.entry_path(key);
+        let written = serde_json::to_vec(entry)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| std::fs::write(&path, contents).map_err(|err| err.to_string()));
+        if let Err(err) = written {
+            log::warn!("Failed to write cache entry {}: {}", path.display(), err);
+        }
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", sha256_hex(key)))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, CacheEntry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn sha256_hex(value: &str) -> String {
+    openssl::sha::sha256(value.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn cache_key(method: &str, url: &str) -> String {
+    format!("{} {}", method.to_ascii_uppercase(), url)
+}
+
+fn is_cacheable_method(method: &str) -> bool {
+    method.eq_ignore_ascii_case("GET") || method.eq_ignore_ascii_case("HEAD")
+}
+
+/// The entry to store for `response`, `None` when it may not be stored or
+/// could never be reused.
+fn storable_entry(
+    url: &str,
+    req: &RequestWithMetadata,
+    response: &ResponseWithMetadata,
+) -> Option<CacheEntry> {
+    if !CACHEABLE_STATUSES.contains(&response.status) {
+        return None;
+    }
+    if cache_control(&req.headers).contains_key("no-store")
+        || cache_control(&response.headers).contains_key("no-store")
+    {
+        return None;
+    }
+
+    let vary_names = header(&response.headers, "vary")
+        .map(|vary| {
+            vary.split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if vary_names.iter().any(|name| name == "*") {
+        return None;
+    }
+
+    let freshness_secs = freshness(&response.headers);
+    let has_validator = header(&response.headers, "etag").is_some()
+        || header(&response.headers, "last-modified").is_some();
+    if freshness_secs == 0 && !has_validator {
+        return None;
+    }
+
+    let now = now_ms();
+    let mut stored = response.clone();
+    stored.data = Vec::new();
+    stored.request_line = String::new();
+    stored.sent_headers = Vec::new();
+    stored.timings = ResponseTimings::default();
+    stored.attempts = Vec::new();
+    stored.trace = Vec::new();
+    stored.cache_status = None;
+    stored.assertions = None;
+    Some(CacheEntry {
+        method: req.method.to_ascii_uppercase(),
+        url: url.to_string(),
+        vary: vary_names
+            .iter()
+            .map(|name| KeyValuePair {
+                key: name.clone(),
+                value: sha256_hex(header(&req.headers, name).unwrap_or_default()),
+            })
+            .collect(),
+        response: stored,
+        body: BASE64.encode(&response.data),
+        stored_at_ms: now,
+        initial_age_secs: initial_age(&response.headers, now),
+        freshness_secs,
+    })
+}
+
+fn vary_matches(entry: &CacheEntry, req: &RequestWithMetadata) -> bool {
+    entry
+        .vary
+        .iter()
+        .all(|vary| sha256_hex(header(&req.headers, &vary.key).unwrap_or_default()) == vary.value)
+}
+
+/// Freshness lifetime in seconds, `0` when the response has to be revalidated.
+fn freshness(headers: &[KeyValuePair]) -> u64 {
+    let directives = cache_control(headers);
+    if directives.contains_key("no-cache") {
+        return 0;
+    }
+    if let Some(max_age) = directives.get("max-age") {
+        return max_age.parse().unwrap_or(0);
+    }
+
+    let date = header(headers, "date")
+        .and_then(|date| httpdate::parse_http_date(date).ok())
+        .unwrap_or_else(SystemTime::now);
+    if let Some(expires) = header(headers, "expires") {
+        // Invalid dates such as `0` mean already expired.
+        return httpdate::parse_http_date(expires)
+            .ok()
+            .and_then(|expires| expires.duration_since(date).ok())
+            .map_or(0, |lifetime| lifetime.as_secs());
+    }
+    header(headers, "last-modified")
+        .and_then(|modified| httpdate::parse_http_date(modified).ok())
+        .and_then(|modified| date.duration_since(modified).ok())
+        .map_or(0, |since| since.as_secs() / 10)
+}
+
+/// Age of a response at the time it arrived, from `Age` or its `Date`.
+fn initial_age(headers: &[KeyValuePair], now_ms: u128) -> u64 {
+    let age = header(headers, "age")
+        .and_then(|age| age.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    let apparent = header(headers, "date")
+        .and_then(|date| httpdate::parse_http_date(date).ok())
+        .and_then(|date| {
+            let now = UNIX_EPOCH + Duration::from_millis(now_ms as u64);
+            now.duration_since(date).ok()
+        })
+        .map_or(0, |apparent| apparent.as_secs());
+    age.max(apparent)
+}
+
+/// `Cache-Control` directives, lowercased, with unquoted values.
+fn cache_control(headers: &[KeyValuePair]) -> HashMap<String, String> {
+    headers
+        .iter()
+        .filter(|header| header.key.eq_ignore_ascii_case("cache-control"))
+        .flat_map(|header| header.value.split(','))
+        .filter_map(|directive| {
+            let directive = directive.trim();
+            if directive.is_empty() {
+                return None;
+            }
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            Some((
+                name.trim().to_ascii_lowercase(),
+                value.trim().trim_matches('"').to_string(),
+            ))
+        })
+        .collect()
+}
+
+fn header<'a>(headers: &'a [KeyValuePair], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.key.eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_str())
+}
+
+fn set_header(headers: &mut Vec<KeyValuePair>, name: &str, value: String) {
+    headers.retain(|header| !header.key.eq_ignore_ascii_case(name));
+    headers.push(KeyValuePair {
+        key: name.to_string(),
+        value,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> KeyValuePair {
+        KeyValuePair {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn response(status: u16, headers: Vec<KeyValuePair>, data: &[u8]) -> ResponseWithMetadata {
+        let mut response: ResponseWithMetadata = serde_json::from_value(serde_json::json!({
+            "status": status,
+            "status_text": "",
+            "headers": [],
+            "data": data,
+            "time_start_ms": 0,
+            "time_end_ms": 0
+        }))
+        .unwrap();
+        response.headers = headers;
+        response
+    }
+
+    fn get(url: &str) -> RequestWithMetadata {
+        let mut req = RequestWithMetadata::new(
+            0,
+            "GET".to_string(),
+            url.to_string(),
+            vec![pair("Accept", "application/json")],
+            None,
+            true,
+            Vec::new(),
+            None,
+            None,
+        );
+        req.cache = true;
+        req
+    }
+
+    #[test]
+    fn serves_fresh_entries_and_revalidates_stale_ones() {
+        let dir = std::env::temp_dir().join(format!("relay-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = HttpCache::open(&dir, CacheConfig::default()).unwrap();
+
+        let mut fresh = get("https://api.example.com/fresh");
+        fresh.headers.push(pair("Authorization", "Bearer s3cret"));
+        let CacheLookup::Send { revalidating, .. } = cache.lookup(&fresh) else {
+            panic!("expected a miss");
+        };
+        let mut sent = response(
+            200,
+            vec![
+                pair("Cache-Control", "public, max-age=60"),
+                pair("Vary", "Accept, Authorization"),
+            ],
+            b"fresh",
+        );
+        sent.request_line = "GET /fresh HTTP/1.1".to_string();
+        sent.sent_headers = fresh.headers.clone();
+        let stored = cache.complete(&fresh, revalidating, Ok(sent)).unwrap();
+        assert_eq!(stored.cache_status, Some(CacheStatus::Miss));
+
+        let CacheLookup::Fresh(hit) = cache.lookup(&fresh) else {
+            panic!("expected a hit");
+        };
+        assert_eq!(hit.cache_status, Some(CacheStatus::Hit));
+        assert_eq!(hit.data, b"fresh");
+        assert_eq!(header(&hit.headers, "age"), Some("0"));
+        assert!(hit.request_line.is_empty() && hit.sent_headers.is_empty());
+
+        // Neither the headers as sent nor the varying values hit the disk.
+        for file in std::fs::read_dir(&dir).unwrap() {
+            let contents = std::fs::read_to_string(file.unwrap().path()).unwrap();
+            assert!(!contents.contains("s3cret"), "{}", contents);
+        }
+
+        // A different `Accept` is a different variant.
+        let mut other = fresh.clone();
+        other.headers = vec![pair("Accept", "text/html")];
+        assert!(matches!(
+            cache.lookup(&other),
+            CacheLookup::Send {
+                revalidating: false,
+                ..
+            }
+        ));
+
+        let stale = get("https://api.example.com/stale");
+        cache
+            .complete(
+                &stale,
+                false,
+                Ok(response(
+                    200,
+                    vec![pair("Cache-Control", "no-cache"), pair("ETag", "\"v1\"")],
+                    b"body",
+                )),
+            )
+            .unwrap();
+
+        // Entries survive reopening.
+        let cache = HttpCache::open(&dir, CacheConfig::default()).unwrap();
+        assert_eq!(cache.entries().len(), 2);
+
+        let CacheLookup::Send {
+            request,
+            revalidating: true,
+        } = cache.lookup(&stale)
+        else {
+            panic!("expected a revalidation");
+        };
+        assert_eq!(header(&request.headers, "if-none-match"), Some("\"v1\""));
+
+        let revalidated = cache
+            .complete(
+                &stale,
+                true,
+                Ok(response(
+                    304,
+                    vec![pair("ETag", "\"v1\""), pair("Cache-Control", "max-age=30")],
+                    b"",
+                )),
+            )
+            .unwrap();
+        assert_eq!(revalidated.status, 200);
+        assert_eq!(revalidated.data, b"body");
+        assert_eq!(revalidated.cache_status, Some(CacheStatus::Revalidated));
+        assert!(matches!(cache.lookup(&stale), CacheLookup::Fresh(_)));
+
+        // A successful unsafe request invalidates the URL.
+        let mut delete = stale.clone();
+        delete.method = "DELETE".to_string();
+        cache
+            .complete(&delete, false, Ok(response(204, Vec::new(), b"")))
+            .unwrap();
+        assert_eq!(cache.entries().len(), 1);
+
+        cache.clear().unwrap();
+        assert!(cache.entries().is_empty());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keys_entries_by_the_url_given() {
+        let dir = std::env::temp_dir().join(format!("relay-cache-as-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = HttpCache::open(&dir, CacheConfig::default()).unwrap();
+
+        let req = get("https://api.example.com/items?key=s3cret");
+        let label = "https://api.example.com/items?key=[secret:api-key]";
+        cache
+            .complete_as(
+                label,
+                &req,
+                false,
+                Ok(response(
+                    200,
+                    vec![pair("Cache-Control", "max-age=60")],
+                    b"items",
+                )),
+            )
+            .unwrap();
+
+        assert_eq!(cache.entries()[0].url, label);
+        for file in std::fs::read_dir(&dir).unwrap() {
+            let contents = std::fs::read_to_string(file.unwrap().path()).unwrap();
+            assert!(!contents.contains("s3cret"), "{}", contents);
+        }
+
+        let cache = HttpCache::open(&dir, CacheConfig::default()).unwrap();
+        assert!(matches!(
+            cache.lookup_as(label, &req),
+            CacheLookup::Fresh(_)
+        ));
+        assert!(matches!(cache.lookup(&req), CacheLookup::Send { .. }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn computes_freshness_from_headers() {
+        assert_eq!(
+            freshness(&[pair("Cache-Control", "max-age=120, must-revalidate")]),
+            120
+        );
+        assert_eq!(
+            freshness(&[
+                pair("Date", "Wed, 21 Oct 2015 07:28:00 GMT"),
+                pair("Expires", "Wed, 21 Oct 2015 08:28:00 GMT"),
+            ]),
+            3600
+        );
+        assert_eq!(
+            freshness(&[
+                pair("Date", "Wed, 21 Oct 2015 07:28:00 GMT"),
+                pair("Expires", "0"),
+            ]),
+            0
+        );
+        assert_eq!(
+            freshness(&[
+                pair("Date", "Wed, 21 Oct 2015 07:28:00 GMT"),
+                pair("Last-Modified", "Wed, 21 Oct 2015 07:18:00 GMT"),
+            ]),
+            60
+        );
+        assert_eq!(
+            freshness(&[pair("Cache-Control", "no-cache, max-age=60")]),
+            0
+        );
+    }
+}
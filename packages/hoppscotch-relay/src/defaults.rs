@@ -0,0 +1,279 @@
+//! Network settings kept once and merged into every request, so the caller
+//! doesn't have to send proxies, CA bundles and certificates along each time.
+//!
+//! A request's own proxy, client certificate, timeouts and TLS version win
+//! over the defaults. CA bundles add up, the request trusts its own and the
+//! default ones. Certificates are validated only when both the request and
+//! the defaults ask for it.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{RelayError, RelayResult},
+    interop::{ClientCertDef, ProxyConfig, RequestWithMetadata, TlsVersion},
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientCertFile {
+    Pem {
+        certificate: PathBuf,
+        key: PathBuf,
+    },
+    Pfx {
+        certificate: PathBuf,
+        password: String,
+    },
+}
+
+/// A client certificate for the hosts matching `host`, either a host name,
+/// `*.example.com` for its subdomains, or `*` for every host.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HostClientCert {
+    pub host: String,
+    pub cert: ClientCertFile,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkDefaults {
+    pub proxy: Option<ProxyConfig>,
+    /// Host patterns, as in [`HostClientCert`], the default proxy is not
+    /// used for.
+    pub no_proxy: Vec<String>,
+    /// PEM files with additional root certificates.
+    pub ca_bundle_paths: Vec<PathBuf>,
+    /// The first entry matching the request's host is used.
+    pub client_certs: Vec<HostClientCert>,
+    pub timeout_ms: Option<u64>,
+    pub connect_timeout_ms: Option<u64>,
+    pub validate_certs: bool,
+    pub tls_min_version: Option<TlsVersion>,
+}
+
+impl Default for NetworkDefaults {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            no_proxy: Vec::new(),
+            ca_bundle_paths: Vec::new(),
+            client_certs: Vec::new(),
+            timeout_ms: None,
+            connect_timeout_ms: None,
+            validate_certs: true,
+            tls_min_version: None,
+        }
+    }
+}
+
+impl NetworkDefaults {
+    /// Reads the CA bundle and certificate files the defaults refer to, so
+    /// merging them doesn't touch the disk.
+    pub fn load(self) -> RelayResult<LoadedNetworkDefaults> {
+        let ca_bundles = self
+            .ca_bundle_paths
+            .iter()
+            .map(|path| read(path))
+            .collect::<RelayResult<_>>()?;
+        let client_certs = self
+            .client_certs
+            .iter()
+            .map(|entry| {
+                let cert = match &entry.cert {
+                    ClientCertFile::Pem { certificate, key } => ClientCertDef::PEMCert {
+                        certificate_pem: read(certificate)?,
+                        key_pem: read(key)?,
+                    },
+                    ClientCertFile::Pfx {
+                        certificate,
+                        password,
+                    } => ClientCertDef::PFXCert {
+                        certificate_pfx: read(certificate)?,
+                        password: password.clone(),
+                    },
+                };
+                Ok((entry.host.clone(), cert))
+            })
+            .collect::<RelayResult<_>>()?;
+
+        Ok(LoadedNetworkDefaults {
+            settings: self,
+            ca_bundles,
+            client_certs,
+        })
+    }
+}
+
+/// [`NetworkDefaults`] along with the contents of the files they refer to.
+/// Changes to those files take effect once the defaults are loaded again.
+#[derive(Clone, Debug, Default)]
+pub struct LoadedNetworkDefaults {
+    settings: NetworkDefaults,
+    ca_bundles: Vec<Vec<u8>>,
+    /// Host pattern and certificate, in the order of `client_certs`.
+    client_certs: Vec<(String, ClientCertDef)>,
+}
+
+impl LoadedNetworkDefaults {
+    pub fn settings(&self) -> &NetworkDefaults {
+        &self.settings
+    }
+
+    /// Merges the defaults into `req`.
+    pub fn apply(&self, req: &mut RequestWithMetadata) {
+        self.apply_to_stream(req);
+        req.timeout_ms = req.timeout_ms.or(self.settings.timeout_ms);
+    }
+
+    /// Merges the defaults into a request for an event stream, a streaming
+    /// call or a long-lived connection. Those end when the caller says so, so
+    /// the default `timeout_ms` is left out. A timeout the request sets
+    /// itself still applies.
+    pub fn apply_to_stream(&self, req: &mut RequestWithMetadata) {
+        let host = req
+            .endpoint
+            .parse::<http::Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_string))
+            .unwrap_or_default();
+
+        if req.proxy.is_none()
+            && !self
+                .settings
+                .no_proxy
+                .iter()
+                .any(|pattern| host_matches(pattern, &host))
+        {
+            req.proxy = self.settings.proxy.clone();
+        }
+        if req.client_cert.is_none() {
+            req.client_cert = self
+                .client_certs
+                .iter()
+                .find(|(pattern, _)| host_matches(pattern, &host))
+                .map(|(_, cert)| cert.clone());
+        }
+        req.root_cert_bundle_files
+            .extend(self.ca_bundles.iter().cloned());
+
+        req.connect_timeout_ms = req.connect_timeout_ms.or(self.settings.connect_timeout_ms);
+        req.tls_min_version = req.tls_min_version.or(self.settings.tls_min_version);
+        req.validate_certs &= self.settings.validate_certs;
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*") {
+        Some("") => true,
+        Some(suffix) if suffix.starts_with('.') => host
+            .to_ascii_lowercase()
+            .ends_with(&suffix.to_ascii_lowercase()),
+        _ => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+fn read(path: &Path) -> RelayResult<Vec<u8>> {
+    std::fs::read(path).map_err(|err| {
+        RelayError::RequestRunError(format!("Failed to read {}: {}", path.display(), err))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_defaults_below_request_settings() {
+        let dir = std::env::temp_dir().join(format!("relay-defaults-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["ca.pem", "client.pem", "client.key"] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+
+        let defaults = NetworkDefaults {
+            proxy: Some(ProxyConfig {
+                url: "http://proxy.corp:3128".to_string(),
+            }),
+            no_proxy: vec!["localhost".to_string(), "*.internal".to_string()],
+            ca_bundle_paths: vec![dir.join("ca.pem")],
+            client_certs: vec![HostClientCert {
+                host: "*.example.com".to_string(),
+                cert: ClientCertFile::Pem {
+                    certificate: dir.join("client.pem"),
+                    key: dir.join("client.key"),
+                },
+            }],
+            timeout_ms: Some(30_000),
+            connect_timeout_ms: Some(5_000),
+            validate_certs: true,
+            tls_min_version: Some(TlsVersion::Tls1_2),
+        };
+        let request = |url: &str| {
+            RequestWithMetadata::new(
+                0,
+                "GET".to_string(),
+                url.to_string(),
+                Vec::new(),
+                None,
+                true,
+                vec![b"own".to_vec()],
+                None,
+                None,
+            )
+        };
+
+        let loaded = defaults.clone().load().unwrap();
+        for name in ["ca.pem", "client.pem", "client.key"] {
+            std::fs::remove_file(dir.join(name)).unwrap();
+        }
+
+        let mut req = request("https://api.example.com/users");
+        req.timeout_ms = Some(1_000);
+        loaded.apply(&mut req);
+        assert_eq!(req.proxy.unwrap().url, "http://proxy.corp:3128");
+        assert!(matches!(
+            req.client_cert,
+            Some(ClientCertDef::PEMCert { ref key_pem, .. }) if key_pem == b"client.key"
+        ));
+        assert_eq!(
+            req.root_cert_bundle_files,
+            [b"own".to_vec(), b"ca.pem".to_vec()]
+        );
+        assert_eq!(
+            (req.timeout_ms, req.connect_timeout_ms),
+            (Some(1_000), Some(5_000))
+        );
+        assert_eq!(req.tls_min_version, Some(TlsVersion::Tls1_2));
+        assert!(req.validate_certs);
+
+        let mut req = request("https://api.example.com/events");
+        loaded.apply_to_stream(&mut req);
+        assert_eq!(
+            (req.timeout_ms, req.connect_timeout_ms),
+            (None, Some(5_000))
+        );
+        let mut req = request("https://api.example.com/users");
+        loaded.apply(&mut req);
+        assert_eq!(req.timeout_ms, Some(30_000));
+
+        let mut req = request("http://db.internal:8080/");
+        req.validate_certs = false;
+        loaded.apply(&mut req);
+        assert!(req.proxy.is_none() && req.client_cert.is_none());
+        assert!(!req.validate_certs);
+
+        let mut req = request("http://localhost/");
+        req.proxy = Some(ProxyConfig {
+            url: "http://debug:8888".to_string(),
+        });
+        loaded.apply(&mut req);
+        assert_eq!(req.proxy.unwrap().url, "http://debug:8888");
+
+        let err = defaults.load().unwrap_err();
+        assert!(err.to_string().contains("ca.pem"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,703 @@
+use curl::multi::{Easy2Handle, Multi, MultiWaker};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    assertions::evaluate_assertions,
+    error::{RelayError, RelayResult},
+    interop::{RequestWithMetadata, ResponseWithMetadata},
+    relay::{collect_response, now_ms, prepare_curl_handle, BodySink, RelayHandler},
+    retry::RetryTracker,
+};
+
+/// Upper bound on how long the engine thread sleeps in `poll` when curl has
+/// no timer of its own pending. Commands wake the thread up immediately, so
+/// this only matters as a safety net. It is also how long a transfer whose
+/// token was cancelled can keep its connection open at most.
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Concurrency limits applied by the [`RelayEngine`].
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    /// Maximum number of transfers running at the same time across all hosts.
+    pub max_concurrent: usize,
+    /// Maximum number of transfers running at the same time against one host.
+    pub max_per_host: usize,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 32,
+            max_per_host: 6,
+        }
+    }
+}
+
+/// A request the engine is running or holding in its queue.
+#[derive(Clone, Debug, Serialize)]
+pub struct ActiveRequest {
+    pub req_id: usize,
+    pub method: String,
+    pub url: String,
+    /// When the request was submitted to the engine.
+    pub queued_ms: u128,
+    /// When its transfer started, `None` while it waits for a free slot.
+    pub started_ms: Option<u128>,
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+}
+
+type ResponseSender = oneshot::Sender<RelayResult<ResponseWithMetadata>>;
+
+enum EngineCommand {
+    Submit {
+        ticket: usize,
+        req: Box<RequestWithMetadata>,
+        body_sink: Option<BodySink>,
+        cancel_token: CancellationToken,
+        reply: ResponseSender,
+    },
+    Cancel(usize),
+    CancelAll(oneshot::Sender<usize>),
+    List(oneshot::Sender<Vec<ActiveRequest>>),
+    Shutdown,
+}
+
+/// Event driven request engine built on `curl::multi::Multi`.
+///
+/// All transfers are driven from a single background thread instead of one
+/// blocking thread per request. Requests beyond the configured limits wait in
+/// a queue, and cancelling a request removes its transfer from the multi
+/// handle right away, which closes the underlying connection.
+pub struct RelayEngine {
+    commands: mpsc::Sender<EngineCommand>,
+    waker: MultiWaker,
+    next_ticket: AtomicUsize,
+}
+
+impl RelayEngine {
+    pub fn new(config: EngineConfig) -> RelayResult<Self> {
+        let (commands_tx, commands_rx) = mpsc::channel();
+        let (waker_tx, waker_rx) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("relay-engine".to_string())
+            .spawn(move || {
+                let multi = Multi::new();
+                if waker_tx.send(multi.waker()).is_err() {
+                    return;
+                }
+                EngineLoop::new(multi, config, commands_rx).run();
+            })
+            .map_err(|err| {
+                RelayError::RequestRunError(format!("Failed to start relay engine: {}", err))
+            })?;
+
+        let waker = waker_rx.recv().map_err(|_| {
+            RelayError::RequestRunError("Relay engine exited during startup".to_string())
+        })?;
+        log::info!("Relay engine started");
+
+        Ok(Self {
+            commands: commands_tx,
+            waker,
+            next_ticket: AtomicUsize::new(0),
+        })
+    }
+
+    /// Runs `req` on the engine and resolves once the response is complete,
+    /// retrying according to the request's retry policy.
+    ///
+    /// Cancelling `cancel_token` resolves this future with
+    /// [`RelayError::RequestCancelled`] and tears down the transfer, closing
+    /// its connection. Dropping the future tears it down as well.
+    pub async fn execute(
+        &self,
+        mut req: RequestWithMetadata,
+        cancel_token: CancellationToken,
+    ) -> RelayResult<ResponseWithMetadata> {
+        let assertions = std::mem::take(&mut req.assertions);
+        let mut response = self.execute_with_retries(req, cancel_token).await?;
+        if !assertions.is_empty() {
+            response.assertions = Some(evaluate_assertions(&assertions, &response));
+        }
+        Ok(response)
+    }
+
+    async fn execute_with_retries(
+        &self,
+        req: RequestWithMetadata,
+        cancel_token: CancellationToken,
+    ) -> RelayResult<ResponseWithMetadata> {
+        if req.retry.is_none() {
+            return self.execute_once(req, None, cancel_token).await;
+        }
+
+        // The engine takes ownership of each submitted request, so attempts
+        // run on a copy and the original stays around for the next one.
+        let mut retry = RetryTracker::new(&req);
+        loop {
+            let time_start_ms = now_ms();
+            let outcome = self
+                .execute_once(req.clone(), None, cancel_token.clone())
+                .await;
+
+            let Some(delay) = retry.record(time_start_ms, &outcome) else {
+                return retry.finish(outcome);
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = cancel_token.cancelled() => return Err(RelayError::RequestCancelled),
+            }
+        }
+    }
+
+    /// Runs `req` on the engine once and hands the response body to
+    /// `body_sink` as it arrives instead of buffering it, for long lived
+    /// streams. The returned response carries the status and headers with an
+    /// empty body. Retries and assertions don't apply.
+    ///
+    /// The stream counts towards the concurrency limits, shows up in
+    /// [`active_requests`](Self::active_requests) and is torn down by
+    /// [`cancel_all`](Self::cancel_all) like any other request.
+    pub(crate) async fn execute_streaming(
+        &self,
+        req: RequestWithMetadata,
+        body_sink: BodySink,
+        cancel_token: CancellationToken,
+    ) -> RelayResult<ResponseWithMetadata> {
+        self.execute_once(req, Some(body_sink), cancel_token).await
+    }
+
+    async fn execute_once(
+        &self,
+        req: RequestWithMetadata,
+        body_sink: Option<BodySink>,
+        cancel_token: CancellationToken,
+    ) -> RelayResult<ResponseWithMetadata> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        self.send(EngineCommand::Submit {
+            ticket,
+            req: Box::new(req),
+            body_sink,
+            cancel_token: cancel_token.clone(),
+            reply: reply_tx,
+        })?;
+
+        // Removes the transfer unless a reply arrived, whether this future is
+        // cancelled or dropped.
+        let mut guard = CancelOnDrop {
+            engine: self,
+            ticket,
+            finished: false,
+        };
+        tokio::select! {
+            res = reply_rx => {
+                guard.finished = true;
+                res.unwrap_or_else(|_| {
+                    Err(RelayError::RequestRunError("Relay engine stopped".to_string()))
+                })
+            }
+            _ = cancel_token.cancelled() => Err(RelayError::RequestCancelled),
+        }
+    }
+
+    /// Requests currently running or queued, in the order they were submitted.
+    pub async fn active_requests(&self) -> RelayResult<Vec<ActiveRequest>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(EngineCommand::List(reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| RelayError::RequestRunError("Relay engine stopped".to_string()))
+    }
+
+    /// Cancels every running and queued request, returning how many there
+    /// were. Their futures resolve with [`RelayError::RequestCancelled`].
+    pub async fn cancel_all(&self) -> RelayResult<usize> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(EngineCommand::CancelAll(reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| RelayError::RequestRunError("Relay engine stopped".to_string()))
+    }
+
+    fn send(&self, command: EngineCommand) -> RelayResult<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| RelayError::RequestRunError("Relay engine stopped".to_string()))?;
+        self.waker.wakeup().map_err(|err| {
+            RelayError::RequestRunError(format!("Failed to wake relay engine: {}", err))
+        })
+    }
+}
+
+struct CancelOnDrop<'a> {
+    engine: &'a RelayEngine,
+    ticket: usize,
+    finished: bool,
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.engine.send(EngineCommand::Cancel(self.ticket));
+        }
+    }
+}
+
+impl Drop for RelayEngine {
+    fn drop(&mut self) {
+        let _ = self.send(EngineCommand::Shutdown);
+    }
+}
+
+struct Queued {
+    ticket: usize,
+    host: String,
+    req: Box<RequestWithMetadata>,
+    body_sink: Option<BodySink>,
+    cancel_token: CancellationToken,
+    reply: ResponseSender,
+    queued_ms: u128,
+}
+
+struct Active {
+    handle: Easy2Handle<RelayHandler>,
+    host: String,
+    req: Box<RequestWithMetadata>,
+    cancel_token: CancellationToken,
+    reply: ResponseSender,
+    queued_ms: u128,
+    start_time_ms: u128,
+}
+
+struct EngineLoop {
+    multi: Multi,
+    config: EngineConfig,
+    commands: mpsc::Receiver<EngineCommand>,
+    queue: VecDeque<Queued>,
+    active: HashMap<usize, Active>,
+    per_host: HashMap<String, usize>,
+}
+
+impl EngineLoop {
+    fn new(multi: Multi, config: EngineConfig, commands: mpsc::Receiver<EngineCommand>) -> Self {
+        Self {
+            multi,
+            config,
+            commands,
+            queue: VecDeque::new(),
+            active: HashMap::new(),
+            per_host: HashMap::new(),
+        }
+    }
+
+    fn run(mut self) {
+        loop {
+            if !self.drain_commands() {
+                break;
+            }
+
+            self.abort_cancelled();
+            self.admit_queued();
+
+            if let Err(err) = self.multi.perform() {
+                log::error!("Relay engine perform failed: {}", err);
+            }
+
+            self.finish_completed();
+
+            let timeout = self
+                .multi
+                .get_timeout()
+                .ok()
+                .flatten()
+                .map_or(MAX_POLL_INTERVAL, |t| t.min(MAX_POLL_INTERVAL));
+
+            if let Err(err) = self.multi.poll(&mut [], timeout) {
+                log::error!("Relay engine poll failed: {}", err);
+            }
+        }
+
+        log::info!(
+            "Relay engine shutting down with {} active and {} queued requests",
+            self.active.len(),
+            self.queue.len()
+        );
+        for ticket in self.active.keys().copied().collect::<Vec<_>>() {
+            self.abort(ticket, RelayError::RequestCancelled);
+        }
+    }
+
+    /// Applies pending commands, returns `false` once the engine should stop.
+    fn drain_commands(&mut self) -> bool {
+        loop {
+            match self.commands.try_recv() {
+                Ok(EngineCommand::Submit {
+                    ticket,
+                    req,
+                    body_sink,
+                    cancel_token,
+                    reply,
+                }) => {
+                    let host = host_key(&req.endpoint);
+                    log::debug!("Queued request {} for host {}", ticket, host);
+                    self.queue.push_back(Queued {
+                        ticket,
+                        host,
+                        req,
+                        body_sink,
+                        cancel_token,
+                        reply,
+                        queued_ms: now_ms(),
+                    });
+                }
+                Ok(EngineCommand::Cancel(ticket)) => {
+                    if let Some(pos) = self.queue.iter().position(|q| q.ticket == ticket) {
+                        log::debug!("Dropped queued request {}", ticket);
+                        self.queue.remove(pos);
+                    } else {
+                        self.abort(ticket, RelayError::RequestCancelled);
+                    }
+                }
+                Ok(EngineCommand::CancelAll(reply)) => {
+                    let count = self.queue.len() + self.active.len();
+                    log::warn!("Cancelling all {} requests on relay engine", count);
+                    for queued in self.queue.drain(..) {
+                        let _ = queued.reply.send(Err(RelayError::RequestCancelled));
+                    }
+                    for ticket in self.active.keys().copied().collect::<Vec<_>>() {
+                        self.abort(ticket, RelayError::RequestCancelled);
+                    }
+                    let _ = reply.send(count);
+                }
+                Ok(EngineCommand::List(reply)) => {
+                    let _ = reply.send(self.list());
+                }
+                Ok(EngineCommand::Shutdown) => return false,
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(mpsc::TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    /// Safety net for cancelled tokens whose `Cancel` command never arrived,
+    /// e.g. when the future was leaked instead of dropped.
+    fn abort_cancelled(&mut self) {
+        let cancelled = self
+            .active
+            .iter()
+            .filter(|(_, active)| active.cancel_token.is_cancelled())
+            .map(|(ticket, _)| *ticket)
+            .collect::<Vec<_>>();
+        for ticket in cancelled {
+            self.abort(ticket, RelayError::RequestCancelled);
+        }
+    }
+
+    fn list(&self) -> Vec<ActiveRequest> {
+        let describe = |req: &RequestWithMetadata, queued_ms| ActiveRequest {
+            req_id: req.req_id,
+            method: req.method.clone(),
+            url: req.endpoint.clone(),
+            queued_ms,
+            started_ms: None,
+            bytes_downloaded: 0,
+            bytes_uploaded: 0,
+        };
+
+        let mut requests = self
+            .active
+            .values()
+            .map(|active| {
+                let handler = active.handle.get_ref();
+                ActiveRequest {
+                    started_ms: Some(active.start_time_ms),
+                    bytes_downloaded: handler.downloaded,
+                    bytes_uploaded: handler.uploaded,
+                    ..describe(&active.req, active.queued_ms)
+                }
+            })
+            .chain(
+                self.queue
+                    .iter()
+                    .map(|queued| describe(&queued.req, queued.queued_ms)),
+            )
+            .collect::<Vec<_>>();
+        requests.sort_by_key(|request| request.queued_ms);
+        requests
+    }
+
+    fn admit_queued(&mut self) {
+        let mut index = 0;
+        while index < self.queue.len() && self.active.len() < self.config.max_concurrent {
+            let host_count = self
+                .per_host
+                .get(&self.queue[index].host)
+                .copied()
+                .unwrap_or(0);
+            if host_count >= self.config.max_per_host {
+                index += 1;
+                continue;
+            }
+
+            let Some(queued) = self.queue.remove(index) else {
+                break;
+            };
+            if queued.cancel_token.is_cancelled() {
+                continue;
+            }
+            self.start(queued);
+        }
+    }
+
+    fn start(&mut self, queued: Queued) {
+        let Queued {
+            ticket,
+            host,
+            req,
+            body_sink,
+            cancel_token,
+            reply,
+            queued_ms,
+        } = queued;
+
+        let mut easy = match prepare_curl_handle(&req, cancel_token.clone()) {
+            Ok(easy) => easy,
+            Err(err) => {
+                let _ = reply.send(Err(err));
+                return;
+            }
+        };
+        easy.get_mut().body_sink = body_sink;
+
+        let mut handle = match self.multi.add2(easy) {
+            Ok(handle) => handle,
+            Err(err) => {
+                log::error!("Failed to add request {} to relay engine: {}", ticket, err);
+                let _ = reply.send(Err(RelayError::RequestRunError(
+                    err.description().to_string(),
+                )));
+                return;
+            }
+        };
+
+        if let Err(err) = handle.set_token(ticket) {
+            log::error!("Failed to tag request {}: {}", ticket, err);
+        }
+
+        let start_time_ms = now_ms();
+        log::info!(
+            "Started request {} on relay engine at timestamp: {}",
+            ticket,
+            start_time_ms
+        );
+
+        *self.per_host.entry(host.clone()).or_insert(0) += 1;
+        self.active.insert(
+            ticket,
+            Active {
+                handle,
+                host,
+                req,
+                cancel_token,
+                reply,
+                queued_ms,
+                start_time_ms,
+            },
+        );
+    }
+
+    fn finish_completed(&mut self) {
+        let mut completed = Vec::new();
+        self.multi.messages(|message| {
+            if let (Ok(ticket), Some(result)) = (message.token(), message.result()) {
+                completed.push((ticket, result));
+            }
+        });
+
+        for (ticket, result) in completed {
+            let Some(active) = self.take_active(ticket) else {
+                continue;
+            };
+            let end_time_ms = now_ms();
+
+            let response = match self.multi.remove2(active.handle) {
+                Ok(mut easy) => match result {
+                    Ok(()) => {
+                        log::info!(
+                            "Request {} completed on relay engine:\nDuration: {}ms",
+                            ticket,
+                            end_time_ms - active.start_time_ms
+                        );
+                        collect_response(&mut easy, &active.req, active.start_time_ms, end_time_ms)
+                    }
+                    Err(err) => {
+                        log::error!("Request {} transfer failed: {}", ticket, err);
+                        Err(easy.get_ref().transfer_error(&err))
+                    }
+                },
+                Err(err) => Err(RelayError::RequestRunError(err.description().to_string())),
+            };
+
+            let _ = active.reply.send(response);
+        }
+    }
+
+    fn abort(&mut self, ticket: usize, reason: RelayError) {
+        let Some(active) = self.take_active(ticket) else {
+            return;
+        };
+
+        log::warn!("Removing request {} from relay engine", ticket);
+        if let Err(err) = self.multi.remove2(active.handle) {
+            log::error!("Failed to remove request {}: {}", ticket, err);
+        }
+        let _ = active.reply.send(Err(reason));
+    }
+
+    fn take_active(&mut self, ticket: usize) -> Option<Active> {
+        let active = self.active.remove(&ticket)?;
+        if let Some(count) = self.per_host.get_mut(&active.host) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                self.per_host.remove(&active.host);
+            }
+        }
+        Some(active)
+    }
+}
+
+/// Key used to group requests for the per host limit, the URL authority
+/// when it parses and the raw endpoint otherwise.
+fn host_key(endpoint: &str) -> String {
+    endpoint
+        .parse::<http::Uri>()
+        .ok()
+        .and_then(|uri| uri.authority().map(|authority| authority.to_string()))
+        .unwrap_or_else(|| endpoint.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+        time::Instant,
+    };
+
+    /// Accepts a connection and reads the request, which is never answered.
+    fn accept(listener: &TcpListener) -> TcpStream {
+        let (mut socket, _) = listener.accept().unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let _ = socket.read(&mut [0; 1024]).unwrap();
+        socket
+    }
+
+    /// Waits off the runtime, so the cancelled request's future can still
+    /// run and tell the engine.
+    async fn wait_for_close(mut socket: TcpStream, since: Instant) -> Duration {
+        tokio::task::spawn_blocking(move || {
+            while socket.read(&mut [0; 1024]).unwrap() > 0 {}
+            since.elapsed()
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn lists_and_cancels_in_flight_requests() {
+        let listener = Arc::new(TcpListener::bind("127.0.0.1:0").unwrap());
+        let url = format!("http://{}/slow", listener.local_addr().unwrap());
+        // One transfer per host keeps the second request queued.
+        let engine = Arc::new(
+            RelayEngine::new(EngineConfig {
+                max_concurrent: 32,
+                max_per_host: 1,
+            })
+            .unwrap(),
+        );
+        let spawn = |req_id, cancel_token| {
+            let engine = engine.clone();
+            let req = RequestWithMetadata::new(
+                req_id,
+                "GET".to_string(),
+                url.clone(),
+                Vec::new(),
+                None,
+                true,
+                Vec::new(),
+                None,
+                None,
+            );
+            tokio::spawn(async move { engine.execute(req, cancel_token).await })
+        };
+
+        let cancel_token = CancellationToken::new();
+        let first = spawn(1, cancel_token.clone());
+        let socket = tokio::task::spawn_blocking({
+            let listener = listener.clone();
+            move || accept(&listener)
+        })
+        .await
+        .unwrap();
+        let second = spawn(2, CancellationToken::new());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let active = engine.active_requests().await.unwrap();
+        assert_eq!(
+            active.iter().map(|req| req.req_id).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(
+            (active[0].method.as_str(), active[0].url.as_str()),
+            ("GET", url.as_str())
+        );
+        assert!(active[0].started_ms.is_some() && active[1].started_ms.is_none());
+
+        // Cancelling the token tears down the first connection and starts
+        // the second request.
+        let since = Instant::now();
+        cancel_token.cancel();
+        let closed = wait_for_close(socket, since).await;
+        assert!(closed < MAX_POLL_INTERVAL, "closed after {:?}", closed);
+        assert!(matches!(
+            first.await.unwrap(),
+            Err(RelayError::RequestCancelled)
+        ));
+
+        let socket = tokio::task::spawn_blocking({
+            let listener = listener.clone();
+            move || accept(&listener)
+        })
+        .await
+        .unwrap();
+        let since = Instant::now();
+        assert_eq!(engine.cancel_all().await.unwrap(), 1);
+        let closed = wait_for_close(socket, since).await;
+        assert!(closed < MAX_POLL_INTERVAL, "closed after {:?}", closed);
+        assert!(matches!(
+            second.await.unwrap(),
+            Err(RelayError::RequestCancelled)
+        ));
+        assert!(engine.active_requests().await.unwrap().is_empty());
+    }
+}
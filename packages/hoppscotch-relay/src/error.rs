@@ -1,6 +1,8 @@
 use serde::Serialize;
 use thiserror::Error;
 
+use crate::retry::RetryAttempt;
+
 #[derive(Debug, Error, Serialize)]
 pub enum RelayError {
     #[error("Invalid method")]
@@ -9,8 +11,21 @@ pub enum RelayError {
     InvalidUrl,
     #[error("Invalid headers")]
     InvalidHeaders,
+    #[error("Connection failed: {0}")]
+    ConnectionFailed(String),
+    #[error("Request timed out: {0}")]
+    Timeout(String),
+    #[error("Request cancelled")]
+    RequestCancelled,
     #[error("Request run error: {0}")]
     RequestRunError(String),
+    /// Every attempt a retry policy allowed failed, `last` is the error of
+    /// the final one.
+    #[error("{last} (after {count} attempts)", count = attempts.len())]
+    RetriesExhausted {
+        attempts: Vec<RetryAttempt>,
+        last: Box<RelayError>,
+    },
 }
 
 pub type RelayResult<T> = std::result::Result<T, RelayError>;
@@ -0,0 +1,976 @@
+//! gRPC calls driven by protobuf descriptors instead of generated code.
+//!
+//! Schemas come from `.proto` files, compiled descriptor sets or server
+//! reflection. Calls go over the relay's own curl transfer using HTTP/2, so the
+//! certificate and proxy settings of `RequestWithMetadata` apply unchanged.
+
+use curl::easy::HttpVersion;
+use prost::Message;
+use prost_reflect::{
+    prost_types::FileDescriptorProto, DescriptorPool, DynamicMessage, MessageDescriptor,
+    MethodDescriptor,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    error::{RelayError, RelayResult},
+    interop::{KeyValuePair, RequestWithMetadata},
+    relay::{collect_response, now_ms, prepare_curl_handle, transfer_error},
+};
+
+/// Length prefix in front of every message: a compression flag and a big endian length.
+const FRAME_HEADER_LEN: usize = 5;
+/// `grpc-status` for a method the server doesn't implement.
+const STATUS_UNIMPLEMENTED: u32 = 12;
+/// `grpc-status` used when the server never sent one.
+const STATUS_UNKNOWN: u32 = 2;
+/// Reflection services to try, newest first.
+const REFLECTION_SERVICES: [&str; 2] = [
+    "grpc.reflection.v1.ServerReflection",
+    "grpc.reflection.v1alpha.ServerReflection",
+];
+
+/// Where to load a schema from.
+#[derive(Clone, Debug, Deserialize)]
+pub enum GrpcSchemaSource {
+    /// `.proto` files, with imports looked up in `include_dirs`. Without any
+    /// include directories, each file's own directory is used.
+    ProtoFiles {
+        files: Vec<String>,
+        include_dirs: Vec<String>,
+    },
+    /// A binary `FileDescriptorSet`, as written by `protoc --descriptor_set_out`.
+    DescriptorSet { path: String },
+    /// Ask the server at `endpoint` through the gRPC server reflection service.
+    Reflection(Box<RequestWithMetadata>),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GrpcServiceInfo {
+    /// Fully qualified service name, e.g. `helloworld.Greeter`.
+    pub name: String,
+    pub methods: Vec<GrpcMethodInfo>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GrpcMethodInfo {
+    pub name: String,
+    /// Request path, e.g. `/helloworld.Greeter/SayHello`.
+    pub path: String,
+    pub input_type: String,
+    pub output_type: String,
+    pub client_streaming: bool,
+    pub server_streaming: bool,
+}
+
+/// Final status of a call, from the `grpc-status` and `grpc-message` trailers.
+#[derive(Clone, Debug, Serialize)]
+pub struct GrpcStatus {
+    pub code: u32,
+    pub message: String,
+}
+
+impl GrpcStatus {
+    pub fn is_ok(&self) -> bool {
+        self.code == 0
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct GrpcResponse {
+    pub status: GrpcStatus,
+    /// Response metadata sent before the first message.
+    pub headers: Vec<KeyValuePair>,
+    /// Response metadata sent after the last message, including the status.
+    pub trailers: Vec<KeyValuePair>,
+    pub message_count: usize,
+    pub time_start_ms: u128,
+    pub time_end_ms: u128,
+}
+
+/// Services and message types loaded from one schema source.
+#[derive(Clone, Debug)]
+pub struct GrpcSchema {
+    pool: DescriptorPool,
+}
+
+impl GrpcSchema {
+    /// Loads a schema. Reflection talks to the server and blocks the calling
+    /// thread until it has every file it needs.
+    pub fn load(source: &GrpcSchemaSource, cancel_token: CancellationToken) -> RelayResult<Self> {
+        match source {
+            GrpcSchemaSource::ProtoFiles {
+                files,
+                include_dirs,
+            } => Self::from_proto_files(files, include_dirs),
+            GrpcSchemaSource::DescriptorSet { path } => {
+                let bytes = std::fs::read(path).map_err(|err| {
+                    RelayError::RequestRunError(format!(
+                        "Failed to read descriptor set '{}': {}",
+                        path, err
+                    ))
+                })?;
+                Self::from_descriptor_set(&bytes)
+            }
+            GrpcSchemaSource::Reflection(req) => Self::from_reflection(req, cancel_token),
+        }
+    }
+
+    pub fn from_proto_files<P: AsRef<Path>>(files: &[P], include_dirs: &[P]) -> RelayResult<Self> {
+        let mut includes: Vec<PathBuf> = include_dirs
+            .iter()
+            .map(|dir| dir.as_ref().to_path_buf())
+            .collect();
+        if includes.is_empty() {
+            for file in files {
+                let dir = file
+                    .as_ref()
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default();
+                if !includes.contains(&dir) {
+                    includes.push(dir);
+                }
+            }
+        }
+
+        let parsed = protobuf_parse::Parser::new()
+            .pure()
+            .includes(&includes)
+            .inputs(files.iter().map(AsRef::as_ref))
+            .parse_and_typecheck()
+            .map_err(|err| {
+                log::error!("Failed to parse proto files: {:?}", err);
+                RelayError::RequestRunError(format!("Failed to parse proto files: {:#}", err))
+            })?;
+
+        // The parser has its own descriptor types, move them over in wire format.
+        let protos = parsed
+            .file_descriptors
+            .iter()
+            .map(|file| {
+                let bytes = protobuf::Message::write_to_bytes(file).map_err(|err| {
+                    RelayError::RequestRunError(format!("Failed to encode descriptor: {}", err))
+                })?;
+                FileDescriptorProto::decode(bytes.as_slice()).map_err(|err| {
+                    RelayError::RequestRunError(format!("Failed to decode descriptor: {}", err))
+                })
+            })
+            .collect::<RelayResult<Vec<_>>>()?;
+
+        Self::from_file_protos(protos)
+    }
+
+    pub fn from_descriptor_set(bytes: &[u8]) -> RelayResult<Self> {
+        let pool = DescriptorPool::decode(bytes).map_err(|err| {
+            RelayError::RequestRunError(format!("Invalid descriptor set: {}", err))
+        })?;
+        log::info!("Loaded descriptor set with {} files", pool.files().len());
+        Ok(Self { pool })
+    }
+
+    /// Builds the schema from what the server at `req.endpoint` reports through
+    /// the reflection service, following imports until every file is known.
+    pub fn from_reflection(
+        req: &RequestWithMetadata,
+        cancel_token: CancellationToken,
+    ) -> RelayResult<Self> {
+        let mut client = ReflectionClient::new(req, cancel_token);
+
+        let services = client
+            .round(vec![MessageRequest::ListServices(String::new())])?
+            .into_iter()
+            .flat_map(|response| match response {
+                MessageResponse::ServiceList(list) => list.service,
+                _ => Vec::new(),
+            })
+            .map(|service| service.name)
+            .filter(|name| !REFLECTION_SERVICES.contains(&name.as_str()))
+            .collect::<Vec<_>>();
+        log::info!("Server reflection listed {} services", services.len());
+
+        let mut files = BTreeMap::new();
+        let mut requests = services
+            .into_iter()
+            .map(MessageRequest::FileContainingSymbol)
+            .collect::<Vec<_>>();
+        let mut requested = HashSet::new();
+
+        while !requests.is_empty() {
+            for response in client.round(requests)? {
+                match response {
+                    MessageResponse::FileDescriptors(response) => {
+                        for bytes in response.file_descriptor_proto {
+                            let file =
+                                FileDescriptorProto::decode(bytes.as_slice()).map_err(|err| {
+                                    RelayError::RequestRunError(format!(
+                                        "Server sent an invalid file descriptor: {}",
+                                        err
+                                    ))
+                                })?;
+                            files.entry(file.name().to_string()).or_insert(file);
+                        }
+                    }
+                    MessageResponse::Error(error) => {
+                        log::warn!(
+                            "Reflection request failed: {} {}",
+                            error.error_code,
+                            error.error_message
+                        );
+                    }
+                    MessageResponse::ServiceList(_) => {}
+                }
+            }
+
+            // Ask for imports the server didn't include on its own.
+            requests = files
+                .values()
+                .flat_map(|file| file.dependency.iter())
+                .filter(|name| !files.contains_key(*name) && requested.insert((*name).clone()))
+                .cloned()
+                .map(MessageRequest::FileByFilename)
+                .collect();
+        }
+
+        Self::from_file_protos(files.into_values().collect())
+    }
+
+    fn from_file_protos(files: Vec<FileDescriptorProto>) -> RelayResult<Self> {
+        let mut pool = DescriptorPool::new();
+        pool.add_file_descriptor_protos(files).map_err(|err| {
+            RelayError::RequestRunError(format!("Invalid protobuf schema: {}", err))
+        })?;
+        log::info!("Loaded protobuf schema with {} files", pool.files().len());
+        Ok(Self { pool })
+    }
+
+    pub fn services(&self) -> Vec<GrpcServiceInfo> {
+        self.pool
+            .services()
+            .map(|service| GrpcServiceInfo {
+                name: service.full_name().to_string(),
+                methods: service
+                    .methods()
+                    .map(|method| GrpcMethodInfo {
+                        name: method.name().to_string(),
+                        path: method_path(&method),
+                        input_type: method.input().full_name().to_string(),
+                        output_type: method.output().full_name().to_string(),
+                        client_streaming: method.is_client_streaming(),
+                        server_streaming: method.is_server_streaming(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Calls `method` (`package.Service/Method`) on the server at `req.endpoint`.
+    ///
+    /// `input` is the request in protobuf JSON form. Client streaming methods
+    /// take an array and send every element before reading the response.
+    /// Each response message is handed to `on_message` as JSON as soon as it
+    /// arrives. Blocks until the server ends the call or `cancel_token` fires.
+    pub fn call<F>(
+        &self,
+        req: &RequestWithMetadata,
+        method: &str,
+        input: serde_json::Value,
+        cancel_token: CancellationToken,
+        mut on_message: F,
+    ) -> RelayResult<GrpcResponse>
+    where
+        F: FnMut(serde_json::Value) + Send + 'static,
+    {
+        let method = self.find_method(method)?;
+
+        let inputs = match input {
+            serde_json::Value::Array(items) if method.is_client_streaming() => items,
+            input => vec![input],
+        };
+        let mut body = Vec::new();
+        for input in inputs {
+            let message = DynamicMessage::deserialize(method.input(), input).map_err(|err| {
+                RelayError::RequestRunError(format!(
+                    "Invalid input for {}: {}",
+                    method.input().full_name(),
+                    err
+                ))
+            })?;
+            encode_frame(&message.encode_to_vec(), &mut body);
+        }
+
+        let output = method.output();
+        unary_or_streaming_call(
+            req,
+            &method_path(&method),
+            body,
+            cancel_token,
+            move |frame| {
+                let message = decode_message(&output, frame)?;
+                let value = serde_json::to_value(&message).map_err(|err| {
+                    RelayError::RequestRunError(format!(
+                        "Failed to convert response to JSON: {}",
+                        err
+                    ))
+                })?;
+                on_message(value);
+                Ok(())
+            },
+        )
+    }
+
+    /// Whether the server answers `method` (`package.Service/Method`) with a
+    /// stream of messages.
+    pub fn is_server_streaming(&self, method: &str) -> RelayResult<bool> {
+        Ok(self.find_method(method)?.is_server_streaming())
+    }
+
+    fn find_method(&self, method: &str) -> RelayResult<MethodDescriptor> {
+        let (service, name) = method
+            .trim_start_matches('/')
+            .rsplit_once(['/', '.'])
+            .ok_or_else(|| {
+                RelayError::RequestRunError(format!("Invalid gRPC method '{}'", method))
+            })?;
+
+        self.pool
+            .get_service_by_name(service)
+            .and_then(|service| service.methods().find(|m| m.name() == name))
+            .ok_or_else(|| RelayError::RequestRunError(format!("Unknown gRPC method '{}'", method)))
+    }
+}
+
+fn method_path(method: &MethodDescriptor) -> String {
+    format!("/{}/{}", method.parent_service().full_name(), method.name())
+}
+
+fn decode_message(desc: &MessageDescriptor, frame: &[u8]) -> RelayResult<DynamicMessage> {
+    DynamicMessage::decode(desc.clone(), frame).map_err(|err| {
+        RelayError::RequestRunError(format!(
+            "Failed to decode {} from response: {}",
+            desc.full_name(),
+            err
+        ))
+    })
+}
+
+fn encode_frame(message: &[u8], out: &mut Vec<u8>) {
+    out.push(0);
+    out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    out.extend_from_slice(message);
+}
+
+/// Splits a response body into length prefixed messages. Chunks may end
+/// anywhere, incomplete frames are kept until the rest arrives.
+#[derive(Debug, Default)]
+struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    fn feed(&mut self, chunk: &[u8]) -> RelayResult<Vec<Vec<u8>>> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut frames = Vec::new();
+        while self.buffer.len() >= FRAME_HEADER_LEN {
+            if self.buffer[0] != 0 {
+                return Err(RelayError::RequestRunError(
+                    "Server sent a compressed message, which is not supported".to_string(),
+                ));
+            }
+            let len = u32::from_be_bytes([
+                self.buffer[1],
+                self.buffer[2],
+                self.buffer[3],
+                self.buffer[4],
+            ]) as usize;
+            if self.buffer.len() < FRAME_HEADER_LEN + len {
+                break;
+            }
+            frames.push(self.buffer[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec());
+            self.buffer.drain(..FRAME_HEADER_LEN + len);
+        }
+
+        Ok(frames)
+    }
+
+    fn has_partial_frame(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct CallState {
+    decoder: FrameDecoder,
+    /// Number of response headers seen when the body started, everything
+    /// after that are trailers.
+    header_count: Option<usize>,
+    message_count: usize,
+    error: Option<RelayError>,
+}
+
+/// Sends the framed `body` to `path` and hands every response frame to
+/// `on_frame`. Unary and streaming calls only differ in how many frames the
+/// server sends back.
+fn unary_or_streaming_call<F>(
+    req: &RequestWithMetadata,
+    path: &str,
+    body: Vec<u8>,
+    cancel_token: CancellationToken,
+    mut on_frame: F,
+) -> RelayResult<GrpcResponse>
+where
+    F: FnMut(&[u8]) -> RelayResult<()> + Send + 'static,
+{
+    let uri = req
+        .endpoint
+        .parse::<http::Uri>()
+        .map_err(|_| RelayError::InvalidUrl)?;
+    let tls = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => return Err(RelayError::InvalidUrl),
+    };
+
+    let mut call_req = req.clone();
+    call_req.method = "POST".to_string();
+    call_req.endpoint = format!("{}{}", req.endpoint.trim_end_matches('/'), path);
+    call_req.body = None;
+    call_req.retry = None;
+    call_req.headers.retain(|header| {
+        !["content-type", "te", "grpc-accept-encoding"]
+            .iter()
+            .any(|key| header.key.eq_ignore_ascii_case(key))
+    });
+    for (key, value) in [
+        ("Content-Type", "application/grpc"),
+        ("TE", "trailers"),
+        ("grpc-accept-encoding", "identity"),
+        // Stops curl from waiting on a `100 Continue` the server never sends.
+        ("Expect", ""),
+    ] {
+        call_req.headers.push(KeyValuePair {
+            key: key.to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    let mut curl_handle = prepare_curl_handle(&call_req, cancel_token.clone())?;
+    let configure = |err: curl::Error| {
+        RelayError::RequestRunError(format!("Failed to configure gRPC call: {}", err))
+    };
+    curl_handle.post_fields_copy(&body).map_err(configure)?;
+    curl_handle
+        .http_version(if tls {
+            HttpVersion::V2TLS
+        } else {
+            HttpVersion::V2PriorKnowledge
+        })
+        .map_err(configure)?;
+    if call_req.proxy.is_some() {
+        // HTTP/2 only works end to end, so even plaintext calls need a tunnel.
+        curl_handle.http_proxy_tunnel(true).map_err(configure)?;
+    }
+
+    let state = Arc::new(Mutex::new(CallState::default()));
+    let sink_state = state.clone();
+    let sink_token = cancel_token.clone();
+    curl_handle.get_mut().body_sink = Some(Box::new(move |_status, headers, data| {
+        // Streams can stay open for long, don't wait for the next progress tick.
+        if sink_token.is_cancelled() {
+            return false;
+        }
+        let Ok(mut state) = sink_state.lock() else {
+            return false;
+        };
+        state.header_count.get_or_insert(headers.len());
+
+        let result = state.decoder.feed(data).and_then(|frames| {
+            frames.iter().try_for_each(|frame| {
+                state.message_count += 1;
+                on_frame(frame)
+            })
+        });
+        match result {
+            Ok(()) => true,
+            Err(err) => {
+                state.error = Some(err);
+                false
+            }
+        }
+    }));
+
+    log::info!("Starting gRPC call: {}", call_req.endpoint);
+    let time_start_ms = now_ms();
+    let result = curl_handle.perform();
+    let time_end_ms = now_ms();
+
+    let mut state = state
+        .lock()
+        .map_err(|_| RelayError::RequestRunError("gRPC call state poisoned".to_string()))?;
+    if let Some(err) = state.error.take() {
+        return Err(err);
+    }
+    if let Err(err) = result {
+        if cancel_token.is_cancelled() {
+            return Err(RelayError::RequestCancelled);
+        }
+        log::error!("gRPC call to {} failed: {}", call_req.endpoint, err);
+        return Err(transfer_error(&err));
+    }
+    if state.decoder.has_partial_frame() {
+        log::warn!("gRPC response ended in the middle of a message");
+    }
+
+    let response = collect_response(&mut curl_handle, &call_req, time_start_ms, time_end_ms)?;
+    if response.status != 200 {
+        return Err(RelayError::RequestRunError(format!(
+            "Server answered the gRPC call with HTTP status {}",
+            response.status
+        )));
+    }
+
+    // A response without messages may carry its status in the headers alone.
+    let mut headers = response.headers;
+    let trailers = match state.header_count {
+        Some(count) if count <= headers.len() => headers.split_off(count),
+        _ => Vec::new(),
+    };
+    let status = grpc_status(&trailers)
+        .or_else(|| grpc_status(&headers))
+        .unwrap_or_else(|| GrpcStatus {
+            code: STATUS_UNKNOWN,
+            message: "Server did not send a grpc-status".to_string(),
+        });
+    log::info!(
+        "gRPC call finished: [Status: {}] [Messages: {}] [Duration: {}ms]",
+        status.code,
+        state.message_count,
+        time_end_ms - time_start_ms
+    );
+
+    Ok(GrpcResponse {
+        status,
+        headers,
+        trailers,
+        message_count: state.message_count,
+        time_start_ms,
+        time_end_ms,
+    })
+}
+
+fn grpc_status(metadata: &[KeyValuePair]) -> Option<GrpcStatus> {
+    let find = |name: &str| {
+        metadata
+            .iter()
+            .rev()
+            .find(|header| header.key.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    };
+
+    let code = find("grpc-status")?.parse().unwrap_or(STATUS_UNKNOWN);
+    let message = find("grpc-message")
+        .map(|message| url_escape::decode(message).into_owned())
+        .unwrap_or_default();
+
+    Some(GrpcStatus { code, message })
+}
+
+/// Runs reflection requests against whichever reflection service version the
+/// server supports.
+struct ReflectionClient<'a> {
+    req: &'a RequestWithMetadata,
+    cancel_token: CancellationToken,
+    service: usize,
+}
+
+impl<'a> ReflectionClient<'a> {
+    fn new(req: &'a RequestWithMetadata, cancel_token: CancellationToken) -> Self {
+        Self {
+            req,
+            cancel_token,
+            service: 0,
+        }
+    }
+
+    /// Sends all `requests` on one stream and returns the answers in order.
+    fn round(&mut self, requests: Vec<MessageRequest>) -> RelayResult<Vec<MessageResponse>> {
+        let mut body = Vec::new();
+        for request in requests {
+            let request = ServerReflectionRequest {
+                host: String::new(),
+                message_request: Some(request),
+            };
+            encode_frame(&request.encode_to_vec(), &mut body);
+        }
+
+        loop {
+            let service = REFLECTION_SERVICES[self.service];
+            let responses = Arc::new(Mutex::new(Vec::new()));
+            let sink = responses.clone();
+
+            let response = unary_or_streaming_call(
+                self.req,
+                &format!("/{}/ServerReflectionInfo", service),
+                body.clone(),
+                self.cancel_token.clone(),
+                move |frame| {
+                    let response = ServerReflectionResponse::decode(frame).map_err(|err| {
+                        RelayError::RequestRunError(format!(
+                            "Invalid server reflection response: {}",
+                            err
+                        ))
+                    })?;
+                    if let (Ok(mut sink), Some(response)) = (sink.lock(), response.message_response)
+                    {
+                        sink.push(response);
+                    }
+                    Ok(())
+                },
+            )?;
+
+            if response.status.code == STATUS_UNIMPLEMENTED
+                && self.service + 1 < REFLECTION_SERVICES.len()
+            {
+                log::debug!("{} not available, trying an older version", service);
+                self.service += 1;
+                continue;
+            }
+            if !response.status.is_ok() {
+                return Err(RelayError::RequestRunError(format!(
+                    "Server reflection failed ({}): {}",
+                    response.status.code, response.status.message
+                )));
+            }
+
+            return Ok(responses
+                .lock()
+                .map(|mut responses| std::mem::take(&mut *responses))
+                .unwrap_or_default());
+        }
+    }
+}
+
+// Messages of `grpc/reflection/v1/reflection.proto`, which v1alpha shares.
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ServerReflectionRequest {
+    #[prost(string, tag = "1")]
+    host: String,
+    #[prost(oneof = "MessageRequest", tags = "3, 4, 7")]
+    message_request: Option<MessageRequest>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum MessageRequest {
+    #[prost(string, tag = "3")]
+    FileByFilename(String),
+    #[prost(string, tag = "4")]
+    FileContainingSymbol(String),
+    #[prost(string, tag = "7")]
+    ListServices(String),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ServerReflectionResponse {
+    #[prost(oneof = "MessageResponse", tags = "4, 6, 7")]
+    message_response: Option<MessageResponse>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum MessageResponse {
+    #[prost(message, tag = "4")]
+    FileDescriptors(FileDescriptorResponse),
+    #[prost(message, tag = "6")]
+    ServiceList(ListServiceResponse),
+    #[prost(message, tag = "7")]
+    Error(ErrorResponse),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct FileDescriptorResponse {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    file_descriptor_proto: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ListServiceResponse {
+    #[prost(message, repeated, tag = "1")]
+    service: Vec<ServiceResponse>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ServiceResponse {
+    #[prost(string, tag = "1")]
+    name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ErrorResponse {
+    #[prost(int32, tag = "1")]
+    error_code: i32,
+    #[prost(string, tag = "2")]
+    error_message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[test]
+    fn decodes_frames_split_across_chunks() {
+        let mut body = Vec::new();
+        encode_frame(b"first", &mut body);
+        encode_frame(b"", &mut body);
+        encode_frame(b"second", &mut body);
+
+        let mut decoder = FrameDecoder::default();
+        let mut frames = decoder.feed(&body[..3]).unwrap();
+        frames.extend(decoder.feed(&body[3..14]).unwrap());
+        frames.extend(decoder.feed(&body[14..]).unwrap());
+
+        assert_eq!(frames, vec![b"first".to_vec(), vec![], b"second".to_vec()]);
+        assert!(!decoder.has_partial_frame());
+        assert!(FrameDecoder::default().feed(&[1, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn loads_services_from_proto_files() {
+        let dir = std::env::temp_dir().join(format!("relay-grpc-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("common.proto"),
+            "syntax = \"proto3\";\npackage demo;\nmessage Name { string value = 1; }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("greeter.proto"),
+            "syntax = \"proto3\";\npackage demo;\nimport \"common.proto\";\n\
+             message Greeting { string text = 1; int32 count = 2; }\n\
+             service Greeter {\n\
+               rpc Hello (Name) returns (Greeting);\n\
+               rpc Repeat (Name) returns (stream Greeting);\n\
+             }\n",
+        )
+        .unwrap();
+
+        let schema = GrpcSchema::from_proto_files(&[dir.join("greeter.proto")], &[]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let services = schema.services();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].name, "demo.Greeter");
+        assert_eq!(services[0].methods[1].path, "/demo.Greeter/Repeat");
+        assert_eq!(services[0].methods[0].input_type, "demo.Name");
+        assert!(services[0].methods[1].server_streaming);
+
+        let method = schema.find_method("demo.Greeter/Hello").unwrap();
+        let message =
+            DynamicMessage::deserialize(method.input(), serde_json::json!({ "value": "relay" }))
+                .unwrap();
+        let decoded = decode_message(&method.input(), &message.encode_to_vec()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::json!({ "value": "relay" })
+        );
+    }
+
+    /// Writes one HTTP/2 frame.
+    fn write_frame(socket: &mut TcpStream, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        socket.write_all(&frame).unwrap();
+    }
+
+    /// HPACK literal header field without indexing, with a new name.
+    fn literal_header(name: &str, value: &str, block: &mut Vec<u8>) {
+        block.push(0);
+        block.push(name.len() as u8);
+        block.extend_from_slice(name.as_bytes());
+        block.push(value.len() as u8);
+        block.extend_from_slice(value.as_bytes());
+    }
+
+    /// Minimal cleartext HTTP/2 server for one call. Answers the first stream
+    /// with `messages` and `grpc_status`, and returns the request body.
+    fn spawn_h2c_server(
+        messages: Vec<Vec<u8>>,
+        grpc_status: u32,
+    ) -> (String, std::thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut preface = [0; 24];
+            socket.read_exact(&mut preface).unwrap();
+            assert_eq!(&preface, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+            write_frame(&mut socket, 4, 0, 0, &[]);
+
+            let mut body = Vec::new();
+            loop {
+                let mut header = [0; 9];
+                if socket.read_exact(&mut header).is_err() {
+                    return body;
+                }
+                let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+                let (kind, flags) = (header[3], header[4]);
+                let stream = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+                let mut payload = vec![0; len];
+                socket.read_exact(&mut payload).unwrap();
+
+                match kind {
+                    // SETTINGS from the client, acknowledged.
+                    4 if flags & 1 == 0 => write_frame(&mut socket, 4, 1, 0, &[]),
+                    0 => body.extend_from_slice(&payload),
+                    _ => {}
+                }
+                // The request ends with END_STREAM on its last DATA or HEADERS frame.
+                if (kind == 0 || kind == 1) && flags & 1 != 0 {
+                    let mut headers = vec![0x88];
+                    literal_header("content-type", "application/grpc", &mut headers);
+                    write_frame(&mut socket, 1, 4, stream, &headers);
+                    for message in &messages {
+                        let mut frame = Vec::new();
+                        encode_frame(message, &mut frame);
+                        write_frame(&mut socket, 0, 0, stream, &frame);
+                    }
+                    let mut trailers = Vec::new();
+                    literal_header("grpc-status", &grpc_status.to_string(), &mut trailers);
+                    literal_header("grpc-message", "all%20done", &mut trailers);
+                    write_frame(&mut socket, 1, 5, stream, &trailers);
+                }
+            }
+        });
+        (url, server)
+    }
+
+    fn greeter_schema(test: &str) -> GrpcSchema {
+        let dir = std::env::temp_dir().join(format!("relay-grpc-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("greeter.proto"),
+            "syntax = \"proto3\";\npackage demo;\n\
+             message Name { string value = 1; }\n\
+             message Greeting { string text = 1; int32 count = 2; }\n\
+             service Greeter {\n\
+               rpc Hello (Name) returns (Greeting);\n\
+               rpc Repeat (Name) returns (stream Greeting);\n\
+             }\n",
+        )
+        .unwrap();
+        let schema = GrpcSchema::from_proto_files(&[dir.join("greeter.proto")], &[]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        schema
+    }
+
+    fn encode_json(desc: MessageDescriptor, value: serde_json::Value) -> Vec<u8> {
+        DynamicMessage::deserialize(desc, value)
+            .unwrap()
+            .encode_to_vec()
+    }
+
+    #[test]
+    fn streams_messages_from_a_server_over_h2c() {
+        let schema = greeter_schema("stream");
+        let method = schema.find_method("demo.Greeter/Repeat").unwrap();
+        let replies = (1..=2)
+            .map(|count| {
+                encode_json(
+                    method.output(),
+                    serde_json::json!({ "text": "hi relay", "count": count }),
+                )
+            })
+            .collect();
+        let (url, server) = spawn_h2c_server(replies, 0);
+
+        let req = RequestWithMetadata::new(
+            0,
+            "POST".to_string(),
+            url,
+            Vec::new(),
+            None,
+            true,
+            Vec::new(),
+            None,
+            None,
+        );
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let response = schema
+            .call(
+                &req,
+                "demo.Greeter/Repeat",
+                serde_json::json!({ "value": "relay" }),
+                CancellationToken::new(),
+                {
+                    let messages = messages.clone();
+                    move |message| messages.lock().unwrap().push(message)
+                },
+            )
+            .unwrap();
+
+        let mut expected_body = Vec::new();
+        encode_frame(
+            &encode_json(method.input(), serde_json::json!({ "value": "relay" })),
+            &mut expected_body,
+        );
+        assert_eq!(server.join().unwrap(), expected_body);
+
+        assert!(response.status.is_ok());
+        assert_eq!(response.status.message, "all done");
+        assert_eq!(response.message_count, 2);
+        assert!(response
+            .trailers
+            .iter()
+            .any(|trailer| trailer.key == "grpc-status" && trailer.value == "0"));
+        assert_eq!(
+            *messages.lock().unwrap(),
+            [
+                serde_json::json!({ "text": "hi relay", "count": 1 }),
+                serde_json::json!({ "text": "hi relay", "count": 2 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_the_status_of_a_failed_unary_call() {
+        let schema = greeter_schema("unary");
+        let (url, server) = spawn_h2c_server(Vec::new(), 5);
+
+        let req = RequestWithMetadata::new(
+            0,
+            "POST".to_string(),
+            url,
+            Vec::new(),
+            None,
+            true,
+            Vec::new(),
+            None,
+            None,
+        );
+        let response = schema
+            .call(
+                &req,
+                "/demo.Greeter/Hello",
+                serde_json::json!({ "value": "nobody" }),
+                CancellationToken::new(),
+                |_| panic!("no message expected"),
+            )
+            .unwrap();
+        server.join().unwrap();
+
+        assert_eq!(response.status.code, 5);
+        assert_eq!(response.message_count, 0);
+    }
+}
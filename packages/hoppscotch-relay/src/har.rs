@@ -0,0 +1,855 @@
+//! HAR 1.2 export and import.
+//!
+//! Exports a request together with its response, headers, cookies, body and
+//! curl's phase timings as a HAR entry. Imports turn entries written by this
+//! module or by browsers back into requests that can be run again.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{RelayError, RelayResult},
+    interop::{
+        BodyDef, FormDataEntry, FormDataValue, KeyValuePair, RequestWithMetadata,
+        ResponseWithMetadata,
+    },
+    relay::now_ms,
+};
+
+const HAR_VERSION: &str = "1.2";
+/// HAR's marker for a value that isn't known.
+const UNKNOWN: f64 = -1.0;
+
+/// A request and, when it got one, its response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HarExchange {
+    pub request: RequestWithMetadata,
+    pub response: Option<ResponseWithMetadata>,
+}
+
+/// Root of a `.har` file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Har {
+    pub log: HarLog,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HarLog {
+    pub version: String,
+    pub creator: HarCreator,
+    #[serde(default)]
+    pub entries: Vec<HarEntry>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HarCreator {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarEntry {
+    /// ISO 8601 start time, e.g. `2024-01-31T09:30:00.000Z`.
+    pub started_date_time: String,
+    /// Total time in milliseconds, the sum of the known `timings`.
+    pub time: f64,
+    pub request: HarRequest,
+    pub response: HarResponse,
+    #[serde(default)]
+    pub cache: HarCache,
+    pub timings: HarTimings,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarRequest {
+    pub method: String,
+    pub url: String,
+    pub http_version: String,
+    #[serde(default)]
+    pub cookies: Vec<HarCookie>,
+    #[serde(default)]
+    pub headers: Vec<HarNameValue>,
+    #[serde(default)]
+    pub query_string: Vec<HarNameValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<HarPostData>,
+    #[serde(default = "unknown_size")]
+    pub headers_size: i64,
+    #[serde(default = "unknown_size")]
+    pub body_size: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarResponse {
+    /// `0` when the request failed without a response.
+    pub status: u16,
+    pub status_text: String,
+    pub http_version: String,
+    #[serde(default)]
+    pub cookies: Vec<HarCookie>,
+    #[serde(default)]
+    pub headers: Vec<HarNameValue>,
+    pub content: HarContent,
+    #[serde(rename = "redirectURL", default)]
+    pub redirect_url: String,
+    #[serde(default = "unknown_size")]
+    pub headers_size: i64,
+    #[serde(default = "unknown_size")]
+    pub body_size: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HarNameValue {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarCookie {
+    pub name: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_only: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secure: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarPostData {
+    pub mime_type: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub params: Vec<HarParam>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarParam {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// `base64` when `value` holds binary file contents. Custom field, HAR
+    /// itself has no way to carry uploaded files.
+    #[serde(rename = "_encoding", default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarContent {
+    pub size: i64,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// `base64` when `text` holds a binary body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HarCache {}
+
+/// Phase durations in milliseconds, `-1` where not applicable.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HarTimings {
+    #[serde(default = "unknown_time")]
+    pub blocked: f64,
+    #[serde(default = "unknown_time")]
+    pub dns: f64,
+    /// Includes `ssl`.
+    #[serde(default = "unknown_time")]
+    pub connect: f64,
+    pub send: f64,
+    pub wait: f64,
+    pub receive: f64,
+    #[serde(default = "unknown_time")]
+    pub ssl: f64,
+}
+
+fn unknown_size() -> i64 {
+    -1
+}
+
+fn unknown_time() -> f64 {
+    UNKNOWN
+}
+
+impl Har {
+    pub fn new(entries: Vec<HarEntry>) -> Self {
+        Self {
+            log: HarLog {
+                version: HAR_VERSION.to_string(),
+                creator: HarCreator {
+                    name: "Postdata Relay".to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                },
+                entries,
+            },
+        }
+    }
+}
+
+/// Serializes `exchanges` as the contents of a `.har` file.
+pub fn export_har(exchanges: &[HarExchange]) -> RelayResult<String> {
+    let har = Har::new(
+        exchanges
+            .iter()
+            .map(|exchange| har_entry(&exchange.request, exchange.response.as_ref()))
+            .collect(),
+    );
+
+    serde_json::to_string_pretty(&har)
+        .map_err(|err| RelayError::RequestRunError(format!("Failed to write HAR: {}", err)))
+}
+
+/// Reads the requests of every entry in a `.har` file. Request IDs are the
+/// entry positions, certificate and proxy settings are left at their defaults.
+pub fn import_har(contents: &str) -> RelayResult<Vec<RequestWithMetadata>> {
+    let har: Har = serde_json::from_str(contents)
+        .map_err(|err| RelayError::RequestRunError(format!("Invalid HAR file: {}", err)))?;
+    log::info!("Importing {} HAR entries", har.log.entries.len());
+
+    har.log
+        .entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| har_request(index, entry.request))
+        .collect()
+}
+
+pub fn har_entry(req: &RequestWithMetadata, res: Option<&ResponseWithMetadata>) -> HarEntry {
+    let started_ms = res.map_or_else(now_ms, |res| res.time_start_ms);
+    let timings = res.map_or(
+        HarTimings {
+            blocked: UNKNOWN,
+            dns: UNKNOWN,
+            connect: UNKNOWN,
+            send: 0.0,
+            wait: 0.0,
+            receive: 0.0,
+            ssl: UNKNOWN,
+        },
+        har_timings,
+    );
+    let time = [
+        timings.blocked,
+        timings.dns,
+        timings.connect,
+        timings.send,
+        timings.wait,
+        timings.receive,
+    ]
+    .iter()
+    .filter(|time| **time > 0.0)
+    .sum();
+
+    HarEntry {
+        started_date_time: iso8601(started_ms),
+        time,
+        request: har_request_entry(req, res),
+        response: res.map_or_else(failed_response, har_response),
+        cache: HarCache {},
+        timings,
+    }
+}
+
+fn har_request_entry(req: &RequestWithMetadata, res: Option<&ResponseWithMetadata>) -> HarRequest {
+    let query_string = req
+        .endpoint
+        .split_once('?')
+        .map(|(_, query)| query.split('#').next().unwrap_or_default())
+        .map(|query| {
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    HarNameValue {
+                        name: decode_query_component(name),
+                        value: decode_query_component(value),
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let cookies = find_header(&req.headers, "cookie")
+        .map(|cookie| {
+            cookie
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .map(|(name, value)| HarCookie {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                    path: None,
+                    domain: None,
+                    expires: None,
+                    http_only: None,
+                    secure: None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let (post_data, body_size) = match &req.body {
+        Some(body) => har_post_data(body, &req.headers),
+        None => (None, 0),
+    };
+
+    HarRequest {
+        method: req.method.to_uppercase(),
+        url: req.endpoint.clone(),
+        http_version: res.map_or_else(|| "HTTP/1.1".to_string(), |res| res.http_version.clone()),
+        cookies,
+        // Prefer the headers as sent, which include the ones curl added.
+        headers: match res {
+            Some(res) if !res.sent_headers.is_empty() => har_headers(&res.sent_headers),
+            _ => har_headers(&req.headers),
+        },
+        query_string,
+        post_data,
+        headers_size: -1,
+        body_size,
+    }
+}
+
+fn har_post_data(body: &BodyDef, headers: &[KeyValuePair]) -> (Option<HarPostData>, i64) {
+    match body {
+        BodyDef::Text(text) => (
+            Some(HarPostData {
+                // curl sends bodies without a content type as a form.
+                mime_type: find_header(headers, "content-type")
+                    .unwrap_or("application/x-www-form-urlencoded")
+                    .to_string(),
+                params: Vec::new(),
+                text: Some(text.clone()),
+            }),
+            text.len() as i64,
+        ),
+        BodyDef::URLEncoded(entries) => {
+            let text = entries
+                .iter()
+                .map(|KeyValuePair { key, value }| {
+                    format!(
+                        "{}={}",
+                        url_escape::encode_www_form_urlencoded(key),
+                        url_escape::encode_www_form_urlencoded(value)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("&");
+            let size = text.len() as i64;
+            (
+                Some(HarPostData {
+                    mime_type: "application/x-www-form-urlencoded".to_string(),
+                    params: entries
+                        .iter()
+                        .map(|entry| HarParam {
+                            name: entry.key.clone(),
+                            value: Some(entry.value.clone()),
+                            file_name: None,
+                            content_type: None,
+                            encoding: None,
+                        })
+                        .collect(),
+                    text: Some(text),
+                }),
+                size,
+            )
+        }
+        BodyDef::FormData(entries) => (
+            Some(HarPostData {
+                mime_type: "multipart/form-data".to_string(),
+                params: entries
+                    .iter()
+                    .map(|entry| match &entry.value {
+                        FormDataValue::Text(value) => HarParam {
+                            name: entry.key.clone(),
+                            value: Some(value.clone()),
+                            file_name: None,
+                            content_type: None,
+                            encoding: None,
+                        },
+                        FormDataValue::File {
+                            filename,
+                            data,
+                            mime,
+                        } => {
+                            let (value, encoding) = encode_body(data);
+                            HarParam {
+                                name: entry.key.clone(),
+                                value: Some(value),
+                                file_name: Some(filename.clone()),
+                                content_type: Some(mime.clone()),
+                                encoding,
+                            }
+                        }
+                    })
+                    .collect(),
+                text: None,
+            }),
+            // The multipart boundary, and with it the size, is picked by curl.
+            -1,
+        ),
+    }
+}
+
+fn har_response(res: &ResponseWithMetadata) -> HarResponse {
+    let (text, encoding) = encode_body(&res.data);
+
+    HarResponse {
+        status: res.status,
+        status_text: res.status_text.clone(),
+        http_version: res.http_version.clone(),
+        cookies: res
+            .headers
+            .iter()
+            .filter(|header| header.key.eq_ignore_ascii_case("set-cookie"))
+            .filter_map(|header| parse_set_cookie(&header.value))
+            .collect(),
+        headers: har_headers(&res.headers),
+        content: HarContent {
+            size: res.data.len() as i64,
+            mime_type: find_header(&res.headers, "content-type")
+                .unwrap_or("x-unknown")
+                .to_string(),
+            text: Some(text),
+            encoding,
+        },
+        redirect_url: find_header(&res.headers, "location")
+            .unwrap_or_default()
+            .to_string(),
+        headers_size: -1,
+        body_size: res.data.len() as i64,
+    }
+}
+
+fn failed_response() -> HarResponse {
+    HarResponse {
+        status: 0,
+        status_text: String::new(),
+        http_version: String::new(),
+        cookies: Vec::new(),
+        headers: Vec::new(),
+        content: HarContent {
+            size: 0,
+            mime_type: "x-unknown".to_string(),
+            text: None,
+            encoding: None,
+        },
+        redirect_url: String::new(),
+        headers_size: -1,
+        body_size: -1,
+    }
+}
+
+/// Turns curl's cumulative phase timings into HAR's per phase durations.
+fn har_timings(res: &ResponseWithMetadata) -> HarTimings {
+    let t = &res.timings;
+    if t.total_ms <= 0.0 {
+        // No phase details, attribute everything to waiting on the server.
+        return HarTimings {
+            blocked: UNKNOWN,
+            dns: UNKNOWN,
+            connect: UNKNOWN,
+            send: 0.0,
+            wait: res.time_end_ms.saturating_sub(res.time_start_ms) as f64,
+            receive: 0.0,
+            ssl: UNKNOWN,
+        };
+    }
+
+    // A reused connection reports no connect time.
+    let reused = t.connect_ms <= 0.0;
+    let connected_ms = t.connect_ms.max(t.tls_handshake_ms).max(t.name_lookup_ms);
+
+    HarTimings {
+        blocked: UNKNOWN,
+        dns: if reused { UNKNOWN } else { t.name_lookup_ms },
+        connect: if reused {
+            UNKNOWN
+        } else {
+            connected_ms - t.name_lookup_ms
+        },
+        send: (t.pre_transfer_ms - connected_ms).max(0.0),
+        wait: (t.start_transfer_ms - t.pre_transfer_ms).max(0.0),
+        receive: (t.total_ms - t.start_transfer_ms).max(0.0),
+        ssl: if t.tls_handshake_ms > 0.0 {
+            t.tls_handshake_ms - t.connect_ms
+        } else {
+            UNKNOWN
+        },
+    }
+}
+
+fn har_request(index: usize, request: HarRequest) -> RelayResult<RequestWithMetadata> {
+    let multipart = request.post_data.as_ref().is_some_and(|post_data| {
+        post_data.mime_type.starts_with("multipart/form-data") && !post_data.params.is_empty()
+    });
+
+    let mut headers: Vec<KeyValuePair> = request
+        .headers
+        .into_iter()
+        // HTTP/2 pseudo headers are derived from the method and URL, the body
+        // length is recomputed when sending, and so is the multipart boundary.
+        .filter(|header| {
+            let skipped = header.name.starts_with(':')
+                || header.name.eq_ignore_ascii_case("content-length")
+                || (multipart && header.name.eq_ignore_ascii_case("content-type"));
+            !skipped
+        })
+        .map(|header| KeyValuePair {
+            key: header.name,
+            value: header.value,
+        })
+        .collect();
+
+    if find_header(&headers, "cookie").is_none() && !request.cookies.is_empty() {
+        headers.push(KeyValuePair {
+            key: "Cookie".to_string(),
+            value: request
+                .cookies
+                .iter()
+                .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+                .collect::<Vec<_>>()
+                .join("; "),
+        });
+    }
+
+    let body = request
+        .post_data
+        .map(|post_data| har_body(post_data, multipart))
+        .transpose()?
+        .flatten();
+
+    Ok(RequestWithMetadata::new(
+        index,
+        request.method,
+        request.url,
+        headers,
+        body,
+        true,
+        Vec::new(),
+        None,
+        None,
+    ))
+}
+
+fn har_body(post_data: HarPostData, multipart: bool) -> RelayResult<Option<BodyDef>> {
+    if multipart {
+        let entries = post_data
+            .params
+            .into_iter()
+            .map(|param| {
+                let value = param.value.unwrap_or_default();
+                let value = match param.file_name {
+                    Some(filename) => FormDataValue::File {
+                        filename,
+                        data: decode_body(&value, param.encoding.as_deref())?,
+                        mime: param
+                            .content_type
+                            .unwrap_or_else(|| "application/octet-stream".to_string()),
+                    },
+                    None => FormDataValue::Text(value),
+                };
+                Ok(FormDataEntry {
+                    key: param.name,
+                    value,
+                })
+            })
+            .collect::<RelayResult<Vec<_>>>()?;
+        return Ok(Some(BodyDef::FormData(entries)));
+    }
+
+    match post_data.text {
+        Some(text) => Ok(Some(BodyDef::Text(text))),
+        None if post_data.params.is_empty() => Ok(None),
+        None => Ok(Some(BodyDef::URLEncoded(
+            post_data
+                .params
+                .into_iter()
+                .map(|param| KeyValuePair {
+                    key: param.name,
+                    value: param.value.unwrap_or_default(),
+                })
+                .collect(),
+        ))),
+    }
+}
+
+fn har_headers(headers: &[KeyValuePair]) -> Vec<HarNameValue> {
+    headers
+        .iter()
+        .map(|header| HarNameValue {
+            name: header.key.clone(),
+            value: header.value.clone(),
+        })
+        .collect()
+}
+
+fn find_header<'a>(headers: &'a [KeyValuePair], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.key.eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_str())
+}
+
+fn decode_query_component(component: &str) -> String {
+    url_escape::decode(&component.replace('+', " ")).into_owned()
+}
+
+/// Text bodies are kept as they are, anything else is base64 encoded.
+fn encode_body(data: &[u8]) -> (String, Option<String>) {
+    match std::str::from_utf8(data) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (BASE64.encode(data), Some("base64".to_string())),
+    }
+}
+
+fn decode_body(value: &str, encoding: Option<&str>) -> RelayResult<Vec<u8>> {
+    match encoding {
+        Some("base64") => BASE64.decode(value).map_err(|err| {
+            RelayError::RequestRunError(format!("Invalid base64 in HAR entry: {}", err))
+        }),
+        _ => Ok(value.as_bytes().to_vec()),
+    }
+}
+
+fn parse_set_cookie(value: &str) -> Option<HarCookie> {
+    let mut parts = value.split(';');
+    let (name, value) = parts.next()?.trim().split_once('=')?;
+
+    let mut cookie = HarCookie {
+        name: name.to_string(),
+        value: value.to_string(),
+        path: None,
+        domain: None,
+        expires: None,
+        http_only: None,
+        secure: None,
+    };
+    for attribute in parts {
+        let (key, value) = attribute
+            .trim()
+            .split_once('=')
+            .unwrap_or((attribute.trim(), ""));
+        match key.to_lowercase().as_str() {
+            "path" => cookie.path = Some(value.to_string()),
+            "domain" => cookie.domain = Some(value.to_string()),
+            "expires" => {
+                cookie.expires = httpdate::parse_http_date(value)
+                    .ok()
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|since_epoch| iso8601(since_epoch.as_millis()))
+            }
+            "httponly" => cookie.http_only = Some(true),
+            "secure" => cookie.secure = Some(true),
+            _ => {}
+        }
+    }
+
+    Some(cookie)
+}
+
+/// Formats milliseconds since the Unix epoch as an ISO 8601 UTC timestamp.
+pub(crate) fn iso8601(epoch_ms: u128) -> String {
+    let secs = (epoch_ms / 1000) as i64;
+    let (days, day_secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+    // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        day_secs / 3600,
+        day_secs % 3600 / 60,
+        day_secs % 60,
+        epoch_ms % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interop::ResponseTimings;
+
+    fn header(key: &str, value: &str) -> KeyValuePair {
+        KeyValuePair {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn exports_timings_cookies_and_binary_bodies() {
+        let req = RequestWithMetadata::new(
+            1,
+            "post".to_string(),
+            "https://example.com/upload?tag=a%20b&flag".to_string(),
+            vec![header("Cookie", "session=abc; theme=dark")],
+            Some(BodyDef::URLEncoded(vec![header("name", "x y")])),
+            true,
+            Vec::new(),
+            None,
+            None,
+        );
+        let res = ResponseWithMetadata {
+            status: 302,
+            status_text: "Found".to_string(),
+            http_version: "HTTP/2".to_string(),
+            headers: vec![
+                header("Content-Type", "image/png"),
+                header("Location", "/done"),
+                header(
+                    "Set-Cookie",
+                    "id=7; Path=/; Expires=Wed, 21 Oct 2015 07:28:00 GMT; HttpOnly",
+                ),
+            ],
+            request_line: String::new(),
+            sent_headers: Vec::new(),
+            data: vec![0x89, 0x50, 0xff],
+            time_start_ms: 1_445_412_480_123,
+            time_end_ms: 1_445_412_480_223,
+            timings: ResponseTimings {
+                name_lookup_ms: 5.0,
+                connect_ms: 15.0,
+                tls_handshake_ms: 40.0,
+                pre_transfer_ms: 41.0,
+                start_transfer_ms: 90.0,
+                total_ms: 100.0,
+                redirect_ms: 0.0,
+            },
+            attempts: Vec::new(),
+            trace: Vec::new(),
+            cache_status: None,
+            assertions: None,
+        };
+
+        let entry = har_entry(&req, Some(&res));
+
+        assert_eq!(entry.started_date_time, "2015-10-21T07:28:00.123Z");
+        assert_eq!(entry.time, 100.0);
+        assert_eq!(entry.timings.dns, 5.0);
+        assert_eq!(entry.timings.connect, 35.0);
+        assert_eq!(entry.timings.ssl, 25.0);
+        assert_eq!(entry.timings.send, 1.0);
+        assert_eq!(entry.timings.wait, 49.0);
+        assert_eq!(entry.timings.receive, 10.0);
+
+        assert_eq!(entry.request.method, "POST");
+        assert_eq!(entry.request.http_version, "HTTP/2");
+        assert_eq!(entry.request.query_string[0].value, "a b");
+        assert_eq!(entry.request.query_string[1].name, "flag");
+        assert_eq!(entry.request.cookies[1].name, "theme");
+        let post_data = entry.request.post_data.as_ref().unwrap();
+        assert_eq!(post_data.text.as_deref(), Some("name=x%20y"));
+        assert_eq!(entry.request.body_size, 10);
+
+        assert_eq!(entry.response.redirect_url, "/done");
+        assert_eq!(entry.response.content.encoding.as_deref(), Some("base64"));
+        assert_eq!(entry.response.content.text.as_deref(), Some("iVD/"));
+        let cookie = &entry.response.cookies[0];
+        assert_eq!(cookie.expires.as_deref(), Some("2015-10-21T07:28:00.000Z"));
+        assert_eq!(cookie.http_only, Some(true));
+    }
+
+    #[test]
+    fn imports_exported_requests() {
+        let multipart = RequestWithMetadata::new(
+            1,
+            "POST".to_string(),
+            "https://example.com/files".to_string(),
+            vec![
+                header("Content-Type", "multipart/form-data; boundary=old"),
+                header("Content-Length", "512"),
+                header("X-Trace", "1"),
+            ],
+            Some(BodyDef::FormData(vec![
+                FormDataEntry {
+                    key: "note".to_string(),
+                    value: FormDataValue::Text("hello".to_string()),
+                },
+                FormDataEntry {
+                    key: "file".to_string(),
+                    value: FormDataValue::File {
+                        filename: "blob.bin".to_string(),
+                        data: vec![0, 159, 146, 150],
+                        mime: "application/octet-stream".to_string(),
+                    },
+                },
+            ])),
+            true,
+            Vec::new(),
+            None,
+            None,
+        );
+        let plain = RequestWithMetadata::new(
+            2,
+            "GET".to_string(),
+            "https://example.com/".to_string(),
+            Vec::new(),
+            None,
+            true,
+            Vec::new(),
+            None,
+            None,
+        );
+
+        let har = export_har(&[
+            HarExchange {
+                request: multipart,
+                response: None,
+            },
+            HarExchange {
+                request: plain,
+                response: None,
+            },
+        ])
+        .unwrap();
+        let imported = import_har(&har).unwrap();
+
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[0].req_id, 0);
+        assert_eq!(imported[0].headers.len(), 1);
+        assert_eq!(imported[0].headers[0].key, "X-Trace");
+        let Some(BodyDef::FormData(entries)) = &imported[0].body else {
+            panic!("expected a multipart body");
+        };
+        assert!(matches!(&entries[0].value, FormDataValue::Text(text) if text == "hello"));
+        assert!(matches!(
+            &entries[1].value,
+            FormDataValue::File { filename, data, .. }
+                if filename == "blob.bin" && data == &[0, 159, 146, 150]
+        ));
+        assert_eq!(imported[1].method, "GET");
+        assert!(imported[1].body.is_none());
+    }
+}
@@ -1,12 +1,18 @@
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    assertions::{Assertion, AssertionReport},
+    retry::{RetryAttempt, RetryPolicy},
+    trace::TraceEntry,
+};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KeyValuePair {
     pub key: String,
     pub value: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum FormDataValue {
     Text(String),
     File {
@@ -16,20 +22,20 @@ pub enum FormDataValue {
     },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FormDataEntry {
     pub key: String,
     pub value: FormDataValue,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum BodyDef {
     Text(String),
     URLEncoded(Vec<KeyValuePair>),
     FormData(Vec<FormDataEntry>),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RequestWithMetadata {
     pub req_id: usize,
     pub method: String,
@@ -40,9 +46,44 @@ pub struct RequestWithMetadata {
     pub root_cert_bundle_files: Vec<Vec<u8>>,
     pub client_cert: Option<ClientCertDef>,
     pub proxy: Option<ProxyConfig>,
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// Host overrides in curl's `--resolve` format, `host:port:address`.
+    #[serde(default)]
+    pub resolve: Vec<String>,
+    /// Records a `curl -v` style trace of the transfer into the response.
+    #[serde(default)]
+    pub trace: bool,
+    /// Fills in `ResponseWithMetadata::request_line` and `sent_headers`.
+    /// Implied by `trace`.
+    #[serde(default)]
+    pub report_sent_headers: bool,
+    #[serde(default)]
+    pub suppress_headers: SuppressedHeaders,
+    /// Serves and stores the response through the caller's HTTP cache.
+    #[serde(default)]
+    pub cache: bool,
+    /// Checks evaluated on the response, see `ResponseWithMetadata::assertions`.
+    #[serde(default)]
+    pub assertions: Vec<Assertion>,
+    /// Limit on the whole transfer, curl's own default is none. WebSocket and
+    /// MQTT connections stay open as long as they are used and ignore it.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Limit on establishing the connection, curl's own default is 300s.
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    /// Oldest TLS version accepted.
+    #[serde(default)]
+    pub tls_min_version: Option<TlsVersion>,
+    /// Aborts the transfer once the response body grows past this many
+    /// bytes, or right away when its `Content-Length` is larger.
+    #[serde(default)]
+    pub max_response_bytes: Option<u64>,
 }
 
 impl RequestWithMetadata {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         req_id: usize,
         method: String,
@@ -64,16 +105,66 @@ impl RequestWithMetadata {
             root_cert_bundle_files,
             client_cert,
             proxy,
+            retry: None,
+            resolve: Vec::new(),
+            trace: false,
+            report_sent_headers: false,
+            suppress_headers: SuppressedHeaders::default(),
+            cache: false,
+            assertions: Vec::new(),
+            timeout_ms: None,
+            connect_timeout_ms: None,
+            tls_min_version: None,
+            max_response_bytes: None,
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Headers curl adds on its own that are left out when set. A header given
+/// explicitly in `headers` is always sent as is. curl adds no `User-Agent`
+/// unless one is given.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SuppressedHeaders {
+    pub host: bool,
+    /// `Accept: */*`.
+    pub accept: bool,
+    pub content_length: bool,
+    /// Added for URL encoded and multipart bodies.
+    pub content_type: bool,
+    /// `Expect: 100-continue`, added for large uploads.
+    pub expect: bool,
+}
+
+impl SuppressedHeaders {
+    pub(crate) fn names(&self) -> impl Iterator<Item = &'static str> {
+        [
+            (self.host, "Host"),
+            (self.accept, "Accept"),
+            (self.content_length, "Content-Length"),
+            (self.content_type, "Content-Type"),
+            (self.expect, "Expect"),
+        ]
+        .into_iter()
+        .filter_map(|(suppressed, name)| suppressed.then_some(name))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsVersion {
+    Tls1_0,
+    Tls1_1,
+    Tls1_2,
+    Tls1_3,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ProxyConfig {
     pub url: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ClientCertDef {
     PEMCert {
         certificate_pem: Vec<u8>,
@@ -85,12 +176,63 @@ pub enum ClientCertDef {
     },
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ResponseWithMetadata {
     pub status: u16,
     pub status_text: String,
+    /// Protocol of the final response, e.g. `HTTP/1.1` or `HTTP/2`.
+    #[serde(default)]
+    pub http_version: String,
     pub headers: Vec<KeyValuePair>,
+    /// Request line of the final request as sent, e.g. `GET /path HTTP/1.1`,
+    /// when the request asked for it with `report_sent_headers` or `trace`.
+    #[serde(default)]
+    pub request_line: String,
+    /// Every header of the final request as sent, including the ones curl added.
+    #[serde(default)]
+    pub sent_headers: Vec<KeyValuePair>,
     pub data: Vec<u8>,
     pub time_start_ms: u128,
     pub time_end_ms: u128,
+    #[serde(default)]
+    pub timings: ResponseTimings,
+    /// Every attempt made for this response when a retry policy was set.
+    #[serde(default)]
+    pub attempts: Vec<RetryAttempt>,
+    /// Wire level trace of the final attempt when the request asked for one.
+    #[serde(default)]
+    pub trace: Vec<TraceEntry>,
+    /// How the HTTP cache answered, for requests that opted into it.
+    #[serde(default)]
+    pub cache_status: Option<CacheStatus>,
+    /// Outcome of the request's assertions, `None` when it had none.
+    #[serde(default)]
+    pub assertions: Option<AssertionReport>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CacheStatus {
+    /// Served from a fresh cache entry without contacting the server.
+    Hit,
+    /// A stale entry the server confirmed with `304 Not Modified`.
+    Revalidated,
+    /// Fetched from the server.
+    Miss,
+}
+
+/// Phase timings of the final transfer as reported by curl. Each value is the
+/// time from the start of the transfer until that phase completed.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ResponseTimings {
+    pub name_lookup_ms: f64,
+    pub connect_ms: f64,
+    /// `0` for plain connections.
+    pub tls_handshake_ms: f64,
+    /// Everything up to the point the request starts going out.
+    pub pre_transfer_ms: f64,
+    /// Until the first response byte arrived.
+    pub start_transfer_ms: f64,
+    pub total_ms: f64,
+    /// Time spent following redirects before the final transfer started.
+    pub redirect_ms: f64,
 }
@@ -1,11 +1,86 @@
+pub(crate) mod agent;
+pub(crate) mod assertions;
+pub(crate) mod batch;
+pub(crate) mod cache;
+pub(crate) mod curl_command;
+pub(crate) mod defaults;
+pub(crate) mod engine;
 pub(crate) mod error;
+pub(crate) mod grpc;
+pub(crate) mod har;
+pub(crate) mod history;
 pub(crate) mod interop;
+pub(crate) mod load;
+pub(crate) mod middleware;
+pub(crate) mod mock;
+pub(crate) mod monitor;
+pub(crate) mod mqtt;
+pub(crate) mod net;
 pub(crate) mod relay;
+pub(crate) mod retry;
+pub(crate) mod runner;
+pub(crate) mod server;
+pub(crate) mod sse;
+pub(crate) mod trace;
 pub(crate) mod util;
+pub(crate) mod variables;
+pub(crate) mod vault;
+pub(crate) mod websocket;
 
+pub use agent::{Agent, AgentConfig, DEFAULT_AGENT_PORT};
+pub use assertions::{evaluate_assertions, Assertion, AssertionReport, AssertionResult, CompareOp};
+pub use batch::{
+    run_batch, BatchItemResult, BatchMode, BatchOptions, BatchOutcome, BatchSummary, BatchTiming,
+};
+pub use cache::{CacheConfig, CacheEntryInfo, CacheLookup, HttpCache};
+pub use curl_command::{export_curl, import_curl, CurlCommand, CurlFile};
+pub use defaults::{ClientCertFile, HostClientCert, LoadedNetworkDefaults, NetworkDefaults};
+pub use engine::{ActiveRequest, EngineConfig, RelayEngine};
 pub use error::{RelayError, RelayResult};
-pub use interop::{RequestWithMetadata, ResponseWithMetadata};
+pub use grpc::{
+    GrpcMethodInfo, GrpcResponse, GrpcSchema, GrpcSchemaSource, GrpcServiceInfo, GrpcStatus,
+};
+pub use har::{export_har, har_entry, import_har, Har, HarEntry, HarExchange};
+pub use history::{
+    HistoryConfig, HistoryEntry, HistoryPage, HistoryPrune, HistoryQuery, HistoryStore,
+    HISTORY_FILE,
+};
+pub use interop::{
+    CacheStatus, KeyValuePair, ProxyConfig, RequestWithMetadata, ResponseTimings,
+    ResponseWithMetadata, SuppressedHeaders, TlsVersion,
+};
+pub use load::{
+    run_load, HistogramBucket, LatencyStats, LoadOptions, LoadRate, LoadReport, LoadRequest,
+    LoadRequestStats, LoadSnapshot, LoadStop,
+};
+pub use middleware::{
+    BodySizeGuard, DefaultHeaders, MiddlewareConfig, MiddlewarePipeline, RequestIdHeader,
+    RequestMiddleware, UserAgent,
+};
+pub use mock::{MockConfig, MockHit, MockServer};
+pub use monitor::{
+    Monitor, MonitorConfig, MonitorResult, MonitorSchedule, MonitorScheduler, MonitorStatus,
+    MONITOR_LOG_FILE,
+};
+pub use mqtt::{
+    MqttConnection, MqttEvent, MqttMessage, MqttOptions, MqttPublish, MqttQoS, MqttSubscription,
+    MqttVersion,
+};
 pub use relay::run_request_task;
+pub use retry::{RetryAttempt, RetryCondition, RetryPolicy};
+pub use runner::{
+    junit_xml, run_collection, workspace_file, Expectation, Expectations, RequestOutcome,
+    RequestReport, RunReport, RunnerOptions, Workspace, WorkspaceCollection, WorkspaceEnvironment,
+    COLLECTIONS_FILE, ENVIRONMENTS_FILE, EXPECTATIONS_FILE,
+};
+pub use sse::{run_sse_stream, SseEvent, SseOptions, SseParser, SseStreamEvent};
+pub use trace::{TraceEntry, TraceKind};
+pub use variables::{resolve_request, ResolvedRequest, VariableScope};
+pub use vault::{
+    referenced_secrets, referenced_secrets_in, SecretInfo, SecretRedactor, Vault, VaultCredential,
+    SECRET_PREFIX, VAULT_FILE,
+};
+pub use websocket::{WebSocketConnection, WebSocketEvent, WebSocketMessage};
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
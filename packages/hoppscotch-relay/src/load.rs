@@ -0,0 +1,694 @@
+//! Load testing on top of the async engine.
+//!
+//! A run sends a weighted mix of requests, either keeping a number of
+//! requests in flight or starting them at a target rate, until a duration has
+//! passed or a number of iterations was started. Latencies are kept per
+//! request so the report can give exact percentiles, and a snapshot of the
+//! last interval is emitted periodically while the run is going.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    engine::RelayEngine,
+    error::{RelayError, RelayResult},
+    interop::{RequestWithMetadata, ResponseWithMetadata},
+};
+
+/// Upper bounds of the latency histogram buckets in milliseconds. A last
+/// bucket without bound catches everything slower.
+const HISTOGRAM_BOUNDS_MS: &[f64] = &[
+    1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0, 10000.0,
+];
+/// How far a rate limited run may fall behind its schedule before it stops
+/// trying to catch up, so a stall isn't followed by a burst.
+const MAX_SCHEDULE_LAG: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoadRequest {
+    pub request: RequestWithMetadata,
+    /// Share of the iterations that run this request, relative to the
+    /// weights of the others.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// How fast requests are started.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum LoadRate {
+    /// Keeps `workers` requests in flight, starting the next one as soon as
+    /// one completes.
+    Concurrency { workers: usize },
+    /// Starts `per_second` requests a second, with at most `max_in_flight`
+    /// running at once.
+    Rps {
+        per_second: f64,
+        max_in_flight: usize,
+    },
+}
+
+/// When a run stops starting new requests. Requests in flight at that point
+/// are allowed to complete.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum LoadStop {
+    Duration { ms: u64 },
+    Iterations { count: usize },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoadOptions {
+    pub rate: LoadRate,
+    pub stop: LoadStop,
+    /// How often a snapshot is emitted while running.
+    #[serde(default = "default_snapshot_interval_ms")]
+    pub snapshot_interval_ms: u64,
+}
+
+fn default_snapshot_interval_ms() -> u64 {
+    1000
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HistogramBucket {
+    /// `None` for the last bucket, which has no upper bound.
+    pub upper_ms: Option<f64>,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LatencyStats {
+    pub count: usize,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+    pub histogram: Vec<HistogramBucket>,
+}
+
+impl LatencyStats {
+    /// Stats of `samples`, sorting them in place. Percentiles use the
+    /// nearest rank.
+    pub fn from_samples(samples: &mut [f64]) -> Self {
+        let mut histogram = HISTOGRAM_BOUNDS_MS
+            .iter()
+            .map(|bound| HistogramBucket {
+                upper_ms: Some(*bound),
+                count: 0,
+            })
+            .chain(std::iter::once(HistogramBucket {
+                upper_ms: None,
+                count: 0,
+            }))
+            .collect::<Vec<_>>();
+        if samples.is_empty() {
+            return Self {
+                histogram,
+                ..Default::default()
+            };
+        }
+
+        samples.sort_by(f64::total_cmp);
+        for sample in samples.iter() {
+            let bucket = HISTOGRAM_BOUNDS_MS
+                .iter()
+                .position(|bound| sample <= bound)
+                .unwrap_or(HISTOGRAM_BOUNDS_MS.len());
+            histogram[bucket].count += 1;
+        }
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0) * samples.len() as f64).ceil() as usize;
+            samples[rank.clamp(1, samples.len()) - 1]
+        };
+
+        Self {
+            count: samples.len(),
+            min_ms: samples[0],
+            mean_ms: samples.iter().sum::<f64>() / samples.len() as f64,
+            p50_ms: percentile(50.0),
+            p90_ms: percentile(90.0),
+            p95_ms: percentile(95.0),
+            p99_ms: percentile(99.0),
+            max_ms: samples[samples.len() - 1],
+            histogram,
+        }
+    }
+}
+
+/// Progress of a running load test, emitted every `snapshot_interval_ms`.
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadSnapshot {
+    pub elapsed_ms: u128,
+    /// Completed requests since the start.
+    pub completed: usize,
+    pub failed: usize,
+    pub in_flight: usize,
+    /// Completions per second over the last interval.
+    pub throughput_rps: f64,
+    /// Latencies of the requests completed in the last interval.
+    pub latency: LatencyStats,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadRequestStats {
+    /// Position of the request in the submitted list.
+    pub index: usize,
+    pub method: String,
+    pub endpoint: String,
+    pub completed: usize,
+    pub failed: usize,
+    pub latency: LatencyStats,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadReport {
+    pub duration_ms: u128,
+    pub completed: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub throughput_rps: f64,
+    /// Failures by kind, e.g. `HTTP 503`, `Timeout` or `Assertion failed`.
+    pub errors: BTreeMap<String, usize>,
+    pub latency: LatencyStats,
+    pub requests: Vec<LoadRequestStats>,
+    /// The run was cancelled before its stop condition was reached.
+    pub cancelled: bool,
+}
+
+struct Completion {
+    index: usize,
+    latency_ms: f64,
+    /// Kind of failure, `None` when the request succeeded.
+    error: Option<String>,
+}
+
+/// Runs a load test of `requests` on `engine`.
+///
+/// `on_snapshot` is called every `snapshot_interval_ms` while the run is
+/// going. Cancelling `cancel_token` cancels the requests in flight and ends
+/// the run with the results so far. A request fails when it errors, has a
+/// status of 400 or above, or fails one of its assertions.
+pub async fn run_load<F>(
+    engine: &RelayEngine,
+    requests: Vec<LoadRequest>,
+    options: LoadOptions,
+    cancel_token: CancellationToken,
+    mut on_snapshot: F,
+) -> RelayResult<LoadReport>
+where
+    F: FnMut(LoadSnapshot),
+{
+    let total_weight = requests
+        .iter()
+        .map(|request| u64::from(request.weight))
+        .sum::<u64>();
+    if total_weight == 0 {
+        return Err(RelayError::RequestRunError(
+            "A load test needs at least one request with a weight above 0".to_string(),
+        ));
+    }
+    let max_in_flight = match options.rate {
+        LoadRate::Concurrency { workers } => workers.max(1),
+        LoadRate::Rps { max_in_flight, .. } => max_in_flight.max(1),
+    };
+    let interval = match options.rate {
+        LoadRate::Rps { per_second, .. } if per_second > 0.0 => Some(
+            Duration::try_from_secs_f64(1.0 / per_second)
+                .map_err(|_| out_of_range(format!("a rate of {} per second", per_second)))?,
+        ),
+        LoadRate::Rps { .. } => {
+            return Err(RelayError::RequestRunError(
+                "The request rate has to be above 0".to_string(),
+            ))
+        }
+        LoadRate::Concurrency { .. } => None,
+    };
+    log::info!(
+        "Starting load test of {} requests, {:?} until {:?}",
+        requests.len(),
+        options.rate,
+        options.stop
+    );
+
+    let run_token = cancel_token.child_token();
+    let start = Instant::now();
+    let deadline = match options.stop {
+        LoadStop::Duration { ms } => Some(
+            start
+                .checked_add(Duration::from_millis(ms))
+                .ok_or_else(|| out_of_range(format!("a duration of {}ms", ms)))?,
+        ),
+        LoadStop::Iterations { .. } => None,
+    };
+    let snapshot_every = Duration::from_millis(options.snapshot_interval_ms.max(100));
+    let mut next_snapshot = start + snapshot_every;
+    let mut next_start = start;
+
+    let mut started = 0;
+    let mut in_flight = FuturesUnordered::new();
+    let mut samples = vec![Vec::new(); requests.len()];
+    let mut failures = vec![0; requests.len()];
+    let mut errors = BTreeMap::<String, usize>::new();
+    let mut window = Vec::new();
+    let mut window_start = start;
+
+    loop {
+        let now = Instant::now();
+        let may_start = !run_token.is_cancelled()
+            && match options.stop {
+                LoadStop::Iterations { count } => started < count,
+                LoadStop::Duration { .. } => deadline.is_some_and(|deadline| now < deadline),
+            };
+
+        while may_start && in_flight.len() < max_in_flight {
+            if let Some(interval) = interval {
+                if Instant::now() < next_start {
+                    break;
+                }
+                let scheduled = next_start
+                    .checked_add(interval)
+                    .ok_or_else(|| out_of_range(format!("an interval of {:?}", interval)))?;
+                let catch_up_limit = Instant::now()
+                    .checked_sub(MAX_SCHEDULE_LAG)
+                    .ok_or_else(|| out_of_range("the schedule lag limit".to_string()))?;
+                next_start = scheduled.max(catch_up_limit);
+            }
+            if matches!(options.stop, LoadStop::Iterations { count } if started >= count) {
+                break;
+            }
+            let index = pick(&requests, total_weight);
+            in_flight.push(run_one(
+                engine,
+                index,
+                requests[index].request.clone(),
+                run_token.child_token(),
+            ));
+            started += 1;
+        }
+
+        if !may_start && in_flight.is_empty() {
+            break;
+        }
+
+        let wake_at = [
+            Some(next_snapshot),
+            interval.filter(|_| may_start).map(|_| next_start),
+            deadline.filter(|_| may_start),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or(next_snapshot);
+
+        tokio::select! {
+            Some(completion) = in_flight.next(), if !in_flight.is_empty() => {
+                let Some(completion) = completion else {
+                    // Cancelled, it doesn't count towards the results.
+                    continue;
+                };
+                if let Some(error) = completion.error {
+                    failures[completion.index] += 1;
+                    *errors.entry(error).or_default() += 1;
+                }
+                samples[completion.index].push(completion.latency_ms);
+                window.push(completion.latency_ms);
+            }
+            _ = tokio::time::sleep_until(wake_at.into()) => {}
+            _ = run_token.cancelled(), if !run_token.is_cancelled() => {}
+        }
+
+        if Instant::now() >= next_snapshot {
+            let completed = samples.iter().map(Vec::len).sum();
+            let window_secs = window_start.elapsed().as_secs_f64();
+            on_snapshot(LoadSnapshot {
+                elapsed_ms: start.elapsed().as_millis(),
+                completed,
+                failed: failures.iter().sum(),
+                in_flight: in_flight.len(),
+                throughput_rps: window.len() as f64 / window_secs.max(f64::EPSILON),
+                latency: LatencyStats::from_samples(&mut window),
+            });
+            window.clear();
+            window_start = Instant::now();
+            next_snapshot = Instant::now() + snapshot_every;
+        }
+    }
+
+    let duration = start.elapsed();
+    let per_request = requests
+        .iter()
+        .enumerate()
+        .map(|(index, load_request)| LoadRequestStats {
+            index,
+            method: load_request.request.method.clone(),
+            endpoint: load_request.request.endpoint.clone(),
+            completed: samples[index].len(),
+            failed: failures[index],
+            latency: LatencyStats::from_samples(&mut samples[index]),
+        })
+        .collect::<Vec<_>>();
+    let mut all = samples.concat();
+    let completed = all.len();
+    let failed = failures.iter().sum::<usize>();
+
+    let report = LoadReport {
+        duration_ms: duration.as_millis(),
+        completed,
+        succeeded: completed - failed,
+        failed,
+        throughput_rps: completed as f64 / duration.as_secs_f64().max(f64::EPSILON),
+        errors,
+        latency: LatencyStats::from_samples(&mut all),
+        requests: per_request,
+        cancelled: cancel_token.is_cancelled(),
+    };
+    log::info!(
+        "Load test finished:\nCompleted: {}\nFailed: {}\nThroughput: {:.1} rps\np95: {:.1}ms",
+        report.completed,
+        report.failed,
+        report.throughput_rps,
+        report.latency.p95_ms
+    );
+
+    Ok(report)
+}
+
+fn out_of_range(what: String) -> RelayError {
+    RelayError::RequestRunError(format!(
+        "The load test can't be scheduled with {}, it is out of range",
+        what
+    ))
+}
+
+fn pick(requests: &[LoadRequest], total_weight: u64) -> usize {
+    let mut target = rand::thread_rng().gen_range(0..total_weight);
+    for (index, request) in requests.iter().enumerate() {
+        let weight = u64::from(request.weight);
+        if target < weight {
+            return index;
+        }
+        target -= weight;
+    }
+    requests.len() - 1
+}
+
+/// Runs one iteration, `None` when it was cancelled.
+async fn run_one(
+    engine: &RelayEngine,
+    index: usize,
+    req: RequestWithMetadata,
+    cancel_token: CancellationToken,
+) -> Option<Completion> {
+    let start = Instant::now();
+    let result = engine.execute(req, cancel_token).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let error = match result {
+        Ok(response) => failure_kind(&response),
+        Err(RelayError::RequestCancelled) => return None,
+        Err(err) => Some(error_kind(&err).to_string()),
+    };
+    Some(Completion {
+        index,
+        latency_ms,
+        error,
+    })
+}
+
+fn failure_kind(response: &ResponseWithMetadata) -> Option<String> {
+    if response.status >= 400 {
+        Some(format!("HTTP {}", response.status))
+    } else if response
+        .assertions
+        .as_ref()
+        .is_some_and(|report| !report.success())
+    {
+        Some("Assertion failed".to_string())
+    } else {
+        None
+    }
+}
+
+fn error_kind(err: &RelayError) -> &'static str {
+    match err {
+        RelayError::InvalidMethod | RelayError::InvalidUrl | RelayError::InvalidHeaders => {
+            "Invalid request"
+        }
+        RelayError::ConnectionFailed(_) => "Connection failed",
+        RelayError::Timeout(_) => "Timeout",
+        RelayError::RequestCancelled => "Cancelled",
+        RelayError::RequestRunError(_) => "Request error",
+        RelayError::RetriesExhausted { last, .. } => error_kind(last),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineConfig;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    /// Answers every request after `delay`, `503` for `/fail` and `200`
+    /// otherwise, counting the requests it saw.
+    fn spawn_server(delay: Duration) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = seen.clone();
+        std::thread::spawn(move || {
+            for socket in listener.incoming() {
+                let Ok(mut socket) = socket else {
+                    break;
+                };
+                let counter = counter.clone();
+                std::thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match socket.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    std::thread::sleep(delay);
+                    let status = if request.starts_with(b"GET /fail ") {
+                        "503 Service Unavailable"
+                    } else {
+                        "200 OK"
+                    };
+                    let _ = write!(
+                        socket,
+                        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    );
+                });
+            }
+        });
+        (url, seen)
+    }
+
+    fn load_request(url: String, weight: u32) -> LoadRequest {
+        LoadRequest {
+            request: RequestWithMetadata::new(
+                0,
+                "GET".to_string(),
+                url,
+                Vec::new(),
+                None,
+                true,
+                Vec::new(),
+                None,
+                None,
+            ),
+            weight,
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_after_the_iteration_count() {
+        let (url, seen) = spawn_server(Duration::ZERO);
+        let engine = RelayEngine::new(EngineConfig::default()).unwrap();
+        let requests = vec![
+            load_request(format!("{}/ok", url), 1),
+            load_request(format!("{}/fail", url), 1),
+            load_request(format!("{}/never", url), 0),
+        ];
+        let options = LoadOptions {
+            rate: LoadRate::Concurrency { workers: 4 },
+            stop: LoadStop::Iterations { count: 20 },
+            snapshot_interval_ms: 1000,
+        };
+
+        let report = run_load(&engine, requests, options, CancellationToken::new(), |_| {})
+            .await
+            .unwrap();
+
+        assert_eq!(report.completed, 20);
+        assert_eq!(seen.load(Ordering::SeqCst), 20);
+        assert!(!report.cancelled);
+        assert_eq!(report.requests[2].completed, 0);
+        assert_eq!(
+            report.requests[0].completed + report.requests[1].completed,
+            20
+        );
+        assert_eq!(report.failed, report.requests[1].completed);
+        assert_eq!(report.succeeded, report.requests[0].completed);
+        if report.failed > 0 {
+            assert_eq!(report.errors.get("HTTP 503"), Some(&report.failed));
+        }
+    }
+
+    #[tokio::test]
+    async fn starts_requests_at_the_target_rate_until_the_duration_passed() {
+        let (url, seen) = spawn_server(Duration::ZERO);
+        let engine = RelayEngine::new(EngineConfig::default()).unwrap();
+        let options = LoadOptions {
+            rate: LoadRate::Rps {
+                per_second: 20.0,
+                max_in_flight: 4,
+            },
+            stop: LoadStop::Duration { ms: 600 },
+            snapshot_interval_ms: 100,
+        };
+        let mut snapshots = 0;
+
+        let report = run_load(
+            &engine,
+            vec![load_request(url, 1)],
+            options,
+            CancellationToken::new(),
+            |_| snapshots += 1,
+        )
+        .await
+        .unwrap();
+
+        // One request every 50ms for 600ms, starting right away.
+        assert!(
+            (10..=14).contains(&report.completed),
+            "completed {}",
+            report.completed
+        );
+        assert_eq!(seen.load(Ordering::SeqCst), report.completed);
+        assert!(report.duration_ms >= 600, "took {}ms", report.duration_ms);
+        assert!(report.duration_ms < 2000, "took {}ms", report.duration_ms);
+        assert!(snapshots >= 3, "{} snapshots", snapshots);
+        assert_eq!(report.failed, 0);
+    }
+
+    #[tokio::test]
+    async fn cancel_ends_the_run_with_the_results_so_far() {
+        let (url, _) = spawn_server(Duration::from_millis(100));
+        let engine = RelayEngine::new(EngineConfig::default()).unwrap();
+        let options = LoadOptions {
+            rate: LoadRate::Concurrency { workers: 2 },
+            stop: LoadStop::Duration { ms: 60_000 },
+            snapshot_interval_ms: 1000,
+        };
+        let cancel_token = CancellationToken::new();
+
+        let cancel = async {
+            tokio::time::sleep(Duration::from_millis(350)).await;
+            cancel_token.cancel();
+        };
+        let run = run_load(
+            &engine,
+            vec![load_request(url, 1)],
+            options,
+            cancel_token.clone(),
+            |_| {},
+        );
+        let (report, ()) = tokio::join!(run, cancel);
+        let report = report.unwrap();
+
+        assert!(report.cancelled);
+        assert!(report.duration_ms < 5000, "took {}ms", report.duration_ms);
+        // Two workers finish a request every 100ms or so, the requests in
+        // flight when cancelling don't count.
+        assert!(report.completed >= 2, "completed {}", report.completed);
+        assert_eq!(report.failed, 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_schedules_out_of_range() {
+        let engine = RelayEngine::new(EngineConfig::default()).unwrap();
+        let run = |rate, stop| {
+            run_load(
+                &engine,
+                vec![load_request("http://127.0.0.1:9".to_string(), 1)],
+                LoadOptions {
+                    rate,
+                    stop,
+                    snapshot_interval_ms: 1000,
+                },
+                CancellationToken::new(),
+                |_| {},
+            )
+        };
+
+        let tiny_rate = run(
+            LoadRate::Rps {
+                per_second: 1e-300,
+                max_in_flight: 1,
+            },
+            LoadStop::Iterations { count: 1 },
+        )
+        .await;
+        assert!(matches!(tiny_rate, Err(RelayError::RequestRunError(_))));
+
+        let no_rate = run(
+            LoadRate::Rps {
+                per_second: 0.0,
+                max_in_flight: 1,
+            },
+            LoadStop::Iterations { count: 1 },
+        )
+        .await;
+        assert!(no_rate.is_err());
+    }
+
+    #[test]
+    fn computes_percentiles_and_histogram() {
+        let mut samples = (1..=100).rev().map(f64::from).collect::<Vec<_>>();
+        let stats = LatencyStats::from_samples(&mut samples);
+
+        assert_eq!(stats.count, 100);
+        assert_eq!((stats.min_ms, stats.max_ms), (1.0, 100.0));
+        assert_eq!(stats.mean_ms, 50.5);
+        assert_eq!(
+            (stats.p50_ms, stats.p90_ms, stats.p95_ms, stats.p99_ms),
+            (50.0, 90.0, 95.0, 99.0)
+        );
+        let counts = stats
+            .histogram
+            .iter()
+            .map(|bucket| bucket.count)
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [1, 1, 3, 5, 10, 30, 50, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(counts.iter().sum::<usize>(), 100);
+
+        let empty = LatencyStats::from_samples(&mut []);
+        assert_eq!(empty.count, 0);
+        assert_eq!(empty.histogram.len(), HISTOGRAM_BOUNDS_MS.len() + 1);
+    }
+}
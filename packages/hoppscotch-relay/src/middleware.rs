@@ -0,0 +1,397 @@
+//! Hooks that run around every request, to add headers, sign requests or
+//! log and redact traffic in one place.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{RelayError, RelayResult},
+    interop::{BodyDef, FormDataValue, KeyValuePair, RequestWithMetadata, ResponseWithMetadata},
+    variables::uuid_v4,
+};
+
+/// A hook around sending a request. An error from either hook fails the
+/// request with it.
+pub trait RequestMiddleware: Send + Sync {
+    /// Called with the request as it is about to be sent, after variables and
+    /// secrets have been resolved.
+    fn before_send(&self, req: &mut RequestWithMetadata) -> RelayResult<()> {
+        let _ = req;
+        Ok(())
+    }
+
+    /// Called with the request as sent and the response it got.
+    fn after_receive(
+        &self,
+        req: &RequestWithMetadata,
+        response: &mut ResponseWithMetadata,
+    ) -> RelayResult<()> {
+        let _ = (req, response);
+        Ok(())
+    }
+}
+
+/// Settings of the built-in middlewares, kept per workspace.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MiddlewareConfig {
+    /// Added to requests that don't set a header of the same name.
+    pub default_headers: Vec<KeyValuePair>,
+    /// Replaces the `User-Agent` of every request.
+    pub user_agent: Option<String>,
+    /// Header that gets a fresh UUID on requests that don't set it, e.g.
+    /// `X-Request-Id`.
+    pub request_id_header: Option<String>,
+    pub max_request_body_bytes: Option<u64>,
+    pub max_response_body_bytes: Option<u64>,
+}
+
+/// Middlewares in the order their `before_send` hooks run. `after_receive`
+/// hooks run in reverse, so the first middleware sees the request first and
+/// the response last.
+#[derive(Clone, Default)]
+pub struct MiddlewarePipeline {
+    middlewares: Vec<Arc<dyn RequestMiddleware>>,
+}
+
+impl MiddlewarePipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The built-in middlewares `config` enables.
+    pub fn from_config(config: &MiddlewareConfig) -> Self {
+        let mut pipeline = Self::new();
+        if !config.default_headers.is_empty() {
+            pipeline.push(Arc::new(DefaultHeaders {
+                headers: config.default_headers.clone(),
+            }));
+        }
+        if let Some(value) = &config.user_agent {
+            pipeline.push(Arc::new(UserAgent {
+                value: value.clone(),
+            }));
+        }
+        if let Some(header) = &config.request_id_header {
+            pipeline.push(Arc::new(RequestIdHeader {
+                header: header.clone(),
+            }));
+        }
+        if config.max_request_body_bytes.is_some() || config.max_response_body_bytes.is_some() {
+            pipeline.push(Arc::new(BodySizeGuard {
+                max_request_bytes: config.max_request_body_bytes,
+                max_response_bytes: config.max_response_body_bytes,
+            }));
+        }
+        pipeline
+    }
+
+    pub fn push(&mut self, middleware: Arc<dyn RequestMiddleware>) {
+        self.middlewares.push(middleware);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middlewares.is_empty()
+    }
+
+    pub fn before_send(&self, req: &mut RequestWithMetadata) -> RelayResult<()> {
+        self.middlewares
+            .iter()
+            .try_for_each(|middleware| middleware.before_send(req))
+    }
+
+    pub fn after_receive(
+        &self,
+        req: &RequestWithMetadata,
+        response: &mut ResponseWithMetadata,
+    ) -> RelayResult<()> {
+        self.middlewares
+            .iter()
+            .rev()
+            .try_for_each(|middleware| middleware.after_receive(req, response))
+    }
+}
+
+fn has_header(req: &RequestWithMetadata, name: &str) -> bool {
+    req.headers
+        .iter()
+        .any(|header| header.key.eq_ignore_ascii_case(name))
+}
+
+/// Adds headers the request doesn't set itself.
+pub struct DefaultHeaders {
+    pub headers: Vec<KeyValuePair>,
+}
+
+impl RequestMiddleware for DefaultHeaders {
+    fn before_send(&self, req: &mut RequestWithMetadata) -> RelayResult<()> {
+        for header in &self.headers {
+            if !has_header(req, &header.key) {
+                req.headers.push(header.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the request's `User-Agent`.
+pub struct UserAgent {
+    pub value: String,
+}
+
+impl RequestMiddleware for UserAgent {
+    fn before_send(&self, req: &mut RequestWithMetadata) -> RelayResult<()> {
+        req.headers
+            .retain(|header| !header.key.eq_ignore_ascii_case("user-agent"));
+        req.headers.push(KeyValuePair {
+            key: "User-Agent".to_string(),
+            value: self.value.clone(),
+        });
+        Ok(())
+    }
+}
+
+/// Sets `header` to a fresh UUID unless the request already has it.
+pub struct RequestIdHeader {
+    pub header: String,
+}
+
+impl RequestMiddleware for RequestIdHeader {
+    fn before_send(&self, req: &mut RequestWithMetadata) -> RelayResult<()> {
+        if !has_header(req, &self.header) {
+            req.headers.push(KeyValuePair {
+                key: self.header.clone(),
+                value: uuid_v4(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Fails requests whose body, or whose response body, is over a limit. URL
+/// encoded and multipart bodies count the bytes of their keys and values.
+/// The response limit is handed to the transfer as `max_response_bytes`, so
+/// an oversized body is never downloaded in full.
+pub struct BodySizeGuard {
+    pub max_request_bytes: Option<u64>,
+    pub max_response_bytes: Option<u64>,
+}
+
+impl RequestMiddleware for BodySizeGuard {
+    fn before_send(&self, req: &mut RequestWithMetadata) -> RelayResult<()> {
+        if let Some(max) = self.max_response_bytes {
+            req.max_response_bytes = Some(req.max_response_bytes.map_or(max, |own| own.min(max)));
+        }
+
+        let size = match &req.body {
+            None => 0,
+            Some(BodyDef::Text(text)) => text.len(),
+            Some(BodyDef::URLEncoded(pairs)) => pairs
+                .iter()
+                .map(|pair| pair.key.len() + pair.value.len())
+                .sum(),
+            Some(BodyDef::FormData(entries)) => entries
+                .iter()
+                .map(|entry| {
+                    entry.key.len()
+                        + match &entry.value {
+                            FormDataValue::Text(text) => text.len(),
+                            FormDataValue::File { data, .. } => data.len(),
+                        }
+                })
+                .sum(),
+        } as u64;
+        match self.max_request_bytes {
+            Some(max) if size > max => Err(RelayError::RequestRunError(format!(
+                "Request body of {} bytes is over the limit of {} bytes",
+                size, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn after_receive(
+        &self,
+        _req: &RequestWithMetadata,
+        response: &mut ResponseWithMetadata,
+    ) -> RelayResult<()> {
+        let size = response.data.len() as u64;
+        match self.max_response_bytes {
+            Some(max) if size > max => Err(RelayError::RequestRunError(format!(
+                "Response body of {} bytes is over the limit of {} bytes",
+                size, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::relay::run_request_task;
+
+    /// Records the order hooks run in.
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RequestMiddleware for Recorder {
+        fn before_send(&self, _req: &mut RequestWithMetadata) -> RelayResult<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("before {}", self.name));
+            Ok(())
+        }
+
+        fn after_receive(
+            &self,
+            _req: &RequestWithMetadata,
+            _response: &mut ResponseWithMetadata,
+        ) -> RelayResult<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("after {}", self.name));
+            Ok(())
+        }
+    }
+
+    fn header<'a>(req: &'a RequestWithMetadata, name: &str) -> Vec<&'a str> {
+        req.headers
+            .iter()
+            .filter(|header| header.key.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn runs_built_in_and_custom_middlewares() {
+        let pair = |key: &str, value: &str| KeyValuePair {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        let mut pipeline = MiddlewarePipeline::from_config(&MiddlewareConfig {
+            default_headers: vec![pair("X-Team", "api"), pair("Accept", "*/*")],
+            user_agent: Some("Corp/1.0".to_string()),
+            request_id_header: Some("X-Request-Id".to_string()),
+            max_request_body_bytes: Some(8),
+            max_response_body_bytes: Some(4),
+        });
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        for name in ["sign", "log"] {
+            pipeline.push(Arc::new(Recorder {
+                name,
+                calls: calls.clone(),
+            }));
+        }
+
+        let mut req = RequestWithMetadata::new(
+            0,
+            "POST".to_string(),
+            "http://localhost/".to_string(),
+            vec![pair("accept", "text/plain"), pair("user-agent", "curl")],
+            Some(BodyDef::Text("12345678".to_string())),
+            true,
+            Vec::new(),
+            None,
+            None,
+        );
+        pipeline.before_send(&mut req).unwrap();
+        assert_eq!(header(&req, "X-Team"), ["api"]);
+        assert_eq!(header(&req, "Accept"), ["text/plain"]);
+        assert_eq!(header(&req, "User-Agent"), ["Corp/1.0"]);
+        assert_eq!(header(&req, "X-Request-Id")[0].len(), 36);
+
+        let mut response: ResponseWithMetadata = serde_json::from_value(serde_json::json!({
+            "status": 200,
+            "status_text": "OK",
+            "headers": [],
+            "data": [1, 2, 3, 4, 5],
+            "time_start_ms": 0,
+            "time_end_ms": 1,
+        }))
+        .unwrap();
+        let err = pipeline.after_receive(&req, &mut response).unwrap_err();
+        assert!(err.to_string().contains("5 bytes"), "{}", err);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["before sign", "before log", "after log", "after sign"]
+        );
+
+        req.body = Some(BodyDef::Text("123456789".to_string()));
+        assert!(pipeline.before_send(&mut req).is_err());
+    }
+
+    #[test]
+    fn aborts_oversized_responses_during_the_transfer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for socket in listener.incoming() {
+                let mut socket = socket.unwrap();
+                std::thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match socket.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    // Either body would take until the timeout to finish.
+                    let _ = match request.starts_with(b"GET /sized ") {
+                        true => {
+                            socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100000\r\n\r\n")
+                        }
+                        false => socket
+                            .write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n")
+                            .and_then(|_| socket.write_all(&[b'x'; 65536])),
+                    };
+                    std::thread::sleep(Duration::from_secs(10));
+                });
+            }
+        });
+
+        let pipeline = MiddlewarePipeline::from_config(&MiddlewareConfig {
+            max_response_body_bytes: Some(1024),
+            ..MiddlewareConfig::default()
+        });
+        for path in ["sized", "streamed"] {
+            let mut req = RequestWithMetadata::new(
+                0,
+                "GET".to_string(),
+                format!("http://{}/{}", addr, path),
+                Vec::new(),
+                None,
+                true,
+                Vec::new(),
+                None,
+                None,
+            );
+            req.timeout_ms = Some(5_000);
+            pipeline.before_send(&mut req).unwrap();
+            assert_eq!(req.max_response_bytes, Some(1024));
+
+            let started = Instant::now();
+            let err = run_request_task(&req, CancellationToken::new()).unwrap_err();
+            assert!(started.elapsed() < Duration::from_secs(4), "{}", path);
+            assert_eq!(
+                err.to_string(),
+                "Request run error: Response body is over the limit of 1024 bytes"
+            );
+        }
+    }
+}
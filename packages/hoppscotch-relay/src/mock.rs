@@ -0,0 +1,671 @@
+//! Local mock server answering with the example responses saved in a
+//! workspace.
+//!
+//! Every REST request with saved examples becomes a route. Its endpoint
+//! gives the path pattern, where segments written as `:id`, `{id}`,
+//! `{{id}}` or `<<id>>` match any value. A scheme and host, or a leading
+//! base URL variable such as `<<baseUrl>>`, are dropped from the pattern, and
+//! as the variable may carry a path prefix such routes match by path suffix.
+//! Captured path and query parameters are filled into `{{name}}` and
+//! `<<name>>` placeholders of the example's body and headers.
+//!
+//! Clients pick an example with `X-Mock-Response-Name` or
+//! `X-Mock-Response-Code` and slow a response down with `X-Mock-Delay`, in
+//! milliseconds. Otherwise the first successful example is served.
+//! `collections.json` is read again whenever it changes on disk.
+
+use std::{
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    error::{RelayError, RelayResult},
+    interop::KeyValuePair,
+    relay::now_ms,
+    runner::{Workspace, WorkspaceCollection, WorkspaceResponse, COLLECTIONS_FILE},
+    server::{
+        is_loopback_host, read_request, write_response, HttpRequest, HttpResponse, READ_TIMEOUT,
+    },
+    variables::substitute,
+};
+
+/// Upper bound for delays asked for with `X-Mock-Delay`.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+/// How often the accept loop checks whether the server was stopped.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(25);
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MockConfig {
+    /// Port on `127.0.0.1`, `0` picks a free one.
+    pub port: u16,
+    /// Workspace directory holding `collections.json`.
+    pub workspace: PathBuf,
+    /// Added before every response.
+    pub delay_ms: u64,
+    /// Status every response is sent with, whatever the example says.
+    pub status_override: Option<u16>,
+    /// Origins browsers may call the mock server from, e.g.
+    /// `https://app.example.com`. Empty allows pages served from localhost.
+    pub allowed_origins: Vec<String>,
+}
+
+impl MockConfig {
+    fn origin_allowed(&self, origin: &str) -> bool {
+        if self.allowed_origins.is_empty() {
+            return origin
+                .strip_prefix("http://")
+                .or_else(|| origin.strip_prefix("https://"))
+                .is_some_and(is_loopback_host);
+        }
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/') == origin)
+    }
+}
+
+/// A request the mock server answered.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MockHit {
+    pub time_ms: u128,
+    pub method: String,
+    /// Request target including the query string.
+    pub path: String,
+    /// `Collection/Folder/Request` of the matched request.
+    pub request: Option<String>,
+    /// Name of the example served.
+    pub example: Option<String>,
+    /// Path parameters captured by the route.
+    pub params: Vec<KeyValuePair>,
+    pub status: u16,
+    pub delay_ms: u64,
+}
+
+/// A running mock server, stopped with [`MockServer::stop`] or when dropped.
+pub struct MockServer {
+    port: u16,
+    routes: usize,
+    stop_token: CancellationToken,
+    accept_thread: Option<std::thread::JoinHandle<()>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+#[derive(Clone, Debug)]
+struct MockRoute {
+    method: String,
+    segments: Vec<Segment>,
+    /// Whether the endpoint started with a base URL variable, which may
+    /// stand for a path prefix.
+    suffix_match: bool,
+    request: String,
+    examples: Vec<WorkspaceResponse>,
+}
+
+struct LoadedRoutes {
+    modified: Option<SystemTime>,
+    routes: Vec<MockRoute>,
+}
+
+struct MockState {
+    config: MockConfig,
+    routes: Mutex<LoadedRoutes>,
+    on_hit: Box<dyn Fn(MockHit) + Send + Sync>,
+}
+
+impl MockServer {
+    /// Loads the workspace routes and starts serving them in the background.
+    pub fn start<F>(config: MockConfig, on_hit: F) -> RelayResult<Self>
+    where
+        F: Fn(MockHit) + Send + Sync + 'static,
+    {
+        let (modified, routes) = load_routes(&config)?;
+        let route_count = routes.len();
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, config.port)).map_err(|err| {
+            RelayError::RequestRunError(format!(
+                "Failed to listen on port {}: {}",
+                config.port, err
+            ))
+        })?;
+        let port = listener
+            .local_addr()
+            .map_err(|err| RelayError::RequestRunError(err.to_string()))?
+            .port();
+        // Polled, so `stop` doesn't depend on another connection coming in.
+        listener
+            .set_nonblocking(true)
+            .map_err(|err| RelayError::RequestRunError(err.to_string()))?;
+
+        let state = Arc::new(MockState {
+            config,
+            routes: Mutex::new(LoadedRoutes { modified, routes }),
+            on_hit: Box::new(on_hit),
+        });
+        let stop_token = CancellationToken::new();
+        let accept_token = stop_token.clone();
+        let accept_thread = std::thread::Builder::new()
+            .name("relay-mock".to_string())
+            .spawn(move || accept_connections(listener, state, accept_token))
+            .map_err(|err| RelayError::RequestRunError(err.to_string()))?;
+
+        log::info!(
+            "Mock server serving {} routes on port {}",
+            route_count,
+            port
+        );
+        Ok(Self {
+            port,
+            routes: route_count,
+            stop_token,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Number of requests with examples found when the server started.
+    pub fn routes(&self) -> usize {
+        self.routes
+    }
+
+    /// Stops accepting connections and frees the port. Requests already
+    /// being answered finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop_token.cancel();
+        if let Some(accept_thread) = self.accept_thread.take() {
+            let _ = accept_thread.join();
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn accept_connections(listener: TcpListener, state: Arc<MockState>, stop_token: CancellationToken) {
+    while !stop_token.is_cancelled() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                let spawned = std::thread::Builder::new()
+                    .name("relay-mock-conn".to_string())
+                    .spawn(move || handle_connection(stream, &state));
+                if let Err(err) = spawned {
+                    log::warn!("Failed to handle mock connection: {}", err);
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            Err(err) => {
+                log::warn!("Failed to accept mock connection: {}", err);
+                std::thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+        }
+    }
+    log::info!("Mock server stopped");
+}
+
+fn handle_connection(mut stream: TcpStream, state: &MockState) {
+    if let Err(err) = stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(Some(READ_TIMEOUT)))
+    {
+        log::warn!("Failed to set up mock connection: {}", err);
+    }
+
+    let request = match read_request(&mut stream) {
+        Ok(request) => request,
+        Err(status) => {
+            let _ = write_response(&mut stream, &HttpResponse::empty(status), false);
+            return;
+        }
+    };
+
+    // As with the agent, a foreign host points at DNS rebinding, and any
+    // page the developer visits could otherwise read the responses.
+    if !request.header("host").is_some_and(is_loopback_host) {
+        log::warn!(
+            "Rejected mock request for host {:?}",
+            request.header("host")
+        );
+        let _ = write_response(&mut stream, &HttpResponse::empty(403), false);
+        return;
+    }
+    if let Some(origin) = request.header("origin") {
+        if !state.config.origin_allowed(origin) {
+            log::warn!("Rejected mock request from origin {}", origin);
+            let _ = write_response(&mut stream, &HttpResponse::empty(403), false);
+            return;
+        }
+    }
+
+    let (mut response, hit) = state.respond(&request);
+    if let Some(origin) = request.header("origin") {
+        if !response
+            .headers
+            .iter()
+            .any(|(key, _)| key.eq_ignore_ascii_case("access-control-allow-origin"))
+        {
+            response.headers.extend([
+                (
+                    "Access-Control-Allow-Origin".to_string(),
+                    origin.to_string(),
+                ),
+                ("Vary".to_string(), "Origin".to_string()),
+            ]);
+        }
+    }
+
+    if hit.delay_ms > 0 {
+        std::thread::sleep(Duration::from_millis(hit.delay_ms).min(MAX_DELAY));
+    }
+    if let Err(err) = write_response(&mut stream, &response, request.method == "HEAD") {
+        log::debug!("Failed to write mock response: {}", err);
+    }
+    (state.on_hit)(hit);
+}
+
+impl MockState {
+    fn respond(&self, request: &HttpRequest) -> (HttpResponse, MockHit) {
+        let mut hit = MockHit {
+            time_ms: now_ms(),
+            method: request.method.clone(),
+            path: request.path.clone(),
+            request: None,
+            example: None,
+            params: Vec::new(),
+            status: 0,
+            delay_ms: request
+                .header("x-mock-delay")
+                .and_then(|delay| delay.trim().parse().ok())
+                .unwrap_or(self.config.delay_ms),
+        };
+
+        let response = if request.method == "OPTIONS"
+            && request.header("access-control-request-method").is_some()
+        {
+            preflight_response(request)
+        } else {
+            self.reload_if_changed();
+            let routes = self.routes.lock().unwrap_or_else(|err| err.into_inner());
+            let (path, query) = request
+                .path
+                .split_once('?')
+                .unwrap_or((request.path.as_str(), ""));
+
+            match match_route(&routes.routes, &request.method, path) {
+                Some((route, params)) => {
+                    hit.request = Some(route.request.clone());
+                    hit.params = params.clone();
+                    self.example_response(route, request, params, query, &mut hit)
+                }
+                None => HttpResponse::json(
+                    404,
+                    &serde_json::json!({
+                        "error": format!("No example matches {} {}", request.method, path),
+                    }),
+                ),
+            }
+        };
+
+        hit.status = response.status;
+        (response, hit)
+    }
+
+    fn example_response(
+        &self,
+        route: &MockRoute,
+        request: &HttpRequest,
+        params: Vec<KeyValuePair>,
+        query: &str,
+        hit: &mut MockHit,
+    ) -> HttpResponse {
+        let requested_code = request
+            .header("x-mock-response-code")
+            .and_then(|code| code.trim().parse::<u16>().ok());
+
+        let example = match request.header("x-mock-response-name") {
+            Some(name) => match route.examples.iter().find(|example| example.name == name) {
+                Some(example) => example,
+                None => {
+                    return HttpResponse::json(
+                        404,
+                        &serde_json::json!({
+                            "error": format!("No example named '{}' for {}", name, route.request),
+                        }),
+                    )
+                }
+            },
+            None => requested_code
+                .and_then(|code| {
+                    route
+                        .examples
+                        .iter()
+                        .find(|example| example.code == Some(code))
+                })
+                .or_else(|| {
+                    route
+                        .examples
+                        .iter()
+                        .find(|example| example.code.is_some_and(|code| code < 300))
+                })
+                .unwrap_or(&route.examples[0]),
+        };
+        hit.example = Some(example.name.clone());
+
+        let mut variables = params;
+        variables.extend(
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    KeyValuePair {
+                        key: url_escape::decode(&key.replace('+', " ")).into_owned(),
+                        value: url_escape::decode(&value.replace('+', " ")).into_owned(),
+                    }
+                }),
+        );
+
+        HttpResponse {
+            status: self
+                .config
+                .status_override
+                .or(requested_code)
+                .or(example.code)
+                .unwrap_or(200),
+            headers: example
+                .headers
+                .iter()
+                .map(|header| (header.key.clone(), substitute(&header.value, &variables)))
+                .collect(),
+            body: substitute(&example.body, &variables).into_bytes(),
+        }
+    }
+
+    fn reload_if_changed(&self) {
+        let modified = collections_modified(&self.config);
+        let mut routes = self.routes.lock().unwrap_or_else(|err| err.into_inner());
+        if modified == routes.modified {
+            return;
+        }
+
+        match load_routes(&self.config) {
+            Ok((modified, loaded)) => {
+                log::info!("Reloaded {} mock routes", loaded.len());
+                *routes = LoadedRoutes {
+                    modified,
+                    routes: loaded,
+                };
+            }
+            // Keeps serving the last good routes while the file is being written.
+            Err(err) => log::warn!("Failed to reload mock routes: {}", err),
+        }
+    }
+}
+
+fn preflight_response(request: &HttpRequest) -> HttpResponse {
+    let mut response = HttpResponse::empty(204);
+    response.headers.extend([
+        (
+            "Access-Control-Allow-Methods".to_string(),
+            "GET, POST, PUT, PATCH, DELETE, HEAD, OPTIONS".to_string(),
+        ),
+        ("Access-Control-Max-Age".to_string(), "600".to_string()),
+    ]);
+    if let Some(headers) = request.header("access-control-request-headers") {
+        response.headers.push((
+            "Access-Control-Allow-Headers".to_string(),
+            headers.to_string(),
+        ));
+    }
+    response
+}
+
+fn collections_modified(config: &MockConfig) -> Option<SystemTime> {
+    std::fs::metadata(config.workspace.join(COLLECTIONS_FILE))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn load_routes(config: &MockConfig) -> RelayResult<(Option<SystemTime>, Vec<MockRoute>)> {
+    let modified = collections_modified(config);
+    let workspace = Workspace::load_files(&config.workspace.join(COLLECTIONS_FILE), None)?;
+
+    let mut routes = Vec::new();
+    for collection in &workspace.collections {
+        collect_routes(collection, &collection.name, &mut routes);
+    }
+    Ok((modified, routes))
+}
+
+fn collect_routes(collection: &WorkspaceCollection, path: &str, routes: &mut Vec<MockRoute>) {
+    for folder in &collection.folders {
+        collect_routes(folder, &format!("{}/{}", path, folder.name), routes);
+    }
+    for request in &collection.requests {
+        if request.responses.is_empty() {
+            continue;
+        }
+        let (segments, suffix_match) = parse_pattern(&request.endpoint);
+        routes.push(MockRoute {
+            method: request.method.to_uppercase(),
+            segments,
+            suffix_match,
+            request: format!("{}/{}", path, request.name),
+            examples: request.responses.values().cloned().collect(),
+        });
+    }
+}
+
+/// Path segments of an endpoint, and whether it started with a variable.
+fn parse_pattern(endpoint: &str) -> (Vec<Segment>, bool) {
+    let endpoint = endpoint.split(['?', '#']).next().unwrap_or_default();
+    let (path, suffix_match) = match endpoint.split_once("://") {
+        Some((_, rest)) => (rest.find('/').map_or("", |start| &rest[start..]), false),
+        None if endpoint.starts_with('/') => (endpoint, false),
+        // `<<baseUrl>>/users` or a host without a scheme, `localhost:3000/users`.
+        None => (
+            endpoint.find('/').map_or("", |start| &endpoint[start..]),
+            endpoint.starts_with("<<") || endpoint.starts_with("{{"),
+        ),
+    };
+
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            let param = segment
+                .strip_prefix(':')
+                .or_else(|| strip_wrapped(segment, "{{", "}}"))
+                .or_else(|| strip_wrapped(segment, "<<", ">>"))
+                .or_else(|| strip_wrapped(segment, "{", "}"));
+            match param {
+                Some(name) if !name.is_empty() => Segment::Param(name.trim().to_string()),
+                _ => Segment::Literal(segment.to_string()),
+            }
+        })
+        .collect();
+    (segments, suffix_match)
+}
+
+fn strip_wrapped<'a>(segment: &'a str, open: &str, close: &str) -> Option<&'a str> {
+    segment.strip_prefix(open)?.strip_suffix(close)
+}
+
+/// The most specific route for `method` and `path`, with its captured
+/// parameters. Exact matches win over suffix matches, then routes with more
+/// literal segments win.
+fn match_route<'a>(
+    routes: &'a [MockRoute],
+    method: &str,
+    path: &str,
+) -> Option<(&'a MockRoute, Vec<KeyValuePair>)> {
+    let incoming = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    let method = if method == "HEAD" { "GET" } else { method };
+
+    routes
+        .iter()
+        .filter(|route| route.method.eq_ignore_ascii_case(method))
+        .filter_map(|route| {
+            let skip = incoming.len().checked_sub(route.segments.len())?;
+            if skip > 0 && !route.suffix_match {
+                return None;
+            }
+
+            let mut params = Vec::new();
+            for (segment, value) in route.segments.iter().zip(&incoming[skip..]) {
+                match segment {
+                    Segment::Literal(literal) if literal == value => {}
+                    Segment::Literal(_) => return None,
+                    Segment::Param(name) => params.push(KeyValuePair {
+                        key: name.clone(),
+                        value: url_escape::decode(value).into_owned(),
+                    }),
+                }
+            }
+
+            let literals = route
+                .segments
+                .iter()
+                .filter(|segment| matches!(segment, Segment::Literal(_)))
+                .count();
+            Some(((skip == 0, literals), route, params))
+        })
+        .max_by_key(|(rank, _, _)| *rank)
+        .map(|(_, route, params)| (route, params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn send(port: u16, request: &str) -> String {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_examples_by_route() {
+        let workspace = std::env::temp_dir().join(format!("relay-mock-{}", std::process::id()));
+        std::fs::create_dir_all(&workspace).unwrap();
+        let example = |name: &str, code: u16, body: &str| {
+            serde_json::json!({
+                "name": name,
+                "status": "",
+                "code": code,
+                "headers": [{ "key": "Content-Type", "value": "application/json" }],
+                "body": body
+            })
+        };
+        let collections = serde_json::json!([{
+            "name": "API",
+            "folders": [],
+            "requests": [
+                {
+                    "name": "Get user",
+                    "method": "GET",
+                    "endpoint": "<<baseUrl>>/users/:id",
+                    "responses": {
+                        "Missing": example("Missing", 404, "{}"),
+                        "Found": example("Found", 200, "{\"id\":\"{{id}}\",\"v\":\"<<v>>\"}")
+                    }
+                },
+                {
+                    "name": "Current user",
+                    "method": "GET",
+                    "endpoint": "https://api.example.com/users/me",
+                    "responses": { "Me": example("Me", 200, "me") }
+                },
+                { "name": "No examples", "method": "GET", "endpoint": "/other", "responses": {} }
+            ]
+        }]);
+        std::fs::write(workspace.join(COLLECTIONS_FILE), collections.to_string()).unwrap();
+
+        let hits = Arc::new(Mutex::new(Vec::new()));
+        let recorded = hits.clone();
+        let server = MockServer::start(
+            MockConfig {
+                workspace: workspace.clone(),
+                ..Default::default()
+            },
+            move |hit| recorded.lock().unwrap().push(hit),
+        )
+        .unwrap();
+        assert_eq!(server.routes(), 2);
+        let port = server.port();
+
+        let response = send(
+            port,
+            "GET /v1/users/42?v=2 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("{\"id\":\"42\",\"v\":\"2\"}"));
+
+        let response = send(port, "GET /users/me HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\nme"));
+
+        let response = send(
+            port,
+            "GET /users/7 HTTP/1.1\r\nHost: localhost\r\nX-Mock-Response-Code: 404\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 404"));
+        assert!(response.ends_with("\r\n\r\n{}"));
+
+        let response = send(port, "GET /other HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404"));
+        assert!(response.contains("No example matches GET /other"));
+
+        let response = send(
+            port,
+            "GET /users/me HTTP/1.1\r\nHost: localhost:3000\r\nOrigin: http://localhost:5173\r\n\r\n",
+        );
+        assert!(response.contains("Access-Control-Allow-Origin: http://localhost:5173"));
+        let response = send(
+            port,
+            "GET /users/me HTTP/1.1\r\nHost: localhost\r\nOrigin: https://evil.example\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 403"));
+        let response = send(port, "GET /users/me HTTP/1.1\r\nHost: evil.example\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403"));
+
+        server.stop();
+        std::fs::remove_dir_all(&workspace).unwrap();
+
+        let hits = hits.lock().unwrap();
+        assert_eq!(hits.len(), 5);
+        assert_eq!(hits[0].request.as_deref(), Some("API/Get user"));
+        assert_eq!(hits[0].example.as_deref(), Some("Found"));
+        assert_eq!(hits[0].params[0].value, "42");
+        assert_eq!(hits[2].example.as_deref(), Some("Missing"));
+        assert_eq!(hits[3].request, None);
+        assert_eq!(hits[3].status, 404);
+    }
+}
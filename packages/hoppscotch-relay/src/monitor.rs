@@ -0,0 +1,726 @@
+//! Scheduled monitors, requests that run on an interval or cron schedule.
+//!
+//! A background thread checks twice a second which monitors are due and runs
+//! each on a thread of its own through `run_request_task`, skipping a run
+//! while the previous one is still going. Each run's request goes through
+//! the caller's prepare hook first, which fills in secrets and defaults. A
+//! run passes when all assertions of the monitor pass, or with a status below
+//! 400 when it has none. Results are appended to a rolling JSON Lines log,
+//! which keeps the current file and the one before it.
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, MutexGuard},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    assertions::Assertion,
+    error::{RelayError, RelayResult},
+    interop::RequestWithMetadata,
+    relay::{now_ms, run_request_task},
+};
+
+pub const MONITOR_LOG_FILE: &str = "monitor-results.jsonl";
+const ROTATED_LOG_FILE: &str = "monitor-results.1.jsonl";
+const TICK: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MonitorSchedule {
+    /// Every `seconds`, counted from when the monitor was added or resumed.
+    Every { seconds: u64 },
+    /// A five field cron expression, `minute hour day month weekday`,
+    /// evaluated in UTC. `@hourly`, `@daily`, `@weekly`, `@monthly` and
+    /// `@yearly` are accepted too.
+    Cron { expression: String },
+}
+
+impl MonitorSchedule {
+    /// First run strictly after `after_ms`.
+    pub fn next_run(&self, after_ms: u128) -> RelayResult<u128> {
+        match self {
+            Self::Every { seconds } if *seconds == 0 => Err(RelayError::RequestRunError(
+                "A monitor interval has to be at least one second".to_string(),
+            )),
+            Self::Every { seconds } => Ok(after_ms + u128::from(*seconds) * 1000),
+            Self::Cron { expression } => CronExpression::parse(expression)?
+                .next_after(after_ms)
+                .ok_or_else(|| {
+                    RelayError::RequestRunError(format!(
+                        "Cron expression '{}' never matches",
+                        expression
+                    ))
+                }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Monitor {
+    pub id: String,
+    pub name: String,
+    pub request: RequestWithMetadata,
+    pub schedule: MonitorSchedule,
+    /// Checks a run has to pass. Without any, a status below 400 passes.
+    #[serde(default)]
+    pub assertions: Vec<Assertion>,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MonitorResult {
+    pub monitor_id: String,
+    pub monitor_name: String,
+    /// When the run started, in milliseconds since the epoch.
+    pub time_ms: u128,
+    pub passed: bool,
+    pub status: Option<u16>,
+    pub duration_ms: f64,
+    /// Messages of the failed assertions.
+    #[serde(default)]
+    pub failures: Vec<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MonitorStatus {
+    pub monitor: Monitor,
+    /// `None` while paused.
+    pub next_run_ms: Option<u128>,
+    pub running: bool,
+    pub last_result: Option<MonitorResult>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MonitorConfig {
+    /// Size at which the log is rotated.
+    pub max_log_bytes: u64,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            max_log_bytes: 2 * 1024 * 1024,
+        }
+    }
+}
+
+struct MonitorState {
+    monitor: Monitor,
+    next_run_ms: Option<u128>,
+    running: bool,
+    last_result: Option<MonitorResult>,
+}
+
+type ResultCallback = dyn Fn(&MonitorResult) + Send + Sync;
+type PrepareHook = dyn Fn(&RequestWithMetadata) -> RelayResult<RequestWithMetadata> + Send + Sync;
+
+struct Shared {
+    monitors: Mutex<BTreeMap<String, MonitorState>>,
+    log: Mutex<MonitorLog>,
+    prepare: Box<PrepareHook>,
+    on_result: Box<ResultCallback>,
+    cancel_token: CancellationToken,
+}
+
+pub struct MonitorScheduler {
+    shared: Arc<Shared>,
+    stop_tx: mpsc::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MonitorScheduler {
+    /// Starts scheduling `monitors`, logging results to `dir`. `prepare`
+    /// turns a monitor's request into the one sent, a run whose request it
+    /// rejects fails with its error without sending anything. `on_result`
+    /// is called on a run's thread for every result.
+    pub fn start<P, F>(
+        dir: &Path,
+        config: MonitorConfig,
+        monitors: Vec<Monitor>,
+        prepare: P,
+        on_result: F,
+    ) -> RelayResult<Self>
+    where
+        P: Fn(&RequestWithMetadata) -> RelayResult<RequestWithMetadata> + Send + Sync + 'static,
+        F: Fn(&MonitorResult) + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            monitors: Mutex::new(BTreeMap::new()),
+            log: Mutex::new(MonitorLog::open(dir, config.max_log_bytes)?),
+            prepare: Box::new(prepare),
+            on_result: Box::new(on_result),
+            cancel_token: CancellationToken::new(),
+        });
+        for monitor in monitors {
+            if let Err(err) = upsert(&shared, monitor.clone()) {
+                log::warn!("Skipping monitor {}: {}", monitor.name, err);
+            }
+        }
+
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let thread_shared = shared.clone();
+        let thread = std::thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(TICK) {
+                run_due(&thread_shared);
+            }
+        });
+
+        Ok(Self {
+            shared,
+            stop_tx,
+            thread: Some(thread),
+        })
+    }
+
+    /// Adds `monitor`, or replaces the one with the same ID.
+    pub fn upsert(&self, monitor: Monitor) -> RelayResult<()> {
+        upsert(&self.shared, monitor)
+    }
+
+    pub fn remove(&self, id: &str) -> RelayResult<Monitor> {
+        lock(&self.shared.monitors)
+            .remove(id)
+            .map(|state| state.monitor)
+            .ok_or_else(|| unknown_monitor(id))
+    }
+
+    pub fn pause(&self, id: &str) -> RelayResult<Monitor> {
+        let mut monitors = lock(&self.shared.monitors);
+        let state = monitors.get_mut(id).ok_or_else(|| unknown_monitor(id))?;
+        state.monitor.paused = true;
+        state.next_run_ms = None;
+        Ok(state.monitor.clone())
+    }
+
+    pub fn resume(&self, id: &str) -> RelayResult<Monitor> {
+        let mut monitors = lock(&self.shared.monitors);
+        let state = monitors.get_mut(id).ok_or_else(|| unknown_monitor(id))?;
+        state.next_run_ms = Some(state.monitor.schedule.next_run(now_ms())?);
+        state.monitor.paused = false;
+        Ok(state.monitor.clone())
+    }
+
+    /// Every monitor with its schedule state, in ID order.
+    pub fn list(&self) -> Vec<MonitorStatus> {
+        lock(&self.shared.monitors)
+            .values()
+            .map(|state| MonitorStatus {
+                monitor: state.monitor.clone(),
+                next_run_ms: state.next_run_ms,
+                running: state.running,
+                last_result: state.last_result.clone(),
+            })
+            .collect()
+    }
+
+    /// The monitors as they should be persisted, including pause state.
+    pub fn monitors(&self) -> Vec<Monitor> {
+        lock(&self.shared.monitors)
+            .values()
+            .map(|state| state.monitor.clone())
+            .collect()
+    }
+
+    /// Logged results, newest first, of one monitor or of all of them.
+    pub fn results(
+        &self,
+        monitor_id: Option<&str>,
+        limit: usize,
+    ) -> RelayResult<Vec<MonitorResult>> {
+        lock(&self.shared.log).read(monitor_id, limit)
+    }
+
+    /// Stops scheduling and cancels the runs in progress.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.shared.cancel_token.cancel();
+        let _ = self.stop_tx.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MonitorScheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn upsert(shared: &Shared, monitor: Monitor) -> RelayResult<()> {
+    let next_run_ms = match monitor.paused {
+        true => None,
+        false => Some(monitor.schedule.next_run(now_ms())?),
+    };
+    let mut monitors = lock(&shared.monitors);
+    let previous = monitors.remove(&monitor.id);
+    monitors.insert(
+        monitor.id.clone(),
+        MonitorState {
+            monitor,
+            next_run_ms,
+            running: previous.as_ref().is_some_and(|state| state.running),
+            last_result: previous.and_then(|state| state.last_result),
+        },
+    );
+    Ok(())
+}
+
+fn run_due(shared: &Arc<Shared>) {
+    let now = now_ms();
+    let mut monitors = lock(&shared.monitors);
+    for state in monitors.values_mut() {
+        if state
+            .next_run_ms
+            .is_none_or(|next_run_ms| next_run_ms > now)
+        {
+            continue;
+        }
+        state.next_run_ms = state.monitor.schedule.next_run(now).ok();
+        if state.running {
+            log::warn!(
+                "Skipping run of monitor {}, the previous one is still going",
+                state.monitor.name
+            );
+            continue;
+        }
+
+        state.running = true;
+        let monitor = state.monitor.clone();
+        let shared = shared.clone();
+        std::thread::spawn(move || {
+            let result = run_monitor(&monitor, &shared.prepare, shared.cancel_token.child_token());
+            if shared.cancel_token.is_cancelled() {
+                return;
+            }
+            if let Err(err) = lock(&shared.log).append(&result) {
+                log::warn!("Failed to log monitor result: {}", err);
+            }
+            if let Some(state) = lock(&shared.monitors).get_mut(&monitor.id) {
+                state.running = false;
+                state.last_result = Some(result.clone());
+            }
+            (shared.on_result)(&result);
+        });
+    }
+}
+
+fn run_monitor(
+    monitor: &Monitor,
+    prepare: &PrepareHook,
+    cancel_token: CancellationToken,
+) -> MonitorResult {
+    let time_ms = now_ms();
+
+    let mut result = MonitorResult {
+        monitor_id: monitor.id.clone(),
+        monitor_name: monitor.name.clone(),
+        time_ms,
+        passed: false,
+        status: None,
+        duration_ms: 0.0,
+        failures: Vec::new(),
+        error: None,
+    };
+    let prepared = prepare(&monitor.request).map(|mut req| {
+        req.assertions = monitor.assertions.clone();
+        req
+    });
+    match prepared.and_then(|req| run_request_task(&req, cancel_token)) {
+        Ok(response) => {
+            result.status = Some(response.status);
+            result.duration_ms = (response.time_end_ms - response.time_start_ms) as f64;
+            match &response.assertions {
+                Some(report) => {
+                    result.failures = report
+                        .results
+                        .iter()
+                        .filter_map(|result| result.message.clone())
+                        .collect();
+                    result.passed = report.success();
+                }
+                None => result.passed = response.status < 400,
+            }
+        }
+        Err(err) => {
+            result.duration_ms = (now_ms() - time_ms) as f64;
+            result.error = Some(err.to_string());
+        }
+    }
+    result
+}
+
+struct MonitorLog {
+    path: PathBuf,
+    rotated_path: PathBuf,
+    max_bytes: u64,
+}
+
+impl MonitorLog {
+    fn open(dir: &Path, max_bytes: u64) -> RelayResult<Self> {
+        std::fs::create_dir_all(dir).map_err(|err| {
+            RelayError::RequestRunError(format!(
+                "Failed to create monitor log directory {}: {}",
+                dir.display(),
+                err
+            ))
+        })?;
+        Ok(Self {
+            path: dir.join(MONITOR_LOG_FILE),
+            rotated_path: dir.join(ROTATED_LOG_FILE),
+            max_bytes,
+        })
+    }
+
+    fn append(&mut self, result: &MonitorResult) -> RelayResult<()> {
+        let io_error = |err: std::io::Error| {
+            RelayError::RequestRunError(format!("Failed to write monitor log: {}", err))
+        };
+        if std::fs::metadata(&self.path).is_ok_and(|metadata| metadata.len() >= self.max_bytes) {
+            std::fs::rename(&self.path, &self.rotated_path).map_err(io_error)?;
+        }
+
+        let mut line = serde_json::to_string(result)
+            .map_err(|err| RelayError::RequestRunError(err.to_string()))?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(io_error)
+    }
+
+    fn read(&self, monitor_id: Option<&str>, limit: usize) -> RelayResult<Vec<MonitorResult>> {
+        let mut results = Vec::new();
+        for path in [&self.rotated_path, &self.path] {
+            let file = match File::open(path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(RelayError::RequestRunError(format!(
+                        "Failed to read {}: {}",
+                        path.display(),
+                        err
+                    )))
+                }
+            };
+            results.extend(
+                BufReader::new(file)
+                    .lines()
+                    .map_while(Result::ok)
+                    .filter_map(|line| serde_json::from_str::<MonitorResult>(&line).ok())
+                    .filter(|result| monitor_id.is_none_or(|id| result.monitor_id == id)),
+            );
+        }
+        results.reverse();
+        results.truncate(limit);
+        Ok(results)
+    }
+}
+
+/// A parsed cron expression, each field as a bit set of the values it
+/// matches.
+struct CronExpression {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Both day fields are restricted, a day then matches either of them.
+    either_day: bool,
+}
+
+impl CronExpression {
+    fn parse(expression: &str) -> RelayResult<Self> {
+        let expanded = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let invalid = |reason: &str| {
+            RelayError::RequestRunError(format!(
+                "Invalid cron expression '{}': {}",
+                expression, reason
+            ))
+        };
+
+        let fields = expanded.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(invalid("expected 5 fields"));
+        };
+        const MONTHS: &[&str] = &[
+            "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+        ];
+        const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+        let mut weekdays = parse_field(weekday, 0, 7, WEEKDAYS, 0).map_err(|err| invalid(&err))?;
+        // 7 is Sunday as well.
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59, &[], 0).map_err(|err| invalid(&err))?,
+            hours: parse_field(hour, 0, 23, &[], 0).map_err(|err| invalid(&err))?,
+            days: parse_field(day, 1, 31, &[], 0).map_err(|err| invalid(&err))?,
+            months: parse_field(month, 1, 12, MONTHS, 1).map_err(|err| invalid(&err))?,
+            weekdays,
+            either_day: !day.starts_with('*') && !weekday.starts_with('*'),
+        })
+    }
+
+    fn next_after(&self, after_ms: u128) -> Option<u128> {
+        let has = |set: u64, value: u64| set & (1 << value) != 0;
+        let mut minute = (after_ms / 60_000) as u64 + 1;
+        // Every schedule that matches at all does so within four years.
+        let limit = minute + 4 * 366 * 24 * 60;
+
+        while minute < limit {
+            let days = minute / (24 * 60);
+            let (_, month, day) = civil_from_days(days);
+            // 1970-01-01 was a Thursday.
+            let weekday = (days + 4) % 7;
+            let day_matches = if self.either_day {
+                has(self.days, day) || has(self.weekdays, weekday)
+            } else {
+                has(self.days, day) && has(self.weekdays, weekday)
+            };
+            if !has(self.months, month) || !day_matches {
+                minute = (days + 1) * 24 * 60;
+                continue;
+            }
+            if !has(self.hours, (minute / 60) % 24) {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if !has(self.minutes, minute % 60) {
+                minute += 1;
+                continue;
+            }
+            return Some(u128::from(minute) * 60_000);
+        }
+        None
+    }
+}
+
+/// Parses a cron field such as `*/15`, `1-5`, `mon-fri` or `0,30` into a bit
+/// set. `names` are alternatives for the values from `names_start` on.
+fn parse_field(
+    field: &str,
+    min: u64,
+    max: u64,
+    names: &[&str],
+    names_start: u64,
+) -> Result<u64, String> {
+    let value = |text: &str| -> Result<u64, String> {
+        let lower = text.to_ascii_lowercase();
+        let value = match names.iter().position(|name| *name == lower) {
+            Some(position) => position as u64 + names_start,
+            None => text
+                .parse::<u64>()
+                .map_err(|_| format!("'{}' is not a number", text))?,
+        };
+        if (min..=max).contains(&value) {
+            Ok(value)
+        } else {
+            Err(format!("{} is outside {}-{}", value, min, max))
+        }
+    };
+
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u64>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("invalid step '{}'", step))?,
+            ),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (value(start)?, value(end)?),
+                // `5/10` runs from 5 to the end of the range.
+                None if step > 1 => (value(range)?, max),
+                None => {
+                    let value = value(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            return Err(format!("range {} is reversed", range));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+/// Year, month and day of the date `days` after 1970-01-01.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn unknown_monitor(id: &str) -> RelayError {
+    RelayError::RequestRunError(format!("No monitor with id {}", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(date: &str) -> u128 {
+        httpdate::parse_http_date(date)
+            .unwrap()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+
+    fn next(expression: &str, after: &str) -> String {
+        let next_ms = MonitorSchedule::Cron {
+            expression: expression.to_string(),
+        }
+        .next_run(ms(after))
+        .unwrap();
+        httpdate::fmt_http_date(std::time::UNIX_EPOCH + Duration::from_millis(next_ms as u64))
+    }
+
+    #[test]
+    fn schedules_cron_expressions() {
+        let after = "Wed, 31 Jan 2024 23:59:30 GMT";
+        assert_eq!(next("* * * * *", after), "Thu, 01 Feb 2024 00:00:00 GMT");
+        assert_eq!(
+            next("*/15 9-17 * * mon-fri", after),
+            "Thu, 01 Feb 2024 09:00:00 GMT"
+        );
+        assert_eq!(next("30 6 29 2 *", after), "Thu, 29 Feb 2024 06:30:00 GMT");
+        assert_eq!(next("0 12 * * 7", after), "Sun, 04 Feb 2024 12:00:00 GMT");
+        // Either day field matches when both are restricted.
+        assert_eq!(next("0 0 15 * 6", after), "Sat, 03 Feb 2024 00:00:00 GMT");
+        assert_eq!(next("@monthly", after), "Thu, 01 Feb 2024 00:00:00 GMT");
+
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "0 0 30 2 *",
+        ] {
+            assert!(
+                MonitorSchedule::Cron {
+                    expression: invalid.to_string()
+                }
+                .next_run(0)
+                .is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn runs_due_monitors_and_logs_results() {
+        let dir = std::env::temp_dir().join(format!("relay-monitor-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let monitor = |id: &str, endpoint: &str| Monitor {
+            id: id.to_string(),
+            name: id.to_string(),
+            request: RequestWithMetadata::new(
+                0,
+                "GET".to_string(),
+                endpoint.to_string(),
+                Vec::new(),
+                None,
+                true,
+                Vec::new(),
+                None,
+                None,
+            ),
+            schedule: MonitorSchedule::Every { seconds: 1 },
+            assertions: Vec::new(),
+            paused: false,
+        };
+        let (results_tx, results_rx) = mpsc::channel();
+        let scheduler = MonitorScheduler::start(
+            &dir,
+            MonitorConfig::default(),
+            vec![
+                monitor("down", "http://127.0.0.1:1/"),
+                monitor("locked", "http://127.0.0.1:1/{{secret:token}}"),
+            ],
+            |req| match crate::vault::referenced_secrets(req).is_empty() {
+                true => Ok(req.clone()),
+                false => Err(RelayError::RequestRunError(
+                    "The vault is locked".to_string(),
+                )),
+            },
+            move |result| {
+                let _ = results_tx.send(result.clone());
+            },
+        )
+        .unwrap();
+
+        let mut results = BTreeMap::new();
+        while results.len() < 2 {
+            let result = results_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            results.insert(result.monitor_id.clone(), result);
+        }
+        assert!(!results["down"].passed);
+        assert!(results["down"].error.is_some());
+        assert!(!results["locked"].passed);
+        assert_eq!(
+            results["locked"].error.as_deref(),
+            Some("Request run error: The vault is locked")
+        );
+
+        scheduler.pause("down").unwrap();
+        assert!(scheduler.list()[0].next_run_ms.is_none());
+        assert!(scheduler.monitors()[0].paused);
+        let logged = scheduler.results(Some("down"), 10).unwrap();
+        assert!(!logged.is_empty());
+        assert_eq!(logged[0].monitor_name, "down");
+
+        scheduler.stop();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
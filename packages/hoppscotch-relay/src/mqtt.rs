@@ -0,0 +1,1031 @@
+//! MQTT 3.1.1 and 5 client.
+//!
+//! Connections go through [`net::connect`], so brokers are reached with the
+//! same proxy, root certificate and client certificate settings as HTTP
+//! requests. Packets are encoded and decoded here, the protocol is small
+//! enough that a full client library would mostly bring its own runtime.
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    io::{ErrorKind, Read, Write},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{RelayError, RelayResult},
+    interop::{KeyValuePair, RequestWithMetadata},
+    net::{self, NetStream},
+};
+
+/// How long connecting may wait on the broker's `CONNACK`.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the connection thread stops reading to check for outgoing packets.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+const PROPERTY_CONTENT_TYPE: u8 = 0x03;
+const PROPERTY_ASSIGNED_CLIENT_ID: u8 = 0x12;
+const PROPERTY_REASON_STRING: u8 = 0x1F;
+const PROPERTY_USER: u8 = 0x26;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum MqttVersion {
+    V311,
+    V5,
+}
+
+impl MqttVersion {
+    fn level(self) -> u8 {
+        match self {
+            MqttVersion::V311 => 4,
+            MqttVersion::V5 => 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MqttQoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl MqttQoS {
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(MqttQoS::AtMostOnce),
+            1 => Some(MqttQoS::AtLeastOnce),
+            2 => Some(MqttQoS::ExactlyOnce),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        self as u8
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct MqttOptions {
+    pub version: MqttVersion,
+    /// Generated when empty. MQTT 5 brokers may assign their own instead.
+    pub client_id: String,
+    /// Seconds between pings on an idle connection, `0` turns them off.
+    pub keep_alive_secs: u16,
+    /// Start without any session state the broker kept for this client ID.
+    pub clean_start: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for MqttOptions {
+    fn default() -> Self {
+        Self {
+            version: MqttVersion::V311,
+            client_id: String::new(),
+            keep_alive_secs: 60,
+            clean_start: true,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MqttSubscription {
+    /// Topic filter, wildcards allowed.
+    pub topic: String,
+    pub qos: MqttQoS,
+}
+
+/// A message to publish.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MqttPublish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: MqttQoS,
+    pub retain: bool,
+    /// Sent as MQTT 5 user properties, ignored on MQTT 3.1.1.
+    #[serde(default)]
+    pub user_properties: Vec<KeyValuePair>,
+    /// Sent as the MQTT 5 content type, ignored on MQTT 3.1.1.
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+/// A message delivered by the broker.
+#[derive(Clone, Debug, Serialize)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: MqttQoS,
+    pub retain: bool,
+    pub user_properties: Vec<KeyValuePair>,
+    pub content_type: Option<String>,
+}
+
+/// Everything that happens on a connection after `CONNACK`, in order.
+#[derive(Clone, Debug, Serialize)]
+pub enum MqttEvent {
+    Connected {
+        session_present: bool,
+        /// The client ID in use, which an MQTT 5 broker may have assigned.
+        client_id: String,
+    },
+    /// The broker answered a subscribe, with one granted QoS or failure code
+    /// (`0x80` and up) per requested topic.
+    Subscribed {
+        packet_id: u16,
+        return_codes: Vec<u8>,
+    },
+    Unsubscribed {
+        packet_id: u16,
+    },
+    /// A QoS 1 or 2 publish finished its acknowledgement flow.
+    Published {
+        packet_id: u16,
+        reason_code: u8,
+    },
+    Message(MqttMessage),
+    Disconnected {
+        reason: String,
+    },
+    Error(String),
+}
+
+enum ConnectionCommand {
+    Send(Vec<u8>),
+    Disconnect,
+}
+
+/// Handle to an open MQTT connection.
+///
+/// Incoming messages and acknowledgements are read on a background thread and
+/// handed to the event callback given to [`MqttConnection::connect`].
+/// Dropping the handle disconnects cleanly.
+pub struct MqttConnection {
+    version: MqttVersion,
+    commands: mpsc::Sender<ConnectionCommand>,
+    next_packet_id: AtomicU16,
+}
+
+impl MqttConnection {
+    /// Connects to `req.endpoint` (`mqtt://` or `mqtts://`) with the
+    /// request's proxy and certificate settings. Headers, method and body are
+    /// ignored.
+    ///
+    /// Blocks until the broker accepted or refused the connection, so refusals
+    /// are returned here rather than reported as events.
+    pub fn connect<F>(
+        req: &RequestWithMetadata,
+        options: &MqttOptions,
+        mut on_event: F,
+    ) -> RelayResult<Self>
+    where
+        F: FnMut(MqttEvent) + Send + 'static,
+    {
+        let uri = req
+            .endpoint
+            .parse::<http::Uri>()
+            .map_err(|_| RelayError::InvalidUrl)?;
+        let tls = match uri.scheme_str() {
+            Some("mqtts") | Some("ssl") | Some("tls") => true,
+            Some("mqtt") | Some("tcp") => false,
+            _ => return Err(RelayError::InvalidUrl),
+        };
+        let host = uri.host().ok_or(RelayError::InvalidUrl)?;
+        let port = uri.port_u16().unwrap_or(if tls { 8883 } else { 1883 });
+
+        let client_id = if options.client_id.is_empty() {
+            let suffix: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(12)
+                .map(char::from)
+                .collect();
+            format!("postdata-{}", suffix)
+        } else {
+            options.client_id.clone()
+        };
+
+        let mut stream = net::connect(host, port, tls, req)?;
+        set_read_timeout(&stream, CONNECT_TIMEOUT)?;
+        stream
+            .write_all(&encode_connect(options, &client_id))
+            .map_err(|err| RelayError::ConnectionFailed(format!("MQTT connect failed: {}", err)))?;
+
+        let mut buffer = Vec::new();
+        let connack = loop {
+            match decode_packet(&mut buffer, options.version)? {
+                Some(Packet::ConnAck(connack)) => break connack,
+                Some(_) => {
+                    return Err(RelayError::ConnectionFailed(
+                        "Broker sent another packet before CONNACK".to_string(),
+                    ))
+                }
+                None => read_more(&mut stream, &mut buffer).map_err(|err| {
+                    RelayError::ConnectionFailed(format!("MQTT connect failed: {}", err))
+                })?,
+            }
+        };
+
+        if connack.code != 0 {
+            let reason = connack
+                .properties
+                .string(PROPERTY_REASON_STRING)
+                .unwrap_or_else(|| connect_refusal(options.version, connack.code).to_string());
+            log::error!(
+                "MQTT broker {} refused connection: {}",
+                req.endpoint,
+                reason
+            );
+            return Err(RelayError::ConnectionFailed(format!(
+                "Broker refused connection ({}): {}",
+                connack.code, reason
+            )));
+        }
+        set_read_timeout(&stream, POLL_INTERVAL)?;
+        log::info!("MQTT connected: {}", req.endpoint);
+
+        on_event(MqttEvent::Connected {
+            session_present: connack.session_present,
+            client_id: connack
+                .properties
+                .string(PROPERTY_ASSIGNED_CLIENT_ID)
+                .unwrap_or(client_id),
+        });
+
+        let (commands_tx, commands_rx) = mpsc::channel();
+        let session = Session {
+            stream,
+            buffer,
+            version: options.version,
+            keep_alive: Duration::from_secs(options.keep_alive_secs.into()),
+            endpoint: req.endpoint.clone(),
+        };
+        thread::Builder::new()
+            .name("relay-mqtt".to_string())
+            .spawn(move || session.run(commands_rx, on_event))
+            .map_err(|err| {
+                RelayError::RequestRunError(format!("Failed to start MQTT reader: {}", err))
+            })?;
+
+        Ok(Self {
+            version: options.version,
+            commands: commands_tx,
+            next_packet_id: AtomicU16::new(1),
+        })
+    }
+
+    /// Subscribes to `subscriptions` and returns the packet ID the matching
+    /// [`MqttEvent::Subscribed`] will carry.
+    pub fn subscribe(&self, subscriptions: &[MqttSubscription]) -> RelayResult<u16> {
+        if subscriptions.is_empty() {
+            return Err(RelayError::RequestRunError(
+                "Subscribe needs at least one topic".to_string(),
+            ));
+        }
+        let packet_id = self.packet_id();
+        self.command(ConnectionCommand::Send(encode_subscribe(
+            self.version,
+            packet_id,
+            subscriptions,
+        )))?;
+        Ok(packet_id)
+    }
+
+    pub fn unsubscribe(&self, topics: &[String]) -> RelayResult<u16> {
+        if topics.is_empty() {
+            return Err(RelayError::RequestRunError(
+                "Unsubscribe needs at least one topic".to_string(),
+            ));
+        }
+        let packet_id = self.packet_id();
+        self.command(ConnectionCommand::Send(encode_unsubscribe(
+            self.version,
+            packet_id,
+            topics,
+        )))?;
+        Ok(packet_id)
+    }
+
+    /// Publishes `message`. For QoS 1 and 2 returns the packet ID the matching
+    /// [`MqttEvent::Published`] will carry.
+    pub fn publish(&self, message: &MqttPublish) -> RelayResult<Option<u16>> {
+        let packet_id = (message.qos != MqttQoS::AtMostOnce).then(|| self.packet_id());
+        self.command(ConnectionCommand::Send(encode_publish(
+            self.version,
+            packet_id,
+            message,
+        )))?;
+        Ok(packet_id)
+    }
+
+    pub fn disconnect(&self) -> RelayResult<()> {
+        self.command(ConnectionCommand::Disconnect)
+    }
+
+    /// Packet IDs run from 1 to 65535, `0` is not allowed.
+    fn packet_id(&self) -> u16 {
+        loop {
+            let id = self.next_packet_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+
+    fn command(&self, command: ConnectionCommand) -> RelayResult<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| RelayError::RequestRunError("MQTT connection is closed".to_string()))
+    }
+}
+
+fn set_read_timeout(stream: &NetStream, timeout: Duration) -> RelayResult<()> {
+    stream
+        .tcp()
+        .set_read_timeout(Some(timeout))
+        .map_err(|err| RelayError::RequestRunError(format!("Failed to configure socket: {}", err)))
+}
+
+/// Reads whatever is available into `buffer`. End of stream is an error.
+fn read_more(stream: &mut NetStream, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+    let mut chunk = [0u8; 8192];
+    let read = stream.read(&mut chunk)?;
+    if read == 0 {
+        return Err(std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "Connection closed by broker",
+        ));
+    }
+    buffer.extend_from_slice(&chunk[..read]);
+    Ok(())
+}
+
+fn connect_refusal(version: MqttVersion, code: u8) -> &'static str {
+    match (version, code) {
+        (MqttVersion::V311, 1) => "unacceptable protocol version",
+        (MqttVersion::V311, 2) => "client identifier rejected",
+        (MqttVersion::V311, 3) => "server unavailable",
+        (MqttVersion::V311, 4) => "bad user name or password",
+        (MqttVersion::V311, 5) => "not authorized",
+        (MqttVersion::V5, 0x84) => "unsupported protocol version",
+        (MqttVersion::V5, 0x85) => "client identifier not valid",
+        (MqttVersion::V5, 0x86) => "bad user name or password",
+        (MqttVersion::V5, 0x87) => "not authorized",
+        (MqttVersion::V5, 0x88) => "server unavailable",
+        (MqttVersion::V5, 0x8A) => "banned",
+        _ => "connection refused",
+    }
+}
+
+/// State of an established connection, owned by its background thread.
+struct Session {
+    stream: NetStream,
+    buffer: Vec<u8>,
+    version: MqttVersion,
+    keep_alive: Duration,
+    endpoint: String,
+}
+
+impl Session {
+    fn run<F>(mut self, commands: mpsc::Receiver<ConnectionCommand>, mut on_event: F)
+    where
+        F: FnMut(MqttEvent),
+    {
+        let mut last_sent = Instant::now();
+        let mut ping_sent: Option<Instant> = None;
+        // QoS 2 messages already delivered, waiting for the broker's PUBREL.
+        let mut awaiting_release = HashSet::new();
+
+        let reason = 'connection: loop {
+            loop {
+                let packet = match commands.try_recv() {
+                    Ok(ConnectionCommand::Send(packet)) => packet,
+                    Ok(ConnectionCommand::Disconnect) | Err(mpsc::TryRecvError::Disconnected) => {
+                        let _ = self.stream.write_all(&[DISCONNECT << 4, 0]);
+                        break 'connection "Disconnected by client".to_string();
+                    }
+                    Err(mpsc::TryRecvError::Empty) => break,
+                };
+                if let Err(err) = self.stream.write_all(&packet) {
+                    on_event(MqttEvent::Error(err.to_string()));
+                    break 'connection format!("Write failed: {}", err);
+                }
+                last_sent = Instant::now();
+            }
+
+            if !self.keep_alive.is_zero() {
+                if let Some(sent) = ping_sent {
+                    if sent.elapsed() > self.keep_alive {
+                        break 'connection "Broker stopped answering pings".to_string();
+                    }
+                } else if last_sent.elapsed() >= self.keep_alive {
+                    if let Err(err) = self.stream.write_all(&[PINGREQ << 4, 0]) {
+                        break 'connection format!("Write failed: {}", err);
+                    }
+                    last_sent = Instant::now();
+                    ping_sent = Some(last_sent);
+                }
+            }
+
+            match read_more(&mut self.stream, &mut self.buffer) {
+                Ok(()) => {}
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue;
+                }
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                    break 'connection err.to_string();
+                }
+                Err(err) => {
+                    log::error!("MQTT connection {} failed: {}", self.endpoint, err);
+                    on_event(MqttEvent::Error(err.to_string()));
+                    break 'connection format!("Read failed: {}", err);
+                }
+            }
+
+            loop {
+                let packet = match decode_packet(&mut self.buffer, self.version) {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => break,
+                    Err(err) => {
+                        on_event(MqttEvent::Error(err.to_string()));
+                        break 'connection err.to_string();
+                    }
+                };
+
+                let reply = match packet {
+                    Packet::Publish(publish) => {
+                        let reply = match (publish.message.qos, publish.packet_id) {
+                            (MqttQoS::AtLeastOnce, Some(id)) => Some(encode_ack(PUBACK, id)),
+                            (MqttQoS::ExactlyOnce, Some(id)) => Some(encode_ack(PUBREC, id)),
+                            _ => None,
+                        };
+                        // A redelivered QoS 2 message was already handed over.
+                        let duplicate = publish.message.qos == MqttQoS::ExactlyOnce
+                            && publish
+                                .packet_id
+                                .is_some_and(|id| !awaiting_release.insert(id));
+                        if !duplicate {
+                            on_event(MqttEvent::Message(publish.message));
+                        }
+                        reply
+                    }
+                    Packet::PubRel(ack) => {
+                        awaiting_release.remove(&ack.packet_id);
+                        Some(encode_ack(PUBCOMP, ack.packet_id))
+                    }
+                    Packet::PubRec(ack) if ack.code < 0x80 => {
+                        Some(encode_ack(PUBREL, ack.packet_id))
+                    }
+                    Packet::PubAck(ack) | Packet::PubRec(ack) | Packet::PubComp(ack) => {
+                        on_event(MqttEvent::Published {
+                            packet_id: ack.packet_id,
+                            reason_code: ack.code,
+                        });
+                        None
+                    }
+                    Packet::SubAck { packet_id, codes } => {
+                        on_event(MqttEvent::Subscribed {
+                            packet_id,
+                            return_codes: codes,
+                        });
+                        None
+                    }
+                    Packet::UnsubAck { packet_id } => {
+                        on_event(MqttEvent::Unsubscribed { packet_id });
+                        None
+                    }
+                    Packet::PingResp => {
+                        ping_sent = None;
+                        None
+                    }
+                    Packet::Disconnect { code, properties } => {
+                        let reason = properties
+                            .string(PROPERTY_REASON_STRING)
+                            .unwrap_or_else(|| "Disconnected by broker".to_string());
+                        break 'connection format!("{} ({})", reason, code);
+                    }
+                    Packet::ConnAck(_) | Packet::Other => None,
+                };
+
+                if let Some(reply) = reply {
+                    if let Err(err) = self.stream.write_all(&reply) {
+                        on_event(MqttEvent::Error(err.to_string()));
+                        break 'connection format!("Write failed: {}", err);
+                    }
+                    last_sent = Instant::now();
+                }
+            }
+        };
+
+        log::info!("MQTT connection {} closed: {}", self.endpoint, reason);
+        on_event(MqttEvent::Disconnected { reason });
+    }
+}
+
+#[derive(Debug, Default)]
+struct Properties(Vec<(u8, PropertyValue)>);
+
+/// Property values the client looks at. Others are skipped while reading.
+#[derive(Debug)]
+enum PropertyValue {
+    String(String),
+    Pair(String, String),
+}
+
+impl Properties {
+    fn string(&self, id: u8) -> Option<String> {
+        self.0.iter().find_map(|(key, value)| match value {
+            PropertyValue::String(value) if *key == id => Some(value.clone()),
+            _ => None,
+        })
+    }
+
+    fn user_properties(&self) -> Vec<KeyValuePair> {
+        self.0
+            .iter()
+            .filter_map(|(_, value)| match value {
+                PropertyValue::Pair(key, value) => Some(KeyValuePair {
+                    key: key.clone(),
+                    value: value.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct ConnAck {
+    session_present: bool,
+    code: u8,
+    properties: Properties,
+}
+
+#[derive(Debug)]
+struct Publish {
+    packet_id: Option<u16>,
+    message: MqttMessage,
+}
+
+#[derive(Debug)]
+struct Ack {
+    packet_id: u16,
+    code: u8,
+}
+
+#[derive(Debug)]
+enum Packet {
+    ConnAck(ConnAck),
+    Publish(Publish),
+    PubAck(Ack),
+    PubRec(Ack),
+    PubRel(Ack),
+    PubComp(Ack),
+    SubAck {
+        packet_id: u16,
+        codes: Vec<u8>,
+    },
+    UnsubAck {
+        packet_id: u16,
+    },
+    PingResp,
+    Disconnect {
+        code: u8,
+        properties: Properties,
+    },
+    /// Packets a client doesn't expect, such as `AUTH`.
+    Other,
+}
+
+/// Takes the next complete packet off the front of `buffer`, if there is one.
+fn decode_packet(buffer: &mut Vec<u8>, version: MqttVersion) -> RelayResult<Option<Packet>> {
+    let Some(&first) = buffer.first() else {
+        return Ok(None);
+    };
+
+    let mut remaining = 0usize;
+    let mut header_len = 1;
+    loop {
+        let Some(&byte) = buffer.get(header_len) else {
+            return Ok(None);
+        };
+        remaining |= ((byte & 0x7F) as usize) << (7 * (header_len - 1));
+        header_len += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if header_len > 4 {
+            return Err(protocol_error("Malformed remaining length"));
+        }
+    }
+    if buffer.len() < header_len + remaining {
+        return Ok(None);
+    }
+
+    let body: Vec<u8> = buffer
+        .drain(..header_len + remaining)
+        .skip(header_len)
+        .collect();
+    let mut reader = PacketReader {
+        data: &body,
+        pos: 0,
+        v5: version == MqttVersion::V5,
+    };
+    let flags = first & 0x0F;
+
+    let packet = match first >> 4 {
+        CONNACK => {
+            let session_present = reader.u8()? & 0x01 == 1;
+            let code = reader.u8()?;
+            Packet::ConnAck(ConnAck {
+                session_present,
+                code,
+                properties: reader.properties()?,
+            })
+        }
+        PUBLISH => {
+            let qos = MqttQoS::from_bits((flags >> 1) & 0x03)
+                .ok_or_else(|| protocol_error("Invalid QoS"))?;
+            let topic = reader.string()?;
+            let packet_id = match qos {
+                MqttQoS::AtMostOnce => None,
+                _ => Some(reader.u16()?),
+            };
+            let properties = reader.properties()?;
+            Packet::Publish(Publish {
+                packet_id,
+                message: MqttMessage {
+                    topic,
+                    payload: reader.rest().to_vec(),
+                    qos,
+                    retain: flags & 0x01 == 1,
+                    user_properties: properties.user_properties(),
+                    content_type: properties.string(PROPERTY_CONTENT_TYPE),
+                },
+            })
+        }
+        kind @ (PUBACK | PUBREC | PUBREL | PUBCOMP) => {
+            let packet_id = reader.u16()?;
+            // MQTT 5 leaves out the reason code when it is 0.
+            let code = if reader.is_empty() { 0 } else { reader.u8()? };
+            let ack = Ack { packet_id, code };
+            match kind {
+                PUBACK => Packet::PubAck(ack),
+                PUBREC => Packet::PubRec(ack),
+                PUBREL => Packet::PubRel(ack),
+                _ => Packet::PubComp(ack),
+            }
+        }
+        SUBACK => {
+            let packet_id = reader.u16()?;
+            reader.properties()?;
+            Packet::SubAck {
+                packet_id,
+                codes: reader.rest().to_vec(),
+            }
+        }
+        UNSUBACK => Packet::UnsubAck {
+            packet_id: reader.u16()?,
+        },
+        PINGRESP => Packet::PingResp,
+        DISCONNECT => {
+            let code = if reader.is_empty() { 0 } else { reader.u8()? };
+            let properties = if reader.is_empty() {
+                Properties::default()
+            } else {
+                reader.properties()?
+            };
+            Packet::Disconnect { code, properties }
+        }
+        kind => {
+            log::debug!("Ignoring MQTT packet of type {}", kind);
+            Packet::Other
+        }
+    };
+
+    Ok(Some(packet))
+}
+
+fn protocol_error(message: &str) -> RelayError {
+    RelayError::RequestRunError(format!("MQTT protocol error: {}", message))
+}
+
+struct PacketReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    v5: bool,
+}
+
+impl<'a> PacketReader<'a> {
+    fn take(&mut self, len: usize) -> RelayResult<&'a [u8]> {
+        let end = self.pos + len;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or_else(|| protocol_error("Packet is shorter than its contents"))?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos.min(self.data.len())..];
+        self.pos = self.data.len();
+        rest
+    }
+
+    fn u8(&mut self) -> RelayResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> RelayResult<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn varint(&mut self) -> RelayResult<u32> {
+        let mut value = 0u32;
+        for shift in 0..4 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as u32) << (7 * shift);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(protocol_error("Malformed variable byte integer"))
+    }
+
+    fn binary(&mut self) -> RelayResult<Vec<u8>> {
+        let len = self.u16()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> RelayResult<String> {
+        String::from_utf8(self.binary()?).map_err(|_| protocol_error("Invalid UTF-8 string"))
+    }
+
+    /// Reads an MQTT 5 property list. MQTT 3.1.1 packets have none.
+    fn properties(&mut self) -> RelayResult<Properties> {
+        if !self.v5 {
+            return Ok(Properties::default());
+        }
+
+        let len = self.varint()? as usize;
+        let mut reader = PacketReader {
+            data: self.take(len)?,
+            pos: 0,
+            v5: true,
+        };
+
+        let mut properties = Vec::new();
+        while !reader.is_empty() {
+            let id = reader.u8()?;
+            let value = match id {
+                0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2A => {
+                    reader.take(1)?;
+                    continue;
+                }
+                0x13 | 0x21 | 0x22 | 0x23 => {
+                    reader.take(2)?;
+                    continue;
+                }
+                0x02 | 0x11 | 0x18 | 0x27 => {
+                    reader.take(4)?;
+                    continue;
+                }
+                0x0B => {
+                    reader.varint()?;
+                    continue;
+                }
+                0x09 | 0x16 => {
+                    reader.binary()?;
+                    continue;
+                }
+                0x03 | 0x08 | 0x12 | 0x15 | 0x1A | 0x1C | 0x1F => {
+                    PropertyValue::String(reader.string()?)
+                }
+                PROPERTY_USER => PropertyValue::Pair(reader.string()?, reader.string()?),
+                _ => return Err(protocol_error(&format!("Unknown property {:#04x}", id))),
+            };
+            properties.push((id, value));
+        }
+
+        Ok(Properties(properties))
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value % 128) as u8;
+        value /= 128;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn put_binary(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+fn put_properties(out: &mut Vec<u8>, version: MqttVersion, properties: &[u8]) {
+    if version == MqttVersion::V5 {
+        put_varint(out, properties.len());
+        out.extend_from_slice(properties);
+    }
+}
+
+/// Prefixes `body` with the fixed header.
+fn finish_packet(first: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![first];
+    put_varint(&mut packet, body.len());
+    packet.extend(body);
+    packet
+}
+
+fn encode_connect(options: &MqttOptions, client_id: &str) -> Vec<u8> {
+    let mut flags = 0u8;
+    if options.clean_start {
+        flags |= 0x02;
+    }
+    if options.username.is_some() {
+        flags |= 0x80;
+    }
+    if options.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let mut body = Vec::new();
+    put_binary(&mut body, b"MQTT");
+    body.push(options.version.level());
+    body.push(flags);
+    body.extend_from_slice(&options.keep_alive_secs.to_be_bytes());
+    put_properties(&mut body, options.version, &[]);
+    put_binary(&mut body, client_id.as_bytes());
+    if let Some(username) = &options.username {
+        put_binary(&mut body, username.as_bytes());
+    }
+    if let Some(password) = &options.password {
+        put_binary(&mut body, password.as_bytes());
+    }
+
+    finish_packet(CONNECT << 4, body)
+}
+
+fn encode_publish(version: MqttVersion, packet_id: Option<u16>, message: &MqttPublish) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_binary(&mut body, message.topic.as_bytes());
+    if let Some(packet_id) = packet_id {
+        body.extend_from_slice(&packet_id.to_be_bytes());
+    }
+
+    let mut properties = Vec::new();
+    if let Some(content_type) = &message.content_type {
+        properties.push(PROPERTY_CONTENT_TYPE);
+        put_binary(&mut properties, content_type.as_bytes());
+    }
+    for KeyValuePair { key, value } in &message.user_properties {
+        properties.push(PROPERTY_USER);
+        put_binary(&mut properties, key.as_bytes());
+        put_binary(&mut properties, value.as_bytes());
+    }
+    put_properties(&mut body, version, &properties);
+    body.extend_from_slice(&message.payload);
+
+    let first = PUBLISH << 4 | message.qos.bits() << 1 | u8::from(message.retain);
+    finish_packet(first, body)
+}
+
+fn encode_subscribe(
+    version: MqttVersion,
+    packet_id: u16,
+    subscriptions: &[MqttSubscription],
+) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    put_properties(&mut body, version, &[]);
+    for subscription in subscriptions {
+        put_binary(&mut body, subscription.topic.as_bytes());
+        body.push(subscription.qos.bits());
+    }
+    finish_packet(SUBSCRIBE << 4 | 0x02, body)
+}
+
+fn encode_unsubscribe(version: MqttVersion, packet_id: u16, topics: &[String]) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    put_properties(&mut body, version, &[]);
+    for topic in topics {
+        put_binary(&mut body, topic.as_bytes());
+    }
+    finish_packet(UNSUBSCRIBE << 4 | 0x02, body)
+}
+
+/// Encodes a success acknowledgement, which is the same on both versions.
+fn encode_ack(kind: u8, packet_id: u16) -> Vec<u8> {
+    let flags = if kind == PUBREL { 0x02 } else { 0 };
+    finish_packet(kind << 4 | flags, packet_id.to_be_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_v5_publish_with_user_properties() {
+        let mut buffer = encode_publish(
+            MqttVersion::V5,
+            Some(7),
+            &MqttPublish {
+                topic: "sensors/1".to_string(),
+                payload: b"21.5".to_vec(),
+                qos: MqttQoS::ExactlyOnce,
+                retain: true,
+                user_properties: vec![KeyValuePair {
+                    key: "unit".to_string(),
+                    value: "celsius".to_string(),
+                }],
+                content_type: Some("text/plain".to_string()),
+            },
+        );
+        buffer.extend(encode_ack(PUBREL, 7));
+
+        // Packets may arrive in pieces.
+        let mut partial = buffer.split_off(10);
+        assert!(decode_packet(&mut buffer, MqttVersion::V5)
+            .unwrap()
+            .is_none());
+        buffer.append(&mut partial);
+
+        let Some(Packet::Publish(publish)) = decode_packet(&mut buffer, MqttVersion::V5).unwrap()
+        else {
+            panic!("expected a publish");
+        };
+        assert_eq!(publish.packet_id, Some(7));
+        assert_eq!(publish.message.topic, "sensors/1");
+        assert_eq!(publish.message.payload, b"21.5");
+        assert_eq!(publish.message.qos, MqttQoS::ExactlyOnce);
+        assert!(publish.message.retain);
+        assert_eq!(publish.message.user_properties[0].value, "celsius");
+        assert_eq!(publish.message.content_type.as_deref(), Some("text/plain"));
+
+        assert!(matches!(
+            decode_packet(&mut buffer, MqttVersion::V5).unwrap(),
+            Some(Packet::PubRel(Ack {
+                packet_id: 7,
+                code: 0
+            }))
+        ));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn encodes_v311_connect() {
+        let options = MqttOptions {
+            keep_alive_secs: 30,
+            username: Some("user".to_string()),
+            password: Some("secret".to_string()),
+            ..MqttOptions::default()
+        };
+
+        let packet = encode_connect(&options, "client");
+
+        let mut expected = vec![CONNECT << 4, 32, 0, 4];
+        expected.extend_from_slice(b"MQTT");
+        expected.extend_from_slice(&[4, 0xC2, 0, 30, 0, 6]);
+        expected.extend_from_slice(b"client");
+        expected.extend_from_slice(&[0, 4]);
+        expected.extend_from_slice(b"user");
+        expected.extend_from_slice(&[0, 6]);
+        expected.extend_from_slice(b"secret");
+        assert_eq!(packet, expected);
+    }
+}
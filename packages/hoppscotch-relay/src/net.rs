@@ -0,0 +1,653 @@
+//! Plain socket transport for the subsystems that don't go through curl.
+//!
+//! Builds TCP connections that honour the same host overrides, proxy, root
+//! certificate, client certificate, connect timeout and TLS version settings
+//! as `RequestWithMetadata`, so protocols such as WebSocket behave like the
+//! HTTP relay on the same network. Proxies are limited to `http`, `socks5`
+//! and `socks5h`, an `https` proxy is rejected. `timeout_ms` is left to the
+//! protocol, these connections stay open for as long as the caller wants.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use openssl::{
+    pkcs12::Pkcs12,
+    pkey::PKey,
+    ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode, SslVersion},
+    x509::X509,
+};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use crate::{
+    error::{RelayError, RelayResult},
+    interop::{ClientCertDef, ProxyConfig, RequestWithMetadata, TlsVersion},
+    relay::get_x509_certs_from_root_cert_bundle_safe,
+};
+
+/// Used when the request sets no `connect_timeout_ms`.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A TCP connection, optionally wrapped in TLS.
+#[derive(Debug)]
+pub(crate) enum NetStream {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>),
+}
+
+impl NetStream {
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            NetStream::Plain(stream) => stream,
+            NetStream::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for NetStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            NetStream::Plain(stream) => stream.read(buf),
+            NetStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for NetStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            NetStream::Plain(stream) => stream.write(buf),
+            NetStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            NetStream::Plain(stream) => stream.flush(),
+            NetStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Opens a connection to `host:port`, tunnelling through the request's proxy
+/// when one is set and negotiating TLS when `tls` is true.
+pub(crate) fn connect(
+    host: &str,
+    port: u16,
+    tls: bool,
+    req: &RequestWithMetadata,
+) -> RelayResult<NetStream> {
+    let host = unbracket(host);
+    log::info!(
+        "Opening connection: [Host: {}:{}] [TLS: {}] [Validate Certs: {}] [Proxy Enabled: {}]",
+        host,
+        port,
+        tls,
+        req.validate_certs,
+        req.proxy.is_some()
+    );
+
+    let connect_timeout = req
+        .connect_timeout_ms
+        .map_or(CONNECT_TIMEOUT, Duration::from_millis);
+    let tcp = match &req.proxy {
+        Some(proxy) => connect_via_proxy(proxy, host, port, connect_timeout, &req.resolve)?,
+        None => connect_tcp(host, port, connect_timeout, &req.resolve)?,
+    };
+
+    if !tls {
+        return Ok(NetStream::Plain(tcp));
+    }
+
+    let connector = build_ssl_connector(req)?;
+    let stream = connector
+        .configure()
+        .map_err(|err| RelayError::RequestRunError(format!("Failed to configure TLS: {}", err)))?
+        .verify_hostname(req.validate_certs)
+        .connect(host, tcp)
+        .map_err(|err| {
+            log::error!("TLS handshake with {} failed: {}", host, err);
+            RelayError::ConnectionFailed(format!("TLS handshake failed: {}", err))
+        })?;
+    log::debug!("TLS session established with {}", host);
+
+    Ok(NetStream::Tls(Box::new(stream)))
+}
+
+/// `http::Uri::host` keeps the brackets around IPv6 literals, which neither
+/// the resolver nor TLS accept.
+fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// The addresses `host:port` resolves to, taking the request's `resolve`
+/// overrides into account.
+fn resolve_addrs(host: &str, port: u16, resolve: &[String]) -> RelayResult<Vec<SocketAddr>> {
+    use std::net::ToSocketAddrs;
+
+    if let Some(addrs) = pinned_addrs(host, port, resolve)? {
+        log::debug!("Using resolve override for {}:{}: {:?}", host, port, addrs);
+        return Ok(addrs);
+    }
+    Ok((host, port)
+        .to_socket_addrs()
+        .map_err(|err| {
+            RelayError::ConnectionFailed(format!("Could not resolve host {}: {}", host, err))
+        })?
+        .collect())
+}
+
+/// Addresses of the first `resolve` entry, in curl's `host:port:address[,address]`
+/// format, matching `host:port`. A `*` host matches every host.
+fn pinned_addrs(host: &str, port: u16, resolve: &[String]) -> RelayResult<Option<Vec<SocketAddr>>> {
+    for entry in resolve {
+        let invalid = || RelayError::RequestRunError(format!("Invalid resolve entry '{}'", entry));
+        let pin = entry.strip_prefix('+').unwrap_or(entry);
+        // `-host:port` removes an entry from curl's cache, which this has none of.
+        if pin.starts_with('-') {
+            continue;
+        }
+        let (pin_host, rest) = match pin.strip_prefix('[') {
+            Some(pin) => pin.split_once("]:").ok_or_else(invalid)?,
+            None => pin.split_once(':').ok_or_else(invalid)?,
+        };
+        let (pin_port, addresses) = rest.split_once(':').ok_or_else(invalid)?;
+        if (pin_host != "*" && !pin_host.eq_ignore_ascii_case(host))
+            || pin_port.parse::<u16>().map_err(|_| invalid())? != port
+        {
+            continue;
+        }
+
+        return addresses
+            .split(',')
+            .map(|address| {
+                unbracket(address.trim())
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .map_err(|_| invalid())
+            })
+            .collect::<RelayResult<_>>()
+            .map(Some);
+    }
+    Ok(None)
+}
+
+fn connect_tcp(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    resolve: &[String],
+) -> RelayResult<TcpStream> {
+    let addrs = resolve_addrs(host, port, resolve)?;
+
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                let _ = stream.set_nodelay(true);
+                log::debug!("Connected to {}", addr);
+                return Ok(stream);
+            }
+            Err(err) => {
+                log::debug!("Connection attempt to {} failed: {}", addr, err);
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(RelayError::ConnectionFailed(match last_err {
+        Some(err) => format!("Could not connect to {}:{}: {}", host, port, err),
+        None => format!("No addresses found for {}", host),
+    }))
+}
+
+/// Opens a tunnel to `host:port` through `proxy`, with an HTTP `CONNECT` or
+/// a SOCKS5 `CONNECT`. `socks5h` leaves resolving `host` to the proxy.
+fn connect_via_proxy(
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    resolve: &[String],
+) -> RelayResult<TcpStream> {
+    let proxy_url = proxy.url.parse::<http::Uri>().map_err(|err| {
+        RelayError::RequestRunError(format!("Invalid proxy URL '{}': {}", proxy.url, err))
+    })?;
+
+    let (default_port, socks) = match proxy_url.scheme_str() {
+        Some("http") | None => (80, None),
+        Some("socks5") => (1080, Some(false)),
+        Some("socks5h") => (1080, Some(true)),
+        Some(scheme) => {
+            return Err(RelayError::RequestRunError(format!(
+                "Proxy scheme '{}' is not supported for WebSocket and MQTT connections, \
+                 use an http, socks5 or socks5h proxy",
+                scheme
+            )))
+        }
+    };
+
+    let authority = proxy_url.authority().ok_or_else(|| {
+        RelayError::RequestRunError(format!("Proxy URL '{}' has no host", proxy.url))
+    })?;
+    let credentials = authority
+        .as_str()
+        .rsplit_once('@')
+        .map(|(userinfo, _)| url_escape::decode(userinfo).into_owned());
+
+    let mut stream = connect_tcp(
+        unbracket(authority.host()),
+        authority.port_u16().unwrap_or(default_port),
+        timeout,
+        resolve,
+    )?;
+    log::debug!("Connected to proxy {}", authority.host());
+
+    if let Some(remote_dns) = socks {
+        let target = match host.parse::<IpAddr>() {
+            Ok(ip) => SocksTarget::Ip(ip),
+            Err(_) if remote_dns => SocksTarget::Domain(host),
+            Err(_) => SocksTarget::Ip(
+                resolve_addrs(host, port, resolve)?
+                    .first()
+                    .ok_or_else(|| {
+                        RelayError::ConnectionFailed(format!("No addresses found for {}", host))
+                    })?
+                    .ip(),
+            ),
+        };
+        socks5_connect(&mut stream, credentials.as_deref(), target, port)?;
+        log::debug!("SOCKS5 tunnel established to {}:{}", host, port);
+        return Ok(stream);
+    }
+
+    let target = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let mut connect_request = format!(
+        "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n",
+        target = target
+    );
+    if let Some(credentials) = credentials {
+        connect_request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            BASE64.encode(credentials)
+        ));
+    }
+    connect_request.push_str("\r\n");
+
+    stream
+        .write_all(connect_request.as_bytes())
+        .map_err(|err| RelayError::ConnectionFailed(format!("Proxy write failed: {}", err)))?;
+
+    // Read the proxy's answer byte by byte so nothing past the header block
+    // is consumed from the tunnelled stream.
+    let mut reader = BufReader::with_capacity(1, &stream);
+    let mut status_line = String::new();
+    reader
+        .read_line(&mut status_line)
+        .map_err(|err| RelayError::ConnectionFailed(format!("Proxy read failed: {}", err)))?;
+
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .map_err(|err| RelayError::ConnectionFailed(format!("Proxy read failed: {}", err)))?;
+        if read == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        log::error!("Proxy refused tunnel: {}", status_line.trim());
+        return Err(RelayError::ConnectionFailed(format!(
+            "Proxy refused CONNECT: {}",
+            status_line.trim()
+        )));
+    }
+    log::debug!("Proxy tunnel established to {}:{}", host, port);
+
+    Ok(stream)
+}
+
+enum SocksTarget<'a> {
+    Ip(IpAddr),
+    Domain(&'a str),
+}
+
+/// Asks a SOCKS5 proxy to connect to `target:port` (RFC 1928), logging in
+/// with `user:password` credentials when given (RFC 1929).
+fn socks5_connect(
+    stream: &mut TcpStream,
+    credentials: Option<&str>,
+    target: SocksTarget,
+    port: u16,
+) -> RelayResult<()> {
+    let io_error =
+        |err: io::Error| RelayError::ConnectionFailed(format!("SOCKS proxy failed: {}", err));
+    let refused = |reason: &str| RelayError::ConnectionFailed(format!("SOCKS proxy {}", reason));
+
+    let method = if credentials.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[5, 1, method]).map_err(io_error)?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).map_err(io_error)?;
+    if reply != [5, method] {
+        return Err(refused(
+            "accepts none of the offered authentication methods",
+        ));
+    }
+
+    if let Some(credentials) = credentials {
+        let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+        if user.len() > 255 || password.len() > 255 {
+            return Err(RelayError::RequestRunError(
+                "SOCKS proxy credentials are limited to 255 bytes each".to_string(),
+            ));
+        }
+        let mut login = vec![1, user.len() as u8];
+        login.extend_from_slice(user.as_bytes());
+        login.push(password.len() as u8);
+        login.extend_from_slice(password.as_bytes());
+        stream.write_all(&login).map_err(io_error)?;
+        stream.read_exact(&mut reply).map_err(io_error)?;
+        if reply[1] != 0 {
+            return Err(refused("rejected the credentials"));
+        }
+    }
+
+    let mut request = vec![5, 1, 0];
+    match target {
+        SocksTarget::Ip(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        SocksTarget::Ip(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        SocksTarget::Domain(domain) => {
+            if domain.len() > 255 {
+                return Err(RelayError::InvalidUrl);
+            }
+            request.extend_from_slice(&[3, domain.len() as u8]);
+            request.extend_from_slice(domain.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).map_err(io_error)?;
+
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).map_err(io_error)?;
+    if header[1] != 0 {
+        return Err(refused(match header[1] {
+            2 => "refused the connection by its rules",
+            3 => "reports the network unreachable",
+            4 => "reports the host unreachable",
+            5 => "was refused the connection by the host",
+            6 => "timed out connecting to the host",
+            _ => "failed to connect to the host",
+        }));
+    }
+    // The address the proxy bound, which isn't needed.
+    let bound_len = match header[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len).map_err(io_error)?;
+            len[0] as usize
+        }
+        _ => return Err(refused("sent an invalid reply")),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound).map_err(io_error)?;
+    Ok(())
+}
+
+/// Builds an OpenSSL connector from the request's certificate settings.
+pub(crate) fn build_ssl_connector(req: &RequestWithMetadata) -> RelayResult<SslConnector> {
+    let tls_error = |context: &str, err: openssl::error::ErrorStack| {
+        RelayError::RequestRunError(format!("{}: {}", context, err))
+    };
+
+    let mut builder = SslConnector::builder(SslMethod::tls_client())
+        .map_err(|err| tls_error("Failed to create TLS context", err))?;
+
+    if req.validate_certs {
+        // The vendored OpenSSL doesn't know where the platform keeps its CA
+        // bundle, so point it at whatever `openssl-probe` finds, like curl does.
+        if let Some(cert_file) = openssl_probe::probe().cert_file {
+            if let Err(err) = builder.set_ca_file(&cert_file) {
+                log::warn!(
+                    "Failed to load system CA certificates from {:?}: {}",
+                    cert_file,
+                    err
+                );
+            }
+        }
+    } else {
+        builder.set_verify(SslVerifyMode::NONE);
+    }
+
+    if let Some(version) = req.tls_min_version {
+        builder
+            .set_min_proto_version(Some(match version {
+                TlsVersion::Tls1_0 => SslVersion::TLS1,
+                TlsVersion::Tls1_1 => SslVersion::TLS1_1,
+                TlsVersion::Tls1_2 => SslVersion::TLS1_2,
+                TlsVersion::Tls1_3 => SslVersion::TLS1_3,
+            }))
+            .map_err(|err| tls_error("Failed to set the minimum TLS version", err))?;
+    }
+
+    let root_certs = get_x509_certs_from_root_cert_bundle_safe(req)
+        .map_err(|err| tls_error("Failed to load root certificates", err))?;
+    for cert in root_certs {
+        if let Err(err) = builder.cert_store_mut().add_cert(cert) {
+            log::warn!("Failed to add root certificate to store: {}", err);
+        }
+    }
+
+    match &req.client_cert {
+        Some(ClientCertDef::PEMCert {
+            certificate_pem,
+            key_pem,
+        }) => {
+            let cert = X509::from_pem(certificate_pem)
+                .map_err(|err| tls_error("Failed to parse PEM certificate", err))?;
+            let key = PKey::private_key_from_pem(key_pem)
+                .map_err(|err| tls_error("Failed to parse PEM key", err))?;
+            builder
+                .set_certificate(&cert)
+                .map_err(|err| tls_error("Failed to set client certificate", err))?;
+            builder
+                .set_private_key(&key)
+                .map_err(|err| tls_error("Failed to set client key", err))?;
+        }
+        Some(ClientCertDef::PFXCert {
+            certificate_pfx,
+            password,
+        }) => {
+            let parsed = Pkcs12::from_der(certificate_pfx)
+                .and_then(|pkcs12| pkcs12.parse2(password))
+                .map_err(|err| tls_error("Failed to parse PFX certificate", err))?;
+            let (Some(cert), Some(key)) = (parsed.cert, parsed.pkey) else {
+                return Err(RelayError::RequestRunError(
+                    "PFX certificate parsing succeeded, but either cert or private key is missing"
+                        .to_string(),
+                ));
+            };
+            builder
+                .set_certificate(&cert)
+                .map_err(|err| tls_error("Failed to set client certificate", err))?;
+            builder
+                .set_private_key(&key)
+                .map_err(|err| tls_error("Failed to set client key", err))?;
+        }
+        None => {}
+    }
+
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread::JoinHandle};
+
+    use super::*;
+
+    fn request(proxy: Option<String>, resolve: Vec<String>) -> RequestWithMetadata {
+        let mut req = RequestWithMetadata::new(
+            0,
+            "GET".to_string(),
+            String::new(),
+            Vec::new(),
+            None,
+            true,
+            Vec::new(),
+            None,
+            proxy.map(|url| ProxyConfig { url }),
+        );
+        req.resolve = resolve;
+        req
+    }
+
+    /// Echoes one line back on the first accepted connection.
+    fn spawn_echo() -> (u16, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut buf = [0; 4];
+            socket.read_exact(&mut buf).unwrap();
+            socket.write_all(&buf).unwrap();
+        });
+        (port, server)
+    }
+
+    fn ping(stream: &mut NetStream) -> [u8; 4] {
+        stream.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn applies_resolve_overrides() {
+        let pins = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let v4 = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let v6 = "::1".parse::<IpAddr>().unwrap();
+
+        assert_eq!(
+            pinned_addrs(
+                "API.test",
+                443,
+                &pins(&["api.test:80:10.0.0.1", "+api.test:443:127.0.0.1,[::1]"])
+            )
+            .unwrap(),
+            Some(vec![SocketAddr::new(v4, 443), SocketAddr::new(v6, 443)])
+        );
+        assert_eq!(
+            pinned_addrs(
+                "::1",
+                8080,
+                &pins(&["-other.test:80", "[::1]:8080:127.0.0.1"])
+            )
+            .unwrap(),
+            Some(vec![SocketAddr::new(v4, 8080)])
+        );
+        assert_eq!(
+            pinned_addrs("any.test", 1, &pins(&["*:1:127.0.0.1"])).unwrap(),
+            Some(vec![SocketAddr::new(v4, 1)])
+        );
+        assert!(pinned_addrs("api.test", 443, &pins(&["api.test:443:nowhere"])).is_err());
+
+        let (port, server) = spawn_echo();
+        let req = request(None, vec![format!("pinned.test:{}:127.0.0.1", port)]);
+        let mut stream = connect("pinned.test", port, false, &req).unwrap();
+        assert_eq!(&ping(&mut stream), b"ping");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn tunnels_through_socks5_proxies() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        // Checks the login, records the requested target and echoes a ping
+        // as if it came from there.
+        let proxy = std::thread::spawn(move || {
+            let mut targets = Vec::new();
+            for _ in 0..2 {
+                let (mut client, _) = listener.accept().unwrap();
+                let mut greeting = [0; 3];
+                client.read_exact(&mut greeting).unwrap();
+                assert_eq!(greeting, [5, 1, 2]);
+                client.write_all(&[5, 2]).unwrap();
+                let mut login = [0; 14];
+                client.read_exact(&mut login).unwrap();
+                assert_eq!(&login, b"\x01\x04user\x07p@ss:wd");
+                client.write_all(&[1, 0]).unwrap();
+
+                let mut header = [0; 4];
+                client.read_exact(&mut header).unwrap();
+                let address = match header[3] {
+                    1 => {
+                        let mut ip = [0; 4];
+                        client.read_exact(&mut ip).unwrap();
+                        IpAddr::from(ip).to_string()
+                    }
+                    3 => {
+                        let mut len = [0; 1];
+                        client.read_exact(&mut len).unwrap();
+                        let mut domain = vec![0; len[0] as usize];
+                        client.read_exact(&mut domain).unwrap();
+                        String::from_utf8(domain).unwrap()
+                    }
+                    other => panic!("address type {}", other),
+                };
+                let mut port = [0; 2];
+                client.read_exact(&mut port).unwrap();
+                targets.push(format!("{}:{}", address, u16::from_be_bytes(port)));
+                client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+
+                let mut buf = [0; 4];
+                client.read_exact(&mut buf).unwrap();
+                client.write_all(&buf).unwrap();
+            }
+            targets
+        });
+
+        let url = |scheme: &str| format!("{}://user:p%40ss:wd@127.0.0.1:{}", scheme, proxy_port);
+        let req = request(Some(url("socks5h")), Vec::new());
+        let mut stream = connect("service.test", 443, false, &req).unwrap();
+        assert_eq!(&ping(&mut stream), b"ping");
+
+        // `socks5` resolves the host itself, here through an override.
+        let req = request(
+            Some(url("socks5")),
+            vec!["service.test:443:127.0.0.1".to_string()],
+        );
+        let mut stream = connect("service.test", 443, false, &req).unwrap();
+        assert_eq!(&ping(&mut stream), b"ping");
+
+        assert_eq!(proxy.join().unwrap(), ["service.test:443", "127.0.0.1:443"]);
+    }
+
+    #[test]
+    fn rejects_https_proxies() {
+        let req = request(Some("https://proxy.test:3129".to_string()), Vec::new());
+        let err = connect("service.test", 443, false, &req).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Proxy scheme 'https' is not supported"),
+            "{}",
+            err
+        );
+    }
+}
@@ -1,22 +1,371 @@
-use curl::easy::{Easy, List};
+use curl::easy::{Easy2, Handler, InfoType, List, SslVersion, WriteError};
 use openssl::{pkcs12::Pkcs12, ssl::SslContextBuilder, x509::X509};
 use openssl_sys::SSL_CTX;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
+    assertions::attach_report,
     error::RelayError,
     interop::{
-        BodyDef, ClientCertDef, FormDataValue, KeyValuePair, RequestWithMetadata,
-        ResponseWithMetadata,
+        BodyDef, ClientCertDef, FormDataValue, KeyValuePair, RequestWithMetadata, ResponseTimings,
+        ResponseWithMetadata, TlsVersion,
     },
+    retry::RetryTracker,
+    trace::TraceRecorder,
     util::get_status_text,
 };
 
+/// Collects the response of a single transfer and hooks the request specific
+/// TLS and cancellation behaviour into curl's callbacks.
+///
+/// Shared by the blocking `run_request_task` and the `Multi` driven engine so
+/// both configure and read back transfers the same way.
+pub(crate) struct RelayHandler {
+    root_certs: Vec<X509>,
+    cancel_token: CancellationToken,
+    response_status: Option<u16>,
+    response_http_version: Option<String>,
+    response_headers: Vec<KeyValuePair>,
+    response_body: Vec<u8>,
+    max_response_bytes: Option<u64>,
+    /// Set when the buffered body went over `max_response_bytes`.
+    response_too_large: bool,
+    /// Bytes transferred so far, as last reported to the progress callback.
+    pub(crate) downloaded: u64,
+    pub(crate) uploaded: u64,
+    /// When set, body chunks are handed here as they arrive instead of being buffered.
+    pub(crate) body_sink: Option<BodySink>,
+    trace: Option<TraceRecorder>,
+    sent_request_line: Option<String>,
+    sent_headers: Vec<KeyValuePair>,
+}
+
+/// Receives the status and headers seen so far along with each body chunk.
+/// Returning `false` aborts the transfer.
+pub(crate) type BodySink = Box<dyn FnMut(Option<u16>, &[KeyValuePair], &[u8]) -> bool + Send>;
+
+impl RelayHandler {
+    fn new(req: &RequestWithMetadata, cancel_token: CancellationToken) -> Self {
+        let root_certs = match get_x509_certs_from_root_cert_bundle_safe(req) {
+            Ok(certs) => {
+                log::debug!("Found {} certificates in root bundle", certs.len());
+                certs
+            }
+            Err(e) => {
+                log::error!("Failed to load certificates from bundle: {:?}", e);
+                Vec::new()
+            }
+        };
+
+        Self {
+            root_certs,
+            cancel_token,
+            response_status: None,
+            response_http_version: None,
+            response_headers: Vec::new(),
+            response_body: Vec::new(),
+            max_response_bytes: req.max_response_bytes,
+            response_too_large: false,
+            downloaded: 0,
+            uploaded: 0,
+            body_sink: None,
+            trace: req.trace.then(TraceRecorder::new),
+            sent_request_line: None,
+            sent_headers: Vec::new(),
+        }
+    }
+
+    /// Maps a failed transfer like [`transfer_error`], reporting a body over
+    /// `max_response_bytes` as such rather than as curl's write error.
+    pub(crate) fn transfer_error(&self, err: &curl::Error) -> RelayError {
+        match self.max_response_bytes {
+            Some(max) if err.is_filesize_exceeded() || self.response_too_large => {
+                RelayError::RequestRunError(format!(
+                    "Response body is over the limit of {} bytes",
+                    max
+                ))
+            }
+            _ => transfer_error(err),
+        }
+    }
+
+    /// Keeps the last request header block curl reports, which belongs to the
+    /// final request after any proxy `CONNECT` or redirects.
+    fn record_sent_headers(&mut self, data: &[u8]) {
+        for line in String::from_utf8_lossy(data).lines() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+
+            let is_request_line = line
+                .rsplit_once(' ')
+                .is_some_and(|(_, version)| version.starts_with("HTTP/"));
+            if is_request_line {
+                self.sent_request_line = Some(line.to_string());
+                self.sent_headers.clear();
+            } else if let Some((key, value)) = line.split_once(':') {
+                self.sent_headers.push(KeyValuePair {
+                    key: key.trim().to_string(),
+                    value: value.trim().to_string(),
+                });
+            }
+        }
+    }
+}
+
+impl Handler for RelayHandler {
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        let chunk_size = data.len();
+
+        if let Some(sink) = self.body_sink.as_mut() {
+            log::debug!("Streaming response chunk: {} bytes", chunk_size);
+            // Reporting fewer bytes than received makes curl abort the transfer.
+            return Ok(
+                if sink(self.response_status, &self.response_headers, data) {
+                    chunk_size
+                } else {
+                    0
+                },
+            );
+        }
+
+        if self
+            .max_response_bytes
+            .is_some_and(|max| (self.response_body.len() + chunk_size) as u64 > max)
+        {
+            log::warn!("Response body is over the limit, aborting the transfer");
+            self.response_too_large = true;
+            return Ok(0);
+        }
+
+        self.response_body.extend_from_slice(data);
+        log::debug!(
+            "Received response chunk: {} bytes (Total size so far: {} bytes)",
+            chunk_size,
+            self.response_body.len()
+        );
+        Ok(chunk_size)
+    }
+
+    fn header(&mut self, header: &[u8]) -> bool {
+        let header = String::from_utf8_lossy(header).into_owned();
+        if let Some((key, value)) = header.split_once(':') {
+            log::debug!("Received header: [{}] = [{}]", key.trim(), value.trim());
+            self.response_headers.push(KeyValuePair {
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+            });
+        } else {
+            log::debug!("Received header line (no key-value): {}", header.trim());
+            // Status lines ("HTTP/1.1 200 OK") start every response, including
+            // interim and redirect ones, so the last one seen wins.
+            if let Some(rest) = header.strip_prefix("HTTP/") {
+                let mut parts = rest.split_whitespace();
+                let version = parts.next();
+                if let Some(status) = parts.next().and_then(|code| code.parse().ok()) {
+                    self.response_status = Some(status);
+                    self.response_http_version = version.map(|version| format!("HTTP/{}", version));
+                }
+            }
+        }
+        true
+    }
+
+    fn debug(&mut self, kind: InfoType, data: &[u8]) {
+        if let InfoType::HeaderOut = kind {
+            self.record_sent_headers(data);
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(kind, data);
+        }
+    }
+
+    fn progress(&mut self, dltotal: f64, dlnow: f64, ultotal: f64, ulnow: f64) -> bool {
+        self.downloaded = dlnow as u64;
+        self.uploaded = ulnow as u64;
+        let cancelled = self.cancel_token.is_cancelled();
+        if cancelled {
+            log::warn!(
+                "Request cancelled by user\nDownload: {}/{} bytes\nUpload: {}/{} bytes",
+                dlnow,
+                dltotal,
+                ulnow,
+                ultotal
+            );
+        } else {
+            log::debug!(
+                "Progress - Download: {}/{} bytes, Upload: {}/{} bytes",
+                dlnow,
+                dltotal,
+                ulnow,
+                ultotal
+            );
+        }
+        !cancelled
+    }
+
+    fn ssl_ctx(&mut self, ssl_ctx_ptr: *mut std::ffi::c_void) -> Result<(), curl::Error> {
+        if self.root_certs.is_empty() {
+            return Ok(());
+        }
+
+        let mut ssl_ctx_builder =
+            unsafe { SslContextBuilder::from_ptr(ssl_ctx_ptr as *mut SSL_CTX) };
+
+        let cert_store = ssl_ctx_builder.cert_store_mut();
+
+        for (index, cert) in self.root_certs.iter().enumerate() {
+            log::debug!(
+                "Processing certificate {}: Subject: {:?}, Not Before: {:?}, Not After: {:?}",
+                index,
+                cert.subject_name(),
+                cert.not_before(),
+                cert.not_after()
+            );
+
+            if let Err(e) = cert_store.add_cert(cert.clone()) {
+                log::warn!(
+                    "Failed to add certificate {} to store\nError: {}\nCert details: {:?}",
+                    index,
+                    e,
+                    cert.subject_name()
+                );
+            } else {
+                log::debug!(
+                    "Successfully added certificate {} to store\nSubject: {:?}",
+                    index,
+                    cert.subject_name()
+                );
+            }
+        }
+
+        // SAFETY: We need to prevent Rust from dropping the `SslContextBuilder` because
+        // the underlying `SSL_CTX` pointer is owned and managed by curl, not us.
+        // From curl docs: "libcurl does not guarantee the lifetime of the passed in
+        // object once this callback function has returned"
+        // and `SslContextBuilder` is just a safe wrapper around curl's `SSL_CTX` from
+        // `openssl_sys::SSL_CTX`.
+        // If dropped, Rust would try to free the `SSL_CTX` which curl still needs.
+        //
+        // This intentional "leak" is safe because:
+        // - We're only leaking the thin Rust wrapper
+        // - Curl manages the actual `SSL_CTX` memory
+        // - Curl will free the `SSL_CTX` during connection cleanup
+        //
+        // See: https://curl.se/libcurl/c/CURLOPT_SSL_CTX_FUNCTION.html
+        std::mem::forget(ssl_ctx_builder);
+
+        Ok(())
+    }
+}
+
+pub(crate) fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+/// Maps a failed transfer to the matching `RelayError`, keeping connection
+/// problems and timeouts apart so retry policies can tell them from other errors.
+pub(crate) fn transfer_error(err: &curl::Error) -> RelayError {
+    let description = err.description().to_string();
+
+    if err.is_couldnt_connect()
+        || err.is_couldnt_resolve_host()
+        || err.is_couldnt_resolve_proxy()
+        || err.is_got_nothing()
+        || err.is_send_error()
+        || err.is_recv_error()
+        // A chunked body cut off by a proxy, or a reset HTTP/2 stream.
+        || err.is_partial_file()
+        || err.is_http2_error()
+        || err.is_http2_stream_error()
+    {
+        RelayError::ConnectionFailed(description)
+    } else if err.is_operation_timedout() {
+        RelayError::Timeout(description)
+    } else {
+        RelayError::RequestRunError(description)
+    }
+}
+
 pub fn run_request_task(
     req: &RequestWithMetadata,
     cancel_token: CancellationToken,
 ) -> Result<ResponseWithMetadata, RelayError> {
+    let mut response = run_with_retries(req, cancel_token)?;
+    attach_report(req, &mut response);
+    Ok(response)
+}
+
+fn run_with_retries(
+    req: &RequestWithMetadata,
+    cancel_token: CancellationToken,
+) -> Result<ResponseWithMetadata, RelayError> {
+    let mut retry = RetryTracker::new(req);
+
+    loop {
+        let time_start_ms = now_ms();
+        let outcome = run_single_attempt(req, cancel_token.clone());
+
+        let Some(delay) = retry.record(time_start_ms, &outcome) else {
+            return retry.finish(outcome);
+        };
+
+        // Sleep in short steps so a cancellation during the backoff is noticed quickly.
+        let resume_at = std::time::Instant::now() + delay;
+        while std::time::Instant::now() < resume_at {
+            if cancel_token.is_cancelled() {
+                return Err(RelayError::RequestCancelled);
+            }
+            std::thread::sleep(
+                resume_at
+                    .saturating_duration_since(std::time::Instant::now())
+                    .min(std::time::Duration::from_millis(50)),
+            );
+        }
+    }
+}
+
+fn run_single_attempt(
+    req: &RequestWithMetadata,
+    cancel_token: CancellationToken,
+) -> Result<ResponseWithMetadata, RelayError> {
+    let mut curl_handle = prepare_curl_handle(req, cancel_token)?;
+
+    let start_time_ms = now_ms();
+    log::info!(
+        "Initiating request transfer at timestamp: {}",
+        start_time_ms
+    );
+
+    if let Err(err) = curl_handle.perform() {
+        log::error!(
+            "Request transfer failed:\nError: {}\nTime elapsed: {}ms",
+            err,
+            now_ms() - start_time_ms,
+        );
+        return Err(curl_handle.get_ref().transfer_error(&err));
+    }
+
+    let end_time_ms = now_ms();
+
+    log::info!(
+        "Request transfer completed:\nDuration: {}ms",
+        end_time_ms - start_time_ms,
+    );
+
+    collect_response(&mut curl_handle, req, start_time_ms, end_time_ms)
+}
+
+/// Builds a fully configured curl handle for `req` without starting the transfer.
+pub(crate) fn prepare_curl_handle(
+    req: &RequestWithMetadata,
+    cancel_token: CancellationToken,
+) -> Result<Easy2<RelayHandler>, RelayError> {
     log::info!(
         "Starting request task: [Method: {}] [URL: {}] [Validate Certs: {}] [Has Body: {}] [Proxy Enabled: {}]",
         req.method,
@@ -26,7 +375,7 @@ pub fn run_request_task(
         req.proxy.is_some()
     );
 
-    let mut curl_handle = Easy::new();
+    let mut curl_handle = Easy2::new(RelayHandler::new(req, cancel_token));
     log::debug!("Initialized new curl handle with default settings");
 
     match curl_handle.progress(true) {
@@ -41,6 +390,18 @@ pub fn run_request_task(
         }
     }
 
+    // curl only calls the debug callback, which reports the headers as sent
+    // and feeds the trace, in verbose mode. It runs for every chunk of the
+    // transfer then, so it stays off unless asked for.
+    if req.trace || req.report_sent_headers {
+        curl_handle
+            .verbose(true)
+            .map_err(|err| RelayError::RequestRunError(err.description().to_string()))?;
+    }
+    if req.trace {
+        log::debug!("Wire level trace enabled");
+    }
+
     match curl_handle.custom_request(&req.method) {
         Ok(_) => log::debug!("HTTP method set: {}", req.method),
         Err(err) => {
@@ -61,7 +422,7 @@ pub fn run_request_task(
         }
     }
 
-    let headers = match get_headers_list(&req) {
+    let headers = match get_headers_list(req) {
         Ok(headers) => {
             log::debug!("Generated headers list");
             headers
@@ -80,7 +441,7 @@ pub fn run_request_task(
         }
     }
 
-    if let Err(err) = apply_body_to_curl_handle(&mut curl_handle, &req) {
+    if let Err(err) = apply_body_to_curl_handle(&mut curl_handle, req) {
         log::error!(
             "Request body application failed:\nError: {:?}\nContent-Type: {:?}",
             err,
@@ -123,7 +484,7 @@ pub fn run_request_task(
         }
     }
 
-    if let Err(err) = apply_client_cert_to_curl_handle(&mut curl_handle, &req) {
+    if let Err(err) = apply_client_cert_to_curl_handle(&mut curl_handle, req) {
         log::error!(
             "Client certificate configuration failed:\nError: {:?}\nCert Info: {:#?}",
             err,
@@ -133,7 +494,7 @@ pub fn run_request_task(
     }
     log::debug!("Client certificate configuration successful");
 
-    if let Err(err) = apply_proxy_config_to_curl_handle(&mut curl_handle, &req) {
+    if let Err(err) = apply_proxy_config_to_curl_handle(&mut curl_handle, req) {
         log::error!(
             "Proxy configuration failed:\nError: {:?}\nProxy Info: {:?}",
             err,
@@ -143,181 +504,75 @@ pub fn run_request_task(
     }
     log::debug!("Proxy configuration applied successfully");
 
-    let mut response_body = Vec::new();
-    let mut response_headers = Vec::new();
-    let (start_time_ms, end_time_ms) = {
-        let mut transfer = curl_handle.transfer();
-        log::debug!("Created curl transfer object for request execution");
-
-        match transfer.ssl_ctx_function(|ssl_ctx_ptr| {
-            let cert_list = match get_x509_certs_from_root_cert_bundle_safe(&req) {
-                Ok(certs) => {
-                    log::debug!("Found {} certificates in root bundle", certs.len());
-                    certs
-                }
-                Err(e) => {
-                    log::error!("Failed to load certificates from bundle: {:?}", e);
-                    return Ok(());
-                }
-            };
-
-            if !cert_list.is_empty() {
-                let mut ssl_ctx_builder =
-                    unsafe { SslContextBuilder::from_ptr(ssl_ctx_ptr as *mut SSL_CTX) };
-
-                let cert_store = ssl_ctx_builder.cert_store_mut();
-
-                for (index, cert) in cert_list.iter().enumerate() {
-                    log::debug!(
-                        "Processing certificate {}: Subject: {:?}, Not Before: {:?}, Not After: {:?}",
-                        index,
-                        cert.subject_name(),
-                        cert.not_before(),
-                        cert.not_after()
-                    );
-
-                    if let Err(e) = cert_store.add_cert(cert.clone()) {
-                        log::warn!(
-                            "Failed to add certificate {} to store\nError: {}\nCert details: {:?}",
-                            index,
-                            e,
-                            cert.subject_name()
-                        );
-                    } else {
-                        log::debug!(
-                            "Successfully added certificate {} to store\nSubject: {:?}",
-                            index,
-                            cert.subject_name()
-                        );
-                    }
-                }
-
-                // SAFETY: We need to prevent Rust from dropping the `SslContextBuilder` because
-                // the underlying `SSL_CTX` pointer is owned and managed by curl, not us.
-                // From curl docs: "libcurl does not guarantee the lifetime of the passed in
-                // object once this callback function has returned"
-                // and `SslContextBuilder` is just a safe wrapper around curl's `SSL_CTX` from
-                // `openssl_sys::SSL_CTX`.
-                // If dropped, Rust would try to free the `SSL_CTX` which curl still needs.
-                //
-                // This intentional "leak" is safe because:
-                // - We're only leaking the thin Rust wrapper
-                // - Curl manages the actual `SSL_CTX` memory
-                // - Curl will free the `SSL_CTX` during connection cleanup
-                //
-                // See: https://curl.se/libcurl/c/CURLOPT_SSL_CTX_FUNCTION.html
-                std::mem::forget(ssl_ctx_builder);
-            }
-
-            Ok(())
-        }) {
-            Ok(_) => log::debug!("SSL context function configured successfully"),
-            Err(err) => {
-                log::error!("SSL context function setup failed: {}", err);
-                return Err(RelayError::RequestRunError(err.description().to_string()));
-            }
-        }
-
-        match transfer.progress_function(|dltotal, dlnow, ultotal, ulnow| {
-            let cancelled = cancel_token.is_cancelled();
-            if cancelled {
-                log::warn!(
-                    "Request cancelled by user\nDownload: {}/{} bytes\nUpload: {}/{} bytes",
-                    dlnow,
-                    dltotal,
-                    ulnow,
-                    ultotal
-                );
-            } else {
-                log::debug!(
-                    "Progress - Download: {}/{} bytes, Upload: {}/{} bytes",
-                    dlnow,
-                    dltotal,
-                    ulnow,
-                    ultotal
-                );
-            }
-            !cancelled
-        }) {
-            Ok(_) => log::debug!("Progress monitoring function configured"),
-            Err(err) => {
-                log::error!("Progress function setup failed: {}", err);
-                return Err(RelayError::RequestRunError(err.description().to_string()));
-            }
-        }
-
-        match transfer.header_function(|header| {
-            let header = String::from_utf8_lossy(header).into_owned();
-            if let Some((key, value)) = header.split_once(':') {
-                log::debug!("Received header: [{}] = [{}]", key.trim(), value.trim());
-                response_headers.push(KeyValuePair {
-                    key: key.trim().to_string(),
-                    value: value.trim().to_string(),
-                });
-            } else {
-                log::debug!("Received header line (no key-value): {}", header.trim());
-            }
-            true
-        }) {
-            Ok(_) => log::debug!("Header processing function configured"),
-            Err(err) => {
-                log::error!("Header function setup failed: {}", err);
-                return Err(RelayError::RequestRunError(err.description().to_string()));
-            }
-        }
-
-        match transfer.write_function(|data| {
-            let chunk_size = data.len();
-            response_body.extend_from_slice(data);
-            log::debug!(
-                "Received response chunk: {} bytes (Total size so far: {} bytes)",
-                chunk_size,
-                response_body.len()
-            );
-            Ok(chunk_size)
-        }) {
-            Ok(_) => log::debug!("Response body processing function configured"),
-            Err(err) => {
-                log::error!("Write function setup failed: {}", err);
-                return Err(RelayError::RequestRunError(err.description().to_string()));
-            }
+    if !req.resolve.is_empty() {
+        let mut resolve = List::new();
+        for entry in &req.resolve {
+            resolve
+                .append(entry)
+                .map_err(|err| RelayError::RequestRunError(err.description().to_string()))?;
         }
+        curl_handle.resolve(resolve).map_err(|err| {
+            log::error!("Failed to set host overrides {:?}: {}", req.resolve, err);
+            RelayError::RequestRunError(err.description().to_string())
+        })?;
+        log::debug!("Host overrides applied: {:?}", req.resolve);
+    }
 
-        let start_time_ms = SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-        log::info!(
-            "Initiating request transfer at timestamp: {}",
-            start_time_ms
+    if let Err(err) = apply_limits_to_curl_handle(&mut curl_handle, req) {
+        log::error!(
+            "Timeout or TLS version configuration failed:\nError: {:?}\nTimeout: {:?}ms\nConnect Timeout: {:?}ms\nTLS Min Version: {:?}",
+            err,
+            req.timeout_ms,
+            req.connect_timeout_ms,
+            req.tls_min_version
         );
+        return Err(err);
+    }
 
-        if let Err(err) = transfer.perform() {
-            log::error!(
-                "Request transfer failed:\nError: {}\nTime elapsed: {}ms",
-                err,
-                SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis()
-                    - start_time_ms,
-            );
-            return Err(RelayError::RequestRunError(err.description().to_string()));
-        }
-
-        let end_time_ms = SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
+    Ok(curl_handle)
+}
 
-        log::info!(
-            "Request transfer completed:\nDuration: {}ms",
-            end_time_ms - start_time_ms,
-        );
+fn apply_limits_to_curl_handle(
+    curl_handle: &mut Easy2<RelayHandler>,
+    req: &RequestWithMetadata,
+) -> Result<(), RelayError> {
+    let to_error = |err: curl::Error| RelayError::RequestRunError(err.description().to_string());
 
-        (start_time_ms, end_time_ms)
-    };
+    if let Some(timeout_ms) = req.timeout_ms {
+        curl_handle
+            .timeout(Duration::from_millis(timeout_ms))
+            .map_err(to_error)?;
+    }
+    if let Some(connect_timeout_ms) = req.connect_timeout_ms {
+        curl_handle
+            .connect_timeout(Duration::from_millis(connect_timeout_ms))
+            .map_err(to_error)?;
+    }
+    if let Some(max_response_bytes) = req.max_response_bytes {
+        curl_handle
+            .max_filesize(max_response_bytes)
+            .map_err(to_error)?;
+    }
+    if let Some(version) = req.tls_min_version {
+        curl_handle
+            .ssl_version(match version {
+                TlsVersion::Tls1_0 => SslVersion::Tlsv10,
+                TlsVersion::Tls1_1 => SslVersion::Tlsv11,
+                TlsVersion::Tls1_2 => SslVersion::Tlsv12,
+                TlsVersion::Tls1_3 => SslVersion::Tlsv13,
+            })
+            .map_err(to_error)?;
+    }
+    Ok(())
+}
 
+/// Reads the status, headers and body of a finished transfer back out of `curl_handle`.
+pub(crate) fn collect_response(
+    curl_handle: &mut Easy2<RelayHandler>,
+    req: &RequestWithMetadata,
+    start_time_ms: u128,
+    end_time_ms: u128,
+) -> Result<ResponseWithMetadata, RelayError> {
     let response_status = match curl_handle.response_code() {
         Ok(status) => {
             let status = status as u16;
@@ -335,24 +590,54 @@ pub fn run_request_task(
     };
 
     let response_status_text = get_status_text(response_status).to_string();
+    let millis = |time: Result<std::time::Duration, curl::Error>| {
+        time.map(|time| time.as_secs_f64() * 1000.0)
+            .unwrap_or_default()
+    };
+    let timings = ResponseTimings {
+        name_lookup_ms: millis(curl_handle.namelookup_time()),
+        connect_ms: millis(curl_handle.connect_time()),
+        tls_handshake_ms: millis(curl_handle.appconnect_time()),
+        pre_transfer_ms: millis(curl_handle.pretransfer_time()),
+        start_transfer_ms: millis(curl_handle.starttransfer_time()),
+        total_ms: millis(curl_handle.total_time()),
+        redirect_ms: millis(curl_handle.redirect_time()),
+    };
+
+    let handler = curl_handle.get_mut();
     log::info!(
         "Request completed successfully:\nStatus: {} ({})\nDuration: {}ms\n\
          Response size: {} bytes\nHeaders: {} received\nEndpoint: {}",
         response_status,
         response_status_text,
         end_time_ms - start_time_ms,
-        response_body.len(),
-        response_headers.len(),
+        handler.response_body.len(),
+        handler.response_headers.len(),
         req.endpoint
     );
 
     Ok(ResponseWithMetadata {
         status: response_status,
         status_text: response_status_text,
-        headers: response_headers,
-        data: response_body,
+        http_version: handler
+            .response_http_version
+            .take()
+            .unwrap_or_else(|| "HTTP/1.1".to_string()),
+        headers: std::mem::take(&mut handler.response_headers),
+        request_line: handler.sent_request_line.take().unwrap_or_default(),
+        sent_headers: std::mem::take(&mut handler.sent_headers),
+        data: std::mem::take(&mut handler.response_body),
         time_start_ms: start_time_ms,
         time_end_ms: end_time_ms,
+        timings,
+        attempts: Vec::new(),
+        trace: handler
+            .trace
+            .take()
+            .map(TraceRecorder::finish)
+            .unwrap_or_default(),
+        cache_status: None,
+        assertions: None,
     })
 }
 
@@ -365,11 +650,24 @@ fn get_headers_list(req: &RequestWithMetadata) -> Result<List, RelayError> {
             .map_err(|err| RelayError::RequestRunError(err.description().to_string()))?;
     }
 
+    // A header without a value makes curl drop its own default for it.
+    for name in req.suppress_headers.names() {
+        if !req
+            .headers
+            .iter()
+            .any(|header| header.key.eq_ignore_ascii_case(name))
+        {
+            result
+                .append(&format!("{}:", name))
+                .map_err(|err| RelayError::RequestRunError(err.description().to_string()))?;
+        }
+    }
+
     Ok(result)
 }
 
 fn apply_body_to_curl_handle(
-    curl_handle: &mut Easy,
+    curl_handle: &mut Easy2<RelayHandler>,
     req: &RequestWithMetadata,
 ) -> Result<(), RelayError> {
     match &req.body {
@@ -446,7 +744,7 @@ fn apply_body_to_curl_handle(
 }
 
 fn apply_client_cert_to_curl_handle(
-    handle: &mut Easy,
+    handle: &mut Easy2<RelayHandler>,
     req: &RequestWithMetadata,
 ) -> Result<(), RelayError> {
     match &req.client_cert {
@@ -486,7 +784,7 @@ fn apply_client_cert_to_curl_handle(
             certificate_pfx,
             password,
         }) => {
-            let pkcs12 = Pkcs12::from_der(&certificate_pfx).map_err(|err| {
+            let pkcs12 = Pkcs12::from_der(certificate_pfx).map_err(|err| {
                 RelayError::RequestRunError(format!(
                     "Failed to parse PFX certificate from DER: {}",
                     err
@@ -555,7 +853,7 @@ fn apply_client_cert_to_curl_handle(
     Ok(())
 }
 
-fn get_x509_certs_from_root_cert_bundle_safe(
+pub(crate) fn get_x509_certs_from_root_cert_bundle_safe(
     req: &RequestWithMetadata,
 ) -> Result<Vec<X509>, openssl::error::ErrorStack> {
     let mut certs = Vec::new();
@@ -573,7 +871,7 @@ fn get_x509_certs_from_root_cert_bundle_safe(
 }
 
 fn apply_proxy_config_to_curl_handle(
-    handle: &mut Easy,
+    handle: &mut Easy2<RelayHandler>,
     req: &RequestWithMetadata,
 ) -> Result<(), RelayError> {
     if let Some(proxy_config) = &req.proxy {
@@ -0,0 +1,392 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    error::{RelayError, RelayResult},
+    interop::{RequestWithMetadata, ResponseWithMetadata},
+    relay::now_ms,
+};
+
+/// Methods that are safe to send again without changing the outcome on the server.
+const IDEMPOTENT_METHODS: [&str; 6] = ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"];
+
+/// Failure conditions that can trigger another attempt.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RetryCondition {
+    /// The connection could not be established or was dropped mid transfer.
+    ConnectError,
+    /// The transfer hit a timeout.
+    Timeout,
+    /// The server answered with this status code.
+    Status(u16),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub retry_on: Vec<RetryCondition>,
+    /// Delay before the first retry, multiplied by `backoff_factor` for each further one.
+    pub initial_delay_ms: u64,
+    /// Upper bound for any single delay, including one requested through `Retry-After`.
+    pub max_delay_ms: u64,
+    pub backoff_factor: f64,
+    /// Randomise each delay between zero and the computed backoff ("full jitter").
+    pub jitter: bool,
+    /// Wait as long as the server's `Retry-After` header asks for, when present.
+    pub respect_retry_after: bool,
+    /// Also retry methods such as `POST` and `PATCH`, which are not idempotent.
+    pub retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            retry_on: vec![
+                RetryCondition::ConnectError,
+                RetryCondition::Timeout,
+                RetryCondition::Status(429),
+                RetryCondition::Status(502),
+                RetryCondition::Status(503),
+                RetryCondition::Status(504),
+            ],
+            initial_delay_ms: 200,
+            max_delay_ms: 10_000,
+            backoff_factor: 2.0,
+            jitter: true,
+            respect_retry_after: true,
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+/// Outcome of a single attempt, recorded on the final response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetryAttempt {
+    /// 1 based attempt number.
+    pub attempt: u32,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub time_start_ms: u128,
+    pub time_end_ms: u128,
+    /// How long the relay waited before the next attempt, if there was one.
+    pub delay_ms: Option<u64>,
+}
+
+/// Tracks the attempts of one request and decides whether to try again.
+pub(crate) struct RetryTracker<'a> {
+    policy: Option<&'a RetryPolicy>,
+    method_allowed: bool,
+    attempts: Vec<RetryAttempt>,
+}
+
+impl<'a> RetryTracker<'a> {
+    pub(crate) fn new(req: &'a RequestWithMetadata) -> Self {
+        let method_allowed = req.retry.as_ref().is_some_and(|policy| {
+            policy.retry_non_idempotent
+                || IDEMPOTENT_METHODS.contains(&req.method.to_uppercase().as_str())
+        });
+
+        Self {
+            policy: req.retry.as_ref(),
+            method_allowed,
+            attempts: Vec::new(),
+        }
+    }
+
+    /// Records the outcome of the attempt that started at `time_start_ms` and
+    /// returns how long to wait before the next one, or `None` when done.
+    pub(crate) fn record(
+        &mut self,
+        time_start_ms: u128,
+        outcome: &RelayResult<ResponseWithMetadata>,
+    ) -> Option<Duration> {
+        let policy = self.policy?;
+
+        let attempt = self.attempts.len() as u32 + 1;
+        let (status, error) = match outcome {
+            Ok(response) => (Some(response.status), None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        self.attempts.push(RetryAttempt {
+            attempt,
+            status,
+            error,
+            time_start_ms,
+            time_end_ms: now_ms(),
+            delay_ms: None,
+        });
+
+        if !self.method_allowed || attempt >= policy.max_attempts || !is_retryable(policy, outcome)
+        {
+            return None;
+        }
+
+        let delay = next_delay(policy, attempt, outcome);
+        if let Some(last) = self.attempts.last_mut() {
+            last.delay_ms = Some(delay.as_millis() as u64);
+        }
+        log::warn!(
+            "Attempt {} of {} failed, retrying in {}ms",
+            attempt,
+            policy.max_attempts,
+            delay.as_millis()
+        );
+
+        Some(delay)
+    }
+
+    /// Attaches the recorded attempts to the final response, or to the error
+    /// as [`RelayError::RetriesExhausted`] when the request was retried.
+    pub(crate) fn finish(
+        self,
+        outcome: RelayResult<ResponseWithMetadata>,
+    ) -> RelayResult<ResponseWithMetadata> {
+        match outcome {
+            Ok(mut response) => {
+                response.attempts = self.attempts;
+                Ok(response)
+            }
+            Err(err) if self.attempts.len() > 1 => {
+                log::error!("Request failed after {} attempts", self.attempts.len());
+                Err(RelayError::RetriesExhausted {
+                    attempts: self.attempts,
+                    last: Box::new(err),
+                })
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn is_retryable(policy: &RetryPolicy, outcome: &RelayResult<ResponseWithMetadata>) -> bool {
+    let condition = match outcome {
+        Ok(response) => RetryCondition::Status(response.status),
+        Err(RelayError::ConnectionFailed(_)) => RetryCondition::ConnectError,
+        Err(RelayError::Timeout(_)) => RetryCondition::Timeout,
+        Err(_) => return false,
+    };
+
+    policy.retry_on.contains(&condition)
+}
+
+fn next_delay(
+    policy: &RetryPolicy,
+    attempt: u32,
+    outcome: &RelayResult<ResponseWithMetadata>,
+) -> Duration {
+    let max_delay_ms = policy.max_delay_ms;
+
+    if policy.respect_retry_after {
+        if let Some(retry_after) = outcome.as_ref().ok().and_then(retry_after) {
+            return retry_after.min(Duration::from_millis(max_delay_ms));
+        }
+    }
+
+    let backoff_ms = (policy.initial_delay_ms as f64
+        * policy.backoff_factor.max(1.0).powi(attempt as i32 - 1))
+    .min(max_delay_ms as f64) as u64;
+
+    let delay_ms = if policy.jitter && backoff_ms > 0 {
+        rand::thread_rng().gen_range(0..=backoff_ms)
+    } else {
+        backoff_ms
+    };
+
+    Duration::from_millis(delay_ms)
+}
+
+/// Parses `Retry-After` in either of its forms, delay seconds or an HTTP date.
+fn retry_after(response: &ResponseWithMetadata) -> Option<Duration> {
+    let value = response
+        .headers
+        .iter()
+        .find(|header| header.key.eq_ignore_ascii_case("retry-after"))?
+        .value
+        .trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    httpdate::parse_http_date(value)
+        .ok()?
+        .duration_since(SystemTime::now())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, retry_after: Option<&str>) -> RelayResult<ResponseWithMetadata> {
+        let headers: Vec<_> = retry_after
+            .map(|value| serde_json::json!({ "key": "Retry-After", "value": value }))
+            .into_iter()
+            .collect();
+        Ok(serde_json::from_value(serde_json::json!({
+            "status": status,
+            "status_text": "",
+            "headers": headers,
+            "data": [],
+            "time_start_ms": 0,
+            "time_end_ms": 1,
+        }))
+        .unwrap())
+    }
+
+    fn request(method: &str, policy: RetryPolicy) -> RequestWithMetadata {
+        let mut req = RequestWithMetadata::new(
+            0,
+            method.to_string(),
+            "http://localhost/".to_string(),
+            Vec::new(),
+            None,
+            true,
+            Vec::new(),
+            None,
+            None,
+        );
+        req.retry = Some(policy);
+        req
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            initial_delay_ms: 100,
+            max_delay_ms: 1_000,
+            jitter: false,
+            ..Default::default()
+        };
+        let delays: Vec<_> = (1..=5)
+            .map(|attempt| next_delay(&policy, attempt, &response(503, None)).as_millis())
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1_000]);
+
+        let jittered = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        for attempt in 1..=5 {
+            assert!(next_delay(&jittered, attempt, &response(503, None)).as_millis() <= 1_000);
+        }
+    }
+
+    #[test]
+    fn honours_retry_after_seconds_and_dates() {
+        let policy = RetryPolicy {
+            max_delay_ms: 60_000,
+            jitter: false,
+            ..Default::default()
+        };
+        let delay = |value| next_delay(&policy, 1, &response(429, Some(value)));
+
+        assert_eq!(delay("7"), Duration::from_secs(7));
+        // Capped by `max_delay_ms`.
+        assert_eq!(delay("600"), Duration::from_secs(60));
+
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(30));
+        let from_date = delay(&date);
+        assert!(
+            from_date > Duration::from_secs(28) && from_date <= Duration::from_secs(30),
+            "{:?}",
+            from_date
+        );
+
+        // A date in the past or garbage falls back to the backoff.
+        let past = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(30));
+        assert_eq!(delay(&past), Duration::from_millis(200));
+        assert_eq!(delay("soon"), Duration::from_millis(200));
+
+        let ignoring = RetryPolicy {
+            respect_retry_after: false,
+            ..policy
+        };
+        assert_eq!(
+            next_delay(&ignoring, 1, &response(429, Some("7"))),
+            Duration::from_millis(200)
+        );
+    }
+
+    #[test]
+    fn retries_only_idempotent_methods_and_listed_conditions() {
+        let policy = RetryPolicy {
+            initial_delay_ms: 0,
+            ..Default::default()
+        };
+
+        let get = request("get", policy.clone());
+        let mut tracker = RetryTracker::new(&get);
+        assert!(tracker.record(0, &response(503, None)).is_some());
+        assert!(tracker.record(0, &response(500, None)).is_none());
+
+        let post = request("POST", policy.clone());
+        let mut tracker = RetryTracker::new(&post);
+        assert!(tracker.record(0, &response(503, None)).is_none());
+
+        let post = request(
+            "POST",
+            RetryPolicy {
+                retry_non_idempotent: true,
+                ..policy.clone()
+            },
+        );
+        let mut tracker = RetryTracker::new(&post);
+        let timeout = Err(RelayError::Timeout("slow".to_string()));
+        assert!(tracker.record(0, &timeout).is_some());
+        assert!(tracker
+            .record(0, &Err(RelayError::ConnectionFailed("reset".to_string())))
+            .is_some());
+        // The third attempt is the last one.
+        assert!(tracker.record(0, &timeout).is_none());
+        assert!(!is_retryable(&policy, &Err(RelayError::InvalidUrl)));
+    }
+
+    #[test]
+    fn keeps_the_attempts_of_a_request_that_kept_failing() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            retry_on: vec![RetryCondition::Timeout],
+            initial_delay_ms: 0,
+            jitter: false,
+            ..RetryPolicy::default()
+        };
+        let get = request("GET", policy);
+        let mut tracker = RetryTracker::new(&get);
+        let timeout = || Err(RelayError::Timeout("slow".to_string()));
+        assert!(tracker.record(0, &timeout()).is_some());
+        assert!(tracker.record(0, &timeout()).is_none());
+
+        let err = tracker.finish(timeout()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Request timed out: slow (after 2 attempts)"
+        );
+        let RelayError::RetriesExhausted { attempts, last } = err else {
+            panic!("attempts dropped: {:?}", err);
+        };
+        assert!(matches!(*last, RelayError::Timeout(_)));
+        assert_eq!(
+            attempts
+                .iter()
+                .map(|attempt| (attempt.attempt, attempt.error.as_deref()))
+                .collect::<Vec<_>>(),
+            [
+                (1, Some("Request timed out: slow")),
+                (2, Some("Request timed out: slow"))
+            ]
+        );
+
+        // A request that was tried once fails with its own error.
+        let mut tracker = RetryTracker::new(&get);
+        assert!(tracker.record(0, &Err(RelayError::InvalidUrl)).is_none());
+        assert!(matches!(
+            tracker.finish(Err(RelayError::InvalidUrl)),
+            Err(RelayError::InvalidUrl)
+        ));
+    }
+}
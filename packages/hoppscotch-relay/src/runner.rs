@@ -0,0 +1,1083 @@
+//! Headless runner for the collections of a workspace directory.
+//!
+//! Reads `collections.json` and `environments.json` as the desktop app
+//! stores them, turns every REST request into a [`RequestWithMetadata`] with
+//! the inherited headers and auth of its folders, substitutes environment
+//! variables and runs the requests one after another. Each response is
+//! checked against the expectations for its request and the outcome is
+//! collected into a [`RunReport`], which renders as JSON or JUnit XML.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    assertions::{Assertion, AssertionReport},
+    error::{RelayError, RelayResult},
+    har::iso8601,
+    interop::{BodyDef, FormDataEntry, FormDataValue, KeyValuePair, RequestWithMetadata},
+    relay::{now_ms, run_request_task},
+    variables::substitute,
+};
+
+pub const COLLECTIONS_FILE: &str = "collections.json";
+pub const ENVIRONMENTS_FILE: &str = "environments.json";
+pub const EXPECTATIONS_FILE: &str = "expectations.json";
+
+/// A REST collection or one of its folders.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WorkspaceCollection {
+    pub name: String,
+    #[serde(default)]
+    pub folders: Vec<WorkspaceCollection>,
+    #[serde(default)]
+    pub requests: Vec<WorkspaceRequest>,
+    #[serde(default)]
+    pub auth: Option<Value>,
+    #[serde(default)]
+    pub headers: Vec<WorkspaceParam>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceRequest {
+    pub name: String,
+    pub method: String,
+    pub endpoint: String,
+    #[serde(default)]
+    pub params: Vec<WorkspaceParam>,
+    #[serde(default)]
+    pub headers: Vec<WorkspaceParam>,
+    #[serde(default)]
+    pub body: WorkspaceBody,
+    #[serde(default)]
+    pub auth: Option<Value>,
+    #[serde(default)]
+    pub request_variables: Vec<WorkspaceParam>,
+    /// Example responses saved for the request, keyed by their name.
+    #[serde(default)]
+    pub responses: BTreeMap<String, WorkspaceResponse>,
+    #[serde(default)]
+    pub assertions: Vec<Assertion>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WorkspaceResponse {
+    pub name: String,
+    /// Status text, e.g. `OK`.
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub code: Option<u16>,
+    #[serde(default)]
+    pub headers: Vec<KeyValuePair>,
+    #[serde(default)]
+    pub body: String,
+}
+
+/// A header, query parameter or request variable.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WorkspaceParam {
+    pub key: String,
+    #[serde(default)]
+    pub value: String,
+    #[serde(default = "active_by_default")]
+    pub active: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceBody {
+    pub content_type: Option<String>,
+    /// Raw text for most content types, a list of fields for multipart forms.
+    #[serde(default)]
+    pub body: Value,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WorkspaceEnvironment {
+    pub name: String,
+    #[serde(default)]
+    pub variables: Vec<EnvironmentVariable>,
+}
+
+/// Values of secret variables are kept out of the workspace, they have to be
+/// passed to the runner as overrides.
+#[derive(Clone, Debug, Deserialize)]
+pub struct EnvironmentVariable {
+    pub key: String,
+    #[serde(default)]
+    pub value: Option<String>,
+    #[serde(default)]
+    pub secret: bool,
+}
+
+fn active_by_default() -> bool {
+    true
+}
+
+/// Collections and environments read from a workspace directory.
+#[derive(Clone, Debug, Default)]
+pub struct Workspace {
+    pub collections: Vec<WorkspaceCollection>,
+    pub environments: Vec<WorkspaceEnvironment>,
+}
+
+impl Workspace {
+    /// Loads `collections.json` and, when present, `environments.json` from `dir`.
+    pub fn load(dir: &Path) -> RelayResult<Self> {
+        Self::load_files(
+            &dir.join(COLLECTIONS_FILE),
+            workspace_file(dir, ENVIRONMENTS_FILE).as_deref(),
+        )
+    }
+
+    pub fn load_files(collections: &Path, environments: Option<&Path>) -> RelayResult<Self> {
+        Ok(Self {
+            collections: read_json(collections)?,
+            environments: match environments {
+                Some(path) => read_json(path)?,
+                None => Vec::new(),
+            },
+        })
+    }
+
+    pub fn collection(&self, name: &str) -> Option<&WorkspaceCollection> {
+        self.collections.iter().find(|c| c.name == name)
+    }
+
+    pub fn environment(&self, name: &str) -> Option<&WorkspaceEnvironment> {
+        self.environments.iter().find(|e| e.name == name)
+    }
+}
+
+/// What a response has to look like for its request to pass.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Expectation {
+    /// Accepted statuses, `200`, `"2xx"` or `"200-204"`. Any status below
+    /// 400 passes when none are given, unless a `Status` assertion of the
+    /// request or expectation decides instead.
+    #[serde(deserialize_with = "one_or_many")]
+    pub status: Vec<String>,
+    pub body_contains: Vec<String>,
+    pub body_equals: Option<String>,
+    /// Values expected at JSON Pointers into the body, e.g. `/data/0/id`.
+    pub json: BTreeMap<String, Value>,
+    pub max_time_ms: Option<f64>,
+    /// Checked in addition to the request's own assertions.
+    pub assertions: Vec<Assertion>,
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = match Value::deserialize(deserializer)? {
+        Value::Array(values) => values,
+        Value::Null => Vec::new(),
+        value => vec![value],
+    };
+    Ok(values
+        .into_iter()
+        .map(|value| match value {
+            Value::String(value) => value,
+            value => value.to_string(),
+        })
+        .collect())
+}
+
+/// Contents of an expectations file. `requests` is keyed by the path of a
+/// request, `Collection/Folder/Request`, and adds to `defaults`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Expectations {
+    pub defaults: Expectation,
+    pub requests: BTreeMap<String, Expectation>,
+}
+
+impl Expectations {
+    pub fn load(path: &Path) -> RelayResult<Self> {
+        read_json(path)
+    }
+
+    /// Expectation for the request at `path`. Its own statuses replace the
+    /// default ones, every other check adds to the defaults.
+    pub fn for_request(&self, path: &str) -> Expectation {
+        let mut expectation = self.defaults.clone();
+        if let Some(own) = self.requests.get(path) {
+            if !own.status.is_empty() {
+                expectation.status = own.status.clone();
+            }
+            expectation
+                .body_contains
+                .extend(own.body_contains.iter().cloned());
+            expectation.json.extend(own.json.clone());
+            if own.body_equals.is_some() {
+                expectation.body_equals = own.body_equals.clone();
+            }
+            if own.max_time_ms.is_some() {
+                expectation.max_time_ms = own.max_time_ms;
+            }
+            expectation
+                .assertions
+                .extend(own.assertions.iter().cloned());
+        }
+        expectation
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RunnerOptions {
+    /// Name of the environment to take variables from.
+    pub environment: Option<String>,
+    /// Only runs requests below this folder path, `Folder/Subfolder`.
+    pub folder: Option<String>,
+    /// Variables that take precedence over the environment and request
+    /// variables, typically secrets passed in by CI.
+    pub variables: Vec<KeyValuePair>,
+    pub expectations: Expectations,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestOutcome {
+    Passed,
+    /// A response arrived but didn't meet the expectations.
+    Failed,
+    /// The request couldn't be built or run.
+    Errored,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequestReport {
+    /// Folders from the collection down to the request.
+    pub folder: Vec<String>,
+    pub name: String,
+    pub method: String,
+    pub url: String,
+    pub outcome: RequestOutcome,
+    pub status: Option<u16>,
+    pub duration_ms: f64,
+    pub failures: Vec<String>,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub assertions: Option<AssertionReport>,
+}
+
+impl RequestReport {
+    pub fn path(&self) -> String {
+        let mut path = self.folder.clone();
+        path.push(self.name.clone());
+        path.join("/")
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RunReport {
+    pub collection: String,
+    pub environment: Option<String>,
+    /// ISO 8601 start time.
+    pub started_at: String,
+    pub duration_ms: f64,
+    pub total: usize,
+    pub passed: usize,
+    pub failed: usize,
+    pub errored: usize,
+    pub requests: Vec<RequestReport>,
+}
+
+impl RunReport {
+    pub fn success(&self) -> bool {
+        self.failed == 0 && self.errored == 0
+    }
+
+    fn write_junit_suite(&self, xml: &mut String) {
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{}\" timestamp=\"{}\">\n",
+            xml_escape(&self.collection),
+            self.total,
+            self.failed,
+            self.errored,
+            junit_seconds(self.duration_ms),
+            xml_escape(&self.started_at)
+        ));
+
+        for request in &self.requests {
+            let mut classname = vec![self.collection.clone()];
+            classname.extend(request.folder.iter().cloned());
+            let open = format!(
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{}\"",
+                xml_escape(&classname.join("/")),
+                xml_escape(&request.name),
+                junit_seconds(request.duration_ms)
+            );
+
+            match request.outcome {
+                RequestOutcome::Passed => xml.push_str(&format!("{}/>\n", open)),
+                RequestOutcome::Failed => {
+                    xml.push_str(&format!("{}>\n", open));
+                    xml.push_str(&format!(
+                        "      <failure message=\"{}\" type=\"ExpectationFailed\">{}</failure>\n",
+                        xml_escape(request.failures.first().map_or("", String::as_str)),
+                        xml_escape(&request.failures.join("\n"))
+                    ));
+                    xml.push_str("    </testcase>\n");
+                }
+                RequestOutcome::Errored => {
+                    xml.push_str(&format!("{}>\n", open));
+                    xml.push_str(&format!(
+                        "      <error message=\"{}\" type=\"RequestError\"/>\n",
+                        xml_escape(request.error.as_deref().unwrap_or_default())
+                    ));
+                    xml.push_str("    </testcase>\n");
+                }
+            }
+        }
+
+        xml.push_str("  </testsuite>\n");
+    }
+}
+
+/// Renders reports as JUnit XML, a test suite per collection and a test case
+/// per request.
+pub fn junit_xml(reports: &[RunReport]) -> String {
+    let count = |field: fn(&RunReport) -> usize| reports.iter().map(field).sum::<usize>();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+
+    xml.push_str(&format!(
+        "<testsuites name=\"postdata\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{}\">\n",
+        count(|report| report.total),
+        count(|report| report.failed),
+        count(|report| report.errored),
+        junit_seconds(reports.iter().map(|report| report.duration_ms).sum())
+    ));
+    for report in reports {
+        report.write_junit_suite(&mut xml);
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn junit_seconds(ms: f64) -> String {
+    format!("{:.3}", ms / 1000.0)
+}
+
+/// Runs every request of `collection`, folders first as the app lists them.
+///
+/// `on_result` is called after each request so callers can print progress.
+/// Cancelling `cancel_token` errors the running request and the ones after it.
+pub fn run_collection<F>(
+    collection: &WorkspaceCollection,
+    workspace: &Workspace,
+    options: &RunnerOptions,
+    cancel_token: CancellationToken,
+    mut on_result: F,
+) -> RelayResult<RunReport>
+where
+    F: FnMut(&RequestReport),
+{
+    let environment = match &options.environment {
+        Some(name) => Some(workspace.environment(name).ok_or_else(|| {
+            RelayError::RequestRunError(format!("No environment named '{}'", name))
+        })?),
+        None => None,
+    };
+    let folder = options
+        .folder
+        .as_deref()
+        .map(|folder| folder.split('/').map(str::to_string).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let mut planned = Vec::new();
+    plan_requests(
+        collection,
+        &Inherited::default(),
+        &mut Vec::new(),
+        &mut planned,
+    );
+    planned.retain(|request| request.folder.starts_with(&folder));
+
+    let started_ms = now_ms();
+    let mut report = RunReport {
+        collection: collection.name.clone(),
+        environment: options.environment.clone(),
+        started_at: iso8601(started_ms),
+        ..Default::default()
+    };
+
+    for (index, planned) in planned.iter().enumerate() {
+        let variables = request_variables(planned, &options.variables, environment);
+
+        let mut path = vec![collection.name.clone()];
+        path.extend(planned.folder.iter().cloned());
+        path.push(planned.request.name.clone());
+        let expectation = options.expectations.for_request(&path.join("/"));
+
+        let result = run_planned(index, planned, &variables, &expectation, &cancel_token);
+        match result.outcome {
+            RequestOutcome::Passed => report.passed += 1,
+            RequestOutcome::Failed => report.failed += 1,
+            RequestOutcome::Errored => report.errored += 1,
+        }
+        on_result(&result);
+        report.requests.push(result);
+    }
+
+    report.total = report.requests.len();
+    report.duration_ms = (now_ms() - started_ms) as f64;
+    Ok(report)
+}
+
+/// Variables for a request, overrides first, then the request's own
+/// variables, then the environment.
+fn request_variables(
+    planned: &PlannedRequest,
+    overrides: &[KeyValuePair],
+    environment: Option<&WorkspaceEnvironment>,
+) -> Vec<KeyValuePair> {
+    let mut variables = overrides.to_vec();
+    variables.extend(
+        planned
+            .request
+            .request_variables
+            .iter()
+            .filter(|variable| variable.active)
+            .map(|variable| KeyValuePair {
+                key: variable.key.clone(),
+                value: variable.value.clone(),
+            }),
+    );
+    if let Some(environment) = environment {
+        variables.extend(environment.variables.iter().filter_map(|variable| {
+            variable.value.as_ref().map(|value| KeyValuePair {
+                key: variable.key.clone(),
+                value: value.clone(),
+            })
+        }));
+    }
+    variables
+}
+
+/// Headers and auth passed down from a collection to its folders and requests.
+#[derive(Clone, Debug, Default)]
+struct Inherited {
+    auth: Option<Value>,
+    headers: Vec<WorkspaceParam>,
+}
+
+struct PlannedRequest<'a> {
+    folder: Vec<String>,
+    request: &'a WorkspaceRequest,
+    inherited: Inherited,
+}
+
+fn plan_requests<'a>(
+    collection: &'a WorkspaceCollection,
+    parent: &Inherited,
+    folder: &mut Vec<String>,
+    planned: &mut Vec<PlannedRequest<'a>>,
+) {
+    let mut inherited = parent.clone();
+    if let Some(auth) = &collection.auth {
+        if auth_type(auth) != "inherit" {
+            inherited.auth = Some(auth.clone());
+        }
+    }
+    inherited.headers = merge_headers(&inherited.headers, &collection.headers);
+
+    for child in &collection.folders {
+        folder.push(child.name.clone());
+        plan_requests(child, &inherited, folder, planned);
+        folder.pop();
+    }
+    for request in &collection.requests {
+        planned.push(PlannedRequest {
+            folder: folder.clone(),
+            request,
+            inherited: inherited.clone(),
+        });
+    }
+}
+
+/// Active headers of `parent` that `own` doesn't set, followed by `own`.
+fn merge_headers(parent: &[WorkspaceParam], own: &[WorkspaceParam]) -> Vec<WorkspaceParam> {
+    let own = own
+        .iter()
+        .filter(|header| header.active && !header.key.is_empty())
+        .collect::<Vec<_>>();
+    parent
+        .iter()
+        .filter(|header| {
+            !own.iter()
+                .any(|other| other.key.eq_ignore_ascii_case(&header.key))
+        })
+        .chain(own.iter().copied())
+        .cloned()
+        .collect()
+}
+
+fn run_planned(
+    index: usize,
+    planned: &PlannedRequest,
+    variables: &[KeyValuePair],
+    expectation: &Expectation,
+    cancel_token: &CancellationToken,
+) -> RequestReport {
+    let mut report = RequestReport {
+        folder: planned.folder.clone(),
+        name: planned.request.name.clone(),
+        method: planned.request.method.to_uppercase(),
+        url: substitute(&planned.request.endpoint, variables),
+        outcome: RequestOutcome::Errored,
+        status: None,
+        duration_ms: 0.0,
+        failures: Vec::new(),
+        error: None,
+        assertions: None,
+    };
+
+    let mut req = match build_request(index, planned, variables) {
+        Ok(req) => req,
+        Err(err) => {
+            report.error = Some(err);
+            return report;
+        }
+    };
+    report.url = req.endpoint.clone();
+    req.assertions = planned
+        .request
+        .assertions
+        .iter()
+        .chain(&expectation.assertions)
+        .cloned()
+        .collect();
+
+    let response = if cancel_token.is_cancelled() {
+        Err(RelayError::RequestCancelled)
+    } else {
+        run_request_task(&req, cancel_token.clone())
+    };
+    match response {
+        Ok(response) => {
+            report.status = Some(response.status);
+            report.duration_ms = (response.time_end_ms - response.time_start_ms) as f64;
+            // Like in the app, a `Status` assertion replaces the default rule.
+            let status_asserted = req
+                .assertions
+                .iter()
+                .any(|assertion| matches!(assertion, Assertion::Status { .. }));
+            report.failures = check_expectation(
+                expectation,
+                status_asserted,
+                variables,
+                response.status,
+                &response.data,
+                report.duration_ms,
+            );
+            if let Some(assertions) = &response.assertions {
+                report.failures.extend(
+                    assertions
+                        .results
+                        .iter()
+                        .filter_map(|result| result.message.clone()),
+                );
+            }
+            report.assertions = response.assertions;
+            report.outcome = if report.failures.is_empty() {
+                RequestOutcome::Passed
+            } else {
+                RequestOutcome::Failed
+            };
+        }
+        Err(err) => report.error = Some(err.to_string()),
+    }
+    report
+}
+
+fn build_request(
+    index: usize,
+    planned: &PlannedRequest,
+    variables: &[KeyValuePair],
+) -> Result<RequestWithMetadata, String> {
+    let request = planned.request;
+    let mut endpoint = substitute(&request.endpoint, variables);
+    let mut query = request
+        .params
+        .iter()
+        .filter(|param| param.active && !param.key.is_empty())
+        .map(|param| {
+            (
+                substitute(&param.key, variables),
+                substitute(&param.value, variables),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut headers = merge_headers(&planned.inherited.headers, &request.headers)
+        .into_iter()
+        .map(|header| KeyValuePair {
+            key: substitute(&header.key, variables),
+            value: substitute(&header.value, variables),
+        })
+        .collect::<Vec<_>>();
+
+    let auth = match &request.auth {
+        Some(auth) if auth_type(auth) != "inherit" => Some(auth),
+        _ => planned.inherited.auth.as_ref(),
+    };
+    if let Some(auth) = auth {
+        apply_auth(auth, variables, &mut headers, &mut query)?;
+    }
+
+    if !query.is_empty() {
+        let separator = if endpoint.contains('?') { '&' } else { '?' };
+        let query = query
+            .iter()
+            .map(|(key, value)| {
+                format!(
+                    "{}={}",
+                    url_escape::encode_component(key),
+                    url_escape::encode_component(value)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        endpoint = format!("{}{}{}", endpoint, separator, query);
+    }
+
+    let body = build_body(&request.body, variables)?;
+    if let Some(content_type) = &request.body.content_type {
+        let has_content_type = headers
+            .iter()
+            .any(|header| header.key.eq_ignore_ascii_case("content-type"));
+        // curl writes the multipart boundary into its own Content-Type.
+        if body.is_some() && !has_content_type && content_type != "multipart/form-data" {
+            headers.push(KeyValuePair {
+                key: "Content-Type".to_string(),
+                value: content_type.clone(),
+            });
+        }
+    }
+
+    Ok(RequestWithMetadata::new(
+        index,
+        request.method.to_uppercase(),
+        endpoint,
+        headers,
+        body,
+        true,
+        Vec::new(),
+        None,
+        None,
+    ))
+}
+
+fn auth_type(auth: &Value) -> &str {
+    auth.get("authType")
+        .and_then(Value::as_str)
+        .unwrap_or("none")
+}
+
+fn apply_auth(
+    auth: &Value,
+    variables: &[KeyValuePair],
+    headers: &mut Vec<KeyValuePair>,
+    query: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    if auth.get("authActive").and_then(Value::as_bool) == Some(false) {
+        return Ok(());
+    }
+    let field = |name: &str| {
+        substitute(
+            auth.get(name).and_then(Value::as_str).unwrap_or_default(),
+            variables,
+        )
+    };
+    let mut authorization = |value: String| {
+        headers.push(KeyValuePair {
+            key: "Authorization".to_string(),
+            value,
+        })
+    };
+
+    match auth_type(auth) {
+        "none" | "inherit" => {}
+        "basic" => authorization(format!(
+            "Basic {}",
+            BASE64.encode(format!("{}:{}", field("username"), field("password")))
+        )),
+        "bearer" => authorization(format!("Bearer {}", field("token"))),
+        "api-key" => {
+            let (key, value) = (field("key"), field("value"));
+            if auth.get("addTo").and_then(Value::as_str) == Some("QUERY_PARAMS") {
+                query.push((key, value));
+            } else {
+                headers.push(KeyValuePair { key, value });
+            }
+        }
+        other => {
+            return Err(format!(
+                "Auth type '{}' isn't supported by the runner",
+                other
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn build_body(body: &WorkspaceBody, variables: &[KeyValuePair]) -> Result<Option<BodyDef>, String> {
+    let Some(content_type) = &body.content_type else {
+        return Ok(None);
+    };
+
+    match content_type.as_str() {
+        "multipart/form-data" => {
+            let fields = body.body.as_array().cloned().unwrap_or_default();
+            let mut entries = Vec::new();
+            for field in fields {
+                if field.get("active").and_then(Value::as_bool) == Some(false) {
+                    continue;
+                }
+                let key = field.get("key").and_then(Value::as_str).unwrap_or_default();
+                if field.get("isFile").and_then(Value::as_bool) == Some(true) {
+                    return Err(format!(
+                        "Form field '{}' is a file, files aren't stored in the workspace",
+                        key
+                    ));
+                }
+                entries.push(FormDataEntry {
+                    key: substitute(key, variables),
+                    value: FormDataValue::Text(substitute(
+                        field
+                            .get("value")
+                            .and_then(Value::as_str)
+                            .unwrap_or_default(),
+                        variables,
+                    )),
+                });
+            }
+            Ok(Some(BodyDef::FormData(entries)))
+        }
+        // Stored in the app's raw key-value format, `key: value` per line
+        // with disabled entries commented out.
+        "application/x-www-form-urlencoded" => {
+            let text = body.body.as_str().unwrap_or_default();
+            let entries = text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| {
+                    let (key, value) = line.split_once(':').unwrap_or((line, ""));
+                    KeyValuePair {
+                        key: substitute(key.trim(), variables),
+                        value: substitute(value.trim(), variables),
+                    }
+                })
+                .collect();
+            Ok(Some(BodyDef::URLEncoded(entries)))
+        }
+        _ => Ok(Some(BodyDef::Text(substitute(
+            body.body.as_str().unwrap_or_default(),
+            variables,
+        )))),
+    }
+}
+
+fn check_expectation(
+    expectation: &Expectation,
+    status_asserted: bool,
+    variables: &[KeyValuePair],
+    status: u16,
+    data: &[u8],
+    duration_ms: f64,
+) -> Vec<String> {
+    let mut failures = Vec::new();
+
+    let status_ok = if expectation.status.is_empty() {
+        status_asserted || status < 400
+    } else {
+        expectation
+            .status
+            .iter()
+            .any(|pattern| status_matches(pattern, status))
+    };
+    if !status_ok {
+        let expected = if expectation.status.is_empty() {
+            "below 400".to_string()
+        } else {
+            expectation.status.join(", ")
+        };
+        failures.push(format!("Expected status {}, got {}", expected, status));
+    }
+
+    let body = String::from_utf8_lossy(data);
+    // Messages quote the expectations as written so secrets stay out of reports.
+    for needle in &expectation.body_contains {
+        if !body.contains(&substitute(needle, variables)) {
+            failures.push(format!("Expected body to contain '{}'", needle));
+        }
+    }
+    if let Some(expected) = &expectation.body_equals {
+        if body != substitute(expected, variables) {
+            failures.push("Expected body to equal the expected text".to_string());
+        }
+    }
+
+    if !expectation.json.is_empty() {
+        match serde_json::from_slice::<Value>(data) {
+            Ok(json) => {
+                for (pointer, expected) in &expectation.json {
+                    match json.pointer(pointer) {
+                        Some(actual) if actual == expected => {}
+                        Some(actual) => failures.push(format!(
+                            "Expected {} to be {}, got {}",
+                            pointer, expected, actual
+                        )),
+                        None => failures.push(format!(
+                            "Expected {} to be {}, but it is missing",
+                            pointer, expected
+                        )),
+                    }
+                }
+            }
+            Err(err) => failures.push(format!("Expected a JSON body: {}", err)),
+        }
+    }
+
+    if let Some(max_time_ms) = expectation.max_time_ms {
+        if duration_ms > max_time_ms {
+            failures.push(format!(
+                "Expected a response within {} ms, took {} ms",
+                max_time_ms, duration_ms
+            ));
+        }
+    }
+
+    failures
+}
+
+/// Matches `200`, a class like `2xx` or a range like `200-299`.
+pub(crate) fn status_matches(pattern: &str, status: u16) -> bool {
+    let pattern = pattern.trim();
+    if let Some((low, high)) = pattern.split_once('-') {
+        return match (low.trim().parse::<u16>(), high.trim().parse::<u16>()) {
+            (Ok(low), Ok(high)) => (low..=high).contains(&status),
+            _ => false,
+        };
+    }
+    if let Some(class) = pattern.strip_suffix("xx").or(pattern.strip_suffix("XX")) {
+        return class.parse::<u16>() == Ok(status / 100);
+    }
+    pattern.parse::<u16>() == Ok(status)
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> RelayResult<T> {
+    let text = std::fs::read_to_string(path).map_err(|err| {
+        RelayError::RequestRunError(format!("Failed to read {}: {}", path.display(), err))
+    })?;
+    serde_json::from_str(&text)
+        .map_err(|err| RelayError::RequestRunError(format!("Invalid {}: {}", path.display(), err)))
+}
+
+/// Default location of a workspace file, `None` when it doesn't exist.
+pub fn workspace_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    let path = dir.join(name);
+    path.exists().then_some(path)
+}
+
+fn xml_escape(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t' | '\r'))
+        .fold(String::with_capacity(text.len()), |mut out, c| {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '"' => out.push_str("&quot;"),
+                '\'' => out.push_str("&apos;"),
+                c => out.push(c),
+            }
+            out
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> KeyValuePair {
+        KeyValuePair {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn builds_requests_with_inherited_auth_and_variables() {
+        let collection: WorkspaceCollection = serde_json::from_value(serde_json::json!({
+            "v": 4,
+            "name": "API",
+            "auth": { "authType": "bearer", "authActive": true, "token": "<<token>>" },
+            "headers": [{ "key": "X-Team", "value": "core", "active": true }],
+            "folders": [{
+                "name": "Users",
+                "auth": { "authType": "inherit", "authActive": true },
+                "headers": [{ "key": "x-team", "value": "users", "active": true }],
+                "requests": [{
+                    "v": "8",
+                    "name": "Create",
+                    "method": "post",
+                    "endpoint": "{{baseUrl}}/users",
+                    "params": [
+                        { "key": "dry run", "value": "yes", "active": true },
+                        { "key": "off", "value": "1", "active": false }
+                    ],
+                    "headers": [],
+                    "body": { "contentType": "application/json", "body": "{\"name\":\"<<name>>\"}" },
+                    "auth": { "authType": "inherit", "authActive": true },
+                    "requestVariables": [{ "key": "name", "value": "Ada", "active": true }]
+                }]
+            }],
+            "requests": []
+        }))
+        .unwrap();
+
+        let mut planned = Vec::new();
+        plan_requests(
+            &collection,
+            &Inherited::default(),
+            &mut Vec::new(),
+            &mut planned,
+        );
+        assert_eq!(planned.len(), 1);
+        assert_eq!(planned[0].folder, vec!["Users".to_string()]);
+
+        let environment: WorkspaceEnvironment = serde_json::from_value(serde_json::json!({
+            "v": 1,
+            "id": "env",
+            "name": "Staging",
+            "variables": [
+                { "key": "baseUrl", "value": "<<host>>/v1", "secret": false },
+                { "key": "host", "value": "http://api.test", "secret": false },
+                { "key": "token", "secret": true }
+            ]
+        }))
+        .unwrap();
+        let variables = request_variables(&planned[0], &[pair("token", "t0k")], Some(&environment));
+        let req = build_request(0, &planned[0], &variables).unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.endpoint, "http://api.test/v1/users?dry%20run=yes");
+
+        let headers = req
+            .headers
+            .iter()
+            .map(|header| (header.key.as_str(), header.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers,
+            vec![
+                ("x-team", "users"),
+                ("Authorization", "Bearer t0k"),
+                ("Content-Type", "application/json"),
+            ]
+        );
+        assert!(matches!(
+            req.body,
+            Some(BodyDef::Text(ref text)) if text == "{\"name\":\"Ada\"}"
+        ));
+    }
+
+    #[test]
+    fn checks_expectations_and_renders_junit() {
+        let expectations: Expectations = serde_json::from_value(serde_json::json!({
+            "defaults": { "maxTimeMs": 1000 },
+            "requests": {
+                "API/Users/Create": {
+                    "status": [201, "200-204"],
+                    "bodyContains": ["<<name>>"],
+                    "json": { "/id": 7 }
+                },
+                "API/Health": { "status": "2xx" }
+            }
+        }))
+        .unwrap();
+
+        let create = expectations.for_request("API/Users/Create");
+        assert_eq!(create.status, vec!["201", "200-204"]);
+        assert_eq!(create.max_time_ms, Some(1000.0));
+
+        let variables = [pair("name", "Ada")];
+        assert!(check_expectation(
+            &create,
+            false,
+            &variables,
+            203,
+            br#"{"id":7,"name":"Ada"}"#,
+            5.0
+        )
+        .is_empty());
+        assert_eq!(
+            check_expectation(&create, false, &variables, 500, br#"{"id":8}"#, 2000.0),
+            vec![
+                "Expected status 201, 200-204, got 500",
+                "Expected body to contain '<<name>>'",
+                "Expected /id to be 7, got 8",
+                "Expected a response within 1000 ms, took 2000 ms",
+            ]
+        );
+        assert!(!status_matches("2xx", 301));
+        let other = expectations.for_request("API/Other");
+        assert!(check_expectation(&other, false, &[], 404, b"", 1.0)
+            .contains(&"Expected status below 400, got 404".to_string()));
+        // A `Status { expected: "404" }` assertion decides on its own.
+        assert!(check_expectation(&other, true, &[], 404, b"", 1.0).is_empty());
+
+        let report = RunReport {
+            collection: "API".to_string(),
+            total: 2,
+            passed: 1,
+            failed: 1,
+            requests: vec![
+                RequestReport {
+                    folder: vec!["Users".to_string()],
+                    name: "Create <new>".to_string(),
+                    method: "POST".to_string(),
+                    url: "http://api.test/users".to_string(),
+                    outcome: RequestOutcome::Failed,
+                    status: Some(500),
+                    duration_ms: 12.0,
+                    failures: vec!["Expected status 201, got 500".to_string()],
+                    error: None,
+                    assertions: None,
+                },
+                RequestReport {
+                    folder: Vec::new(),
+                    name: "Health".to_string(),
+                    method: "GET".to_string(),
+                    url: "http://api.test/health".to_string(),
+                    outcome: RequestOutcome::Passed,
+                    status: Some(200),
+                    duration_ms: 3.0,
+                    failures: Vec::new(),
+                    error: None,
+                    assertions: None,
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(!report.success());
+
+        let junit = junit_xml(std::slice::from_ref(&report));
+        assert!(junit.contains(
+            "<testcase classname=\"API/Users\" name=\"Create &lt;new&gt;\" time=\"0.012\">"
+        ));
+        assert!(junit.contains("<failure message=\"Expected status 201, got 500\""));
+        assert!(junit.contains("<testcase classname=\"API\" name=\"Health\" time=\"0.003\"/>"));
+    }
+}
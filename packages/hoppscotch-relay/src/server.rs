@@ -0,0 +1,165 @@
+//! Server side of HTTP/1.1 for the local endpoints the relay offers, the
+//! agent and the mock server. Every connection carries a single request and
+//! is closed after the response.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+const MAX_HEADER_BYTES: usize = 64 * 1024;
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+/// Time allowed for a client to send its request.
+pub(crate) const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    /// Request target as sent, including the query string.
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub(crate) fn empty(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub(crate) fn json(status: u16, value: &impl serde::Serialize) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self {
+                status,
+                headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+                body,
+            },
+            Err(err) => {
+                log::error!("Failed to serialize response: {}", err);
+                Self::empty(500)
+            }
+        }
+    }
+}
+
+/// Reads one request, or the status to answer a malformed one with.
+pub(crate) fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, u16> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 8192];
+
+    let (header_len, mut request) = loop {
+        let read = stream.read(&mut chunk).map_err(|_| 408u16)?;
+        if read == 0 {
+            return Err(400);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&buffer) {
+            Ok(httparse::Status::Complete(header_len)) => {
+                let request = HttpRequest {
+                    method: parsed.method.unwrap_or_default().to_string(),
+                    path: parsed.path.unwrap_or_default().to_string(),
+                    headers: parsed
+                        .headers
+                        .iter()
+                        .map(|header| {
+                            (
+                                header.name.to_string(),
+                                String::from_utf8_lossy(header.value).into_owned(),
+                            )
+                        })
+                        .collect(),
+                    body: Vec::new(),
+                };
+                break (header_len, request);
+            }
+            Ok(httparse::Status::Partial) if buffer.len() < MAX_HEADER_BYTES => {}
+            Ok(httparse::Status::Partial) => return Err(431),
+            Err(_) => return Err(400),
+        }
+    };
+
+    if request.header("transfer-encoding").is_some() {
+        return Err(411);
+    }
+    let content_length = match request.header("content-length") {
+        Some(value) => value.trim().parse::<usize>().map_err(|_| 400u16)?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_BYTES {
+        return Err(413);
+    }
+
+    let mut body = buffer.split_off(header_len);
+    body.truncate(content_length);
+    while body.len() < content_length {
+        let read = stream.read(&mut chunk).map_err(|_| 408u16)?;
+        if read == 0 {
+            return Err(400);
+        }
+        body.extend_from_slice(&chunk[..read.min(content_length - body.len())]);
+    }
+    request.body = body;
+
+    Ok(request)
+}
+
+/// Writes `response` and closes the connection. `Content-Length` and
+/// `Connection` are always set here, the same headers in `response` are
+/// dropped.
+pub(crate) fn write_response(
+    stream: &mut TcpStream,
+    response: &HttpResponse,
+    head_only: bool,
+) -> std::io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        crate::util::get_status_text(response.status),
+        response.body.len()
+    );
+    for (key, value) in &response.headers {
+        if ["content-length", "connection", "transfer-encoding"]
+            .iter()
+            .any(|name| key.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes())?;
+    if !head_only {
+        stream.write_all(&response.body)?;
+    }
+    stream.flush()
+}
+
+pub(crate) fn is_loopback_host(host: &str) -> bool {
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => host,
+        _ => host,
+    };
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
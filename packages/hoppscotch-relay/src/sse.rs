@@ -0,0 +1,699 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    engine::RelayEngine,
+    error::{RelayError, RelayResult},
+    interop::{KeyValuePair, RequestWithMetadata},
+    relay::BodySink,
+};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SseOptions {
+    /// Reconnect when the stream ends or the connection drops.
+    pub reconnect: bool,
+    /// Delay before reconnecting until the server sends a `retry` field.
+    pub default_retry_ms: u64,
+    /// Give up after this many reconnects in a row. `None` keeps trying.
+    pub max_reconnects: Option<u32>,
+    /// Sent as `Last-Event-ID` on the first connection, to resume a stream.
+    pub last_event_id: Option<String>,
+}
+
+impl Default for SseOptions {
+    fn default() -> Self {
+        Self {
+            reconnect: true,
+            default_retry_ms: 3000,
+            max_reconnects: None,
+            last_event_id: None,
+        }
+    }
+}
+
+/// A dispatched server-sent event.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SseEvent {
+    /// Event type, `message` unless the server set one.
+    pub event: String,
+    pub data: String,
+    /// The last event ID at the time this event was dispatched.
+    pub id: String,
+}
+
+/// What a running stream reports, in order.
+#[derive(Clone, Debug, Serialize)]
+pub enum SseStreamEvent {
+    Open {
+        status: u16,
+        headers: Vec<KeyValuePair>,
+    },
+    Event(SseEvent),
+    /// The server changed the reconnection delay.
+    Retry(u64),
+    Reconnecting {
+        delay_ms: u64,
+        last_event_id: String,
+    },
+    /// The stream is over and won't reconnect.
+    Closed {
+        reason: String,
+    },
+}
+
+/// Incremental `text/event-stream` parser.
+///
+/// Follows the WHATWG event stream interpretation rules. Chunks may split
+/// lines, and even multi-byte characters, at any point.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+    started: bool,
+    skip_newline: bool,
+    event: String,
+    data: String,
+    has_data: bool,
+    last_event_id: String,
+    retry: Option<u64>,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from a known last event ID, e.g. after a reconnect.
+    pub fn with_last_event_id(last_event_id: String) -> Self {
+        Self {
+            last_event_id,
+            ..Self::default()
+        }
+    }
+
+    pub fn last_event_id(&self) -> &str {
+        &self.last_event_id
+    }
+
+    /// Returns a `retry` value received since the last call, if any.
+    pub fn take_retry(&mut self) -> Option<u64> {
+        self.retry.take()
+    }
+
+    /// Feeds the next chunk of the stream and returns every event it completes.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+
+        for &byte in chunk {
+            if self.skip_newline {
+                self.skip_newline = false;
+                if byte == b'\n' {
+                    continue;
+                }
+            }
+
+            match byte {
+                b'\r' => {
+                    self.skip_newline = true;
+                    self.end_line(&mut events);
+                }
+                b'\n' => self.end_line(&mut events),
+                _ => self.buffer.push(byte),
+            }
+        }
+
+        events
+    }
+
+    fn end_line(&mut self, events: &mut Vec<SseEvent>) {
+        let raw = std::mem::take(&mut self.buffer);
+        let mut line = String::from_utf8_lossy(&raw).into_owned();
+
+        if !self.started {
+            self.started = true;
+            if let Some(stripped) = line.strip_prefix('\u{feff}') {
+                line = stripped.to_string();
+            }
+        }
+
+        if line.is_empty() {
+            self.dispatch(events);
+            return;
+        }
+
+        if line.starts_with(':') {
+            return;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_str(), ""),
+        };
+
+        match field {
+            "event" => self.event = value.to_string(),
+            "data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value);
+                self.has_data = true;
+            }
+            "id" if !value.contains('\0') => self.last_event_id = value.to_string(),
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                self.retry = value.parse().ok();
+            }
+            _ => {}
+        }
+    }
+
+    fn dispatch(&mut self, events: &mut Vec<SseEvent>) {
+        let event = std::mem::take(&mut self.event);
+        let data = std::mem::take(&mut self.data);
+
+        if !std::mem::take(&mut self.has_data) {
+            return;
+        }
+
+        events.push(SseEvent {
+            event: if event.is_empty() {
+                "message".to_string()
+            } else {
+                event
+            },
+            data,
+            id: self.last_event_id.clone(),
+        });
+    }
+}
+
+/// Connects to an event stream and reports events as they arrive, until the
+/// server stops the stream, reconnecting is exhausted or `cancel_token` fires.
+///
+/// Each connection runs as a streaming transfer on `engine`, so the request's
+/// certificate and proxy settings apply, the stream is listed by
+/// [`RelayEngine::active_requests`] and [`RelayEngine::cancel_all`] ends it.
+/// A `204` answer ends the stream with `Ok`, per the spec.
+pub async fn run_sse_stream<F>(
+    engine: &RelayEngine,
+    req: &RequestWithMetadata,
+    options: &SseOptions,
+    cancel_token: CancellationToken,
+    on_event: F,
+) -> RelayResult<()>
+where
+    F: FnMut(SseStreamEvent) + Send + 'static,
+{
+    let on_event = Arc::new(Mutex::new(on_event));
+    let emit = |event: SseStreamEvent| {
+        if let Ok(mut on_event) = on_event.lock() {
+            on_event(event);
+        }
+    };
+
+    let mut last_event_id = options.last_event_id.clone().unwrap_or_default();
+    let mut retry_ms = options.default_retry_ms;
+    let mut reconnects = 0;
+
+    loop {
+        let outcome =
+            run_connection(engine, req, &last_event_id, cancel_token.clone(), &on_event).await;
+
+        if cancel_token.is_cancelled() || matches!(outcome, Err(RelayError::RequestCancelled)) {
+            emit(SseStreamEvent::Closed {
+                reason: "Cancelled".to_string(),
+            });
+            return Err(RelayError::RequestCancelled);
+        }
+
+        let reason = match outcome {
+            Ok(connection) => {
+                last_event_id = connection.last_event_id;
+                if let Some(retry) = connection.retry {
+                    retry_ms = retry;
+                }
+                if connection.received_events {
+                    reconnects = 0;
+                }
+                match connection.end {
+                    ConnectionEnd::Ended => "Stream ended".to_string(),
+                    ConnectionEnd::Dropped(reason) => reason,
+                    ConnectionEnd::Stopped(reason) => {
+                        log::info!("Event stream {} stopped: {}", req.endpoint, reason);
+                        emit(SseStreamEvent::Closed { reason });
+                        return Ok(());
+                    }
+                    ConnectionEnd::Failed(reason) => {
+                        log::warn!("Event stream {} failed: {}", req.endpoint, reason);
+                        emit(SseStreamEvent::Closed {
+                            reason: reason.clone(),
+                        });
+                        return Err(RelayError::RequestRunError(reason));
+                    }
+                }
+            }
+            Err(err) => {
+                emit(SseStreamEvent::Closed {
+                    reason: err.to_string(),
+                });
+                return Err(err);
+            }
+        };
+
+        let exhausted = options
+            .max_reconnects
+            .is_some_and(|max_reconnects| reconnects >= max_reconnects);
+        if !options.reconnect || exhausted {
+            emit(SseStreamEvent::Closed { reason });
+            return Ok(());
+        }
+
+        reconnects += 1;
+        log::info!(
+            "Reconnecting to event stream {} in {}ms ({})",
+            req.endpoint,
+            retry_ms,
+            reason
+        );
+        emit(SseStreamEvent::Reconnecting {
+            delay_ms: retry_ms,
+            last_event_id: last_event_id.clone(),
+        });
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(retry_ms)) => {}
+            _ = cancel_token.cancelled() => {
+                emit(SseStreamEvent::Closed {
+                    reason: "Cancelled".to_string(),
+                });
+                return Err(RelayError::RequestCancelled);
+            }
+        }
+    }
+}
+
+enum ConnectionEnd {
+    /// The server closed a valid stream, reconnecting is allowed.
+    Ended,
+    /// The connection failed or dropped mid-stream, reconnecting is allowed.
+    Dropped(String),
+    /// The server answered `204`, the stream is over without an error.
+    Stopped(String),
+    /// The response wasn't an event stream, reconnecting must not happen.
+    Failed(String),
+}
+
+struct ConnectionOutcome {
+    end: ConnectionEnd,
+    last_event_id: String,
+    retry: Option<u64>,
+    received_events: bool,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    parser: Option<SseParser>,
+    rejected: Option<ConnectionEnd>,
+    retry: Option<u64>,
+    received_events: bool,
+}
+
+async fn run_connection<F>(
+    engine: &RelayEngine,
+    req: &RequestWithMetadata,
+    last_event_id: &str,
+    cancel_token: CancellationToken,
+    on_event: &Arc<Mutex<F>>,
+) -> RelayResult<ConnectionOutcome>
+where
+    F: FnMut(SseStreamEvent) + Send + 'static,
+{
+    let mut req = req.clone();
+    set_header_if_missing(&mut req.headers, "Accept", "text/event-stream");
+    set_header_if_missing(&mut req.headers, "Cache-Control", "no-cache");
+    req.headers
+        .retain(|header| !header.key.eq_ignore_ascii_case("last-event-id"));
+    if !last_event_id.is_empty() {
+        req.headers.push(KeyValuePair {
+            key: "Last-Event-ID".to_string(),
+            value: last_event_id.to_string(),
+        });
+    }
+
+    let state = Arc::new(Mutex::new(ConnectionState::default()));
+
+    let sink_state = state.clone();
+    let sink_on_event = on_event.clone();
+    let initial_event_id = last_event_id.to_string();
+    let body_sink: BodySink = Box::new(move |status, headers, data| {
+        let (Ok(mut state), Ok(mut on_event)) = (sink_state.lock(), sink_on_event.lock()) else {
+            return false;
+        };
+
+        if state.parser.is_none() {
+            if let Err(end) = check_stream_response(status, headers) {
+                state.rejected = Some(end);
+                return false;
+            }
+            on_event(SseStreamEvent::Open {
+                status: status.unwrap_or_default(),
+                headers: headers.to_vec(),
+            });
+            state.parser = Some(SseParser::with_last_event_id(initial_event_id.clone()));
+        }
+
+        let Some(parser) = state.parser.as_mut() else {
+            return false;
+        };
+        let events = parser.feed(data);
+        let retry = parser.take_retry();
+
+        if let Some(retry) = retry {
+            state.retry = Some(retry);
+            on_event(SseStreamEvent::Retry(retry));
+        }
+        if !events.is_empty() {
+            state.received_events = true;
+        }
+        for event in events {
+            on_event(SseStreamEvent::Event(event));
+        }
+        true
+    });
+
+    log::info!("Opening event stream: {}", req.endpoint);
+    let result = engine.execute_streaming(req, body_sink, cancel_token).await;
+
+    let mut state = state
+        .lock()
+        .map_err(|_| RelayError::RequestRunError("Event stream state poisoned".to_string()))?;
+
+    if let Some(end) = state.rejected.take() {
+        return Ok(ConnectionOutcome {
+            end,
+            last_event_id: last_event_id.to_string(),
+            retry: None,
+            received_events: false,
+        });
+    }
+
+    let response = match result {
+        Ok(response) => response,
+        Err(err @ (RelayError::ConnectionFailed(_) | RelayError::Timeout(_))) => {
+            let end = ConnectionEnd::Dropped(err.to_string());
+            return Ok(match state.parser.take() {
+                Some(parser) => ConnectionOutcome {
+                    end,
+                    last_event_id: parser.last_event_id().to_string(),
+                    retry: state.retry,
+                    received_events: state.received_events,
+                },
+                None => ConnectionOutcome {
+                    end,
+                    last_event_id: last_event_id.to_string(),
+                    retry: None,
+                    received_events: false,
+                },
+            });
+        }
+        Err(err) => return Err(err),
+    };
+
+    let Some(parser) = state.parser.take() else {
+        // The server answered without sending any body at all.
+        let end = match check_stream_response(Some(response.status), &response.headers) {
+            Ok(()) => ConnectionEnd::Ended,
+            Err(end) => end,
+        };
+        return Ok(ConnectionOutcome {
+            end,
+            last_event_id: last_event_id.to_string(),
+            retry: None,
+            received_events: false,
+        });
+    };
+
+    Ok(ConnectionOutcome {
+        end: ConnectionEnd::Ended,
+        last_event_id: parser.last_event_id().to_string(),
+        retry: state.retry,
+        received_events: state.received_events,
+    })
+}
+
+/// Per the spec, only a `200` with a `text/event-stream` body is a stream
+/// that may be reconnected to. `204` tells the client to stop.
+fn check_stream_response(
+    status: Option<u16>,
+    headers: &[KeyValuePair],
+) -> Result<(), ConnectionEnd> {
+    match status {
+        Some(200) => {}
+        Some(204) => {
+            return Err(ConnectionEnd::Stopped(
+                "Server asked to stop reconnecting (204)".to_string(),
+            ))
+        }
+        Some(status) => {
+            return Err(ConnectionEnd::Failed(format!(
+                "Unexpected status {}",
+                status
+            )))
+        }
+        None => return Err(ConnectionEnd::Failed("Missing response status".to_string())),
+    }
+
+    let content_type = headers
+        .iter()
+        .rev()
+        .find(|header| header.key.eq_ignore_ascii_case("content-type"))
+        .map(|header| header.value.to_lowercase());
+
+    match content_type {
+        Some(value) if value.starts_with("text/event-stream") => Ok(()),
+        Some(value) => Err(ConnectionEnd::Failed(format!(
+            "Unexpected content type '{}'",
+            value
+        ))),
+        None => Err(ConnectionEnd::Failed("Missing content type".to_string())),
+    }
+}
+
+fn set_header_if_missing(headers: &mut Vec<KeyValuePair>, key: &str, value: &str) {
+    if !headers
+        .iter()
+        .any(|header| header.key.eq_ignore_ascii_case(key))
+    {
+        headers.push(KeyValuePair {
+            key: key.to_string(),
+            value: value.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineConfig;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn parses_events_split_across_chunks() {
+        let mut parser = SseParser::new();
+
+        assert!(parser.feed(b"event: up").is_empty());
+        assert!(parser.feed(b"date\ndata: first\r\nda").is_empty());
+        let events = parser.feed(b"ta: second\r\nid: 7\n\n");
+
+        assert_eq!(
+            events,
+            vec![SseEvent {
+                event: "update".to_string(),
+                data: "first\nsecond".to_string(),
+                id: "7".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn ignores_comments_and_keeps_last_event_id() {
+        let mut parser = SseParser::with_last_event_id("3".to_string());
+
+        let events = parser.feed(b"\xef\xbb\xbf: keep-alive\n\ndata\n\ndata:x\rretry: 1500\r\r");
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data, "");
+        assert_eq!(events[0].event, "message");
+        assert_eq!(events[1].id, "3");
+        assert_eq!(parser.take_retry(), Some(1500));
+    }
+
+    /// Sends one event on each of the first two connections and cuts them
+    /// off mid-stream, then answers `204`. Returns the request heads.
+    fn spawn_dropping_server() -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for id in 1..=3 {
+                let (mut socket, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8_lossy(&request).into_owned());
+
+                if id == 3 {
+                    socket
+                        .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                        .unwrap();
+                    continue;
+                }
+                let event = format!("id: {}\ndata: event {}\n\n", id, id);
+                write!(
+                    socket,
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                     Transfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n",
+                    event.len(),
+                    event
+                )
+                .unwrap();
+                // Closing without the last chunk cuts the stream off.
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    fn get(url: String) -> RequestWithMetadata {
+        RequestWithMetadata::new(
+            0,
+            "GET".to_string(),
+            url,
+            Vec::new(),
+            None,
+            true,
+            Vec::new(),
+            None,
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn reconnects_with_last_event_id_after_a_dropped_stream() {
+        let (url, server) = spawn_dropping_server();
+        let engine = RelayEngine::new(EngineConfig::default()).unwrap();
+        let req = get(url);
+        // Receiving events resets the count, so one reconnect in a row is
+        // enough for both drops.
+        let options = SseOptions {
+            default_retry_ms: 10,
+            max_reconnects: Some(1),
+            ..Default::default()
+        };
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let result = run_sse_stream(&engine, &req, &options, CancellationToken::new(), {
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event)
+        })
+        .await;
+        // `204` stops the stream without it being an error.
+        assert!(result.is_ok(), "{:?}", result);
+
+        let requests = server.join().unwrap();
+        let last_event_ids: Vec<_> = requests
+            .iter()
+            .map(|request| {
+                request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Last-Event-ID: "))
+                    .map(str::to_string)
+            })
+            .collect();
+        assert_eq!(
+            last_event_ids,
+            [None, Some("1".to_string()), Some("2".to_string())]
+        );
+
+        let events = events.lock().unwrap();
+        let reconnects: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                SseStreamEvent::Reconnecting { last_event_id, .. } => Some(last_event_id.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reconnects, ["1", "2"]);
+        assert!(matches!(
+            events.last(),
+            Some(SseStreamEvent::Closed { reason }) if reason.contains("204")
+        ));
+    }
+
+    #[tokio::test]
+    async fn runs_on_the_engine_and_ends_on_cancel_all() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = socket.read(&mut buf).unwrap();
+            let event = "data: hello\n\n";
+            write!(
+                socket,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                 Transfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n",
+                event.len(),
+                event
+            )
+            .unwrap();
+            // Holds the stream open until the client goes away.
+            while socket.read(&mut buf).is_ok_and(|n| n > 0) {}
+        });
+
+        let engine = RelayEngine::new(EngineConfig::default()).unwrap();
+        let (opened_tx, opened_rx) = tokio::sync::oneshot::channel();
+        let opened_tx = Mutex::new(Some(opened_tx));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let req = get(url.clone());
+        let options = SseOptions::default();
+        let stream = run_sse_stream(&engine, &req, &options, CancellationToken::new(), {
+            let events = events.clone();
+            move |event| {
+                if let SseStreamEvent::Event(_) = event {
+                    if let Some(opened_tx) = opened_tx.lock().unwrap().take() {
+                        let _ = opened_tx.send(());
+                    }
+                }
+                events.lock().unwrap().push(event);
+            }
+        });
+
+        let control = async {
+            opened_rx.await.unwrap();
+            let active = engine.active_requests().await.unwrap();
+            assert_eq!(active.len(), 1);
+            assert_eq!(active[0].url, url);
+            assert_eq!(engine.cancel_all().await.unwrap(), 1);
+        };
+
+        let (result, ()) = tokio::join!(stream, control);
+        assert!(matches!(result, Err(RelayError::RequestCancelled)));
+        server.join().unwrap();
+
+        let events = events.lock().unwrap();
+        assert!(matches!(
+            events.last(),
+            Some(SseStreamEvent::Closed { reason }) if reason == "Cancelled"
+        ));
+    }
+}
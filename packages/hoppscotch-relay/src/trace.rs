@@ -0,0 +1,224 @@
+//! Wire level trace of a transfer, built from curl's debug callback.
+//!
+//! Mirrors what `curl -v` prints: informational notes about name
+//! resolution, connection reuse and the TLS handshake, the request headers
+//! exactly as sent and the raw response headers. Body and TLS record bytes
+//! are summarised by size only. Credentials are redacted before they are
+//! recorded.
+
+use std::time::Instant;
+
+use curl::easy::InfoType;
+use serde::{Deserialize, Serialize};
+
+/// Upper bound for the recorded trace text of a single transfer.
+const TRACE_LIMIT_BYTES: usize = 256 * 1024;
+
+/// Headers whose values never end up in a trace.
+pub(crate) const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TraceKind {
+    /// Notes from curl, `*` in `curl -v` output.
+    Info,
+    /// Request headers as sent, `>`.
+    HeaderOut,
+    /// Response headers as received, `<`.
+    HeaderIn,
+    /// Request body bytes sent.
+    DataOut,
+    /// Response body bytes received.
+    DataIn,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TraceEntry {
+    /// Milliseconds since the transfer started.
+    pub elapsed_ms: f64,
+    pub kind: TraceKind,
+    pub text: String,
+}
+
+pub(crate) struct TraceRecorder {
+    started: Instant,
+    entries: Vec<TraceEntry>,
+    recorded_bytes: usize,
+    /// Byte count of the data entry at the end of `entries`, so consecutive
+    /// chunks are merged into one line.
+    data_run: usize,
+    truncated: bool,
+}
+
+impl TraceRecorder {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            entries: Vec::new(),
+            recorded_bytes: 0,
+            data_run: 0,
+            truncated: false,
+        }
+    }
+
+    pub(crate) fn record(&mut self, kind: InfoType, data: &[u8]) {
+        if self.truncated {
+            return;
+        }
+
+        let kind = match kind {
+            InfoType::Text => TraceKind::Info,
+            InfoType::HeaderOut => TraceKind::HeaderOut,
+            InfoType::HeaderIn => TraceKind::HeaderIn,
+            InfoType::DataOut => TraceKind::DataOut,
+            InfoType::DataIn => TraceKind::DataIn,
+            // Encrypted records, the handshake itself is reported as text.
+            _ => return,
+        };
+
+        if matches!(kind, TraceKind::DataOut | TraceKind::DataIn) {
+            if let Some(last) = self.entries.last_mut().filter(|last| last.kind == kind) {
+                self.data_run += data.len();
+                last.text = format!("[{} bytes data]", self.data_run);
+                return;
+            }
+            self.data_run = data.len();
+            self.push(kind, format!("[{} bytes data]", data.len()));
+            return;
+        }
+
+        let text = String::from_utf8_lossy(data);
+        let text = text.lines().map(redact_line).collect::<Vec<_>>().join("\n");
+        let text = text.trim_end();
+        if !text.is_empty() {
+            self.push(kind, text.to_string());
+        }
+    }
+
+    fn push(&mut self, kind: TraceKind, text: String) {
+        let elapsed_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+
+        self.recorded_bytes += text.len();
+        if self.recorded_bytes > TRACE_LIMIT_BYTES {
+            self.truncated = true;
+            self.entries.push(TraceEntry {
+                elapsed_ms,
+                kind: TraceKind::Info,
+                text: format!("Trace truncated after {} bytes", TRACE_LIMIT_BYTES),
+            });
+            return;
+        }
+
+        self.entries.push(TraceEntry {
+            elapsed_ms,
+            kind,
+            text,
+        });
+    }
+
+    pub(crate) fn finish(self) -> Vec<TraceEntry> {
+        self.entries
+    }
+}
+
+/// Redacts credentials in a header line, `Authorization: Basic abc`, or in
+/// curl's notes on HTTP/2 and HTTP/3 headers, `[authorization: Basic abc]`.
+fn redact_line(line: &str) -> String {
+    let lower = line.to_ascii_lowercase();
+
+    for name in REDACTED_HEADERS {
+        if lower.starts_with(name) && lower[name.len()..].starts_with(':') {
+            return format!(
+                "{}: {}",
+                &line[..name.len()],
+                redacted_value(name, &line[name.len() + 1..])
+            );
+        }
+
+        let marker = format!("[{}: ", name);
+        if let Some(start) = lower.find(&marker) {
+            let value_start = start + marker.len();
+            let value_end = line
+                .rfind(']')
+                .filter(|end| *end >= value_start)
+                .unwrap_or(line.len());
+            return format!(
+                "{}{}{}",
+                &line[..value_start],
+                redacted_value(name, &line[value_start..value_end]),
+                &line[value_end..]
+            );
+        }
+    }
+
+    line.to_string()
+}
+
+/// Keeps the scheme of credentials, `Bearer [redacted]`, to still tell them apart.
+pub(crate) fn redacted_value(name: &str, value: &str) -> String {
+    let value = value.trim();
+    match value.split_once(' ') {
+        Some((scheme, _)) if name.ends_with("authorization") => format!("{} [redacted]", scheme),
+        _ => "[redacted]".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_credentials() {
+        let mut recorder = TraceRecorder::new();
+        recorder.record(
+            InfoType::HeaderOut,
+            b"GET / HTTP/1.1\r\nHost: example.com\r\nAuthorization: Bearer secret\r\n\
+              cookie: session=abc\r\nX-Other: visible\r\n\r\n",
+        );
+        recorder.record(
+            InfoType::Text,
+            b"[HTTP/2] [1] [proxy-authorization: Basic c2VjcmV0]\n",
+        );
+        recorder.record(InfoType::HeaderIn, b"Set-Cookie: id=7; Path=/\r\n");
+
+        let entries = recorder.finish();
+        assert_eq!(
+            entries[0].text,
+            "GET / HTTP/1.1\nHost: example.com\nAuthorization: Bearer [redacted]\n\
+             cookie: [redacted]\nX-Other: visible"
+        );
+        assert_eq!(
+            entries[1].text,
+            "[HTTP/2] [1] [proxy-authorization: Basic [redacted]]"
+        );
+        assert_eq!(entries[2].text, "Set-Cookie: [redacted]");
+        assert!(entries.iter().all(|entry| !entry.text.contains("secret")));
+    }
+
+    #[test]
+    fn merges_data_and_stops_at_the_limit() {
+        let mut recorder = TraceRecorder::new();
+        recorder.record(InfoType::DataIn, &[0; 100]);
+        recorder.record(InfoType::DataIn, &[0; 50]);
+        recorder.record(InfoType::SslDataIn, &[0; 10]);
+        recorder.record(
+            InfoType::Text,
+            b"Connection #0 to host example.com left intact\n",
+        );
+        for _ in 0..TRACE_LIMIT_BYTES / 1000 + 1 {
+            recorder.record(InfoType::Text, &[b'x'; 1000]);
+        }
+        recorder.record(InfoType::Text, b"after the limit\n");
+
+        let entries = recorder.finish();
+        assert_eq!(entries[0].kind, TraceKind::DataIn);
+        assert_eq!(entries[0].text, "[150 bytes data]");
+        assert_eq!(entries[1].kind, TraceKind::Info);
+        assert!(entries.last().unwrap().text.starts_with("Trace truncated"));
+        assert!(entries.iter().all(|entry| entry.text != "after the limit"));
+    }
+}
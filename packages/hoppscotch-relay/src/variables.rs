@@ -0,0 +1,280 @@
+//! Variable substitution for request templates.
+//!
+//! Variables are written as `<<name>>` or `{{name}}` and may refer to other
+//! variables. Names starting with `$` are dynamic values generated for each
+//! occurrence:
+//!
+//! - `$timestamp`, Unix time in seconds.
+//! - `$isoTimestamp`, the current time as ISO 8601.
+//! - `$guid` and `$uuid`, a random version 4 UUID.
+//! - `$randomInt`, an integer from 0 to 1000.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    har::iso8601,
+    interop::{BodyDef, ClientCertDef, FormDataValue, KeyValuePair, RequestWithMetadata},
+    relay::now_ms,
+};
+
+/// How often variables referencing other variables are expanded.
+const MAX_EXPAND_DEPTH: usize = 10;
+
+/// A named set of variables such as the global, collection or request
+/// variables.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct VariableScope {
+    pub name: String,
+    pub variables: Vec<KeyValuePair>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResolvedRequest {
+    pub request: RequestWithMetadata,
+    /// Names of the variables no scope defines, in order of appearance. They
+    /// are left in the request as written.
+    pub unresolved: Vec<String>,
+}
+
+/// Substitutes variables in the URL, headers, body, proxy URL, `resolve`
+/// overrides and PFX password of `template`. `scopes` go from least to most
+/// specific, e.g. global, collection, request, and a later scope wins.
+///
+/// Certificates are part of the request as file contents, not paths, and
+/// are passed through unchanged.
+pub fn resolve_request(
+    template: &RequestWithMetadata,
+    scopes: &[VariableScope],
+) -> ResolvedRequest {
+    let mut resolver = Resolver {
+        variables: scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.variables.iter().cloned())
+            .collect(),
+        unresolved: Vec::new(),
+    };
+
+    let mut request = template.clone();
+    request.endpoint = resolver.text(&template.endpoint);
+    request.headers = resolver.pairs(&template.headers);
+    if let Some(body) = &mut request.body {
+        match body {
+            BodyDef::Text(text) => *text = resolver.text(text),
+            BodyDef::URLEncoded(pairs) => *pairs = resolver.pairs(pairs),
+            BodyDef::FormData(entries) => {
+                for entry in entries {
+                    entry.key = resolver.text(&entry.key);
+                    if let FormDataValue::Text(text) = &mut entry.value {
+                        *text = resolver.text(text);
+                    }
+                }
+            }
+        }
+    }
+    for entry in &mut request.resolve {
+        *entry = resolver.text(entry);
+    }
+    if let Some(proxy) = &mut request.proxy {
+        proxy.url = resolver.text(&proxy.url);
+    }
+    if let Some(ClientCertDef::PFXCert { password, .. }) = &mut request.client_cert {
+        *password = resolver.text(password);
+    }
+
+    ResolvedRequest {
+        request,
+        unresolved: resolver.unresolved,
+    }
+}
+
+struct Resolver {
+    /// Most specific scope first.
+    variables: Vec<KeyValuePair>,
+    unresolved: Vec<String>,
+}
+
+impl Resolver {
+    fn text(&mut self, text: &str) -> String {
+        let resolved = substitute(text, &self.variables);
+        for name in placeholders(&resolved) {
+            if !self.unresolved.contains(&name) {
+                self.unresolved.push(name);
+            }
+        }
+        resolved
+    }
+
+    fn pairs(&mut self, pairs: &[KeyValuePair]) -> Vec<KeyValuePair> {
+        pairs
+            .iter()
+            .map(|pair| KeyValuePair {
+                key: self.text(&pair.key),
+                value: self.text(&pair.value),
+            })
+            .collect()
+    }
+}
+
+/// Replaces `<<name>>` and `{{name}}` with the first variable of that name
+/// or a dynamic value. Unknown variables are left as they are.
+pub(crate) fn substitute(text: &str, variables: &[KeyValuePair]) -> String {
+    let mut result = text.to_string();
+
+    for _ in 0..MAX_EXPAND_DEPTH {
+        let mut expanded = String::with_capacity(result.len());
+        let mut rest = result.as_str();
+
+        while let Some((start, open, close)) = next_placeholder(rest) {
+            let name_start = start + open.len();
+            let Some(len) = rest[name_start..].find(close) else {
+                break;
+            };
+            let name = rest[name_start..name_start + len].trim();
+            expanded.push_str(&rest[..start]);
+            match variables
+                .iter()
+                .find(|variable| variable.key == name)
+                .map(|variable| variable.value.clone())
+                .or_else(|| dynamic_value(name))
+            {
+                Some(value) => expanded.push_str(&value),
+                None => expanded.push_str(&rest[start..name_start + len + close.len()]),
+            }
+            rest = &rest[name_start + len + close.len()..];
+        }
+        expanded.push_str(rest);
+
+        if expanded == result {
+            break;
+        }
+        result = expanded;
+    }
+
+    result
+}
+
+fn next_placeholder(text: &str) -> Option<(usize, &'static str, &'static str)> {
+    [("<<", ">>"), ("{{", "}}")]
+        .into_iter()
+        .filter_map(|(open, close)| text.find(open).map(|start| (start, open, close)))
+        .min_by_key(|(start, _, _)| *start)
+}
+
+/// Names of the placeholders left in `text`.
+pub(crate) fn placeholders(text: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = text;
+    while let Some((start, open, close)) = next_placeholder(rest) {
+        let name_start = start + open.len();
+        let Some(len) = rest[name_start..].find(close) else {
+            break;
+        };
+        let name = rest[name_start..name_start + len].trim();
+        if !name.is_empty() {
+            names.push(name.to_string());
+        }
+        rest = &rest[name_start + len + close.len()..];
+    }
+    names
+}
+
+fn dynamic_value(name: &str) -> Option<String> {
+    let value = match name {
+        "$timestamp" => (now_ms() / 1000).to_string(),
+        "$isoTimestamp" => iso8601(now_ms()),
+        "$guid" | "$uuid" => uuid_v4(),
+        "$randomInt" => rand::thread_rng().gen_range(0..=1000).to_string(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+pub(crate) fn uuid_v4() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> KeyValuePair {
+        KeyValuePair {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn resolves_scopes_in_order_and_reports_unresolved() {
+        let scopes = [
+            VariableScope {
+                name: "global".to_string(),
+                variables: vec![
+                    pair("baseUrl", "https://api.example.com"),
+                    pair("version", "v1"),
+                ],
+            },
+            VariableScope {
+                name: "collection".to_string(),
+                variables: vec![
+                    pair("version", "v2"),
+                    pair("root", "{{baseUrl}}/{{version}}"),
+                ],
+            },
+            VariableScope {
+                name: "request".to_string(),
+                variables: vec![pair("id", "42")],
+            },
+        ];
+        let template = RequestWithMetadata::new(
+            0,
+            "POST".to_string(),
+            "<<root>>/users/{{id}}?t={{$timestamp}}".to_string(),
+            vec![
+                pair("Authorization", "Bearer {{token}}"),
+                pair("X-Request-Id", "{{$guid}}"),
+            ],
+            Some(BodyDef::Text(
+                r#"{"n": {{$randomInt}}, "missing": "{{ nope }}"}"#.to_string(),
+            )),
+            true,
+            Vec::new(),
+            None,
+            None,
+        );
+
+        let resolved = resolve_request(&template, &scopes);
+        let (url, query) = resolved.request.endpoint.split_once("?t=").unwrap();
+        assert_eq!(url, "https://api.example.com/v2/users/42");
+        assert!(query.parse::<u64>().is_ok());
+        assert_eq!(resolved.unresolved, vec!["token", "nope"]);
+        assert_eq!(resolved.request.headers[0].value, "Bearer {{token}}");
+
+        let guid = &resolved.request.headers[1].value;
+        assert_eq!(guid.len(), 36);
+        assert_eq!(&guid[14..15], "4");
+
+        let Some(BodyDef::Text(body)) = &resolved.request.body else {
+            panic!("expected a text body");
+        };
+        let body: serde_json::Value =
+            serde_json::from_str(&body.replace("{{ nope }}", "x")).unwrap();
+        assert!(body["n"].as_u64().is_some_and(|n| n <= 1000));
+    }
+}
@@ -0,0 +1,317 @@
+use serde::{Deserialize, Serialize};
+use std::{io::ErrorKind, sync::mpsc, thread, time::Duration};
+use tungstenite::{
+    client::IntoClientRequest,
+    http::{HeaderName, HeaderValue},
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message, WebSocket,
+};
+
+use crate::{
+    error::{RelayError, RelayResult},
+    interop::{KeyValuePair, RequestWithMetadata},
+    net::{self, NetStream},
+};
+
+/// How long the handshake may wait on the server before giving up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the connection thread stops reading to check for outgoing frames.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Frames that can be sent on an open connection.
+#[derive(Clone, Debug, Deserialize)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+}
+
+/// Everything that happens on a connection after the handshake, in order.
+#[derive(Clone, Debug, Serialize)]
+pub enum WebSocketEvent {
+    Connected {
+        protocol: Option<String>,
+        headers: Vec<KeyValuePair>,
+    },
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Closed {
+        code: u16,
+        reason: String,
+    },
+    Error(String),
+}
+
+enum ConnectionCommand {
+    Send(Message),
+    Close(Option<CloseFrame>),
+}
+
+/// Handle to an open WebSocket connection.
+///
+/// Incoming frames are read on a background thread and handed to the event
+/// callback given to [`WebSocketConnection::connect`]. Dropping the handle
+/// closes the connection with a normal closure.
+pub struct WebSocketConnection {
+    commands: mpsc::Sender<ConnectionCommand>,
+}
+
+impl WebSocketConnection {
+    /// Connects to `req.endpoint` (`ws://` or `wss://`) with the request's
+    /// headers, proxy and certificate settings. The method and body are ignored.
+    ///
+    /// Blocks until the handshake completes, so handshake failures are
+    /// returned here rather than reported as events.
+    pub fn connect<F>(
+        req: &RequestWithMetadata,
+        protocols: &[String],
+        mut on_event: F,
+    ) -> RelayResult<Self>
+    where
+        F: FnMut(WebSocketEvent) + Send + 'static,
+    {
+        let uri = req
+            .endpoint
+            .parse::<tungstenite::http::Uri>()
+            .map_err(|_| RelayError::InvalidUrl)?;
+        let tls = match uri.scheme_str() {
+            Some("wss") | Some("https") => true,
+            Some("ws") | Some("http") => false,
+            _ => return Err(RelayError::InvalidUrl),
+        };
+        let host = uri.host().ok_or(RelayError::InvalidUrl)?;
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+        let mut request = req
+            .endpoint
+            .as_str()
+            .into_client_request()
+            .map_err(|_| RelayError::InvalidUrl)?;
+        for KeyValuePair { key, value } in &req.headers {
+            let name =
+                HeaderName::from_bytes(key.as_bytes()).map_err(|_| RelayError::InvalidHeaders)?;
+            let value = HeaderValue::from_str(value).map_err(|_| RelayError::InvalidHeaders)?;
+            request.headers_mut().append(name, value);
+        }
+        if !protocols.is_empty() {
+            let value = HeaderValue::from_str(&protocols.join(", "))
+                .map_err(|_| RelayError::InvalidHeaders)?;
+            request
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", value);
+        }
+
+        let stream = net::connect(host, port, tls, req)?;
+        set_read_timeout(&stream, HANDSHAKE_TIMEOUT)?;
+
+        let (socket, response) = tungstenite::client(request, stream).map_err(|err| {
+            log::error!("WebSocket handshake with {} failed: {}", req.endpoint, err);
+            RelayError::RequestRunError(format!("WebSocket handshake failed: {}", err))
+        })?;
+        set_read_timeout(socket.get_ref(), POLL_INTERVAL)?;
+        log::info!("WebSocket connected: {}", req.endpoint);
+
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(key, value)| KeyValuePair {
+                key: key.to_string(),
+                value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            })
+            .collect();
+        let protocol = response
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        on_event(WebSocketEvent::Connected { protocol, headers });
+
+        let (commands_tx, commands_rx) = mpsc::channel();
+        let endpoint = req.endpoint.clone();
+        thread::Builder::new()
+            .name("relay-websocket".to_string())
+            .spawn(move || run_connection(socket, commands_rx, on_event, endpoint))
+            .map_err(|err| {
+                RelayError::RequestRunError(format!("Failed to start WebSocket reader: {}", err))
+            })?;
+
+        Ok(Self {
+            commands: commands_tx,
+        })
+    }
+
+    pub fn send(&self, message: WebSocketMessage) -> RelayResult<()> {
+        let message = match message {
+            WebSocketMessage::Text(text) => Message::text(text),
+            WebSocketMessage::Binary(data) => Message::binary(data),
+            WebSocketMessage::Ping(data) => Message::Ping(data.into()),
+        };
+        self.command(ConnectionCommand::Send(message))
+    }
+
+    /// Starts the closing handshake. A [`WebSocketEvent::Closed`] follows once
+    /// the server has answered.
+    pub fn close(&self, code: u16, reason: String) -> RelayResult<()> {
+        self.command(ConnectionCommand::Close(Some(CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.into(),
+        })))
+    }
+
+    fn command(&self, command: ConnectionCommand) -> RelayResult<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| RelayError::RequestRunError("WebSocket connection is closed".to_string()))
+    }
+}
+
+fn set_read_timeout(stream: &NetStream, timeout: Duration) -> RelayResult<()> {
+    stream
+        .tcp()
+        .set_read_timeout(Some(timeout))
+        .map_err(|err| RelayError::RequestRunError(format!("Failed to configure socket: {}", err)))
+}
+
+fn run_connection<F>(
+    mut socket: WebSocket<NetStream>,
+    commands: mpsc::Receiver<ConnectionCommand>,
+    mut on_event: F,
+    endpoint: String,
+) where
+    F: FnMut(WebSocketEvent),
+{
+    let mut closing = false;
+    let mut close_reported = false;
+
+    loop {
+        loop {
+            let result = match commands.try_recv() {
+                Ok(ConnectionCommand::Send(message)) => socket.send(message),
+                Ok(ConnectionCommand::Close(frame)) => {
+                    closing = true;
+                    socket.close(frame)
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    if closing {
+                        break;
+                    }
+                    closing = true;
+                    socket.close(None)
+                }
+            };
+
+            if let Err(err) = result {
+                log::warn!("WebSocket write to {} failed: {}", endpoint, err);
+                on_event(WebSocketEvent::Error(err.to_string()));
+            }
+        }
+
+        match socket.read() {
+            Ok(Message::Text(text)) => on_event(WebSocketEvent::Text(text.to_string())),
+            Ok(Message::Binary(data)) => on_event(WebSocketEvent::Binary(data.to_vec())),
+            Ok(Message::Ping(data)) => on_event(WebSocketEvent::Ping(data.to_vec())),
+            Ok(Message::Pong(data)) => on_event(WebSocketEvent::Pong(data.to_vec())),
+            Ok(Message::Close(frame)) => {
+                let (code, reason) = frame
+                    .map(|frame| (u16::from(frame.code), frame.reason.to_string()))
+                    .unwrap_or((1005, String::new()));
+                log::info!("WebSocket {} closed: {} {}", endpoint, code, reason);
+                close_reported = true;
+                on_event(WebSocketEvent::Closed { code, reason });
+            }
+            Ok(Message::Frame(_)) => {}
+            Err(tungstenite::Error::Io(err))
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                // Nothing to read right now, push out anything still queued
+                // (such as automatic pong replies) and go back to the commands.
+                if let Err(err) = socket.flush() {
+                    if !matches!(
+                        &err,
+                        tungstenite::Error::Io(io) if io.kind() == ErrorKind::WouldBlock
+                    ) {
+                        log::warn!("WebSocket flush to {} failed: {}", endpoint, err);
+                    }
+                }
+            }
+            Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => {
+                break;
+            }
+            Err(err) => {
+                log::error!("WebSocket {} failed: {}", endpoint, err);
+                on_event(WebSocketEvent::Error(err.to_string()));
+                break;
+            }
+        }
+    }
+
+    if !close_reported {
+        // 1006: the connection went away without a close frame.
+        on_event(WebSocketEvent::Closed {
+            code: 1006,
+            reason: "Connection closed abnormally".to_string(),
+        });
+    }
+    log::debug!("WebSocket reader for {} finished", endpoint);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Echoes one message back and then answers the closing handshake.
+    fn spawn_echo_server(listener: TcpListener) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let message = socket.read().unwrap();
+            socket.send(message).unwrap();
+            while socket.read().is_ok() {}
+        })
+    }
+
+    #[test]
+    fn echoes_text_and_closes_over_ipv6_loopback() {
+        // Fall back to IPv4 on hosts without IPv6.
+        let listener = TcpListener::bind("[::1]:0")
+            .or_else(|_| TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = spawn_echo_server(listener);
+
+        let req = RequestWithMetadata::new(
+            0,
+            "GET".to_string(),
+            format!("ws://{}/echo", addr),
+            Vec::new(),
+            None,
+            true,
+            Vec::new(),
+            None,
+            None,
+        );
+        let (events_tx, events) = mpsc::channel();
+        let connection = WebSocketConnection::connect(&req, &[], move |event| {
+            let _ = events_tx.send(event);
+        })
+        .unwrap();
+        let next = || events.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(matches!(next(), WebSocketEvent::Connected { .. }));
+        connection
+            .send(WebSocketMessage::Text("hello".to_string()))
+            .unwrap();
+        assert!(matches!(next(), WebSocketEvent::Text(text) if text == "hello"));
+
+        connection.close(1000, "done".to_string()).unwrap();
+        assert!(matches!(
+            next(),
+            WebSocketEvent::Closed { code: 1000, ref reason } if reason == "done"
+        ));
+        server.join().unwrap();
+    }
+}
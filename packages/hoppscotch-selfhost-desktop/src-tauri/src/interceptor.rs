@@ -1,16 +1,440 @@
-use dashmap::DashMap;
-use postdata_relay::{RequestWithMetadata, ResponseWithMetadata};
-use serde::Serialize;
+use dashmap::{mapref::entry::Entry, DashMap};
+use postdata_relay::{
+    ActiveRequest, Assertion, AssertionReport, BatchItemResult, BatchOptions, BatchOutcome,
+    BatchSummary, CacheConfig, CacheEntryInfo, CacheLookup, CurlCommand, EngineConfig, GrpcSchema,
+    GrpcSchemaSource, GrpcServiceInfo, HarExchange, HistoryConfig, HistoryEntry, HistoryPage,
+    HistoryPrune, HistoryQuery, HistoryStore, HttpCache, LoadOptions, LoadRequest,
+    LoadedNetworkDefaults, MiddlewareConfig, MiddlewarePipeline, MockConfig, MockHit, MockServer,
+    Monitor, MonitorConfig, MonitorResult, MonitorScheduler, MonitorStatus, MqttConnection,
+    MqttOptions, MqttPublish, MqttSubscription, NetworkDefaults, RelayEngine, RelayError,
+    RelayResult, RequestMiddleware, RequestWithMetadata, ResolvedRequest, ResponseWithMetadata,
+    SecretInfo, SecretRedactor, SseOptions, VariableScope, Vault, VaultCredential,
+    WebSocketConnection, WebSocketMessage,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
 use tauri::{
+    ipc::Channel,
     plugin::{Builder, TauriPlugin},
-    Manager, Runtime, State,
+    AppHandle, Emitter, Manager, RunEvent, Runtime, State, Window, WindowEvent,
 };
 use thiserror::Error;
 use tokio_util::sync::CancellationToken;
 
-#[derive(Default, Debug)]
+use crate::util::store::AppState;
+
+/// `AppState` key the monitors are persisted under.
+const MONITORS_KEY: &str = "monitors";
+/// `AppState` key the per workspace [`MiddlewareConfig`]s are persisted under.
+const MIDDLEWARE_KEY: &str = "middleware";
+/// `AppState` key the [`NetworkDefaults`] are persisted under.
+const NETWORK_DEFAULTS_KEY: &str = "network_defaults";
+/// `AppState` key the [`DuplicateIdPolicy`] is persisted under.
+const DUPLICATE_ID_POLICY_KEY: &str = "duplicate_request_id_policy";
+
+/// What happens when a window starts a request, batch or load test with an
+/// ID it already has in flight, or opens a connection or loads a gRPC schema
+/// under an ID it already uses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateIdPolicy {
+    /// Fail the new one with `DuplicateRequestId`.
+    Reject,
+    /// Cancel the running one, or close the open connection, and register the
+    /// new one in its place. The frontend loses its cancel handles on reload,
+    /// so this is the default.
+    #[default]
+    CancelPrevious,
+    /// Run both, the new one under a fresh ID counted down from
+    /// [`MAX_FRESH_ID`]. The window is told the ID through a
+    /// `<kind>://fresh-id` event, and for requests it is what
+    /// `active_requests` reports.
+    FreshId,
+}
+
+/// The largest integer a JavaScript number holds exactly, so fresh IDs reach
+/// the frontend unchanged.
+const MAX_FRESH_ID: usize = (1 << 53) - 1;
+
+/// Payload of the `<kind>://fresh-id` event sent when an operation runs
+/// under a fresh ID.
+#[derive(Clone, Debug, Serialize)]
+pub struct FreshIdAssigned {
+    /// The ID the window asked for, which is still in use.
+    pub requested: usize,
+    /// The ID to cancel the new operation with.
+    pub assigned: usize,
+}
+
+/// Cancellation tokens of in-flight operations, keyed by the label of the
+/// window that started them and the ID that window chose, so windows can't
+/// cancel or replace each other's.
+struct RequestRegistry {
+    /// `request`, `batch` or `load`, the prefix of its events.
+    kind: &'static str,
+    tokens: DashMap<(String, usize), (u64, CancellationToken)>,
+    next_generation: AtomicU64,
+    next_fresh_id: AtomicUsize,
+}
+
+/// Unregisters its token when dropped, unless another operation has taken
+/// over the ID in the meantime.
+struct Registration<'a> {
+    registry: &'a RequestRegistry,
+    key: (String, usize),
+    generation: u64,
+    cancel_token: CancellationToken,
+}
+
+impl Registration<'_> {
+    fn id(&self) -> usize {
+        self.key.1
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.registry
+            .tokens
+            .remove_if(&self.key, |_, (generation, _)| {
+                *generation == self.generation
+            });
+    }
+}
+
+impl RequestRegistry {
+    fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            tokens: DashMap::new(),
+            next_generation: AtomicU64::new(0),
+            next_fresh_id: AtomicUsize::new(MAX_FRESH_ID),
+        }
+    }
+
+    fn register(
+        &self,
+        window: &str,
+        id: usize,
+        policy: DuplicateIdPolicy,
+    ) -> Result<Registration<'_>, RunRequestError> {
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let cancel_token = CancellationToken::new();
+        let mut key = (window.to_string(), id);
+        loop {
+            match self.tokens.entry(key.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert((generation, cancel_token.clone()));
+                    break;
+                }
+                Entry::Occupied(mut entry) => match policy {
+                    DuplicateIdPolicy::Reject => {
+                        return Err(RunRequestError::DuplicateRequestId(id))
+                    }
+                    DuplicateIdPolicy::CancelPrevious => {
+                        let (_, previous) = entry.insert((generation, cancel_token.clone()));
+                        previous.cancel();
+                        break;
+                    }
+                    DuplicateIdPolicy::FreshId => {
+                        key.1 = self.next_fresh_id.fetch_sub(1, Ordering::Relaxed);
+                    }
+                },
+            }
+        }
+        if key.1 != id {
+            log::info!("Running duplicate id {} of {} as {}", id, window, key.1);
+        }
+
+        Ok(Registration {
+            registry: self,
+            key,
+            generation,
+            cancel_token,
+        })
+    }
+
+    fn cancel(&self, window: &str, id: usize) {
+        if let Some((_, (_, cancel_token))) = self.tokens.remove(&(window.to_string(), id)) {
+            cancel_token.cancel();
+        }
+    }
+
+    /// Cancels everything of `window`, or of all windows when `None`.
+    fn cancel_window(&self, window: Option<&str>) {
+        self.tokens.retain(|(label, _), (_, cancel_token)| {
+            if window.is_some_and(|window| window != label) {
+                return true;
+            }
+            cancel_token.cancel();
+            false
+        });
+    }
+}
+
+/// Open connections and loaded schemas, keyed like [`RequestRegistry`] by
+/// the label of the window that opened them and the ID that window chose.
+/// Dropping an entry closes the connection it holds.
+struct ResourceRegistry<T> {
+    /// `websocket`, `mqtt` or `grpc-schema`, the prefix of its events.
+    kind: &'static str,
+    entries: DashMap<(String, usize), T>,
+    next_fresh_id: AtomicUsize,
+}
+
+impl<T> ResourceRegistry<T> {
+    fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            entries: DashMap::new(),
+            next_fresh_id: AtomicUsize::new(MAX_FRESH_ID),
+        }
+    }
+
+    /// Fails when `policy` would reject `id`, so nothing is opened for it.
+    fn check(
+        &self,
+        window: &str,
+        id: usize,
+        policy: DuplicateIdPolicy,
+    ) -> Result<(), RunRequestError> {
+        if policy == DuplicateIdPolicy::Reject
+            && self.entries.contains_key(&(window.to_string(), id))
+        {
+            return Err(RunRequestError::DuplicateRequestId(id));
+        }
+        Ok(())
+    }
+
+    /// Stores `value` under `id` of `window` according to `policy` and
+    /// returns the ID it is stored under. A replaced entry is dropped.
+    fn insert(
+        &self,
+        window: &str,
+        id: usize,
+        value: T,
+        policy: DuplicateIdPolicy,
+    ) -> Result<usize, RunRequestError> {
+        let mut key = (window.to_string(), id);
+        loop {
+            match self.entries.entry(key.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(value);
+                    break;
+                }
+                Entry::Occupied(mut entry) => match policy {
+                    DuplicateIdPolicy::Reject => {
+                        return Err(RunRequestError::DuplicateRequestId(id))
+                    }
+                    DuplicateIdPolicy::CancelPrevious => {
+                        drop(entry.insert(value));
+                        break;
+                    }
+                    DuplicateIdPolicy::FreshId => {
+                        key.1 = self.next_fresh_id.fetch_sub(1, Ordering::Relaxed);
+                    }
+                },
+            }
+        }
+        if key.1 != id {
+            log::info!(
+                "Opened duplicate {} id {} of {} as {}",
+                self.kind,
+                id,
+                window,
+                key.1
+            );
+        }
+        Ok(key.1)
+    }
+
+    fn get(
+        &self,
+        window: &str,
+        id: usize,
+    ) -> Option<dashmap::mapref::one::Ref<'_, (String, usize), T>> {
+        self.entries.get(&(window.to_string(), id))
+    }
+
+    fn remove(&self, window: &str, id: usize) -> Option<T> {
+        self.entries
+            .remove(&(window.to_string(), id))
+            .map(|(_, value)| value)
+    }
+
+    /// Takes out everything of `window`.
+    fn remove_window(&self, window: &str) -> Vec<T> {
+        let keys = self
+            .entries
+            .iter()
+            .filter(|entry| entry.key().0 == window)
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        keys.into_iter()
+            .filter_map(|key| self.entries.remove(&key).map(|(_, value)| value))
+            .collect()
+    }
+}
+
 pub struct InterceptorState {
-    cancellation_tokens: DashMap<usize, CancellationToken>,
+    requests: RequestRegistry,
+    batches: RequestRegistry,
+    loads: RequestRegistry,
+    duplicate_id_policy: Mutex<DuplicateIdPolicy>,
+    websockets: ResourceRegistry<WebSocketConnection>,
+    grpc_schemas: ResourceRegistry<GrpcSchema>,
+    mqtt_connections: ResourceRegistry<MqttConnection>,
+    mock_server: Mutex<Option<MockServer>>,
+    /// `None` when the history file couldn't be opened, requests still run.
+    history: Mutex<Option<HistoryStore>>,
+    /// `None` when the cache directory couldn't be opened, requests opting
+    /// into the cache are then always sent.
+    http_cache: Option<HttpCache>,
+    /// `None` when the vault file couldn't be read.
+    vault: Mutex<Option<Vault>>,
+    /// Unset until the plugin is set up and this state is managed, as the
+    /// monitors prepare their requests through it. Stays unset when the
+    /// monitor log directory couldn't be created.
+    monitors: OnceLock<MonitorScheduler>,
+    /// Merged into every request before it is sent.
+    network_defaults: Mutex<LoadedNetworkDefaults>,
+    /// Built-in middleware settings by workspace.
+    middleware_configs: Mutex<BTreeMap<String, MiddlewareConfig>>,
+    /// Middlewares registered with `init_with_middleware`, run after the
+    /// built-in ones of the workspace.
+    middleware: Vec<Arc<dyn RequestMiddleware>>,
+    engine: RelayEngine,
+}
+
+impl InterceptorState {
+    pub fn new(config: EngineConfig, data_dir: &std::path::Path) -> RelayResult<Self> {
+        let history = HistoryStore::open(data_dir, HistoryConfig::default())
+            .inspect_err(|err| log::error!("Request history disabled: {}", err))
+            .ok();
+        let http_cache = HttpCache::open(&data_dir.join("http-cache"), CacheConfig::default())
+            .inspect_err(|err| log::error!("HTTP cache disabled: {}", err))
+            .ok();
+        let vault = Vault::open(data_dir)
+            .inspect_err(|err| log::error!("Secrets vault disabled: {}", err))
+            .ok();
+
+        Ok(Self {
+            requests: RequestRegistry::new("request"),
+            batches: RequestRegistry::new("batch"),
+            loads: RequestRegistry::new("load"),
+            duplicate_id_policy: Mutex::new(DuplicateIdPolicy::default()),
+            websockets: ResourceRegistry::new("websocket"),
+            grpc_schemas: ResourceRegistry::new("grpc-schema"),
+            mqtt_connections: ResourceRegistry::new("mqtt"),
+            mock_server: Mutex::new(None),
+            history: Mutex::new(history),
+            http_cache,
+            vault: Mutex::new(vault),
+            monitors: OnceLock::new(),
+            network_defaults: Mutex::new(LoadedNetworkDefaults::default()),
+            middleware_configs: Mutex::new(BTreeMap::new()),
+            middleware: Vec::new(),
+            engine: RelayEngine::new(config)?,
+        })
+    }
+
+    fn duplicate_id_policy(&self) -> DuplicateIdPolicy {
+        self.duplicate_id_policy
+            .lock()
+            .map(|policy| *policy)
+            .unwrap_or_default()
+    }
+
+    /// Registers operation `id` of `window` under the current duplicate id
+    /// policy, telling the window when it runs under a fresh ID.
+    fn register<'a, R: Runtime>(
+        &self,
+        registry: &'a RequestRegistry,
+        window: &Window<R>,
+        id: usize,
+    ) -> Result<Registration<'a>, RunRequestError> {
+        let registration = registry.register(window.label(), id, self.duplicate_id_policy())?;
+        announce_fresh_id(window, registry.kind, id, registration.id());
+        Ok(registration)
+    }
+
+    /// Keeps connection or schema `value` under `id` of `window`, like
+    /// [`InterceptorState::register`], and returns the ID it is kept under.
+    fn store<T, R: Runtime>(
+        &self,
+        registry: &ResourceRegistry<T>,
+        window: &Window<R>,
+        id: usize,
+        value: T,
+    ) -> Result<usize, RunRequestError> {
+        let stored = registry.insert(window.label(), id, value, self.duplicate_id_policy())?;
+        announce_fresh_id(window, registry.kind, id, stored);
+        Ok(stored)
+    }
+
+    /// The built-in middlewares configured for `workspace` followed by the
+    /// registered ones.
+    fn middleware_pipeline(&self, workspace: Option<&str>) -> MiddlewarePipeline {
+        let mut pipeline = workspace
+            .and_then(|workspace| {
+                self.middleware_configs
+                    .lock()
+                    .ok()?
+                    .get(workspace)
+                    .map(MiddlewarePipeline::from_config)
+            })
+            .unwrap_or_default();
+        for middleware in &self.middleware {
+            pipeline.push(middleware.clone());
+        }
+        pipeline
+    }
+
+    /// Cancels whatever a closed window left running and closes the
+    /// connections it left open.
+    fn release_window(&self, window: &str) {
+        for registry in [&self.requests, &self.batches, &self.loads] {
+            registry.cancel_window(Some(window));
+        }
+        for connection in self.websockets.remove_window(window) {
+            if let Err(err) = connection.close(1001, "Window closed".to_string()) {
+                log::debug!("WebSocket of {} already closed: {}", window, err);
+            }
+        }
+        for connection in self.mqtt_connections.remove_window(window) {
+            if let Err(err) = connection.disconnect() {
+                log::debug!("MQTT connection of {} already closed: {}", window, err);
+            }
+        }
+        self.grpc_schemas.remove_window(window);
+    }
+}
+
+/// Tells `window` the ID an operation of `kind` runs under when it isn't
+/// the `requested` one.
+fn announce_fresh_id<R: Runtime>(
+    window: &Window<R>,
+    kind: &str,
+    requested: usize,
+    assigned: usize,
+) {
+    if assigned == requested {
+        return;
+    }
+    let event = format!("{}://fresh-id", kind);
+    let payload = FreshIdAssigned {
+        requested,
+        assigned,
+    };
+    if let Err(err) = window.emit_to(window.label(), &event, payload) {
+        log::warn!("Failed to emit {}: {}", event, err);
+    }
 }
 
 #[derive(Debug, Serialize, Error)]
@@ -19,70 +443,1278 @@ pub enum RunRequestError {
     RequestCancelled,
     #[error("Internal server error")]
     InternalServerError,
+    #[error("No open connection with id {0}")]
+    ConnectionNotFound(usize),
+    #[error("No gRPC schema loaded with id {0}")]
+    SchemaNotFound(usize),
+    #[error("File error: {0}")]
+    FileError(String),
+    #[error("Request history is unavailable")]
+    HistoryUnavailable,
+    #[error("Secrets vault is unavailable")]
+    VaultUnavailable,
+    #[error("Monitors are unavailable")]
+    MonitorsUnavailable,
+    #[error("Id {0} is already in use")]
+    DuplicateRequestId(usize),
     #[error("Relay error: {0}")]
     Relay(#[from] postdata_relay::RelayError),
 }
 
 #[tauri::command]
-pub async fn run_request(
-    req: RequestWithMetadata,
+pub async fn run_request<R: Runtime>(
+    mut req: RequestWithMetadata,
+    workspace: Option<String>,
+    window: Window<R>,
     state: State<'_, InterceptorState>,
 ) -> Result<ResponseWithMetadata, RunRequestError> {
-    let req_id = req.req_id;
-    let cancel_token = CancellationToken::new();
-    // NOTE: This will drop reference to an existing cancellation token
-    // if you send a request with the same request id as an existing one,
-    // thereby, dropping any means to cancel a running operation with the old token.
-    // This is done so because, on FE side, we may lose cancel token info upon reloads
-    // and this allows us to work around that.
+    // A request reusing an id that is still in flight is handled according
+    // to the `DuplicateIdPolicy`.
+    let registration = state.register(&state.requests, &window, req.req_id)?;
+    req.req_id = registration.id();
+    let cancel_token = registration.cancel_token.clone();
+    // History keeps the secret references, never their values.
+    let recorded = req.clone();
+    let (mut req, redactor) = prepare_request(&state, req)?;
+    let pipeline = state.middleware_pipeline(workspace.as_deref());
+    pipeline.before_send(&mut req)?;
+    // The request line, headers as sent and trace show the secret values.
+    let redact = |mut response: ResponseWithMetadata| {
+        redactor.redact_response(&mut response);
+        response
+    };
+
+    // Requests are driven by the relay's `Multi` based engine, so no thread is
+    // held per in-flight request and cancelling removes the transfer right away
+    // instead of waiting for curl's next progress callback.
+    let result = match &state.http_cache {
+        // Entries are keyed and labelled without the secret values, which
+        // would otherwise end up in the cache directory.
+        Some(cache) => {
+            let cache_url = redactor.redact(&req.endpoint);
+            match cache.lookup_as(&cache_url, &req) {
+                CacheLookup::Fresh(response) => Ok(*response),
+                CacheLookup::Send {
+                    request,
+                    revalidating,
+                } => {
+                    let result = state.engine.execute(*request, cancel_token).await;
+                    cache.complete_as(&cache_url, &req, revalidating, result.map(redact))
+                }
+            }
+        }
+        None => state
+            .engine
+            .execute(req.clone(), cancel_token)
+            .await
+            .map(redact),
+    };
+    let result = result.and_then(|mut response| {
+        pipeline.after_receive(&req, &mut response)?;
+        Ok(response)
+    });
+    drop(registration);
+    record_history(&state, &recorded, &result);
+
+    match result {
+        Ok(response) => Ok(response),
+        Err(RelayError::RequestCancelled) => Err(RunRequestError::RequestCancelled),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Entries of the HTTP cache, most recently stored first.
+#[tauri::command]
+pub fn cache_entries(state: State<'_, InterceptorState>) -> Vec<CacheEntryInfo> {
+    state
+        .http_cache
+        .as_ref()
+        .map(HttpCache::entries)
+        .unwrap_or_default()
+}
+
+#[tauri::command]
+pub fn cache_clear(state: State<'_, InterceptorState>) -> Result<(), RunRequestError> {
+    if let Some(cache) = &state.http_cache {
+        cache.clear()?;
+    }
+    Ok(())
+}
+
+/// Merges the network defaults into the request and then resolves its secret
+/// references, those of the defaults included, giving what is actually sent
+/// and a redactor for the secret values in it.
+fn prepare_request(
+    state: &InterceptorState,
+    mut req: RequestWithMetadata,
+) -> Result<(RequestWithMetadata, SecretRedactor), RunRequestError> {
+    state
+        .network_defaults
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)?
+        .apply(&mut req);
+    resolve_secrets(state, req, &mut [])
+}
+
+/// Like [`prepare_request`] for event streams, streaming calls and
+/// long-lived connections, which don't get the default timeout.
+fn prepare_stream_request(
+    state: &InterceptorState,
+    mut req: RequestWithMetadata,
+) -> Result<(RequestWithMetadata, SecretRedactor), RunRequestError> {
+    state
+        .network_defaults
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)?
+        .apply_to_stream(&mut req);
+    resolve_secrets(state, req, &mut [])
+}
+
+/// Like [`prepare_stream_request`] for an MQTT connection, also filling in
+/// the secrets its username and password refer to.
+fn prepare_mqtt_request(
+    state: &InterceptorState,
+    mut req: RequestWithMetadata,
+    options: &mut MqttOptions,
+) -> Result<(RequestWithMetadata, SecretRedactor), RunRequestError> {
     state
-        .cancellation_tokens
-        .insert(req_id, cancel_token.clone());
-
-    let cancel_token_clone = cancel_token.clone();
-    // Execute the HTTP request in a blocking thread pool and handles cancellation.
-    //
-    // It:
-    // 1. Uses `spawn_blocking` to run the sync `run_request_task`
-    //    without blocking the main Tokio runtime.
-    // 2. Uses `select!` to concurrently wait for either
-    //      a. the task to complete,
-    //      b. or a cancellation signal.
-    //
-    // Why spawn_blocking?
-    // - `run_request_task` uses synchronous curl operations which would block
-    //   the async runtime if not run in a separate thread.
-    // - `spawn_blocking` moves this operation to a thread pool designed for
-    //   blocking tasks, so other async operations to continue unblocked.
-    let result = tokio::select! {
-        res = tokio::task::spawn_blocking(move || postdata_relay::run_request_task(&req, cancel_token_clone)) => {
-            match res {
-                Ok(task_result) => Ok(task_result?),
-                Err(_) => Err(RunRequestError::InternalServerError),
+        .network_defaults
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)?
+        .apply_to_stream(&mut req);
+    let mut credentials = options
+        .username
+        .iter_mut()
+        .chain(options.password.iter_mut())
+        .collect::<Vec<_>>();
+    resolve_secrets(state, req, &mut credentials)
+}
+
+/// Fills in the vault secrets `req` and `extra` refer to. Requests without
+/// references pass through even when the vault is locked or missing.
+fn resolve_secrets(
+    state: &InterceptorState,
+    req: RequestWithMetadata,
+    extra: &mut [&mut String],
+) -> Result<(RequestWithMetadata, SecretRedactor), RunRequestError> {
+    if postdata_relay::referenced_secrets(&req).is_empty()
+        && extra
+            .iter()
+            .all(|text| postdata_relay::referenced_secrets_in(text).is_empty())
+    {
+        return Ok((req, SecretRedactor::default()));
+    }
+    with_vault(state, |vault| Ok(vault.resolve_secrets_along(&req, extra)?))
+}
+
+fn with_vault<T>(
+    state: &InterceptorState,
+    f: impl FnOnce(&mut Vault) -> Result<T, RunRequestError>,
+) -> Result<T, RunRequestError> {
+    let mut vault = state
+        .vault
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)?;
+    f(vault.as_mut().ok_or(RunRequestError::VaultUnavailable)?)
+}
+
+#[derive(Debug, Serialize)]
+pub struct VaultStatus {
+    pub exists: bool,
+    pub unlocked: bool,
+}
+
+#[tauri::command]
+pub fn vault_status(state: State<'_, InterceptorState>) -> Result<VaultStatus, RunRequestError> {
+    with_vault(&state, |vault| {
+        Ok(VaultStatus {
+            exists: vault.exists(),
+            unlocked: vault.is_unlocked(),
+        })
+    })
+}
+
+/// Creates the vault on first use and leaves it unlocked.
+#[tauri::command]
+pub async fn vault_create(
+    credential: VaultCredential,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    with_vault(&state, |vault| Ok(vault.create(&credential)?))
+}
+
+#[tauri::command]
+pub async fn vault_unlock(
+    credential: VaultCredential,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    with_vault(&state, |vault| Ok(vault.unlock(&credential)?))
+}
+
+#[tauri::command]
+pub fn vault_lock(state: State<'_, InterceptorState>) -> Result<(), RunRequestError> {
+    with_vault(&state, |vault| {
+        vault.lock();
+        Ok(())
+    })
+}
+
+/// Re-encrypts the vault for a new passphrase or keyfile.
+#[tauri::command]
+pub async fn vault_change_credential(
+    current: VaultCredential,
+    new: VaultCredential,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    with_vault(&state, |vault| Ok(vault.change_credential(&current, &new)?))
+}
+
+#[tauri::command]
+pub fn vault_generate_keyfile(path: String) -> Result<(), RunRequestError> {
+    Ok(Vault::generate_keyfile(std::path::Path::new(&path))?)
+}
+
+/// Names and IDs of the stored secrets, never their values.
+#[tauri::command]
+pub fn vault_secrets(
+    state: State<'_, InterceptorState>,
+) -> Result<Vec<SecretInfo>, RunRequestError> {
+    with_vault(&state, |vault| Ok(vault.secrets()))
+}
+
+#[tauri::command]
+pub fn vault_add_secret(
+    name: String,
+    value: String,
+    state: State<'_, InterceptorState>,
+) -> Result<SecretInfo, RunRequestError> {
+    with_vault(&state, |vault| Ok(vault.add_secret(&name, &value)?))
+}
+
+#[tauri::command]
+pub fn vault_update_secret(
+    id: String,
+    value: String,
+    state: State<'_, InterceptorState>,
+) -> Result<SecretInfo, RunRequestError> {
+    with_vault(&state, |vault| Ok(vault.update_secret(&id, &value)?))
+}
+
+#[tauri::command]
+pub fn vault_remove_secret(
+    id: String,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    with_vault(&state, |vault| Ok(vault.remove_secret(&id)?))
+}
+
+/// Adds a finished request to the history. A failure to write it is logged
+/// rather than failing the request.
+fn record_history(
+    state: &InterceptorState,
+    req: &RequestWithMetadata,
+    result: &RelayResult<ResponseWithMetadata>,
+) {
+    let Ok(mut history) = state.history.lock() else {
+        return;
+    };
+    if let Some(history) = history.as_mut() {
+        if let Err(err) = history.record(req, result) {
+            log::warn!("Failed to record request history: {}", err);
+        }
+    }
+}
+
+fn with_history<T>(
+    state: &InterceptorState,
+    f: impl FnOnce(&mut HistoryStore) -> Result<T, RunRequestError>,
+) -> Result<T, RunRequestError> {
+    let mut history = state
+        .history
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)?;
+    f(history
+        .as_mut()
+        .ok_or(RunRequestError::HistoryUnavailable)?)
+}
+
+/// Newest history entries first, without response bodies.
+#[tauri::command]
+pub fn history_list(
+    offset: usize,
+    limit: usize,
+    state: State<'_, InterceptorState>,
+) -> Result<HistoryPage, RunRequestError> {
+    with_history(&state, |history| {
+        Ok(history.search(&HistoryQuery {
+            offset,
+            limit,
+            ..Default::default()
+        }))
+    })
+}
+
+/// Filters the history by URL text, method, status and date range.
+#[tauri::command]
+pub fn history_search(
+    query: HistoryQuery,
+    state: State<'_, InterceptorState>,
+) -> Result<HistoryPage, RunRequestError> {
+    with_history(&state, |history| Ok(history.search(&query)))
+}
+
+/// A single history entry including its stored response body.
+#[tauri::command]
+pub fn history_get(
+    id: u64,
+    state: State<'_, InterceptorState>,
+) -> Result<Option<HistoryEntry>, RunRequestError> {
+    with_history(&state, |history| Ok(history.get(id).cloned()))
+}
+
+#[tauri::command]
+pub fn history_pin(
+    id: u64,
+    pinned: bool,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    with_history(&state, |history| Ok(history.pin(id, pinned)?))
+}
+
+/// Removes unpinned entries, returning how many were removed.
+#[tauri::command]
+pub fn history_prune(
+    prune: HistoryPrune,
+    state: State<'_, InterceptorState>,
+) -> Result<usize, RunRequestError> {
+    with_history(&state, |history| Ok(history.prune(&prune)?))
+}
+
+#[tauri::command]
+pub fn cancel_request<R: Runtime>(
+    req_id: usize,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) {
+    state.requests.cancel(window.label(), req_id);
+}
+
+/// Requests the engine is running or holding in its queue, including those
+/// of batches and load tests.
+#[tauri::command]
+pub async fn active_requests(
+    state: State<'_, InterceptorState>,
+) -> Result<Vec<ActiveRequest>, RunRequestError> {
+    Ok(state.engine.active_requests().await?)
+}
+
+/// Cancels every request, batch and load test in flight. Transfers are torn
+/// down, closing their connections, before this returns.
+#[tauri::command]
+pub async fn cancel_all(state: State<'_, InterceptorState>) -> Result<(), RunRequestError> {
+    for registry in [&state.requests, &state.batches, &state.loads] {
+        registry.cancel_window(None);
+    }
+    state.engine.cancel_all().await?;
+    Ok(())
+}
+
+/// Opens a `text/event-stream` request and pushes each parsed event through
+/// `on_event` as it arrives, reconnecting with `Last-Event-ID` as needed.
+///
+/// The stream is registered under `req.req_id`, so `cancel_request` stops it.
+#[tauri::command]
+pub async fn run_sse<R: Runtime>(
+    req: RequestWithMetadata,
+    options: SseOptions,
+    on_event: Channel<serde_json::Value>,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    let registration = state.register(&state.requests, &window, req.req_id)?;
+    let (mut req, redactor) = prepare_stream_request(&state, req)?;
+    req.req_id = registration.id();
+    let cancel_token = registration.cancel_token.clone();
+
+    let result =
+        postdata_relay::run_sse_stream(&state.engine, &req, &options, cancel_token, move |event| {
+            if let Err(err) = on_event.send(redactor.redact_serialized(&event)) {
+                log::warn!("Failed to deliver event stream event: {}", err);
+            }
+        })
+        .await;
+
+    drop(registration);
+    match result {
+        Ok(()) => Ok(()),
+        Err(RelayError::RequestCancelled) => Err(RunRequestError::RequestCancelled),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Runs a whole collection of requests in one IPC call.
+///
+/// Each result is pushed through `on_result` as soon as it is known and the
+/// aggregate summary is returned once the batch is done. The batch can be
+/// stopped through `cancel_batch` with the same `batch_id`.
+#[tauri::command]
+pub async fn run_batch<R: Runtime>(
+    batch_id: usize,
+    requests: Vec<RequestWithMetadata>,
+    options: BatchOptions,
+    on_result: Channel<BatchItemResult>,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<BatchSummary, RunRequestError> {
+    let (requests, redactors): (Vec<_>, Vec<_>) = requests
+        .into_iter()
+        .map(|req| prepare_request(&state, req))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .unzip();
+    let registration = state.register(&state.batches, &window, batch_id)?;
+    let cancel_token = registration.cancel_token.clone();
+
+    let summary = postdata_relay::run_batch(
+        &state.engine,
+        requests,
+        options,
+        cancel_token,
+        |mut result| {
+            if let BatchOutcome::Response(response) = &mut result.outcome {
+                redactors[result.index].redact_response(response);
+            }
+            if let Err(err) = on_result.send(result) {
+                log::warn!("Failed to deliver batch result: {}", err);
             }
         },
-        _ = cancel_token.cancelled() => {
-          Err(RunRequestError::RequestCancelled)
+    )
+    .await;
+
+    drop(registration);
+    Ok(summary)
+}
+
+#[tauri::command]
+pub fn cancel_batch<R: Runtime>(
+    batch_id: usize,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) {
+    state.batches.cancel(window.label(), batch_id);
+}
+
+/// Runs a load test, streaming a snapshot of the last interval through
+/// `on_snapshot` while it runs.
+#[tauri::command]
+pub async fn run_load<R: Runtime>(
+    load_id: usize,
+    mut requests: Vec<LoadRequest>,
+    options: LoadOptions,
+    on_snapshot: Channel<serde_json::Value>,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<serde_json::Value, RunRequestError> {
+    let mut redactor = SecretRedactor::default();
+    for load_request in &mut requests {
+        let (request, request_redactor) = prepare_request(&state, load_request.request.clone())?;
+        load_request.request = request;
+        redactor.extend(request_redactor);
+    }
+    let registration = state.register(&state.loads, &window, load_id)?;
+    let cancel_token = registration.cancel_token.clone();
+
+    let report =
+        postdata_relay::run_load(&state.engine, requests, options, cancel_token, |snapshot| {
+            if let Err(err) = on_snapshot.send(redactor.redact_serialized(&snapshot)) {
+                log::warn!("Failed to deliver load test snapshot: {}", err);
+            }
+        })
+        .await;
+
+    drop(registration);
+    Ok(redactor.redact_serialized(&report?))
+}
+
+/// Stops a load test. It still returns the report of what ran so far.
+#[tauri::command]
+pub fn cancel_load<R: Runtime>(
+    load_id: usize,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) {
+    state.loads.cancel(window.label(), load_id);
+}
+
+/// Proxy, CA bundles, client certificates, timeouts and TLS settings used by
+/// requests that don't set their own.
+#[tauri::command]
+pub fn network_defaults(
+    state: State<'_, InterceptorState>,
+) -> Result<NetworkDefaults, RunRequestError> {
+    Ok(state
+        .network_defaults
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)?
+        .settings()
+        .clone())
+}
+
+#[tauri::command]
+pub fn set_network_defaults<R: Runtime>(
+    defaults: NetworkDefaults,
+    app: AppHandle<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    // Unreadable files are reported here rather than on every request.
+    let loaded = defaults.clone().load()?;
+    AppState::new(&app)
+        .set(NETWORK_DEFAULTS_KEY, &defaults)
+        .map_err(RunRequestError::FileError)?;
+    *state
+        .network_defaults
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)? = loaded;
+    Ok(())
+}
+
+/// Built-in middleware settings of `workspace`, applied to the requests
+/// `run_request` is given that workspace for.
+#[tauri::command]
+pub fn middleware_config(
+    workspace: String,
+    state: State<'_, InterceptorState>,
+) -> Result<MiddlewareConfig, RunRequestError> {
+    let configs = state
+        .middleware_configs
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)?;
+    Ok(configs.get(&workspace).cloned().unwrap_or_default())
+}
+
+#[tauri::command]
+pub fn set_middleware_config<R: Runtime>(
+    workspace: String,
+    config: MiddlewareConfig,
+    app: AppHandle<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    let mut configs = state
+        .middleware_configs
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)?;
+    let mut updated = configs.clone();
+    updated.insert(workspace, config);
+    AppState::new(&app)
+        .set(MIDDLEWARE_KEY, &updated)
+        .map_err(RunRequestError::FileError)?;
+    *configs = updated;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn duplicate_id_policy(state: State<'_, InterceptorState>) -> DuplicateIdPolicy {
+    state.duplicate_id_policy()
+}
+
+#[tauri::command]
+pub fn set_duplicate_id_policy<R: Runtime>(
+    policy: DuplicateIdPolicy,
+    app: AppHandle<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    AppState::new(&app)
+        .set(DUPLICATE_ID_POLICY_KEY, policy)
+        .map_err(RunRequestError::FileError)?;
+    *state
+        .duplicate_id_policy
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)? = policy;
+    Ok(())
+}
+
+fn monitors(state: &InterceptorState) -> Result<&MonitorScheduler, RunRequestError> {
+    state
+        .monitors
+        .get()
+        .ok_or(RunRequestError::MonitorsUnavailable)
+}
+
+/// Writes the current monitors, including whether they are paused, to the
+/// app settings so they are scheduled again on the next start.
+fn persist_monitors<R: Runtime>(
+    app: &AppHandle<R>,
+    scheduler: &MonitorScheduler,
+) -> Result<(), RunRequestError> {
+    AppState::new(app)
+        .set(MONITORS_KEY, scheduler.monitors())
+        .map_err(RunRequestError::FileError)
+}
+
+#[tauri::command]
+pub fn monitor_list(
+    state: State<'_, InterceptorState>,
+) -> Result<Vec<MonitorStatus>, RunRequestError> {
+    Ok(monitors(&state)?.list())
+}
+
+/// Adds a monitor, or replaces the one with the same ID.
+#[tauri::command]
+pub fn monitor_save<R: Runtime>(
+    monitor: Monitor,
+    app: AppHandle<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    let scheduler = monitors(&state)?;
+    scheduler.upsert(monitor)?;
+    persist_monitors(&app, scheduler)
+}
+
+#[tauri::command]
+pub fn monitor_remove<R: Runtime>(
+    id: String,
+    app: AppHandle<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<Monitor, RunRequestError> {
+    let scheduler = monitors(&state)?;
+    let monitor = scheduler.remove(&id)?;
+    persist_monitors(&app, scheduler)?;
+    Ok(monitor)
+}
+
+#[tauri::command]
+pub fn monitor_pause<R: Runtime>(
+    id: String,
+    app: AppHandle<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<Monitor, RunRequestError> {
+    let scheduler = monitors(&state)?;
+    let monitor = scheduler.pause(&id)?;
+    persist_monitors(&app, scheduler)?;
+    Ok(monitor)
+}
+
+#[tauri::command]
+pub fn monitor_resume<R: Runtime>(
+    id: String,
+    app: AppHandle<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<Monitor, RunRequestError> {
+    let scheduler = monitors(&state)?;
+    let monitor = scheduler.resume(&id)?;
+    persist_monitors(&app, scheduler)?;
+    Ok(monitor)
+}
+
+/// Logged monitor results, newest first, of one monitor or of all of them.
+#[tauri::command]
+pub fn monitor_results(
+    monitor_id: Option<String>,
+    limit: usize,
+    state: State<'_, InterceptorState>,
+) -> Result<Vec<MonitorResult>, RunRequestError> {
+    Ok(monitors(&state)?.results(monitor_id.as_deref(), limit)?)
+}
+
+/// Opens a WebSocket connection using the relay's TLS, client certificate,
+/// proxy and header handling.
+///
+/// Frames and the final close code arrive through `on_event`. A `conn_id`
+/// the window already has open is handled according to the
+/// `DuplicateIdPolicy`, replacing closes the old connection.
+#[tauri::command]
+pub async fn ws_connect<R: Runtime>(
+    conn_id: usize,
+    req: RequestWithMetadata,
+    protocols: Vec<String>,
+    on_event: Channel<serde_json::Value>,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    state
+        .websockets
+        .check(window.label(), conn_id, state.duplicate_id_policy())?;
+    let (req, redactor) = prepare_stream_request(&state, req)?;
+    let connection = tauri::async_runtime::spawn_blocking(move || {
+        WebSocketConnection::connect(&req, &protocols, move |event| {
+            if let Err(err) = on_event.send(redactor.redact_serialized(&event)) {
+                log::warn!("Failed to deliver WebSocket event: {}", err);
+            }
+        })
+    })
+    .await
+    .map_err(|_| RunRequestError::InternalServerError)??;
+
+    state.store(&state.websockets, &window, conn_id, connection)?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn ws_send<R: Runtime>(
+    conn_id: usize,
+    message: WebSocketMessage,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    let connection = state
+        .websockets
+        .get(window.label(), conn_id)
+        .ok_or(RunRequestError::ConnectionNotFound(conn_id))?;
+    Ok(connection.send(message)?)
+}
+
+#[tauri::command]
+pub fn ws_close<R: Runtime>(
+    conn_id: usize,
+    code: u16,
+    reason: String,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    let connection = state
+        .websockets
+        .remove(window.label(), conn_id)
+        .ok_or(RunRequestError::ConnectionNotFound(conn_id))?;
+    Ok(connection.close(code, reason)?)
+}
+
+/// Loads a protobuf schema from `.proto` files, a descriptor set or server
+/// reflection and keeps it under `schema_id` for later `grpc_call`s.
+///
+/// A `schema_id` the window already uses is handled according to the
+/// `DuplicateIdPolicy`, replacing drops the old schema.
+#[tauri::command]
+pub async fn grpc_load_schema<R: Runtime>(
+    schema_id: usize,
+    mut source: GrpcSchemaSource,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<Vec<GrpcServiceInfo>, RunRequestError> {
+    state
+        .grpc_schemas
+        .check(window.label(), schema_id, state.duplicate_id_policy())?;
+    if let GrpcSchemaSource::Reflection(req) = &mut source {
+        **req = prepare_request(&state, (**req).clone())?.0;
+    }
+    let schema = tauri::async_runtime::spawn_blocking(move || {
+        GrpcSchema::load(&source, CancellationToken::new())
+    })
+    .await
+    .map_err(|_| RunRequestError::InternalServerError)??;
+
+    let services = schema.services();
+    state.store(&state.grpc_schemas, &window, schema_id, schema)?;
+    Ok(services)
+}
+
+/// Calls a unary or server streaming gRPC method described by a loaded schema.
+///
+/// Response messages arrive through `on_message` as JSON, the final status and
+/// metadata are returned. The call is registered under `req.req_id`, so
+/// `cancel_request` stops it.
+#[tauri::command]
+pub async fn grpc_call<R: Runtime>(
+    schema_id: usize,
+    req: RequestWithMetadata,
+    method: String,
+    input: serde_json::Value,
+    on_message: Channel<serde_json::Value>,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<serde_json::Value, RunRequestError> {
+    let schema = state
+        .grpc_schemas
+        .get(window.label(), schema_id)
+        .map(|schema| schema.clone())
+        .ok_or(RunRequestError::SchemaNotFound(schema_id))?;
+
+    let (mut req, redactor) = match schema.is_server_streaming(&method)? {
+        true => prepare_stream_request(&state, req)?,
+        false => prepare_request(&state, req)?,
+    };
+    let registration = state.register(&state.requests, &window, req.req_id)?;
+    req.req_id = registration.id();
+    let cancel_token = registration.cancel_token.clone();
+
+    let message_redactor = redactor.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        schema.call(&req, &method, input, cancel_token, move |message| {
+            if let Err(err) = on_message.send(message_redactor.redact_serialized(&message)) {
+                log::warn!("Failed to deliver gRPC message: {}", err);
+            }
+        })
+    })
+    .await;
+
+    drop(registration);
+    match result {
+        Ok(Ok(response)) => Ok(redactor.redact_serialized(&response)),
+        Ok(Err(RelayError::RequestCancelled)) => Err(RunRequestError::RequestCancelled),
+        Ok(Err(err)) => Err(err.into()),
+        Err(_) => Err(RunRequestError::InternalServerError),
+    }
+}
+
+#[tauri::command]
+pub fn grpc_drop_schema<R: Runtime>(
+    schema_id: usize,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) {
+    state.grpc_schemas.remove(window.label(), schema_id);
+}
+
+/// Connects to an MQTT broker using the relay's TLS, client certificate and
+/// proxy handling.
+///
+/// Messages, acknowledgements and the final disconnect arrive through
+/// `on_event`. A `conn_id` the window already has open is handled according
+/// to the `DuplicateIdPolicy`, replacing disconnects the old connection.
+#[tauri::command]
+pub async fn mqtt_connect<R: Runtime>(
+    conn_id: usize,
+    req: RequestWithMetadata,
+    mut options: MqttOptions,
+    on_event: Channel<serde_json::Value>,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    state
+        .mqtt_connections
+        .check(window.label(), conn_id, state.duplicate_id_policy())?;
+    let (req, redactor) = prepare_mqtt_request(&state, req, &mut options)?;
+    let connection = tauri::async_runtime::spawn_blocking(move || {
+        MqttConnection::connect(&req, &options, move |event| {
+            if let Err(err) = on_event.send(redactor.redact_serialized(&event)) {
+                log::warn!("Failed to deliver MQTT event: {}", err);
+            }
+        })
+    })
+    .await
+    .map_err(|_| RunRequestError::InternalServerError)??;
+
+    state.store(&state.mqtt_connections, &window, conn_id, connection)?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn mqtt_subscribe<R: Runtime>(
+    conn_id: usize,
+    subscriptions: Vec<MqttSubscription>,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<u16, RunRequestError> {
+    let connection = state
+        .mqtt_connections
+        .get(window.label(), conn_id)
+        .ok_or(RunRequestError::ConnectionNotFound(conn_id))?;
+    Ok(connection.subscribe(&subscriptions)?)
+}
+
+#[tauri::command]
+pub fn mqtt_unsubscribe<R: Runtime>(
+    conn_id: usize,
+    topics: Vec<String>,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<u16, RunRequestError> {
+    let connection = state
+        .mqtt_connections
+        .get(window.label(), conn_id)
+        .ok_or(RunRequestError::ConnectionNotFound(conn_id))?;
+    Ok(connection.unsubscribe(&topics)?)
+}
+
+#[tauri::command]
+pub fn mqtt_publish<R: Runtime>(
+    conn_id: usize,
+    message: MqttPublish,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<Option<u16>, RunRequestError> {
+    let connection = state
+        .mqtt_connections
+        .get(window.label(), conn_id)
+        .ok_or(RunRequestError::ConnectionNotFound(conn_id))?;
+    Ok(connection.publish(&message)?)
+}
+
+#[tauri::command]
+pub fn mqtt_disconnect<R: Runtime>(
+    conn_id: usize,
+    window: Window<R>,
+    state: State<'_, InterceptorState>,
+) -> Result<(), RunRequestError> {
+    let connection = state
+        .mqtt_connections
+        .remove(window.label(), conn_id)
+        .ok_or(RunRequestError::ConnectionNotFound(conn_id))?;
+    Ok(connection.disconnect()?)
+}
+
+/// Writes `exchanges` to `path` as a HAR 1.2 file.
+#[tauri::command]
+pub fn export_har(path: String, exchanges: Vec<HarExchange>) -> Result<(), RunRequestError> {
+    let har = postdata_relay::export_har(&exchanges)?;
+    std::fs::write(&path, har).map_err(|err| RunRequestError::FileError(err.to_string()))
+}
+
+/// Reads the requests recorded in the HAR file at `path`.
+#[tauri::command]
+pub fn import_har(path: String) -> Result<Vec<RequestWithMetadata>, RunRequestError> {
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|err| RunRequestError::FileError(err.to_string()))?;
+    Ok(postdata_relay::import_har(&contents)?)
+}
+
+/// Checks `assertions` against a response that was already received, e.g.
+/// one from the history. Requests with assertions get their report in the
+/// response without this.
+#[tauri::command]
+pub fn check_assertions(
+    assertions: Vec<Assertion>,
+    response: ResponseWithMetadata,
+) -> AssertionReport {
+    postdata_relay::evaluate_assertions(&assertions, &response)
+}
+
+/// Substitutes variables in `template` from `scopes`, ordered from least to
+/// most specific, e.g. global, collection, request.
+#[tauri::command]
+pub fn resolve_variables(
+    template: RequestWithMetadata,
+    scopes: Vec<VariableScope>,
+) -> ResolvedRequest {
+    postdata_relay::resolve_request(&template, &scopes)
+}
+
+/// Parses a curl command line. Relative file paths in it are resolved against
+/// `base_dir`, or the current directory when none is given.
+#[tauri::command]
+pub fn import_curl(
+    command: String,
+    base_dir: Option<String>,
+) -> Result<RequestWithMetadata, RunRequestError> {
+    let base_dir = match base_dir {
+        Some(base_dir) => std::path::PathBuf::from(base_dir),
+        None => {
+            std::env::current_dir().map_err(|err| RunRequestError::FileError(err.to_string()))?
         }
     };
-    state.cancellation_tokens.remove(&req_id);
-    result
+    Ok(postdata_relay::import_curl(&command, &base_dir)?)
 }
 
 #[tauri::command]
-pub fn cancel_request(req_id: usize, state: State<'_, InterceptorState>) {
-    if let Some((_, cancel_token)) = state.cancellation_tokens.remove(&req_id) {
-        cancel_token.cancel();
+pub fn export_curl(req: RequestWithMetadata) -> CurlCommand {
+    postdata_relay::export_curl(&req)
+}
+
+#[derive(Debug, Serialize)]
+pub struct MockServerInfo {
+    pub port: u16,
+    /// Requests with saved examples the server answers for.
+    pub routes: usize,
+}
+
+/// Starts the mock server for the workspace in `config`, replacing a running
+/// one. Every request it answers is reported through `on_hit`.
+#[tauri::command]
+pub fn mock_start(
+    config: MockConfig,
+    on_hit: Channel<MockHit>,
+    state: State<'_, InterceptorState>,
+) -> Result<MockServerInfo, RunRequestError> {
+    let mut mock_server = state
+        .mock_server
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)?;
+    // Frees the port first, restarting on the same one is the common case.
+    if let Some(server) = mock_server.take() {
+        server.stop();
+    }
+
+    let server = MockServer::start(config, move |hit| {
+        if let Err(err) = on_hit.send(hit) {
+            log::warn!("Failed to deliver mock server hit: {}", err);
+        }
+    })?;
+    let info = MockServerInfo {
+        port: server.port(),
+        routes: server.routes(),
+    };
+    *mock_server = Some(server);
+    Ok(info)
+}
+
+#[tauri::command]
+pub fn mock_stop(state: State<'_, InterceptorState>) -> Result<(), RunRequestError> {
+    let server = state
+        .mock_server
+        .lock()
+        .map_err(|_| RunRequestError::InternalServerError)?
+        .take();
+    if let Some(server) = server {
+        server.stop();
     }
+    Ok(())
 }
 
 pub fn init<R: Runtime>() -> TauriPlugin<R> {
+    init_with_middleware(Vec::new())
+}
+
+/// Like [`init`], additionally running `middleware` around every request sent
+/// through `run_request`, in order and after the workspace's built-in ones.
+pub fn init_with_middleware<R: Runtime>(
+    middleware: Vec<Arc<dyn RequestMiddleware>>,
+) -> TauriPlugin<R> {
     Builder::new("postdata_native_interceptor")
-        .invoke_handler(tauri::generate_handler![run_request, cancel_request])
-        .setup(|app_handle, _| {
-            app_handle.manage(InterceptorState::default());
+        .invoke_handler(tauri::generate_handler![
+            run_request,
+            cancel_request,
+            active_requests,
+            cancel_all,
+            run_sse,
+            run_batch,
+            cancel_batch,
+            run_load,
+            cancel_load,
+            duplicate_id_policy,
+            set_duplicate_id_policy,
+            network_defaults,
+            set_network_defaults,
+            middleware_config,
+            set_middleware_config,
+            monitor_list,
+            monitor_save,
+            monitor_remove,
+            monitor_pause,
+            monitor_resume,
+            monitor_results,
+            ws_connect,
+            ws_send,
+            ws_close,
+            grpc_load_schema,
+            grpc_call,
+            grpc_drop_schema,
+            mqtt_connect,
+            mqtt_subscribe,
+            mqtt_unsubscribe,
+            mqtt_publish,
+            mqtt_disconnect,
+            export_har,
+            import_har,
+            import_curl,
+            export_curl,
+            mock_start,
+            mock_stop,
+            history_list,
+            history_search,
+            history_get,
+            history_pin,
+            history_prune,
+            cache_entries,
+            cache_clear,
+            resolve_variables,
+            check_assertions,
+            vault_status,
+            vault_create,
+            vault_unlock,
+            vault_lock,
+            vault_change_credential,
+            vault_generate_keyfile,
+            vault_secrets,
+            vault_add_secret,
+            vault_update_secret,
+            vault_remove_secret
+        ])
+        .setup(move |app_handle, _| {
+            let data_dir = app_handle.path().app_data_dir()?;
+            let mut state = InterceptorState::new(EngineConfig::default(), &data_dir)?;
+
+            let settings = AppState::new(app_handle);
+            state.duplicate_id_policy =
+                Mutex::new(settings.get(DUPLICATE_ID_POLICY_KEY, DuplicateIdPolicy::default()));
+            let network_defaults = settings
+                .get(NETWORK_DEFAULTS_KEY, NetworkDefaults::default())
+                .load()
+                .inspect_err(|err| log::error!("Network defaults disabled: {}", err))
+                .unwrap_or_default();
+            state.network_defaults = Mutex::new(network_defaults);
+            state.middleware_configs = Mutex::new(settings.get(MIDDLEWARE_KEY, BTreeMap::new()));
+            state.middleware = middleware;
+            let saved = settings.get(MONITORS_KEY, Vec::<Monitor>::new());
+            app_handle.manage(state);
+
+            // Started once the state is managed, the prepare hook looks it up.
+            let emitter = app_handle.clone();
+            let preparer = app_handle.clone();
+            let monitors = MonitorScheduler::start(
+                &data_dir.join("monitors"),
+                MonitorConfig::default(),
+                saved,
+                move |req| {
+                    let state = preparer.try_state::<InterceptorState>().ok_or_else(|| {
+                        RelayError::RequestRunError("The interceptor is not ready".to_string())
+                    })?;
+                    match prepare_request(&state, req.clone()) {
+                        Ok((req, _)) => Ok(req),
+                        Err(RunRequestError::Relay(err)) => Err(err),
+                        Err(err) => Err(RelayError::RequestRunError(err.to_string())),
+                    }
+                },
+                move |result| {
+                    if result.passed {
+                        return;
+                    }
+                    if let Err(err) = emitter.emit("monitor://failure", result) {
+                        log::warn!("Failed to emit monitor failure: {}", err);
+                    }
+                },
+            )
+            .inspect_err(|err| log::error!("Monitors disabled: {}", err));
+            if let Ok(monitors) = monitors {
+                let _ = app_handle
+                    .state::<InterceptorState>()
+                    .monitors
+                    .set(monitors);
+            }
             // Err("Failed to initialize plugin".into())
             Ok(())
         })
+        .on_event(|app_handle, event| {
+            if let RunEvent::WindowEvent {
+                label,
+                event: WindowEvent::Destroyed,
+                ..
+            } = event
+            {
+                if let Some(state) = app_handle.try_state::<InterceptorState>() {
+                    state.release_window(label);
+                }
+            }
+        })
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_duplicate_ids_by_policy() {
+        let registry = RequestRegistry::new("request");
+        let first = registry
+            .register("main", 1, DuplicateIdPolicy::Reject)
+            .unwrap();
+        assert!(matches!(
+            registry.register("main", 1, DuplicateIdPolicy::Reject),
+            Err(RunRequestError::DuplicateRequestId(1))
+        ));
+        let other_window = registry
+            .register("other", 1, DuplicateIdPolicy::Reject)
+            .unwrap();
+        assert_eq!(other_window.id(), 1);
+
+        let fresh = registry
+            .register("main", 1, DuplicateIdPolicy::FreshId)
+            .unwrap();
+        let fresher = registry
+            .register("main", 1, DuplicateIdPolicy::FreshId)
+            .unwrap();
+        assert_eq!((fresh.id(), fresher.id()), (MAX_FRESH_ID, MAX_FRESH_ID - 1));
+        assert!(!first.cancel_token.is_cancelled());
+
+        let replacement = registry
+            .register("main", 1, DuplicateIdPolicy::CancelPrevious)
+            .unwrap();
+        assert!(first.cancel_token.is_cancelled());
+        assert!(!replacement.cancel_token.is_cancelled());
+        assert!(!other_window.cancel_token.is_cancelled());
+    }
+
+    #[test]
+    fn keeps_resources_by_window_and_policy() {
+        let registry = ResourceRegistry::new("websocket");
+        let first = Arc::new(());
+        registry
+            .insert("main", 1, first.clone(), DuplicateIdPolicy::Reject)
+            .unwrap();
+        assert!(registry
+            .check("other", 1, DuplicateIdPolicy::Reject)
+            .is_ok());
+        assert!(matches!(
+            registry.check("main", 1, DuplicateIdPolicy::Reject),
+            Err(RunRequestError::DuplicateRequestId(1))
+        ));
+        assert!(registry
+            .insert("main", 1, Arc::new(()), DuplicateIdPolicy::Reject)
+            .is_err());
+
+        let fresh = registry
+            .insert("main", 1, Arc::new(()), DuplicateIdPolicy::FreshId)
+            .unwrap();
+        assert_eq!(fresh, MAX_FRESH_ID);
+        assert_eq!(Arc::strong_count(&first), 2);
+
+        // Replacing drops, and thereby closes, the previous connection.
+        registry
+            .insert("main", 1, Arc::new(()), DuplicateIdPolicy::CancelPrevious)
+            .unwrap();
+        assert_eq!(Arc::strong_count(&first), 1);
+
+        registry
+            .insert("other", 1, Arc::new(()), DuplicateIdPolicy::Reject)
+            .unwrap();
+        assert_eq!(registry.remove_window("main").len(), 2);
+        assert!(registry.get("main", 1).is_none());
+        assert!(registry.get("other", 1).is_some());
+        assert!(registry.remove("other", 1).is_some());
+        assert!(registry.entries.is_empty());
+    }
+
+    #[test]
+    fn cancels_by_window() {
+        let registry = RequestRegistry::new("request");
+        let main = registry
+            .register("main", 1, DuplicateIdPolicy::Reject)
+            .unwrap();
+        let other = registry
+            .register("other", 1, DuplicateIdPolicy::Reject)
+            .unwrap();
+
+        registry.cancel_window(Some("main"));
+        assert!(main.cancel_token.is_cancelled());
+        assert!(!other.cancel_token.is_cancelled());
+        assert!(!registry.tokens.contains_key(&("main".to_string(), 1)));
+
+        registry.cancel_window(None);
+        assert!(other.cancel_token.is_cancelled());
+        assert!(registry.tokens.is_empty());
+    }
+
+    #[test]
+    fn dropping_a_replaced_registration_keeps_its_successor() {
+        let registry = RequestRegistry::new("request");
+        let key = ("main".to_string(), 1);
+        let first = registry
+            .register("main", 1, DuplicateIdPolicy::CancelPrevious)
+            .unwrap();
+        let second = registry
+            .register("main", 1, DuplicateIdPolicy::CancelPrevious)
+            .unwrap();
+
+        drop(first);
+        assert!(registry.tokens.contains_key(&key));
+        registry.cancel("main", 1);
+        assert!(second.cancel_token.is_cancelled());
+
+        let third = registry
+            .register("main", 1, DuplicateIdPolicy::Reject)
+            .unwrap();
+        drop(second);
+        assert!(registry.tokens.contains_key(&key));
+        drop(third);
+        assert!(!registry.tokens.contains_key(&key));
+    }
+}
@@ -65,6 +65,61 @@ pub fn run() {
             git::git_new_branch,
             interceptor::run_request,
             interceptor::cancel_request,
+            interceptor::active_requests,
+            interceptor::cancel_all,
+            interceptor::run_sse,
+            interceptor::run_batch,
+            interceptor::cancel_batch,
+            interceptor::run_load,
+            interceptor::cancel_load,
+            interceptor::duplicate_id_policy,
+            interceptor::set_duplicate_id_policy,
+            interceptor::network_defaults,
+            interceptor::set_network_defaults,
+            interceptor::middleware_config,
+            interceptor::set_middleware_config,
+            interceptor::monitor_list,
+            interceptor::monitor_save,
+            interceptor::monitor_remove,
+            interceptor::monitor_pause,
+            interceptor::monitor_resume,
+            interceptor::monitor_results,
+            interceptor::ws_connect,
+            interceptor::ws_send,
+            interceptor::ws_close,
+            interceptor::grpc_load_schema,
+            interceptor::grpc_call,
+            interceptor::grpc_drop_schema,
+            interceptor::mqtt_connect,
+            interceptor::mqtt_subscribe,
+            interceptor::mqtt_unsubscribe,
+            interceptor::mqtt_publish,
+            interceptor::mqtt_disconnect,
+            interceptor::export_har,
+            interceptor::import_har,
+            interceptor::import_curl,
+            interceptor::export_curl,
+            interceptor::mock_start,
+            interceptor::mock_stop,
+            interceptor::history_list,
+            interceptor::history_search,
+            interceptor::history_get,
+            interceptor::history_pin,
+            interceptor::history_prune,
+            interceptor::cache_entries,
+            interceptor::cache_clear,
+            interceptor::resolve_variables,
+            interceptor::check_assertions,
+            interceptor::vault_status,
+            interceptor::vault_create,
+            interceptor::vault_unlock,
+            interceptor::vault_lock,
+            interceptor::vault_change_credential,
+            interceptor::vault_generate_keyfile,
+            interceptor::vault_secrets,
+            interceptor::vault_add_secret,
+            interceptor::vault_update_secret,
+            interceptor::vault_remove_secret,
             menu::change_language,
         ])
         .setup(|app| {